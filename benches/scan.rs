@@ -0,0 +1,85 @@
+//! Compares full table scan throughput with pages read into the cache and with pages read in place
+//! from a memory map, both cold (a new connection per scan) and warm (every scan on one
+//! connection). Run with `cargo bench --bench scan`.
+
+use std::hint::black_box;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use tarsier::{prepare_statement, ExecuteResult, PrepareResult, Table};
+
+const ROWS: u32 = 20_000;
+const SCANS: u32 = 20;
+const MMAP_SIZE: usize = 1 << 30;
+
+fn execute(table: &mut Table, sql: &str) -> ExecuteResult {
+    let PrepareResult::Success(statement) = prepare_statement(sql) else {
+        panic!("{sql} didn't prepare");
+    };
+    table.execute_statement(statement)
+}
+
+/// Scans the table once and checks every row came back.
+fn scan_once(table: &mut Table) {
+    match execute(table, "select") {
+        ExecuteResult::SelectSuccess(rows) => assert_eq!(black_box(rows).len(), ROWS as usize),
+        other => panic!("{other:?}"),
+    }
+}
+
+/// Scans the table `SCANS` times, each on a new connection with nothing cached, so every scan reads
+/// every page from the file. Only the scans are timed.
+fn cold(path: &Path, mmap_size: usize) -> Duration {
+    let mut elapsed = Duration::ZERO;
+    for _ in 0..SCANS {
+        let mut table = Table::open_read_only(path).unwrap();
+        table.set_mmap_size(mmap_size);
+        let start = Instant::now();
+        scan_once(&mut table);
+        elapsed += start.elapsed();
+    }
+    elapsed
+}
+
+/// Scans the table `SCANS` times on one connection after an untimed first scan, so the pager serves
+/// pages from its cache and the map is already made.
+fn warm(path: &Path, mmap_size: usize) -> Duration {
+    let mut table = Table::open_read_only(path).unwrap();
+    table.set_mmap_size(mmap_size);
+    scan_once(&mut table);
+    let start = Instant::now();
+    for _ in 0..SCANS {
+        scan_once(&mut table);
+    }
+    start.elapsed()
+}
+
+fn main() {
+    let path = std::env::temp_dir().join(format!("tarsier-bench-scan-{}.db", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let mut table = Table::open(&path);
+    for id in 0..ROWS {
+        let result = execute(
+            &mut table,
+            &format!("insert {id} user{id} user{id}@example.com"),
+        );
+        assert_eq!(result, ExecuteResult::InsertSuccess);
+    }
+    table.close();
+    drop(table);
+
+    // Once untimed, to warm the OS page cache
+    cold(&path, 0);
+    for (run, scan) in [
+        ("cold", cold as fn(&Path, usize) -> Duration),
+        ("warm", warm),
+    ] {
+        for (name, mmap_size) in [("pager", 0), ("mmap", MMAP_SIZE)] {
+            let elapsed = scan(&path, mmap_size);
+            let rows = f64::from(ROWS * SCANS) / elapsed.as_secs_f64();
+            println!("{run} {name:>5}: {elapsed:>10.2?} for {SCANS} scans, {rows:>12.0} rows/s");
+        }
+    }
+
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,357 @@
+use std::collections::HashMap;
+
+use crate::spill::{Merge, Run, SpillStore};
+use crate::value::Value;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AggregateFunction {
+    CountStar,
+    Count,
+    Sum,
+    Avg,
+    Min,
+    Max,
+}
+
+impl AggregateFunction {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "count" => Some(AggregateFunction::Count),
+            "sum" => Some(AggregateFunction::Sum),
+            "avg" => Some(AggregateFunction::Avg),
+            "min" => Some(AggregateFunction::Min),
+            "max" => Some(AggregateFunction::Max),
+            _ => None,
+        }
+    }
+
+    /// Number of values an accumulator of this function takes up in a spilled record.
+    fn state_width(&self) -> usize {
+        match self {
+            AggregateFunction::Avg => 2,
+            _ => 1,
+        }
+    }
+}
+
+/// Running state of one aggregate function within one group.
+///
+/// States are mergeable, so partial aggregates that were spilled separately can be combined.
+#[derive(Debug, Clone)]
+pub enum Accumulator {
+    Count(i64),
+    /// NULL until the first non-NULL input. Integer sums fall back to reals on overflow.
+    Sum(Value),
+    Avg {
+        sum: f64,
+        count: i64,
+    },
+    Min(Value),
+    Max(Value),
+}
+
+impl Accumulator {
+    pub fn new(function: AggregateFunction) -> Self {
+        match function {
+            AggregateFunction::CountStar | AggregateFunction::Count => Accumulator::Count(0),
+            AggregateFunction::Sum => Accumulator::Sum(Value::Null),
+            AggregateFunction::Avg => Accumulator::Avg { sum: 0.0, count: 0 },
+            AggregateFunction::Min => Accumulator::Min(Value::Null),
+            AggregateFunction::Max => Accumulator::Max(Value::Null),
+        }
+    }
+
+    /// Folds one input into the state. `count(*)` is fed NULLs, so it has to be told apart.
+    pub fn update(&mut self, function: AggregateFunction, arg: &Value) {
+        if arg.is_null() {
+            if let Accumulator::Count(n) = self {
+                if function == AggregateFunction::CountStar {
+                    *n += 1;
+                }
+            }
+            return;
+        }
+        match self {
+            Accumulator::Count(n) => *n += 1,
+            Accumulator::Sum(sum) => *sum = add(sum, arg),
+            Accumulator::Avg { sum, count } => {
+                *sum += numeric(arg);
+                *count += 1;
+            }
+            Accumulator::Min(min) => {
+                if min.is_null() || arg < min {
+                    *min = arg.clone();
+                }
+            }
+            Accumulator::Max(max) => {
+                if max.is_null() || arg > max {
+                    *max = arg.clone();
+                }
+            }
+        }
+    }
+
+    pub fn merge(&mut self, other: Accumulator) {
+        match (self, other) {
+            (Accumulator::Count(a), Accumulator::Count(b)) => *a += b,
+            (Accumulator::Sum(a), Accumulator::Sum(b)) => {
+                if !b.is_null() {
+                    *a = add(a, &b);
+                }
+            }
+            (
+                Accumulator::Avg { sum, count },
+                Accumulator::Avg {
+                    sum: other_sum,
+                    count: other_count,
+                },
+            ) => {
+                *sum += other_sum;
+                *count += other_count;
+            }
+            (Accumulator::Min(a), Accumulator::Min(b)) => {
+                if a.is_null() || (!b.is_null() && b < *a) {
+                    *a = b;
+                }
+            }
+            (Accumulator::Max(a), Accumulator::Max(b)) => {
+                if a.is_null() || b > *a {
+                    *a = b;
+                }
+            }
+            (a, b) => panic!("Can't merge {a:?} with {b:?}"),
+        }
+    }
+
+    pub fn finish(&self) -> Value {
+        match self {
+            Accumulator::Count(n) => Value::Integer(*n),
+            Accumulator::Sum(sum) => sum.clone(),
+            Accumulator::Avg { count: 0, .. } => Value::Null,
+            Accumulator::Avg { sum, count } => Value::Real(sum / *count as f64),
+            Accumulator::Min(v) | Accumulator::Max(v) => v.clone(),
+        }
+    }
+
+    fn save(&self, record: &mut Vec<Value>) {
+        match self {
+            Accumulator::Count(n) => record.push(Value::Integer(*n)),
+            Accumulator::Avg { sum, count } => {
+                record.push(Value::Real(*sum));
+                record.push(Value::Integer(*count));
+            }
+            Accumulator::Sum(v) | Accumulator::Min(v) | Accumulator::Max(v) => {
+                record.push(v.clone())
+            }
+        }
+    }
+
+    fn restore(function: AggregateFunction, state: &[Value]) -> Self {
+        match (function, state) {
+            (AggregateFunction::CountStar | AggregateFunction::Count, [Value::Integer(n)]) => {
+                Accumulator::Count(*n)
+            }
+            (AggregateFunction::Sum, [v]) => Accumulator::Sum(v.clone()),
+            (AggregateFunction::Avg, [Value::Real(sum), Value::Integer(count)]) => {
+                Accumulator::Avg {
+                    sum: *sum,
+                    count: *count,
+                }
+            }
+            (AggregateFunction::Min, [v]) => Accumulator::Min(v.clone()),
+            (AggregateFunction::Max, [v]) => Accumulator::Max(v.clone()),
+            _ => panic!("Corrupt spilled state for {function:?}: {state:?}"),
+        }
+    }
+}
+
+fn numeric(v: &Value) -> f64 {
+    match v {
+        Value::Text(s) => s.trim().parse().unwrap_or(0.0),
+        v => v.as_f64().unwrap_or(0.0),
+    }
+}
+
+fn add(sum: &Value, v: &Value) -> Value {
+    match (sum, v) {
+        (Value::Null, Value::Integer(_) | Value::Real(_)) => v.clone(),
+        (Value::Null, v) => Value::Real(numeric(v)),
+        (Value::Integer(a), Value::Integer(b)) => match a.checked_add(*b) {
+            Some(s) => Value::Integer(s),
+            None => Value::Real(*a as f64 + *b as f64),
+        },
+        (a, b) => Value::Real(numeric(a) + numeric(b)),
+    }
+}
+
+/// Hash aggregation that spills partial aggregates once it holds too many groups.
+///
+/// When the table is full, its groups are written out as a run sorted by group key and the table
+/// starts over. At the end the runs are merged, combining the partial states of equal keys, so
+/// memory stays bounded by the group limit no matter how many distinct groups there are. Groups
+/// come out in key order either way.
+pub struct HashAggregate {
+    functions: Vec<AggregateFunction>,
+    groups: HashMap<Vec<Value>, Vec<Accumulator>>,
+    max_groups: usize,
+    spill: Option<SpillStore>,
+    runs: Vec<Run>,
+}
+
+impl HashAggregate {
+    pub fn new(functions: Vec<AggregateFunction>, max_groups: usize) -> Self {
+        Self {
+            functions,
+            groups: HashMap::new(),
+            max_groups: max_groups.max(1),
+            spill: None,
+            runs: Vec::new(),
+        }
+    }
+
+    /// Makes sure a group exists even if no row ever lands in it, like the single group of an
+    /// aggregate query without GROUP BY.
+    pub fn add_group(&mut self, key: Vec<Value>) {
+        self.group(key);
+    }
+
+    /// Adds a row to its group. `args` holds one input per aggregate function.
+    pub fn update(&mut self, key: Vec<Value>, args: &[Value]) {
+        let functions = self.functions.clone();
+        let accumulators = self.group(key);
+        for ((acc, function), arg) in accumulators.iter_mut().zip(functions).zip(args) {
+            acc.update(function, arg);
+        }
+    }
+
+    fn group(&mut self, key: Vec<Value>) -> &mut Vec<Accumulator> {
+        if !self.groups.contains_key(&key) && self.groups.len() >= self.max_groups {
+            self.spill_groups();
+        }
+        let functions = &self.functions;
+        self.groups
+            .entry(key)
+            .or_insert_with(|| functions.iter().map(|f| Accumulator::new(*f)).collect())
+    }
+
+    fn sorted_groups(&mut self) -> Vec<(Vec<Value>, Vec<Accumulator>)> {
+        let mut groups: Vec<_> = self.groups.drain().collect();
+        groups.sort_by(|a, b| a.0.cmp(&b.0));
+        groups
+    }
+
+    fn spill_groups(&mut self) {
+        let groups = self.sorted_groups();
+        let store = self.spill.get_or_insert_with(SpillStore::new);
+        let mut writer = store.writer();
+        for (key, accumulators) in groups {
+            let mut record = key;
+            for acc in &accumulators {
+                acc.save(&mut record);
+            }
+            writer.write(&record);
+        }
+        self.runs.push(writer.finish());
+    }
+
+    /// Finishes the aggregation, returning every group's key and aggregate results in key order.
+    pub fn finish(mut self) -> Vec<(Vec<Value>, Vec<Value>)> {
+        let finish =
+            |accumulators: &[Accumulator]| accumulators.iter().map(|a| a.finish()).collect();
+        if self.runs.is_empty() {
+            return self
+                .sorted_groups()
+                .into_iter()
+                .map(|(key, accumulators)| (key, finish(&accumulators)))
+                .collect();
+        }
+
+        self.spill_groups();
+        let state_width: usize = self.functions.iter().map(|f| f.state_width()).sum();
+        let store = self.spill.take().unwrap();
+        let readers = self.runs.drain(..).map(|run| store.reader(run)).collect();
+        let key_len = |record: &Vec<Value>| record.len() - state_width;
+        let merge = Merge::new(readers, |a: &Vec<Value>, b: &Vec<Value>| {
+            a[..key_len(a)].cmp(&b[..key_len(b)])
+        });
+
+        let mut results: Vec<(Vec<Value>, Vec<Value>)> = Vec::new();
+        let mut current: Option<(Vec<Value>, Vec<Accumulator>)> = None;
+        for mut record in merge {
+            let state = record.split_off(key_len(&record));
+            let mut pos = 0;
+            let accumulators: Vec<Accumulator> = self
+                .functions
+                .iter()
+                .map(|f| {
+                    let acc = Accumulator::restore(*f, &state[pos..pos + f.state_width()]);
+                    pos += f.state_width();
+                    acc
+                })
+                .collect();
+            match current {
+                Some((ref key, ref mut merged)) if *key == record => {
+                    for (m, acc) in merged.iter_mut().zip(accumulators) {
+                        m.merge(acc);
+                    }
+                }
+                _ => {
+                    if let Some((key, merged)) = current.take() {
+                        results.push((key, finish(&merged)));
+                    }
+                    current = Some((record, accumulators));
+                }
+            }
+        }
+        if let Some((key, merged)) = current {
+            results.push((key, finish(&merged)));
+        }
+        results
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::aggregate::{AggregateFunction, HashAggregate};
+    use crate::value::Value;
+
+    fn aggregate(max_groups: usize) -> Vec<(Vec<Value>, Vec<Value>)> {
+        let mut agg = HashAggregate::new(
+            vec![
+                AggregateFunction::CountStar,
+                AggregateFunction::Sum,
+                AggregateFunction::Avg,
+                AggregateFunction::Min,
+                AggregateFunction::Max,
+            ],
+            max_groups,
+        );
+        for i in 0..1000_i64 {
+            let v = Value::Integer(i);
+            agg.update(
+                vec![Value::Integer(i % 7)],
+                &[Value::Null, v.clone(), v.clone(), v.clone(), v],
+            );
+        }
+        agg.finish()
+    }
+
+    #[test]
+    fn spilled_groups_match_in_memory() {
+        let in_memory = aggregate(100);
+        assert_eq!(in_memory.len(), 7);
+        assert_eq!(in_memory[0].0, vec![Value::Integer(0)]);
+        assert_eq!(
+            in_memory[0].1,
+            vec![
+                Value::Integer(143),
+                Value::Integer(71071),
+                Value::Real(497.0),
+                Value::Integer(0),
+                Value::Integer(994),
+            ]
+        );
+        // Room for only two groups at a time forces a spill nearly every row
+        assert_eq!(aggregate(2), in_memory);
+    }
+}
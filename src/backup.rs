@@ -0,0 +1,163 @@
+use std::io;
+use std::path::Path;
+
+use crate::catalog;
+use crate::datastore::Table;
+use crate::pager::{Offset, Pager};
+use crate::trace::{event, Level};
+use crate::vfs::{DiskVfs, Lock};
+
+/// The header is always the first page.
+const HEADER_PAGE: Offset = Offset(0);
+
+/// A copy of a database being made while it stays in use. Each step copies a few pages of the
+/// file as of its last commit, holding a shared lock only for that long, so other connections
+/// can write between steps. If one commits, the next step notices from the header's change
+/// counter and starts over. The copy is written out in one commit once the last page is in, so a
+/// backup that never finishes leaves the destination as it was.
+pub struct Backup {
+    destination: Pager,
+    /// The next page to copy.
+    next: usize,
+    /// How many pages the source had when the copy started.
+    page_count: usize,
+    /// The source's change counter when the copy started, or `None` before the first step.
+    change_counter: Option<u64>,
+}
+
+impl Backup {
+    /// Starts backing `source` up to the file at `path`, which is replaced once the backup is
+    /// done. Fails if the file has a different page size, or if another connection is writing to
+    /// it for longer than the busy timeout.
+    pub fn new(source: &Table, path: impl AsRef<Path>) -> io::Result<Self> {
+        let page_size = source.pager().page_size();
+        let destination = Pager::with_vfs(Box::new(DiskVfs::open(&path)?), page_size)?;
+        if destination.page_size() != page_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} has {}-byte pages, but the database has {page_size}-byte pages",
+                    path.as_ref().display(),
+                    destination.page_size()
+                ),
+            ));
+        }
+        destination.lock(Lock::Reserved)?;
+        Ok(Self {
+            destination,
+            next: 0,
+            page_count: 0,
+            change_counter: None,
+        })
+    }
+
+    /// Copies up to `pages` more pages, starting over if the source has committed since the last
+    /// step. Returns whether the backup is done, in which case the destination has been written.
+    pub fn step(&mut self, source: &mut Table, pages: usize) -> io::Result<bool> {
+        source.begin(Lock::Shared)?;
+        let result = self.copy(source.pager(), pages);
+        source.end();
+        result
+    }
+
+    /// How many pages are left to copy.
+    pub fn remaining(&self) -> usize {
+        self.page_count - self.next
+    }
+
+    /// How many pages the copy has in all.
+    pub fn page_count(&self) -> usize {
+        self.page_count
+    }
+
+    fn copy(&mut self, source: &Pager, pages: usize) -> io::Result<bool> {
+        // Straight from the file, so that changes the source hasn't committed are left out
+        let header = source.read_page(&HEADER_PAGE);
+        let change_counter = catalog::change_counter(header.as_bytes());
+        if self.change_counter != Some(change_counter) {
+            if self.change_counter.is_some() {
+                event!(Level::Info, "backup_restart", copied = self.next);
+            }
+            self.change_counter = Some(change_counter);
+            self.page_count = source.file_pages()?;
+            self.next = 0;
+        }
+        let end = self.page_count.min(self.next.saturating_add(pages));
+        for page in self.next..end {
+            let offset = Offset(page);
+            self.destination
+                .commit_page(offset, source.read_page(&offset));
+        }
+        self.next = end;
+        if self.next < self.page_count {
+            return Ok(false);
+        }
+        self.destination.truncate(self.page_count);
+        self.destination.lock(Lock::Exclusive)?;
+        self.destination.flush()?;
+        self.destination.remove_journal()?;
+        self.destination.unlock(Lock::Unlocked);
+        event!(Level::Info, "backup_done", pages = self.page_count);
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cursor::Scan;
+    use crate::datastore::TABLE_NAME;
+    use crate::fixtures::{insert, row, temp_path};
+    use crate::{Backup, Table};
+
+    #[test]
+    fn backups_start_over_when_the_source_commits() {
+        let (path, copy) = (temp_path("backup-source"), temp_path("backup-copy"));
+        let mut source = Table::open(&path);
+        insert(&mut source, 0..300);
+        source.commit().unwrap();
+
+        let mut backup = Backup::new(&source, &copy).unwrap();
+        assert!(!backup.step(&mut source, 3).unwrap());
+        let before = backup.page_count();
+        assert_eq!(backup.remaining(), before - 3);
+
+        // Another connection commits part way through
+        let mut writer = Table::open(&path);
+        insert(&mut writer, 300..600);
+        writer.commit().unwrap();
+        drop(writer);
+        assert!(!backup.step(&mut source, 3).unwrap());
+        assert!(backup.page_count() > before);
+        assert_eq!(backup.remaining(), backup.page_count() - 3);
+
+        // Changes the source hasn't committed stay out of the copy
+        insert(&mut source, 600..700);
+        while !backup.step(&mut source, 3).unwrap() {}
+        assert_eq!(backup.remaining(), 0);
+
+        let copied = Table::open(&copy);
+        assert!(copied.integrity_check().is_empty());
+        assert_eq!(
+            Scan::new(&copied.trees()[TABLE_NAME])
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap(),
+            (0..600).map(row).collect::<Vec<_>>()
+        );
+
+        // The whole thing in one go, over the earlier copy
+        source.commit().unwrap();
+        source.backup(&copy).unwrap();
+        let copied = Table::open(&copy);
+        assert!(copied.integrity_check().is_empty());
+        assert_eq!(
+            Scan::new(&copied.trees()[TABLE_NAME])
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap(),
+            (0..700).map(row).collect::<Vec<_>>()
+        );
+        source.close();
+        for file in [&path, &copy] {
+            std::fs::remove_file(file).unwrap();
+        }
+    }
+}
@@ -1,170 +1,500 @@
+use std::sync::Arc;
+
+use crate::bulk_load::{BulkLoadResult, BulkLoader};
 use crate::cursor::Cursor;
-use crate::node::{InsertResult, Node, SplitEntry, MAX_INTERNAL_NODES, MAX_LEAF_NODES};
+use crate::node::{
+    max_internal_separators, max_leaf_cells, InsertResult, Node, NodeView, SplitEntry,
+};
 use crate::node_type::{InternalNode, KeyValuePair, LeafNode, NodeType};
-use crate::pager::{Offset, Pager};
+use crate::pager::{CorruptPage, HasOffset, Offset, PageLatch, Pager, Snapshot};
+use crate::trace::{event, Level};
 use crate::Row;
 
-pub const NODE_SIZE: usize = 4096;
 pub const NODE_TYPE_OFFSET: usize = 0;
 pub const IS_ROOT_OFFSET: usize = 1;
 pub const PARENT_OFFSET: usize = 2;
-pub const NUM_CELLS_OFFSET: usize = 6;
+pub const NUM_CELLS_OFFSET: usize = 10;
+pub const NEXT_LEAF_OFFSET: usize = 14;
+pub const LAST_LEAF_OFFSET: usize = 22;
 pub const CELL_KEY_SIZE: usize = 4;
 pub const CELL_VALUE_SIZE: usize = 291;
-pub const CELL_OFFSET: usize = 10;
+pub const CELL_OFFSET: usize = 30;
 pub const CELL_SIZE: usize = CELL_VALUE_SIZE + CELL_KEY_SIZE;
 
-#[derive(Debug)]
+/// A B-tree of rows keyed by id. Any number of threads may read it while one writes to it:
+/// readers latch each page on the way down before letting go of its parent, and the writer
+/// latches every page it might change, from the lowest one that won't have to split. A tree read
+/// through a snapshot needs no latches, since the pages it reads never change.
+///
+/// When the pager copies on write, a write first copies every page on its way down that an
+/// earlier commit wrote, so the root moves to a new page. Parent and sibling pointers aren't kept
+/// up to date then, and scans find each next leaf from the root instead.
+///
+/// Reading a corrupt page is an error that names the page. A write that runs into one may have
+/// changed other pages already, so the changes since the last commit are best thrown away.
+#[derive(Debug, Clone)]
 pub struct BTree {
     root: Offset,
-    pager: Pager,
-    is_empty: bool,
+    pager: Arc<Pager>,
+    snapshot: Option<Arc<Snapshot>>,
 }
 
 impl BTree {
-    pub fn new(mut pager: Pager) -> Self {
+    /// Opens a tree that has a file all to itself, rooted at the first page.
+    pub fn new(pager: Pager) -> Self {
+        let pager = Arc::new(pager);
         if pager.num_pages() == 0 {
-            let mut root_node = pager.get(&Offset(0));
+            let mut root_node = Node::leaf();
             root_node.is_root = true;
+            root_node.offset = Offset(0);
             pager.commit(&root_node);
             Self {
                 root: Offset(0),
                 pager,
-                is_empty: true,
+                snapshot: None,
             }
         } else {
-            let root = pager.get(&Offset(0));
+            Self::open(pager, Offset(0))
+        }
+    }
 
-            Self {
-                root: Offset(0),
-                pager,
-                is_empty: root.num_cells > 0,
-            }
+    /// Opens an existing tree rooted at `root`, sharing the pager with the file's other trees.
+    pub fn open(pager: Arc<Pager>, root: Offset) -> Self {
+        Self {
+            root,
+            pager,
+            snapshot: None,
         }
     }
 
-    pub fn get(&self, offset: &Offset, cell_num: usize) -> Option<Row> {
-        let node = self.pager.get(offset);
-        match node.node_type {
-            NodeType::Leaf(LeafNode { children, .. }) => {
-                children.get(cell_num).map(|kv| kv.value.clone())
-            }
-            _ => panic!("Can't retrive a row from an internal node"),
+    /// The same tree as it was when the snapshot was taken, for reading only.
+    pub fn as_of(&self, snapshot: &Arc<Snapshot>) -> Self {
+        Self {
+            snapshot: Some(snapshot.clone()),
+            ..self.clone()
         }
     }
 
-    pub fn insert(&mut self, key: usize, value: Row) -> bool {
-        let SplitEntry { separator, tree } = match self._insert(&self.root.clone(), key, value) {
-            InsertResult::Success => {
-                self.is_empty = false;
-                return true;
-            }
-            InsertResult::DuplicateKey => return false,
+    /// Reads one of the tree's nodes in place, as of its snapshot if it has one.
+    fn view(&self, offset: &Offset) -> Result<NodeView, CorruptPage> {
+        match &self.snapshot {
+            Some(snapshot) => snapshot.view(offset),
+            None => self.pager.view(offset),
+        }
+    }
+
+    /// Latches a page for reading, unless the tree is read through a snapshot.
+    fn latch(&self, offset: Offset) -> Option<PageLatch<'_>> {
+        match self.snapshot {
+            Some(_) => None,
+            None => Some(self.pager.latch_shared(offset)),
+        }
+    }
+
+    /// Creates an empty tree on a freshly allocated root page.
+    pub fn create(pager: Arc<Pager>) -> Self {
+        let root = pager.new_page();
+        let mut root_node = Node::leaf();
+        root_node.is_root = true;
+        root_node.offset = root;
+        pager.commit(&root_node);
+        Self::open(pager, root)
+    }
+
+    pub fn get(&self, offset: &Offset, cell_num: usize) -> Result<Option<Row>, CorruptPage> {
+        let leaf = self.view(offset)?;
+        if !leaf.is_leaf() {
+            return Err(CorruptPage(*offset));
+        }
+        (cell_num < leaf.num_cells())
+            .then(|| leaf.value(cell_num))
+            .transpose()
+    }
+
+    /// Returns false if the key is already in the tree, or if the file can't grow by the pages
+    /// the insert might need.
+    pub fn insert(&mut self, key: usize, value: Row) -> Result<bool, CorruptPage> {
+        if self.pager.pages_left() < self.pages_to_insert(key)? {
+            return Ok(false);
+        }
+        self.shadow(key)?;
+        // Held until the root split below is done, if there is one
+        let mut latches = Vec::new();
+        let SplitEntry {
+            separator,
+            mut tree,
+        } = match self._insert(&self.root(), key, value, &mut latches)? {
+            InsertResult::Success => return Ok(true),
+            InsertResult::DuplicateKey => return Ok(false),
             InsertResult::ParentSplit(x) => x,
         };
         //infamous root split case
-        let mut root_node = self.pager.get(&self.root);
-        let grow_tree = if let NodeType::Leaf(_) = root_node.node_type {
-            true
-        } else {
-            root_node.num_cells >= MAX_INTERNAL_NODES
+        // The root always lives at the same page, so its (already split) contents move out to a
+        // fresh page and the root becomes an internal node over the two halves.
+        let mut left = self.pager.get(&self.root)?;
+        left.offset = self.pager.new_page();
+        left.is_root = false;
+        left.parent_offset = Some(self.root);
+        tree.parent_offset = Some(self.root);
+        if let NodeType::Leaf(..) = left.node_type {
+            if !self.pager.copy_on_write() {
+                tree.set_last_leaf(Some(left.offset));
+            }
+        }
+        self.adopt_children(&left)?;
+
+        event!(
+            Level::Debug,
+            "root_split",
+            root = self.root.0,
+            left = left.offset.0,
+            right = tree.offset.0,
+            separator = separator,
+        );
+        let mut new_root =
+            Node::internal_with_separators(vec![separator], vec![left.offset, tree.offset]);
+        new_root.is_root = true;
+        new_root.offset = self.root;
+
+        self.pager.commit(&left);
+        self.pager.commit(&tree);
+        self.pager.commit(&new_root);
+        Ok(true)
+    }
+    /// Removes a key, returning whether it was there. Leaves aren't merged afterwards, so a leaf
+    /// can be left empty until later inserts fill it again.
+    pub fn delete(&mut self, key: usize) -> Result<bool, CorruptPage> {
+        if self.find(key)?.is_err() {
+            return Ok(false);
+        }
+        self.shadow(key)?;
+        let Ok(cursor) = self.find(key)? else {
+            unreachable!("copying pages doesn't lose keys")
         };
-        if grow_tree {
-            // root is either a leaf node and we're making it an internal
-            // or its internal and we're splitting it up
-            let mut new_root = Node::internal();
-            new_root.is_root = true;
-            new_root.offset = Offset(0);
-            root_node.offset = self.pager.new_page();
-            root_node.is_root = false;
-            if let NodeType::Internal(InternalNode {
-                ref mut separators,
-                ref mut children,
-            }) = new_root.node_type
-            {
-                separators.push(separator);
-                children.push(root_node.offset);
-                children.push(tree.offset);
+        let _latch = self.pager.latch_exclusive(*cursor.offset());
+        let mut node = self.pager.get(cursor.offset())?;
+        if let NodeType::Leaf(LeafNode {
+            ref mut children, ..
+        }) = node.node_type
+        {
+            children.remove(cursor.cell_num());
+        }
+        node.num_cells -= 1;
+        self.pager.commit(&node);
+        Ok(true)
+    }
+
+    /// Builds a new tree out of rows that are sorted by id, bottom-up like [`BTree::bulk_load`].
+    /// The root is left wherever the top node was built, so no page is left over.
+    pub fn build(
+        pager: Arc<Pager>,
+        rows: impl IntoIterator<Item = Row>,
+        fill_factor: f64,
+    ) -> Result<Self, BulkLoadResult> {
+        let mut loader = BulkLoader::new(&pager, fill_factor);
+        for row in rows {
+            if let Err(error) = loader.push(row.id as usize, row) {
+                loader.abandon();
+                return Err(error);
+            }
+        }
+        match loader.finish_new() {
+            Ok(Some(root)) => Ok(Self::open(pager, root)),
+            Ok(None) => Ok(Self::create(pager)),
+            Err(corrupt) => Err(BulkLoadResult::Corrupt(corrupt)),
+        }
+    }
+
+    /// Fills an empty tree with rows that are sorted by id, building it bottom-up instead of
+    /// inserting them one at a time. `fill_factor` is how full to pack each page, as a fraction.
+    pub fn bulk_load(
+        &mut self,
+        rows: impl IntoIterator<Item = Row>,
+        fill_factor: f64,
+    ) -> BulkLoadResult {
+        match self.is_empty() {
+            Ok(true) => {}
+            Ok(false) => return BulkLoadResult::NotEmpty,
+            Err(corrupt) => return BulkLoadResult::Corrupt(corrupt),
+        }
+        // The root is filled in last, in place
+        if let Err(corrupt) = self.shadow(0) {
+            return BulkLoadResult::Corrupt(corrupt);
+        }
+        let mut loader = BulkLoader::new(&self.pager, fill_factor);
+        for row in rows {
+            if let Err(error) = loader.push(row.id as usize, row) {
+                loader.abandon();
+                return error;
+            }
+        }
+        match loader.finish(self.root) {
+            Ok(rows) => BulkLoadResult::Success(rows),
+            Err(corrupt) => BulkLoadResult::Corrupt(corrupt),
+        }
+    }
+
+    /// When copying on write, copies every page on the way down to `k` that an earlier commit
+    /// wrote, pointing each parent at its child's copy and moving the root to its copy. The pages
+    /// the copies replace are freed when the commit is made. Afterwards the path to `k` can be
+    /// changed in place.
+    fn shadow(&mut self, k: usize) -> Result<(), CorruptPage> {
+        if !self.pager.copy_on_write() {
+            return Ok(());
+        }
+        let mut parent: Option<(Offset, usize)> = None;
+        let mut offset = self.root;
+        loop {
+            let mut node = self.pager.view(&offset)?;
+            if !self.pager.is_fresh(offset) {
+                let mut copy = node.to_node()?;
+                copy.offset = self.pager.new_page();
+                self.pager.retire(offset);
+                self.pager.commit(&copy);
+                event!(
+                    Level::Debug,
+                    "page_copy",
+                    page = offset.0,
+                    copy = copy.offset.0,
+                );
+                match parent {
+                    Some((parent, child)) => {
+                        let mut parent = self.pager.get(&parent)?;
+                        if let NodeType::Internal(InternalNode {
+                            ref mut children, ..
+                        }) = parent.node_type
+                        {
+                            children[child] = copy.offset;
+                        }
+                        self.pager.commit(&parent);
+                    }
+                    None => self.root = copy.offset,
+                }
+                node = self.pager.view(&copy.offset)?;
+            }
+            if node.is_leaf() {
+                return Ok(());
+            }
+            let child = node.child_index(k);
+            offset = node.child(child);
+            parent = Some((node.offset(), child));
+        }
+    }
+
+    /// How many new pages inserting `key` takes: one for each full node that splits, from the
+    /// leaf up to the first node with room, and one more if the root splits too. Copying on
+    /// write takes one more for each page on the way down that has to be copied.
+    fn pages_to_insert(&self, key: usize) -> Result<usize, CorruptPage> {
+        let mut full = Vec::new();
+        let mut copies = 0;
+        let mut node = self.pager.view(&self.root)?;
+        loop {
+            if self.pager.copy_on_write() && !self.pager.is_fresh(node.offset()) {
+                copies += 1;
             }
-            self.pager.commit(&new_root);
-            self.pager.commit(&root_node);
-            self.pager.commit(&tree);
+            if node.is_leaf() {
+                full.push(node.num_cells() >= max_leaf_cells(self.pager.page_size()));
+                break;
+            }
+            full.push(node.num_separators() >= max_internal_separators(self.pager.page_size()));
+            node = self.pager.view(&node.child(node.child_index(key)))?;
+        }
+        let splits = full.iter().rev().take_while(|&&full| full).count();
+        if splits == full.len() {
+            Ok(copies + splits + 1)
         } else {
-            if let NodeType::Internal(InternalNode {
-                ref mut separators,
-                ref mut children,
-            }) = root_node.node_type
-            {
-                separators.push(separator);
-                children.push(tree.offset);
+            Ok(copies + splits)
+        }
+    }
+
+    /// Points the parent pointers of an internal node's children at it, after they've moved.
+    /// Copying on write leaves them be, since the children may be shared with a snapshot.
+    fn adopt_children(&self, node: &Node<usize, Row>) -> Result<(), CorruptPage> {
+        if self.pager.copy_on_write() {
+            return Ok(());
+        }
+        if let NodeType::Internal(InternalNode { ref children, .. }) = node.node_type {
+            for offset in children {
+                if self.pager.view(offset)?.parent_offset() != Some(node.offset) {
+                    let mut child = self.pager.get(offset)?;
+                    child.parent_offset = Some(node.offset);
+                    self.pager.commit(&child);
+                }
             }
-            self.pager.commit(&root_node)
         }
-        self.is_empty = false;
-        true
+        Ok(())
     }
+
     pub fn root(&self) -> Offset {
-        self.root.clone()
+        self.root
     }
 
-    pub fn is_empty(&self) -> bool {
-        self.is_empty
+    /// Every page the tree is made of, the root first.
+    pub fn pages(&self) -> Result<Vec<Offset>, CorruptPage> {
+        let mut pages = Vec::new();
+        let mut stack = vec![self.root];
+        while let Some(offset) = stack.pop() {
+            pages.push(offset);
+            stack.extend(self.view(&offset)?.children());
+        }
+        Ok(pages)
     }
 
-    pub fn advance_cursor(&self, cursor: &mut Cursor) {
-        let node = self.pager.get(cursor.offset());
-        match node.node_type {
-            NodeType::Internal(..) => panic!("Cursors shouldn't point at internal nodes"),
-            NodeType::Leaf(LeafNode {
-                children,
-                next_leaf,
-                ..
-            }) => {
-                if children.len() - 1 > cursor.cell_num() {
-                    cursor.cell_num += 1;
-                    cursor.end_of_table =
-                        next_leaf.is_none() && cursor.cell_num == children.len() - 1
-                } else {
-                    match next_leaf {
-                        Some(next) => {
-                            cursor.offset = next.clone();
-                            cursor.cell_num = 0;
-                            cursor.end_of_table = false;
-                        }
-                        None => cursor.end_of_table = true,
+    /// Whether the root is a leaf with no rows. Leaves that deletes emptied below an internal
+    /// root don't count.
+    pub fn is_empty(&self) -> Result<bool, CorruptPage> {
+        let root = self.view(&self.root)?;
+        Ok(root.num_cells() == 0 && root.is_leaf())
+    }
+
+    pub fn advance_cursor(&self, cursor: &mut Cursor) -> Result<(), CorruptPage> {
+        cursor.cell_num += 1;
+        self.settle_cursor(cursor, &mut None)
+    }
+
+    /// Like [`BTree::advance_cursor`], for a cursor whose leaf is latched. Each leaf it moves to
+    /// is latched before the one it leaves is let go.
+    pub fn advance_latched_cursor<'a>(
+        &'a self,
+        cursor: &mut Cursor,
+        latch: &mut Option<PageLatch<'a>>,
+    ) -> Result<(), CorruptPage> {
+        cursor.cell_num += 1;
+        self.settle_cursor(cursor, latch)
+    }
+
+    /// Moves a cursor that's past the last cell of its leaf on to the next row, stepping over
+    /// any leaves that deletes have emptied. If its leaf is latched, so is each one it moves to.
+    fn settle_cursor<'a>(
+        &'a self,
+        cursor: &mut Cursor,
+        latch: &mut Option<PageLatch<'a>>,
+    ) -> Result<(), CorruptPage> {
+        // The key the next leaf's keys start at, when copying on write
+        let mut floor = None;
+        loop {
+            let leaf = match cursor.leaf.take() {
+                Some(leaf) if leaf.offset() == cursor.offset => leaf,
+                _ => self.view(cursor.offset())?,
+            };
+            // Cursors only ever point at leaves, unless a page is damaged
+            if !leaf.is_leaf() {
+                return Err(CorruptPage(leaf.offset()));
+            }
+            if cursor.cell_num < leaf.num_cells() {
+                cursor.end_of_table = false;
+                cursor.leaf = Some(leaf);
+                return Ok(());
+            }
+            let next_leaf = if self.pager.copy_on_write() {
+                let last = leaf.num_cells().checked_sub(1).map(|cell| leaf.key(cell));
+                let key = last.or(floor).unwrap_or(0);
+                floor = self.leaf_bound(key)?.1;
+                match floor {
+                    Some(floor) => Some(self.leaf_bound(floor)?.0),
+                    None => None,
+                }
+            } else {
+                leaf.next_leaf()
+            };
+            match next_leaf {
+                Some(next) => {
+                    if let Some(latch) = latch.as_mut() {
+                        *latch = self.pager.latch_shared(next);
                     }
+                    cursor.offset = next;
+                    cursor.cell_num = 0;
+                }
+                None => {
+                    cursor.end_of_table = true;
+                    return Ok(());
                 }
             }
         }
     }
 
-    pub fn close(&mut self) {
+    pub fn close(&self) {
         self.pager.close()
     }
 
-    pub fn find(&self, k: usize) -> Result<Cursor, Cursor> {
-        self._find(k, &self.root)
+    /// A cursor at `k` if it's in the tree, or else at where it would go.
+    pub fn find(&self, k: usize) -> Result<Result<Cursor, Cursor>, CorruptPage> {
+        let (leaf, _latch) = self.descend(k)?;
+        Ok(match leaf.search(k) {
+            Ok(index) => Ok(Cursor::on_leaf(leaf, index, false)),
+            Err(index) => {
+                let end_of_table = leaf.next_leaf().is_none() && index == leaf.num_cells();
+                Err(Cursor::on_leaf(leaf, index, end_of_table))
+            }
+        })
     }
-    fn _find(&self, k: usize, offset: &Offset) -> Result<Cursor, Cursor> {
-        let node = self.pager.get(offset);
-        if let NodeType::Internal(InternalNode {
-            ref separators,
-            ref children,
-        }) = node.node_type
-        {
-            let child = match separators.binary_search(&k) {
-                Ok(index) => index + 1,
-                Err(index) => index,
-            };
-            let child_offset = children.get(child).unwrap();
-            self._find(k, child_offset)
-        } else {
-            node.find(&k)
+
+    /// Returns the row with id `k`, read before its leaf's latch is let go.
+    pub fn search(&self, k: usize) -> Result<Option<Row>, CorruptPage> {
+        let (leaf, _latch) = self.descend(k)?;
+        leaf.search(k)
+            .ok()
+            .map(|index| leaf.value(index))
+            .transpose()
+    }
+
+    /// Walks down to the leaf where `k` is or would be, latching each page before letting go of
+    /// its parent's latch. Returns the leaf with its latch still held.
+    fn descend(&self, k: usize) -> Result<(NodeView, Option<PageLatch<'_>>), CorruptPage> {
+        let mut latch = self.latch(self.root);
+        let mut node = self.view(&self.root)?;
+        while !node.is_leaf() {
+            let child = node.child(node.child_index(k));
+            latch = self.latch(child);
+            node = self.view(&child)?;
+        }
+        Ok((node, latch))
+    }
+
+    /// The leaf where `k` is or would be, and the lowest separator above `k` on the way down to
+    /// it, which is where the keys of the leaf after it start. That's how scans find the next
+    /// leaf when sibling pointers aren't kept up to date.
+    fn leaf_bound(&self, k: usize) -> Result<(Offset, Option<usize>), CorruptPage> {
+        let mut offset = self.root;
+        let mut bound = None;
+        loop {
+            let node = self.view(&offset)?;
+            if node.is_leaf() {
+                return Ok((offset, bound));
+            }
+            let child = node.child_index(k);
+            if child < node.num_separators() {
+                bound = Some(node.separator(child));
+            }
+            offset = node.child(child);
+        }
+    }
+
+    /// Whether one more entry would split a node, which would change its parent as well.
+    fn is_full(&self, node: &Node<usize, Row>) -> bool {
+        match node.node_type {
+            NodeType::Leaf(..) => node.num_cells >= max_leaf_cells(self.pager.page_size()),
+            NodeType::Internal(InternalNode { ref separators, .. }) => {
+                separators.len() >= max_internal_separators(self.pager.page_size())
+            }
         }
     }
 
-    fn _insert(&mut self, offset: &Offset, k: usize, value: Row) -> InsertResult<usize, Row> {
-        let mut node = self.pager.get(offset);
+    /// Inserts below the page at `offset`, latching it first. The latches on the pages above it
+    /// are let go if this one has room, since then nothing above it will change.
+    fn _insert<'a>(
+        &'a self,
+        offset: &Offset,
+        k: usize,
+        value: Row,
+        latches: &mut Vec<PageLatch<'a>>,
+    ) -> Result<InsertResult<usize, Row>, CorruptPage> {
+        let latch = self.pager.latch_exclusive(*offset);
+        let mut node = self.pager.get(offset)?;
+        if !self.is_full(&node) {
+            latches.clear();
+        }
+        latches.push(latch);
         if let NodeType::Internal(InternalNode {
             ref mut separators,
             ref mut children,
@@ -172,169 +502,265 @@ impl BTree {
         {
             // find the child page of the key that we wish to insert on
             let child = match separators.binary_search(&k) {
-                Ok(index) => index,
+                Ok(index) => index + 1,
                 Err(index) => index,
             };
-            let child_offset = children.get(child).unwrap();
-
-            match self._insert(child_offset, k, value) {
-                InsertResult::ParentSplit(SplitEntry {
-                    separator,
-                    mut tree,
-                }) => {
-                    tree.parent_offset = Some(offset.clone());
-                    let mut left_child = self.pager.get(child_offset);
-                    tree.set_last_leaf(Some(left_child.offset));
-
-                    // Voodoo to insert tree into the middle of two leaves
-                    left_child
-                        .set_next_leaf(Some(tree.offset))
-                        .map(|right_child_offset| {
-                            tree.set_next_leaf(Some(right_child_offset));
-                            let mut right_child = self.pager.get(&right_child_offset);
-                            right_child.set_last_leaf(Some(tree.offset));
-                            self.pager.commit(&right_child);
-                        });
+            let child_offset = children[child];
 
+            Ok(match self._insert(&child_offset, k, value, latches)? {
+                InsertResult::ParentSplit(SplitEntry { separator, tree }) => {
                     let location = separators.binary_search(&separator).unwrap_err();
-                    separators.insert(location, separator.clone());
-                    self.pager.commit(&tree);
-                    self.pager.commit(&left_child);
+                    separators.insert(location, separator);
                     children.insert(location + 1, tree.offset);
-                    node.is_dirty = true;
 
-                    return if separators.len() >= MAX_INTERNAL_NODES {
-                        //split internal
-                        let upper_keys = separators.split_off((separators.len() / 2) - 1);
-                        let separator = upper_keys.first().unwrap().clone();
-                        let upper_children = children.split_off(separators.len() / 2);
+                    let result = if separators.len()
+                        > max_internal_separators(self.pager.page_size())
+                    {
+                        // The middle separator moves up to the parent. The children on either
+                        // side of it stay with the separators on their side.
+                        let middle = separators.len() / 2;
+                        let upper_keys = separators.split_off(middle + 1);
+                        let separator = separators.pop().unwrap();
+                        let upper_children = children.split_off(middle + 1);
                         let mut tree = Node::internal_with_separators(upper_keys, upper_children);
                         tree.offset = self.pager.new_page();
+                        tree.parent_offset = node.parent_offset;
+                        self.adopt_children(&tree)?;
+                        self.pager.commit(&tree);
+                        event!(
+                            Level::Debug,
+                            "internal_split",
+                            page = node.offset.0,
+                            new_page = tree.offset.0,
+                            separator = separator,
+                        );
                         InsertResult::ParentSplit(SplitEntry { separator, tree })
                     } else {
                         InsertResult::Success
                     };
-                }
-                result => {
-                    if node.is_dirty {
-                        self.pager.commit(&node);
-                        node.is_dirty = false;
-                    }
+                    self.pager.commit(&node);
                     result
                 }
-            }
+                result => result,
+            })
         } else {
-            match self.insert_leaf(&mut node, value.id as usize, value) {
-                result => {
-                    if node.is_dirty {
-                        self.pager.commit(&node);
-                        node.is_dirty = false;
-                    }
-                    result
-                }
+            let result = self.insert_leaf(&mut node, k, value)?;
+            if node.is_dirty {
+                self.pager.commit(&node);
+                node.is_dirty = false;
             }
+            Ok(result)
         }
     }
+
     pub fn insert_leaf(
-        &mut self,
+        &self,
         node: &mut Node<usize, Row>,
         key: usize,
         value: Row,
-    ) -> InsertResult<usize, Row> {
+    ) -> Result<InsertResult<usize, Row>, CorruptPage> {
+        let next_leaf = node.get_next_leaf().filter(|_| !self.pager.copy_on_write());
         if let NodeType::Leaf(LeafNode {
             ref mut children, ..
         }) = node.node_type
         {
-            let location = match children.binary_search_by_key(&key, |pair| pair.key.clone()) {
-                Ok(_duplicate_index) => return InsertResult::DuplicateKey,
+            let location = match children.binary_search_by_key(&key, |pair| pair.key) {
+                Ok(_duplicate_index) => return Ok(InsertResult::DuplicateKey),
                 Err(index) => index,
             };
             children.insert(location, KeyValuePair { key, value });
             node.is_dirty = true;
 
-            return if children.len() <= MAX_LEAF_NODES {
+            if children.len() <= max_leaf_cells(self.pager.page_size()) {
                 node.num_cells += 1;
-                InsertResult::Success
+                Ok(InsertResult::Success)
             } else {
+                // The old neighbour is read before a page is handed out, in case it's corrupt
+                let right = match next_leaf {
+                    Some(right) => {
+                        Some((self.pager.latch_exclusive(right), self.pager.get(&right)?))
+                    }
+                    None => None,
+                };
                 let upper = children.split_off(children.len() / 2);
-                let mut new_node = Node::leaf_with_children(upper);
                 node.num_cells = children.len();
+                let mut new_node = Node::leaf_with_children(upper);
                 new_node.offset = self.pager.new_page();
+                new_node.parent_offset = node.parent_offset;
+
+                // Voodoo to insert the new leaf in between this one and its old neighbour. Not
+                // when copying on write, since the neighbour may be shared with a snapshot
+                if !self.pager.copy_on_write() {
+                    new_node.set_last_leaf(Some(node.offset));
+                    node.set_next_leaf(Some(new_node.offset));
+                    if let Some((_right_latch, mut right)) = right {
+                        new_node.set_next_leaf(Some(right.offset));
+                        right.set_last_leaf(Some(new_node.offset));
+                        self.pager.commit(&right);
+                    }
+                }
+                self.pager.commit(&new_node);
+                event!(
+                    Level::Debug,
+                    "leaf_split",
+                    page = node.offset.0,
+                    new_page = new_node.offset.0,
+                    separator = new_node.smallest_key().unwrap(),
+                );
 
-                InsertResult::ParentSplit(SplitEntry {
+                Ok(InsertResult::ParentSplit(SplitEntry {
                     separator: new_node.smallest_key().unwrap(),
                     tree: new_node,
-                })
-            };
+                }))
+            }
         } else {
-            panic!()
+            // The search that led here only stops at leaves, unless a page is damaged
+            Err(CorruptPage(node.offset))
         }
     }
 
-    pub fn cursor_start(&self) -> Cursor {
-        let mut cursor = self.root.clone();
-        let mut end_of_table = false;
-        loop {
-            let node = self.pager.get(&cursor);
-            match node.node_type {
-                NodeType::Internal(InternalNode { children, .. }) => {
-                    cursor = children.first().unwrap().clone()
-                }
-                NodeType::Leaf(LeafNode { children, .. }) => {
-                    end_of_table = children.is_empty();
-                    break;
-                }
-            }
-        }
-        Cursor {
-            offset: cursor,
-            cell_num: 0,
-            end_of_table,
+    pub fn cursor_start(&self) -> Result<Cursor, CorruptPage> {
+        Ok(self.latched_cursor_start()?.0)
+    }
+
+    /// A cursor at the first row, and the latch on its leaf. Pages are latched on the way down,
+    /// like [`BTree::find`] does.
+    pub fn latched_cursor_start(&self) -> Result<(Cursor, Option<PageLatch<'_>>), CorruptPage> {
+        let mut latch = self.latch(self.root);
+        let mut node = self.view(&self.root)?;
+        while !node.is_leaf() {
+            let child = node.child(0);
+            latch = self.latch(child);
+            node = self.view(&child)?;
         }
+        let mut cursor = Cursor::on_leaf(node, 0, false);
+        self.settle_cursor(&mut cursor, &mut latch)?;
+        Ok((cursor, latch))
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::fs::OpenOptions;
+    use std::sync::Arc;
 
     use crate::btree::BTree;
-    use crate::cursor::Cursor;
-    use crate::pager::Pager;
+    use crate::bulk_load::BulkLoadResult;
+    use crate::cursor::Scan;
+    use crate::fixtures::row;
+    use crate::pager::{CorruptPage, Offset, Pager};
     use crate::Row;
 
-    fn test_db_file_truncate() {
-        let test_db = OpenOptions::new()
-            .write(true)
-            .truncate(true)
-            .create(true)
-            .open("test.db")
-            .expect("test database");
-        test_db.sync_all().expect("sync changes to disk");
-    }
     #[test]
     fn test_multiple_leaf_splits() {
-        test_db_file_truncate();
-        let pager = Pager::open("test.db");
+        let pager = Pager::open(":memory:");
         let mut bt = BTree::new(pager);
         let count = 60;
 
         for i in 0..count {
-            assert!(bt.insert(
-                i,
-                Row {
-                    id: i as u32,
-                    username: String::from(format!("user{i}")),
-                    email: String::from(format!("user{i}@example.com"))
-                }
-            ));
+            assert!(bt.insert(i, row(i as u32)).unwrap());
         }
-        let mut cursor = bt.cursor_start();
+        let mut cursor = bt.cursor_start().unwrap();
         let mut i: u32 = 0;
         while !cursor.is_at_end_of_table() {
-            assert_eq!(cursor.value(&bt).id, i);
-            bt.advance_cursor(&mut cursor);
+            assert_eq!(cursor.value(&bt).unwrap().id, i);
+            bt.advance_cursor(&mut cursor).unwrap();
             i += 1;
         }
+        assert_eq!(i, count as u32);
+    }
+
+    #[test]
+    fn deletes_can_empty_leaves() {
+        let mut bt = BTree::create(Arc::new(Pager::open(":memory:")));
+        for row in rows(0..60) {
+            assert!(bt.insert(row.id as usize, row).unwrap());
+        }
+        // Empties the first leaf and one in the middle
+        for id in (0..6).chain(20..40) {
+            assert!(bt.delete(id).unwrap());
+        }
+        assert!(!bt.delete(20).unwrap());
+        let ids: Vec<u32> = Scan::new(&bt).map(|row| row.unwrap().id).collect();
+        assert_eq!(ids, (6..20).chain(40..60).collect::<Vec<_>>());
+        assert!(bt.find(30).unwrap().is_err());
+
+        for row in rows(0..60).step_by(3) {
+            bt.insert(row.id as usize, row).unwrap();
+        }
+        let ids: Vec<u32> = Scan::new(&bt).map(|row| row.unwrap().id).collect();
+        let expected: Vec<u32> = (0..60)
+            .filter(|i| i % 3 == 0 || (6..20).contains(i) || *i >= 40)
+            .collect();
+        assert_eq!(ids, expected);
+    }
+
+    fn rows(ids: impl Iterator<Item = u32>) -> impl Iterator<Item = Row> {
+        ids.map(row)
+    }
+
+    #[test]
+    fn bulk_load_builds_a_searchable_tree() {
+        // A tiny fill factor packs one row per leaf and three children per internal node, so
+        // 1000 rows make a tree seven levels deep
+        for fill_factor in [1.0, 0.5, 0.01] {
+            let mut bt = BTree::create(Arc::new(Pager::open(":memory:")));
+            let evens = (0..2000).step_by(2);
+            assert_eq!(
+                bt.bulk_load(rows(evens.clone()), fill_factor),
+                BulkLoadResult::Success(1000)
+            );
+            assert!(Scan::new(&bt).map(|row| row.unwrap().id).eq(evens.clone()));
+            for i in evens {
+                let cursor = bt.find(i as usize).unwrap().unwrap();
+                assert_eq!(cursor.value(&bt).unwrap().id, i);
+                assert!(bt.find(i as usize + 1).unwrap().is_err());
+            }
+
+            // The loaded tree takes ordinary inserts in the gaps
+            for row in rows((1..2000).step_by(2)) {
+                assert!(bt.insert(row.id as usize, row).unwrap());
+            }
+            assert!(Scan::new(&bt).map(|row| row.unwrap().id).eq(0..2000));
+            assert_eq!(
+                bt.bulk_load(rows(0..1), fill_factor),
+                BulkLoadResult::NotEmpty
+            );
+        }
+    }
+
+    #[test]
+    fn bulk_load_packs_leaves() {
+        let pager = Arc::new(Pager::open(":memory:"));
+        let mut bt = BTree::create(pager.clone());
+        let pages = pager.num_pages();
+        assert_eq!(
+            bt.bulk_load(rows(0..120), 1.0),
+            BulkLoadResult::Success(120)
+        );
+        // Ten full leaves, plus the page the top node was built on before it moved to the root
+        assert_eq!(pager.num_pages() - pages, 11);
+
+        let mut bt = BTree::create(pager.clone());
+        assert_eq!(
+            bt.bulk_load(rows([1, 2, 5, 4].into_iter()), 1.0),
+            BulkLoadResult::Unsorted(4)
+        );
+        assert_eq!(
+            bt.bulk_load(rows([1, 2, 2].into_iter()), 1.0),
+            BulkLoadResult::DuplicateKey(2)
+        );
+        assert!(bt.is_empty().unwrap());
+        assert_eq!(Scan::new(&bt).count(), 0);
+    }
+
+    #[test]
+    fn snapshots_report_pages_they_have_no_version_of() {
+        let pager = Arc::new(Pager::open(":memory:"));
+        pager.keep_versions();
+        let snapshot = Arc::new(pager.snapshot());
+        let tree = BTree::open(pager, Offset(100)).as_of(&snapshot);
+        let corrupt = CorruptPage(Offset(100));
+        assert_eq!(tree.search(1), Err(corrupt));
+        assert_eq!(Scan::new(&tree).next(), Some(Err(corrupt)));
     }
 }
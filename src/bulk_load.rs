@@ -0,0 +1,268 @@
+use crate::node::{max_internal_separators, max_leaf_cells, Node};
+use crate::node_type::{InternalNode, KeyValuePair, LeafNode, NodeType};
+use crate::pager::{CorruptPage, Offset, Pager};
+use crate::Row;
+
+#[derive(Debug, PartialEq)]
+pub enum BulkLoadResult {
+    /// The tree was built, holding this many rows.
+    Success(usize),
+    NotEmpty,
+    DuplicateKey(usize),
+    /// A key was smaller than the one before it.
+    Unsorted(usize),
+    /// The file reached its maximum size.
+    TableFull,
+    /// A page the load had to read back was corrupt.
+    Corrupt(CorruptPage),
+}
+
+/// Builds a B-tree bottom-up out of rows that come sorted by key.
+///
+/// Every level of the tree has one node that is being filled. Once it's full, the next node on
+/// that level is started and added to the level above, and only then is the full node committed,
+/// since that's when its successor and its parent are known. Pages are allocated in the order
+/// rows arrive, so leaves end up laid out in key order.
+pub struct BulkLoader<'a> {
+    pager: &'a Pager,
+    leaf_capacity: usize,
+    internal_capacity: usize,
+    /// The node being filled on each level, leaves first.
+    levels: Vec<Node<usize, Row>>,
+    /// The smallest key under each of those nodes.
+    first_keys: Vec<usize>,
+    /// The node committed before each of those nodes, on the same level.
+    previous: Vec<Option<Offset>>,
+    allocated: Vec<Offset>,
+    last_key: Option<usize>,
+    rows: usize,
+}
+
+impl<'a> BulkLoader<'a> {
+    /// `fill_factor` is the fraction of each node to fill, leaving room for later inserts. Leaves
+    /// get at least one row, and internal nodes at least three children so that the last node on
+    /// a level can always borrow a child from its neighbour.
+    pub fn new(pager: &'a Pager, fill_factor: f64) -> Self {
+        let fill = |capacity: usize, min: usize| {
+            ((capacity as f64 * fill_factor).round() as usize).clamp(min, capacity)
+        };
+        Self {
+            pager,
+            leaf_capacity: fill(max_leaf_cells(pager.page_size()), 1),
+            internal_capacity: fill(max_internal_separators(pager.page_size()) + 1, 3),
+            levels: Vec::new(),
+            first_keys: Vec::new(),
+            previous: Vec::new(),
+            allocated: Vec::new(),
+            last_key: None,
+            rows: 0,
+        }
+    }
+
+    pub fn push(&mut self, key: usize, value: Row) -> Result<(), BulkLoadResult> {
+        match self.last_key {
+            Some(last) if key == last => return Err(BulkLoadResult::DuplicateKey(key)),
+            Some(last) if key < last => return Err(BulkLoadResult::Unsorted(key)),
+            _ => self.last_key = Some(key),
+        }
+        if self.levels.is_empty() || self.levels[0].num_cells >= self.leaf_capacity {
+            // Starting a leaf can start a new node on every level, and then a new top level
+            if self.pager.pages_left() < self.levels.len() + 1 {
+                return Err(BulkLoadResult::TableFull);
+            }
+            self.start_node(0, key).map_err(BulkLoadResult::Corrupt)?;
+        }
+        let leaf = &mut self.levels[0];
+        if let NodeType::Leaf(LeafNode {
+            ref mut children, ..
+        }) = leaf.node_type
+        {
+            children.push(KeyValuePair { key, value });
+        }
+        leaf.num_cells += 1;
+        self.rows += 1;
+        Ok(())
+    }
+
+    fn allocate(&mut self) -> Offset {
+        let offset = self.pager.new_page();
+        self.allocated.push(offset);
+        offset
+    }
+
+    /// Starts a new node on `level`, committing the one it replaces.
+    fn start_node(&mut self, level: usize, first_key: usize) -> Result<(), CorruptPage> {
+        let mut node = if level == 0 {
+            Node::leaf()
+        } else {
+            Node::internal()
+        };
+        node.offset = self.allocate();
+        if level == self.levels.len() {
+            self.levels.push(node);
+            self.first_keys.push(first_key);
+            self.previous.push(None);
+            return Ok(());
+        }
+
+        if level + 1 == self.levels.len() {
+            // The level had just the one node so far, so it needs a parent now
+            let mut parent =
+                Node::internal_with_separators(vec![], vec![self.levels[level].offset]);
+            parent.offset = self.allocate();
+            self.levels[level].parent_offset = Some(parent.offset);
+            self.levels.push(parent);
+            self.first_keys.push(self.first_keys[level]);
+            self.previous.push(None);
+        }
+        node.parent_offset = Some(self.add_child(level + 1, first_key, node.offset)?);
+
+        let new_offset = node.offset;
+        let mut full = std::mem::replace(&mut self.levels[level], node);
+        if level == 0 {
+            full.set_next_leaf(Some(new_offset));
+            self.levels[0].set_last_leaf(Some(full.offset));
+        }
+        self.pager.commit(&full);
+        self.first_keys[level] = first_key;
+        self.previous[level] = Some(full.offset);
+        Ok(())
+    }
+
+    /// Adds a child to the node being filled on `level`, returning the offset of its parent.
+    fn add_child(
+        &mut self,
+        level: usize,
+        first_key: usize,
+        child: Offset,
+    ) -> Result<Offset, CorruptPage> {
+        if internal(&self.levels[level])?.children.len() >= self.internal_capacity {
+            self.start_node(level, first_key)?;
+        }
+        let node = &mut self.levels[level];
+        let InternalNode {
+            separators,
+            children,
+        } = internal_mut(node)?;
+        if !children.is_empty() {
+            separators.push(first_key);
+        }
+        children.push(child);
+        Ok(node.offset)
+    }
+
+    /// Moves the last child of the previous node on `level` into the node being filled, which
+    /// only has one child.
+    fn borrow_child(&mut self, level: usize) -> Result<(), CorruptPage> {
+        let previous_offset = self.previous[level].unwrap();
+        let mut previous = self.pager.get(&previous_offset)?;
+        let InternalNode {
+            separators,
+            children,
+        } = internal_mut(&mut previous)?;
+        let (Some(key), Some(child_offset)) = (separators.pop(), children.pop()) else {
+            return Err(CorruptPage(previous_offset));
+        };
+        self.pager.commit(&previous);
+
+        let mut child = self.pager.get(&child_offset)?;
+        child.parent_offset = Some(self.levels[level].offset);
+        self.pager.commit(&child);
+
+        let old_first_key = self.first_keys[level];
+        let InternalNode {
+            separators,
+            children,
+        } = internal_mut(&mut self.levels[level])?;
+        separators.insert(0, old_first_key);
+        children.insert(0, child_offset);
+
+        // The node's smallest key went down, so the separator in front of it has to as well. It
+        // lives in the first ancestor where this branch isn't the leftmost child.
+        self.first_keys[level] = key;
+        for up in level + 1..self.levels.len() {
+            match internal_mut(&mut self.levels[up])?.separators.last_mut() {
+                Some(separator) => {
+                    *separator = key;
+                    break;
+                }
+                None => self.first_keys[up] = key,
+            }
+        }
+        Ok(())
+    }
+
+    /// Commits the nodes still being filled, with the topmost one written to `root`. Returns the
+    /// number of rows loaded.
+    pub fn finish(mut self, root: Offset) -> Result<usize, CorruptPage> {
+        let Some(mut top) = self.commit_levels()? else {
+            return Ok(0);
+        };
+        let top_offset = top.offset;
+        if let NodeType::Internal(InternalNode { ref children, .. }) = top.node_type {
+            for offset in children {
+                let mut child = self.pager.get(offset)?;
+                child.parent_offset = Some(root);
+                self.pager.commit(&child);
+            }
+        }
+        top.offset = root;
+        top.is_root = true;
+        top.parent_offset = None;
+        self.pager.commit(&top);
+        self.pager.recycle(top_offset);
+        Ok(self.rows)
+    }
+
+    /// Commits the nodes still being filled, making the topmost one the root of a new tree right
+    /// where it is. Returns the root, or `None` if no rows were loaded.
+    pub fn finish_new(mut self) -> Result<Option<Offset>, CorruptPage> {
+        let Some(mut top) = self.commit_levels()? else {
+            return Ok(None);
+        };
+        top.is_root = true;
+        self.pager.commit(&top);
+        Ok(Some(top.offset))
+    }
+
+    /// Commits every node still being filled but the topmost one, which is returned.
+    fn commit_levels(&mut self) -> Result<Option<Node<usize, Row>>, CorruptPage> {
+        if self.levels.is_empty() {
+            return Ok(None);
+        }
+        // The top level only ever has one node, which got its second child when it was created
+        for level in 1..self.levels.len() - 1 {
+            if internal(&self.levels[level])?.children.len() == 1 {
+                self.borrow_child(level)?;
+            }
+        }
+        let top = self.levels.pop().unwrap();
+        for node in &self.levels {
+            self.pager.commit(node);
+        }
+        Ok(Some(top))
+    }
+
+    /// Gives back every page allocated so far, for when the load can't be finished.
+    pub fn abandon(self) {
+        for offset in self.allocated {
+            self.pager.recycle(offset);
+        }
+    }
+}
+
+/// The separators and children of a node above the leaves. A leaf there means its page is
+/// damaged.
+fn internal(node: &Node<usize, Row>) -> Result<&InternalNode<usize>, CorruptPage> {
+    match node.node_type {
+        NodeType::Internal(ref internal) => Ok(internal),
+        NodeType::Leaf(..) => Err(CorruptPage(node.offset)),
+    }
+}
+
+fn internal_mut(node: &mut Node<usize, Row>) -> Result<&mut InternalNode<usize>, CorruptPage> {
+    match node.node_type {
+        NodeType::Internal(ref mut internal) => Ok(internal),
+        NodeType::Leaf(..) => Err(CorruptPage(node.offset)),
+    }
+}
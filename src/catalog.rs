@@ -0,0 +1,336 @@
+use crate::page::{is_valid_page_size, Page, CHECKSUM_SIZE};
+use crate::pager::Offset;
+
+/// The first page of every database file is its header: a magic string, the page size, how many
+/// tables there are, flags for optional features, the first page of the free list and its length,
+/// a count of the commits made to the file, then the list of tables and the page each one's
+/// B-tree is rooted at. Any snapshots follow the tables, with the page each one's catalog is kept
+/// on, up to the first entry with no name. Page numbers, the free list's length and the commit
+/// count are `u64`s, and the rest `u32`s.
+const MAGIC: &[u8; 8] = b"tarsier\0";
+const PAGE_SIZE_OFFSET: usize = 8;
+/// How much of the header has to be read to learn the page size.
+pub const PAGE_SIZE_END: usize = PAGE_SIZE_OFFSET + 4;
+const TABLE_COUNT_OFFSET: usize = 12;
+const FLAGS_OFFSET: usize = 16;
+const FREE_LIST_OFFSET: usize = 20;
+const FREE_COUNT_OFFSET: usize = 28;
+/// Bumped by every commit, so that other connections can tell their cached pages are stale.
+const CHANGE_COUNTER_OFFSET: usize = 36;
+const TABLES_OFFSET: usize = 44;
+/// Every page's reserved bytes hold a checksum of the rest of it.
+const CHECKSUMS_FLAG: u32 = 1;
+/// The file has pointer maps, and commits give free pages back to the file system.
+const AUTO_VACUUM_FLAG: u32 = 2;
+/// The file has pointer maps, and free pages are given back by `PRAGMA incremental_vacuum`.
+const INCREMENTAL_VACUUM_FLAG: u32 = 4;
+/// Pages written by an earlier commit are copied instead of changed, so snapshots can share them.
+const COPY_ON_WRITE_FLAG: u32 = 8;
+pub const MAX_TABLE_NAME: usize = 32;
+const TABLE_ENTRY_SIZE: usize = MAX_TABLE_NAME + 8;
+
+/// How many tables and snapshots fit in the header of a database with pages of `page_size` bytes.
+pub fn max_tables(page_size: usize) -> usize {
+    (page_size - CHECKSUM_SIZE - TABLES_OFFSET) / TABLE_ENTRY_SIZE
+}
+
+/// Reads the page size out of the start of a header, or `None` if it isn't one.
+pub fn page_size(header: &[u8]) -> Option<usize> {
+    if &header[..MAGIC.len()] != MAGIC {
+        return None;
+    }
+    let page_size = u32::from_le_bytes(header[PAGE_SIZE_OFFSET..PAGE_SIZE_END].try_into().unwrap());
+    Some(page_size as usize).filter(|&page_size| is_valid_page_size(page_size))
+}
+
+/// Reads the change counter out of a header, without loading the rest of it.
+pub fn change_counter(header: &[u8]) -> u64 {
+    u64::from_le_bytes(
+        header[CHANGE_COUNTER_OFFSET..CHANGE_COUNTER_OFFSET + 8]
+            .try_into()
+            .unwrap(),
+    )
+}
+
+/// When free pages are given back to the file system, by moving pages off the end of the file
+/// into them and cutting it short.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum AutoVacuum {
+    /// Only by `VACUUM`.
+    #[default]
+    None,
+    /// On every commit.
+    Full,
+    /// When asked to, by `PRAGMA incremental_vacuum`.
+    Incremental,
+}
+
+impl AutoVacuum {
+    /// Whether the file keeps pointer maps, which moving pages needs.
+    pub fn has_pointer_maps(self) -> bool {
+        self != AutoVacuum::None
+    }
+}
+
+impl std::fmt::Display for AutoVacuum {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            AutoVacuum::None => "none",
+            AutoVacuum::Full => "full",
+            AutoVacuum::Incremental => "incremental",
+        };
+        write!(f, "{name}")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Catalog {
+    page_size: usize,
+    tables: Vec<(String, Offset)>,
+    /// The first free page, if any, and how many pages are on the free list.
+    free_list: Option<Offset>,
+    free_count: usize,
+    checksums: bool,
+    auto_vacuum: AutoVacuum,
+    change_counter: u64,
+    copy_on_write: bool,
+    /// Named snapshots, and the page each one's catalog is kept on.
+    snapshots: Vec<(String, Offset)>,
+}
+
+impl Catalog {
+    pub fn new(page_size: usize) -> Self {
+        Self {
+            page_size,
+            tables: Vec::new(),
+            free_list: None,
+            free_count: 0,
+            checksums: false,
+            auto_vacuum: AutoVacuum::None,
+            change_counter: 0,
+            copy_on_write: false,
+            snapshots: Vec::new(),
+        }
+    }
+
+    /// Reads the catalog out of a header page, or `None` if the page isn't a header.
+    pub fn load(page: &Page) -> Option<Self> {
+        let bytes = page.as_bytes();
+        let page_size = page_size(bytes).filter(|&page_size| page_size == page.size())?;
+        let read = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap()) as usize;
+        let read_u64 =
+            |at: usize| u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap()) as usize;
+        let count = read(TABLE_COUNT_OFFSET);
+        if count > max_tables(page_size) {
+            return None;
+        }
+        let mut entries = (0..max_tables(page_size)).map(|i| {
+            let entry = &bytes[TABLES_OFFSET + i * TABLE_ENTRY_SIZE..][..TABLE_ENTRY_SIZE];
+            let (name, root) = entry.split_at(MAX_TABLE_NAME);
+            let name = std::str::from_utf8(name).unwrap_or_default();
+            let name = name.split('\0').next().unwrap().to_string();
+            let root = u64::from_le_bytes(root.try_into().unwrap()) as usize;
+            (name, Offset(root))
+        });
+        let tables = entries.by_ref().take(count).collect();
+        let snapshots = entries.take_while(|(name, _)| !name.is_empty()).collect();
+        let free_list = match read_u64(FREE_LIST_OFFSET) {
+            0 => None,
+            page => Some(Offset(page)),
+        };
+        let flags = read(FLAGS_OFFSET) as u32;
+        let auto_vacuum = if flags & AUTO_VACUUM_FLAG != 0 {
+            AutoVacuum::Full
+        } else if flags & INCREMENTAL_VACUUM_FLAG != 0 {
+            AutoVacuum::Incremental
+        } else {
+            AutoVacuum::None
+        };
+        Some(Self {
+            page_size,
+            tables,
+            free_list,
+            free_count: read_u64(FREE_COUNT_OFFSET),
+            checksums: flags & CHECKSUMS_FLAG != 0,
+            auto_vacuum,
+            change_counter: change_counter(bytes),
+            copy_on_write: flags & COPY_ON_WRITE_FLAG != 0,
+            snapshots,
+        })
+    }
+
+    pub fn to_page(&self) -> Page {
+        let mut page = Page::new(self.page_size);
+        let bytes = page.as_bytes_mut();
+        bytes[..MAGIC.len()].copy_from_slice(MAGIC);
+        bytes[PAGE_SIZE_OFFSET..PAGE_SIZE_END]
+            .copy_from_slice(&(self.page_size as u32).to_le_bytes());
+        bytes[TABLE_COUNT_OFFSET..TABLE_COUNT_OFFSET + 4]
+            .copy_from_slice(&(self.tables.len() as u32).to_le_bytes());
+        let free_list = self.free_list.map_or(0, |o| o.0 as u64);
+        bytes[FREE_LIST_OFFSET..FREE_LIST_OFFSET + 8].copy_from_slice(&free_list.to_le_bytes());
+        bytes[FREE_COUNT_OFFSET..FREE_COUNT_OFFSET + 8]
+            .copy_from_slice(&(self.free_count as u64).to_le_bytes());
+        let mut flags = if self.checksums { CHECKSUMS_FLAG } else { 0 };
+        if self.copy_on_write {
+            flags |= COPY_ON_WRITE_FLAG;
+        }
+        flags |= match self.auto_vacuum {
+            AutoVacuum::None => 0,
+            AutoVacuum::Full => AUTO_VACUUM_FLAG,
+            AutoVacuum::Incremental => INCREMENTAL_VACUUM_FLAG,
+        };
+        bytes[FLAGS_OFFSET..FLAGS_OFFSET + 4].copy_from_slice(&flags.to_le_bytes());
+        bytes[CHANGE_COUNTER_OFFSET..CHANGE_COUNTER_OFFSET + 8]
+            .copy_from_slice(&self.change_counter.to_le_bytes());
+        for (i, (name, root)) in self.tables.iter().chain(&self.snapshots).enumerate() {
+            let entry = &mut bytes[TABLES_OFFSET + i * TABLE_ENTRY_SIZE..][..TABLE_ENTRY_SIZE];
+            entry[..name.len()].copy_from_slice(name.as_bytes());
+            entry[MAX_TABLE_NAME..].copy_from_slice(&(root.0 as u64).to_le_bytes());
+        }
+        page
+    }
+
+    pub fn root(&self, name: &str) -> Option<Offset> {
+        self.tables
+            .iter()
+            .find(|(table, _)| table == name)
+            .map(|(_, root)| *root)
+    }
+
+    /// Records that the table rooted at `from` is now rooted at `to`.
+    pub fn move_root(&mut self, from: Offset, to: Offset) {
+        for (_, root) in self.tables.iter_mut().filter(|(_, root)| *root == from) {
+            *root = to;
+        }
+    }
+
+    /// Registers a table. The caller makes sure the name is new, fits and that there's room.
+    pub fn add(&mut self, name: &str, root: Offset) {
+        self.tables.push((name.to_string(), root));
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.tables.len() + self.snapshots.len() >= max_tables(self.page_size)
+    }
+
+    pub fn tables(&self) -> &[(String, Offset)] {
+        &self.tables
+    }
+
+    pub fn free_list(&self) -> (Option<Offset>, usize) {
+        (self.free_list, self.free_count)
+    }
+
+    pub fn set_free_list(&mut self, first: Option<Offset>, count: usize) {
+        self.free_list = first;
+        self.free_count = count;
+    }
+
+    pub fn checksums(&self) -> bool {
+        self.checksums
+    }
+
+    pub fn set_checksums(&mut self, checksums: bool) {
+        self.checksums = checksums;
+    }
+
+    pub fn auto_vacuum(&self) -> AutoVacuum {
+        self.auto_vacuum
+    }
+
+    pub fn set_auto_vacuum(&mut self, auto_vacuum: AutoVacuum) {
+        self.auto_vacuum = auto_vacuum;
+    }
+
+    pub fn change_counter(&self) -> u64 {
+        self.change_counter
+    }
+
+    /// Counts another commit.
+    pub fn bump_change_counter(&mut self) {
+        self.change_counter = self.change_counter.wrapping_add(1);
+    }
+
+    pub fn copy_on_write(&self) -> bool {
+        self.copy_on_write
+    }
+
+    pub fn set_copy_on_write(&mut self, copy_on_write: bool) {
+        self.copy_on_write = copy_on_write;
+    }
+
+    pub fn snapshots(&self) -> &[(String, Offset)] {
+        &self.snapshots
+    }
+
+    /// The page the named snapshot's catalog is kept on.
+    pub fn snapshot(&self, name: &str) -> Option<Offset> {
+        self.snapshots
+            .iter()
+            .find(|(snapshot, _)| snapshot == name)
+            .map(|(_, page)| *page)
+    }
+
+    /// Registers a snapshot. The caller makes sure the name is new, fits and that there's room.
+    pub fn add_snapshot(&mut self, name: &str, page: Offset) {
+        self.snapshots.push((name.to_string(), page));
+    }
+
+    /// Forgets a snapshot, returning the page its catalog was kept on.
+    pub fn remove_snapshot(&mut self, name: &str) -> Option<Offset> {
+        let index = self
+            .snapshots
+            .iter()
+            .position(|(snapshot, _)| snapshot == name)?;
+        Some(self.snapshots.remove(index).1)
+    }
+
+    /// A copy of the catalog to keep as a snapshot, which doesn't list the other snapshots.
+    pub fn to_snapshot(&self) -> Self {
+        Self {
+            snapshots: Vec::new(),
+            ..self.clone()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::catalog::{change_counter, AutoVacuum, Catalog};
+    use crate::page::{Page, MIN_PAGE_SIZE};
+    use crate::pager::Offset;
+
+    #[test]
+    fn catalog_round_trip() {
+        let mut catalog = Catalog::new(MIN_PAGE_SIZE);
+        catalog.add("users", Offset(1));
+        catalog.add("a_table_with_a_32_character_name", Offset(7));
+        catalog.set_free_list(Some(Offset(3)), 2);
+        catalog.set_checksums(true);
+        catalog.set_auto_vacuum(AutoVacuum::Incremental);
+        catalog.bump_change_counter();
+        catalog.set_copy_on_write(true);
+        catalog.add_snapshot("before", Offset(9));
+        let loaded = Catalog::load(&catalog.to_page()).unwrap();
+        assert_eq!(loaded, catalog);
+        assert_eq!(
+            loaded.root("a_table_with_a_32_character_name"),
+            Some(Offset(7))
+        );
+        assert_eq!(loaded.root("admins"), None);
+        assert_eq!(loaded.free_list(), (Some(Offset(3)), 2));
+        assert!(loaded.checksums());
+        assert_eq!(loaded.auto_vacuum(), AutoVacuum::Incremental);
+        assert_eq!(loaded.change_counter(), 1);
+        assert!(loaded.copy_on_write());
+        assert_eq!(loaded.snapshot("before"), Some(Offset(9)));
+        assert_eq!(loaded.root("before"), None);
+        assert!(loaded.to_snapshot().snapshots().is_empty());
+        assert_eq!(change_counter(catalog.to_page().as_bytes()), 1);
+
+        assert_eq!(loaded.page_size, MIN_PAGE_SIZE);
+
+        assert_eq!(Catalog::load(&Page::new(MIN_PAGE_SIZE)), None);
+    }
+}
@@ -1,23 +1,29 @@
 use crate::btree::BTree;
-use crate::pager::Offset;
+use crate::node::NodeView;
+use crate::pager::{CorruptPage, HasOffset, Offset, PageLatch};
 use crate::Row;
 
+/// A position in a tree. A cursor holds on to the leaf it's on, read once when it gets there, so
+/// stepping through the leaf's cells doesn't read the page again for each one.
 #[derive(Debug)]
 pub struct Cursor {
     pub offset: Offset,
     pub cell_num: usize,
     pub end_of_table: bool,
+    /// The leaf at `offset`, if it's been read.
+    pub(crate) leaf: Option<NodeView>,
 }
 
 impl Cursor {
-    pub fn start(tree: &BTree) -> Self {
+    pub fn start(tree: &BTree) -> Result<Self, CorruptPage> {
         let offset = tree.root();
-        let end_of_table = tree.is_empty();
-        Self {
+        let end_of_table = tree.is_empty()?;
+        Ok(Self {
             offset,
             cell_num: 0,
             end_of_table,
-        }
+            leaf: None,
+        })
     }
 
     pub fn new(offset: Offset, cell_num: usize, end_of_table: bool) -> Self {
@@ -25,6 +31,17 @@ impl Cursor {
             offset,
             cell_num,
             end_of_table,
+            leaf: None,
+        }
+    }
+
+    /// A cursor on a leaf that's already been read.
+    pub fn on_leaf(leaf: NodeView, cell_num: usize, end_of_table: bool) -> Self {
+        Self {
+            offset: leaf.offset(),
+            cell_num,
+            end_of_table,
+            leaf: Some(leaf),
         }
     }
 
@@ -38,11 +55,73 @@ impl Cursor {
         self.cell_num
     }
 
-    pub fn value(&self, tree: &BTree) -> Row {
-        tree.get(&self.offset, self.cell_num).unwrap()
+    pub fn value(&self, tree: &BTree) -> Result<Row, CorruptPage> {
+        match &self.leaf {
+            Some(leaf) if leaf.offset() == self.offset => leaf.value(self.cell_num),
+            _ => Ok(tree.get(&self.offset, self.cell_num)?.unwrap()),
+        }
     }
 
     pub fn is_at_end_of_table(&self) -> bool {
         self.end_of_table
     }
 }
+
+/// Walks every row of a tree in key order. The leaf it's on stays latched, so the writer can't
+/// split rows out from under it. A corrupt page ends the scan, with an error in place of the
+/// next row. So does a row that's out of order, naming its leaf.
+pub struct Scan<'a> {
+    tree: &'a BTree,
+    cursor: Cursor,
+    latch: Option<PageLatch<'a>>,
+    /// The corrupt page that kept the scan from starting, which it reports first.
+    corrupt: Option<CorruptPage>,
+    /// The id of the last row returned.
+    last: Option<u32>,
+}
+
+impl<'a> Scan<'a> {
+    pub fn new(tree: &'a BTree) -> Self {
+        let (cursor, latch, corrupt) = match tree.latched_cursor_start() {
+            Ok((cursor, latch)) => (cursor, latch, None),
+            Err(corrupt) => (Cursor::new(tree.root(), 0, true), None, Some(corrupt)),
+        };
+        Self {
+            tree,
+            cursor,
+            latch,
+            corrupt,
+            last: None,
+        }
+    }
+}
+
+impl<'a> Iterator for Scan<'a> {
+    type Item = Result<Row, CorruptPage>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(corrupt) = self.corrupt.take() {
+            return Some(Err(corrupt));
+        }
+        if self.cursor.is_at_end_of_table() {
+            return None;
+        }
+        let leaf = *self.cursor.offset();
+        let row = self.cursor.value(self.tree).and_then(|row| {
+            if self.last.is_some_and(|last| row.id <= last) {
+                return Err(CorruptPage(leaf));
+            }
+            self.last = Some(row.id);
+            self.tree
+                .advance_latched_cursor(&mut self.cursor, &mut self.latch)?;
+            Ok(row)
+        });
+        if row.is_err() {
+            self.cursor.end_of_table = true;
+        }
+        if self.cursor.is_at_end_of_table() {
+            self.latch = None;
+        }
+        Some(row)
+    }
+}
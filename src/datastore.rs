@@ -1,22 +1,62 @@
+use std::collections::{HashMap, HashSet};
 use std::fmt::Formatter;
+use std::fs;
+use std::io;
 use std::path::Path;
+use std::process::exit;
+use std::str::Utf8Error;
+use std::sync::Arc;
+use std::time::Duration;
 
+use crate::backup::Backup;
 use crate::btree::BTree;
-use crate::cursor::Cursor;
-use crate::page::{PAGE_SIZE, TABLE_MAX_PAGES};
-use crate::pager::Pager;
+use crate::bulk_load::BulkLoadResult;
+use crate::catalog::{self, AutoVacuum, Catalog, MAX_TABLE_NAME};
+use crate::cursor::{Cursor, Scan};
+use crate::integrity;
+use crate::page::{Page, DEFAULT_PAGE_SIZE, FREE_PAGE_TYPE};
+use crate::pager::{CorruptPage, Offset, Pager};
+use crate::parser::{ParseError, Select};
+use crate::pointer_map;
+use crate::query::{execute_select, ResultSet, SelectError, DEFAULT_SPILL_THRESHOLD};
+use crate::statement::PreparedStatement;
+use crate::trace::{event, Level};
+use crate::value::Value;
+use crate::vfs::{DiskVfs, Lock, MemoryVfs, Vfs};
+use crate::visualize;
 use crate::{Statement, StatementType};
 
+/// A row is stored as its id, then its username and email, each padded out with NULs.
+const USERNAME_OFFSET: usize = 4;
+const EMAIL_OFFSET: usize = 36;
 pub const ROW_SIZE: usize = 291;
-pub const ROWS_PER_PAGE: usize = PAGE_SIZE as usize / ROW_SIZE;
-pub const TABLE_MAX_ROWS: usize = ROWS_PER_PAGE * TABLE_MAX_PAGES;
+/// The table every new database starts out with, and the one the short `insert` form writes to.
+pub const TABLE_NAME: &str = "users";
+const HEADER_PAGE: Offset = Offset(0);
+pub const COLUMNS: [&str; 3] = ["id", "username", "email"];
+/// How many pages [`Table::backup`] copies at a time.
+const BACKUP_STEP_PAGES: usize = 64;
 
 #[derive(Debug, PartialEq)]
 pub enum ExecuteResult {
     InsertSuccess,
+    /// How many rows were deleted.
+    DeleteSuccess(usize),
+    CreateTableSuccess,
+    VacuumSuccess,
+    SnapshotSuccess,
     SelectSuccess(Vec<Row>),
+    QuerySuccess(ResultSet),
+    QueryError(String),
     TableFull,
     DuplicateKey,
+    /// Another connection held on to the file for longer than the busy timeout.
+    Busy,
+    /// The statement would change a database that was opened read-only.
+    ReadOnly,
+    /// The statement read a corrupt page. A statement that writes may have been part way through
+    /// its changes, so every change since the last commit is thrown away.
+    Corrupt(CorruptPage),
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -35,109 +75,1059 @@ impl std::fmt::Display for Row {
 impl Row {
     pub fn serialize(&self) -> Box<[u8]> {
         let mut ser = Vec::new();
-        ser.extend(self.id.to_ne_bytes());
-        ser.extend(self.username.as_str().as_bytes());
-        ser.resize(36, 0);
-        ser.extend(self.email.as_str().as_bytes());
-        ser.resize(291, 0);
+        ser.extend(self.id.to_le_bytes());
+        ser.extend(self.username.as_bytes());
+        ser.resize(EMAIL_OFFSET, 0);
+        ser.extend(self.email.as_bytes());
+        ser.resize(ROW_SIZE, 0);
 
         ser.into_boxed_slice()
     }
 
-    pub fn deserialize(data: &[u8]) -> Self {
-        let (id_bytes, rest) = data.split_at(std::mem::size_of::<u32>());
-        let id: u32 = u32::from_ne_bytes(id_bytes.try_into().unwrap());
-        let (username_bytes, email) = rest.split_at(32);
-        let mut username = std::str::from_utf8(username_bytes).unwrap().to_string();
+    /// Decodes a row written by [`Row::serialize`]. Fails if its text isn't UTF-8, which only
+    /// happens if the bytes were damaged.
+    pub fn deserialize(data: &[u8]) -> Result<Self, Utf8Error> {
+        let (id_bytes, rest) = data.split_at(USERNAME_OFFSET);
+        let id: u32 = u32::from_le_bytes(id_bytes.try_into().unwrap());
+        let (username_bytes, email) = rest.split_at(EMAIL_OFFSET - USERNAME_OFFSET);
+        let mut username = std::str::from_utf8(username_bytes)?.to_string();
         if let Some((u, _)) = username.split_once("\0") {
             username = u.to_string();
         }
-        let mut email = std::str::from_utf8(email).unwrap().to_string();
+        let mut email = std::str::from_utf8(email)?.to_string();
         if let Some((e, _)) = email.split_once("\0") {
             email = e.to_string();
         }
-        Self {
+        Ok(Self {
             id,
             username,
             email,
-        }
+        })
     }
 }
 
 pub struct Table {
-    btree: BTree,
+    pager: Arc<Pager>,
+    catalog: Catalog,
+    trees: HashMap<String, BTree>,
+    /// Every page a snapshot keeps alive, which copies made since mustn't free.
+    snapshot_pages: HashSet<Offset>,
+    spill_threshold: usize,
 }
 
 impl Table {
+    /// Opens a database file, creating it with an empty `users` table if it doesn't exist yet.
+    /// `:memory:` opens a new database that only lives as long as the table.
     pub fn open(filename: impl AsRef<Path>) -> Self {
-        let pager = Pager::open(filename);
-        let btree = BTree::new(pager);
+        or_exit(Self::with_pager(Pager::open(filename)))
+    }
+
+    /// Opens a database file like [`Table::open`], giving it pages of `page_size` bytes if it's
+    /// created. An existing database keeps the page size it was created with.
+    pub fn open_with_page_size(filename: impl AsRef<Path>, page_size: usize) -> Self {
+        or_exit(Self::with_pager(Pager::open_with_page_size(
+            filename, page_size,
+        )))
+    }
+
+    /// Opens an existing database file for reading only. Statements that would change it fail
+    /// with [`ExecuteResult::ReadOnly`], and nothing is written when it's closed. Fails if the
+    /// file doesn't exist, rather than creating it, if it's empty, or if it's corrupt.
+    pub fn open_read_only(filename: impl AsRef<Path>) -> io::Result<Self> {
+        let mut vfs = DiskVfs::open_read_only(&filename)?;
+        if vfs.size()? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is empty", filename.as_ref().display()),
+            ));
+        }
+        Self::with_pager(Pager::with_vfs(Box::new(vfs), DEFAULT_PAGE_SIZE)?)
+    }
+
+    /// Opens a database kept in some other storage.
+    pub fn with_vfs(vfs: Box<dyn Vfs>) -> Self {
+        or_exit(Pager::with_vfs(vfs, DEFAULT_PAGE_SIZE).and_then(Self::with_pager))
+    }
+
+    /// Reads the database in, creating it first if the file is empty.
+    fn with_pager(pager: Pager) -> io::Result<Self> {
+        let catalog = Catalog::new(pager.page_size());
+        let mut table = Table {
+            pager: Arc::new(pager),
+            catalog,
+            trees: HashMap::new(),
+            snapshot_pages: HashSet::new(),
+            spill_threshold: DEFAULT_SPILL_THRESHOLD,
+        };
+        table.begin(Lock::Shared)?;
+        if table.catalog.tables().is_empty() {
+            table.end();
+            table.create()?;
+        }
+        table.end();
+        Ok(table)
+    }
+
+    /// Writes out a new database straight away, so that other connections find it there.
+    fn create(&mut self) -> io::Result<()> {
+        self.begin_write()?;
+        // Someone else may have created it while this connection waited for the lock
+        if !self.catalog.tables().is_empty() {
+            return Ok(());
+        }
+        if self.pager.num_pages() == 0 {
+            self.catalog = Catalog::new(self.pager.page_size());
+            self.pager.commit_page(HEADER_PAGE, self.catalog.to_page());
+        }
+        self.create_table(TABLE_NAME);
+        self.commit()
+    }
+
+    /// Reads the catalog, the trees and the free list in from the file.
+    fn load(&mut self) -> io::Result<()> {
+        // The header has to be read before anyone knows whether to check it
+        self.pager.set_checksums(false);
+        let header = self.pager.get_page(&HEADER_PAGE)?;
+        let Some(catalog) = Catalog::load(&header) else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the file has no valid header",
+            ));
+        };
+        if catalog.checksums() && !header.checksum_matches() {
+            return Err(CorruptPage(HEADER_PAGE).into());
+        }
+        self.pager.set_checksums(catalog.checksums());
+        self.pager
+            .set_pointer_maps(catalog.auto_vacuum().has_pointer_maps());
+        self.pager.set_copy_on_write(catalog.copy_on_write());
+        self.trees = open_trees(&self.pager, &catalog);
+        self.catalog = catalog;
+        self.snapshot_pages = self.snapshot_pages()?;
+        self.load_free_list()?;
+        Ok(())
+    }
+
+    /// Takes the lock a statement needs. Other connections may have committed while this one held
+    /// no lock, so the first lock taken after that catches up with them. If that fails, the lock
+    /// is let go again, and the next one tries again.
+    pub(crate) fn begin(&mut self, lock: Lock) -> io::Result<()> {
+        let caught_up = self.pager.lock_level() > Lock::Unlocked;
+        self.pager.lock(lock)?;
+        if !caught_up {
+            if let Err(why) = self.refresh() {
+                self.unload();
+                self.pager.unlock(Lock::Unlocked);
+                return Err(why);
+            }
+        }
+        Ok(())
+    }
+
+    /// Takes the lock a statement needs, or returns the result the statement fails with.
+    fn begin_statement(&mut self, lock: Lock) -> Result<(), ExecuteResult> {
+        match self.begin(lock) {
+            Ok(()) => Ok(()),
+            Err(why) if why.kind() == io::ErrorKind::WouldBlock => Err(ExecuteResult::Busy),
+            Err(why) => match corrupt_page(&why) {
+                Some(corrupt) => Err(ExecuteResult::Corrupt(corrupt)),
+                None => {
+                    println!("Unable to read the database: {why}");
+                    exit(-1);
+                }
+            },
+        }
+    }
+
+    /// Throws away every change since the last commit, reads the database in again as it was then,
+    /// and lets go of the lock. If it can't be read, it's left empty, and the next statement tries
+    /// again.
+    fn forget(&mut self) {
+        if let Err(why) = self.pager.forget() {
+            println!("Unable to read the database: {why}");
+            exit(-1);
+        }
+        if self.load().is_err() {
+            self.unload();
+        }
+        self.pager.unlock(Lock::Unlocked);
+    }
+
+    /// Drops the catalog and the trees, so that the next lock taken reads them in again.
+    fn unload(&mut self) {
+        self.catalog = Catalog::new(self.pager.page_size());
+        self.trees.clear();
+        self.snapshot_pages.clear();
+    }
+
+    /// Takes the reserved lock for a change, failing if the database was opened read-only.
+    fn begin_write(&mut self) -> io::Result<()> {
+        if self.pager.read_only() {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "the database is read-only",
+            ));
+        }
+        self.begin(Lock::Reserved)
+    }
+
+    /// Lets go of a shared lock once a statement is done. A reserved lock is kept until the
+    /// changes made under it are committed.
+    pub(crate) fn end(&mut self) {
+        if self.pager.lock_level() == Lock::Shared {
+            self.pager.unlock(Lock::Unlocked);
+        }
+    }
 
-        Table { btree }
+    /// Finishes a commit that a crash interrupted, and loads everything again if another
+    /// connection committed since this one last looked.
+    fn refresh(&mut self) -> io::Result<()> {
+        self.pager.recover()?;
+        if self.pager.num_pages() == 0 {
+            return Ok(());
+        }
+        let header = self.pager.read_page(&HEADER_PAGE);
+        let loaded = !self.catalog.tables().is_empty();
+        if loaded && catalog::change_counter(header.as_bytes()) == self.catalog.change_counter() {
+            return Ok(());
+        }
+        if loaded {
+            event!(Level::Info, "reload");
+        }
+        self.pager.forget()?;
+        self.load()
     }
 
     pub fn execute_statement(&mut self, stmt: Statement) -> ExecuteResult {
-        match stmt.statement_type {
-            StatementType::Insert => self.execute_insert(stmt.row_to_insert.unwrap()),
+        event!(
+            Level::Info,
+            "execute",
+            statement = stmt.statement_type.kind()
+        );
+        if stmt.statement_type.writes() && self.pager.read_only() {
+            return ExecuteResult::ReadOnly;
+        }
+        let lock = if stmt.statement_type.writes() {
+            Lock::Reserved
+        } else {
+            Lock::Shared
+        };
+        if let Err(result) = self.begin_statement(lock) {
+            return result;
+        }
+        let writes = stmt.statement_type.writes();
+        let result = match stmt.statement_type {
+            StatementType::Insert => self.execute_insert(TABLE_NAME, stmt.row_to_insert.unwrap()),
+            StatementType::InsertInto(name) => {
+                self.execute_insert(&name, stmt.row_to_insert.unwrap())
+            }
             StatementType::Select => self.execute_select(),
+            StatementType::Query(select) => self.execute_query(&select),
+            StatementType::CreateTable(name) => self.execute_create_table(&name),
+            StatementType::Pragma(name, value) => self.execute_pragma(&name, value.as_deref()),
+            StatementType::Vacuum(into) => self.execute_vacuum(into.as_deref()),
+            StatementType::CreateSnapshot(name) => self.execute_create_snapshot(&name),
+            StatementType::DropSnapshot(name) => self.execute_drop_snapshot(&name),
+        };
+        if writes && matches!(result, ExecuteResult::Corrupt(_)) {
+            self.forget();
+        }
+        self.end();
+        result
+    }
+
+    /// Parses a statement once, to be bound and executed any number of times.
+    pub fn prepare(&self, sql: &str) -> Result<PreparedStatement, ParseError> {
+        PreparedStatement::new(sql)
+    }
+
+    /// Sets how many groups a query may hold in memory before spilling them to temporary pages.
+    pub fn set_spill_threshold(&mut self, spill_threshold: usize) {
+        self.spill_threshold = spill_threshold;
+    }
+
+    /// Caps the size of the file, in pages. Inserts that would need to grow it further fail with
+    /// [`ExecuteResult::TableFull`]. The cap can't go below the pages already in use, and that's
+    /// what it's set to if asked to; returns the cap that was set.
+    pub fn set_max_page_count(&mut self, max_page_count: usize) -> usize {
+        self.pager.set_max_page_count(max_page_count)
+    }
+
+    /// Lets up to `mmap_size` bytes of the file be read through a memory map, decoding pages
+    /// straight from it rather than copying them into the cache. 0, the default, turns it off.
+    /// Writes go through the cache either way. Returns the size that was set.
+    pub fn set_mmap_size(&mut self, mmap_size: usize) -> usize {
+        self.pager.set_mmap_size(mmap_size);
+        mmap_size
+    }
+
+    /// Makes every change so far durable, and lets go of the lock. A crash at any point leaves
+    /// the database with all of them or none. Fails with [`io::ErrorKind::WouldBlock`] if other
+    /// connections keep reading for longer than the busy timeout; the changes are kept, to be
+    /// committed again. If they ran into a corrupt page, they're thrown away instead.
+    pub fn commit(&mut self) -> io::Result<()> {
+        // Nothing was let change a read-only database, so there's nothing to write
+        if self.pager.read_only()
+            || self.pager.lock_level() < Lock::Reserved && !self.pager.has_changes()
+        {
+            return Ok(());
         }
+        let result = self.write_changes();
+        // Changes that ran into a corrupt page can never be written, so they're thrown away
+        if result
+            .as_ref()
+            .is_err_and(|why| corrupt_page(why).is_some())
+        {
+            self.forget();
+        }
+        result
+    }
+
+    fn write_changes(&mut self) -> io::Result<()> {
+        self.auto_vacuum()?;
+        self.free_retired();
+        self.catalog.bump_change_counter();
+        self.save_free_list();
+        self.pager.lock(Lock::Exclusive)?;
+        self.pager.flush()?;
+        self.pager.unlock(Lock::Unlocked);
+        Ok(())
     }
 
+    /// Commits anything still uncommitted, and deletes the journal unless other connections are
+    /// still using the file.
     pub fn close(&mut self) {
-        self.btree.close()
+        if let Err(why) = self.commit() {
+            println!("Unable to write page to file because: {why}");
+            exit(-1);
+        }
+        if !self.pager.read_only() && self.pager.try_lock(Lock::Exclusive) {
+            if let Err(why) = self.pager.remove_journal() {
+                println!("Unable to remove the journal because: {why}");
+            }
+        }
+        self.pager.unlock(Lock::Unlocked);
+    }
+
+    pub fn busy_timeout(&self) -> Duration {
+        self.pager.busy_timeout()
+    }
+
+    /// Sets how long to wait for other connections to let go of the file before giving up with
+    /// [`ExecuteResult::Busy`].
+    pub fn set_busy_timeout(&mut self, busy_timeout: Duration) {
+        self.pager.set_busy_timeout(busy_timeout);
+    }
+
+    /// In full auto-vacuum mode, gives every free page back before a commit.
+    fn auto_vacuum(&mut self) -> io::Result<()> {
+        if self.catalog.auto_vacuum() == AutoVacuum::Full {
+            self.incremental_vacuum(usize::MAX)?;
+        }
+        Ok(())
+    }
+
+    /// Frees the pages that copies replaced, unless a snapshot still uses them.
+    fn free_retired(&mut self) {
+        for offset in self.pager.take_retired() {
+            if !self.snapshot_pages.contains(&offset) {
+                self.pager.recycle(offset);
+            }
+        }
+    }
+
+    /// Shrinks the file by up to `limit` free pages, moving pages off its end into them. Returns
+    /// how many pages were given back. Only databases with pointer maps can do this; others give
+    /// back nothing. Fails with [`io::ErrorKind::WouldBlock`] if another connection keeps writing
+    /// for longer than the busy timeout.
+    pub fn incremental_vacuum(&mut self, limit: usize) -> io::Result<usize> {
+        self.begin_write()?;
+        if !self.catalog.auto_vacuum().has_pointer_maps() {
+            return Ok(0);
+        }
+        let freed = pointer_map::incremental_vacuum(&self.pager, &mut self.catalog, limit)?;
+        if freed > 0 {
+            self.trees = open_trees(&self.pager, &self.catalog);
+            self.pager.commit_page(HEADER_PAGE, self.catalog.to_page());
+        }
+        Ok(freed)
+    }
+
+    /// Changes when free pages are given back. Turning pointer maps on or off changes the layout of
+    /// the whole file, so that rebuilds and commits it like [`Table::vacuum`].
+    pub fn set_auto_vacuum(&mut self, auto_vacuum: AutoVacuum) -> io::Result<()> {
+        self.begin_write()?;
+        if auto_vacuum.has_pointer_maps() && self.catalog.copy_on_write() {
+            return Err(io::Error::other(
+                "pointer maps can't be kept while copying on write",
+            ));
+        }
+        let rebuild =
+            auto_vacuum.has_pointer_maps() != self.catalog.auto_vacuum().has_pointer_maps();
+        self.catalog.set_auto_vacuum(auto_vacuum);
+        if rebuild {
+            self.vacuum()
+        } else {
+            self.pager.commit_page(HEADER_PAGE, self.catalog.to_page());
+            Ok(())
+        }
+    }
+
+    /// Rebuilds the database: every table is loaded again in key order onto consecutive pages,
+    /// and the free pages are dropped. The rebuilt file replaces this one in a single commit,
+    /// along with any other changes that weren't committed yet.
+    pub fn vacuum(&mut self) -> io::Result<()> {
+        self.begin_write()?;
+        if !self.catalog.snapshots().is_empty() {
+            return Err(io::Error::other(
+                "snapshots keep pages where they are, so they have to be dropped first",
+            ));
+        }
+        let compacted = Arc::new(Pager::with_vfs(
+            Box::new(MemoryVfs::new()),
+            self.pager.page_size(),
+        )?);
+        self.catalog = self.rebuild_into(&compacted)?;
+        self.pager.copy_from(&compacted)?;
+        self.pager.set_pointer_maps(compacted.pointer_maps());
+        // The pages copies replaced are gone along with everything else
+        self.pager.take_retired();
+        self.trees = open_trees(&self.pager, &self.catalog);
+        self.commit()
+    }
+
+    /// Writes a rebuilt copy of the database, as [`Table::vacuum`] would leave it, to a new file
+    /// at `path`. This database is left as it is.
+    pub fn vacuum_into(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        if fs::metadata(&path).is_ok_and(|metadata| metadata.len() > 0) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already exists", path.as_ref().display()),
+            ));
+        }
+        let pager = Arc::new(Pager::with_vfs(
+            Box::new(DiskVfs::open(path)?),
+            self.pager.page_size(),
+        )?);
+        self.rebuild_into(&pager)?;
+        pager.flush()
+    }
+
+    /// Copies the database, as of its last commit, to the file at `path`. See [`Backup`] for
+    /// copying it a few pages at a time while other connections keep writing.
+    pub fn backup(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut backup = Backup::new(self, path)?;
+        while !backup.step(self, BACKUP_STEP_PAGES)? {}
+        Ok(())
+    }
+
+    /// Copies every table, in key order, into an empty file. Returns the copy's catalog.
+    fn rebuild_into(&self, pager: &Arc<Pager>) -> io::Result<Catalog> {
+        let mut catalog = Catalog::new(pager.page_size());
+        catalog.set_checksums(self.catalog.checksums());
+        catalog.set_auto_vacuum(self.catalog.auto_vacuum());
+        catalog.set_copy_on_write(self.catalog.copy_on_write());
+        pager.set_checksums(self.catalog.checksums());
+        pager.set_pointer_maps(self.catalog.auto_vacuum().has_pointer_maps());
+        // Claim the header's page before the trees take any
+        pager.commit_page(HEADER_PAGE, catalog.to_page());
+        for (name, _) in self.catalog.tables() {
+            let mut corrupt = None;
+            let rows = Scan::new(&self.trees[name])
+                .map_while(|row| row.map_err(|why| corrupt = Some(why)).ok());
+            let built = BTree::build(pager.clone(), rows, 1.0);
+            // A corrupt page ends the scan early, which the load can't tell from the end
+            if let Some(why) = corrupt {
+                return Err(why.into());
+            }
+            match built {
+                Ok(tree) => catalog.add(name, tree.root()),
+                Err(BulkLoadResult::Corrupt(why)) => return Err(why.into()),
+                Err(why) => {
+                    return Err(io::Error::other(format!(
+                        "rows scanned from {name} didn't load: {why:?}"
+                    )))
+                }
+            }
+        }
+        pager.commit_page(HEADER_PAGE, catalog.to_page());
+        Ok(catalog)
+    }
+
+    /// Starts or stops copying pages on write, which snapshots need. Trees don't keep their parent
+    /// and sibling pointers up to date while it's on, so stopping rebuilds the file like
+    /// [`Table::vacuum`] does, and can't be done while there are snapshots.
+    pub fn set_copy_on_write(&mut self, copy_on_write: bool) -> io::Result<()> {
+        self.begin_write()?;
+        if copy_on_write == self.catalog.copy_on_write() {
+            return Ok(());
+        }
+        if copy_on_write && self.catalog.auto_vacuum().has_pointer_maps() {
+            return Err(io::Error::other(
+                "pointer maps can't be kept while copying on write",
+            ));
+        }
+        if !copy_on_write && !self.catalog.snapshots().is_empty() {
+            return Err(io::Error::other(
+                "snapshots need copying on write, so they have to be dropped first",
+            ));
+        }
+        self.catalog.set_copy_on_write(copy_on_write);
+        if copy_on_write {
+            self.pager.set_copy_on_write(true);
+            self.pager.commit_page(HEADER_PAGE, self.catalog.to_page());
+            Ok(())
+        } else {
+            // The old trees are scanned by key, the way they were written
+            let result = self.vacuum();
+            match result {
+                Ok(()) => self.pager.set_copy_on_write(false),
+                Err(_) => self.catalog.set_copy_on_write(true),
+            }
+            result
+        }
+    }
+
+    /// Every page the snapshots keep alive: the ones their catalogs are kept on, and every page of
+    /// their trees.
+    fn snapshot_pages(&self) -> Result<HashSet<Offset>, CorruptPage> {
+        let mut pages = HashSet::new();
+        for (_, page) in self.catalog.snapshots() {
+            pages.insert(*page);
+            for tree in self.snapshot_trees(*page)?.values() {
+                pages.extend(tree.pages()?);
+            }
+        }
+        Ok(pages)
+    }
+
+    /// The trees of the snapshot whose catalog is kept on `page`.
+    fn snapshot_trees(&self, page: Offset) -> Result<HashMap<String, BTree>, CorruptPage> {
+        Ok(match Catalog::load(&self.pager.get_page(&page)?) {
+            Some(catalog) => open_trees(&self.pager, &catalog),
+            None => HashMap::new(),
+        })
+    }
+
+    /// Checks every table's B-tree and the free list, returning a description of each problem
+    /// found, with the page it's on. No problems means the database is consistent.
+    pub fn integrity_check(&self) -> Vec<String> {
+        integrity::check(&self.pager, &self.catalog)
+    }
+
+    /// Each table's B-tree as an indented outline of its pages.
+    pub fn btree_outline(&self) -> String {
+        visualize::outline(&self.pager, self.catalog.tables())
+    }
+
+    /// Each table's B-tree as a Graphviz DOT graph.
+    pub fn btree_dot(&self) -> String {
+        visualize::dot(&self.pager, self.catalog.tables())
+    }
+
+    /// Hands the pages on the free list back to the pager. A broken list is cut short, and the
+    /// pages past the break show up in the integrity check as never used.
+    fn load_free_list(&self) -> Result<(), CorruptPage> {
+        let (mut next, count) = self.catalog.free_list();
+        let mut seen = HashSet::new();
+        while let Some(offset) = next {
+            if seen.len() >= count || offset.0 >= self.pager.num_pages() || !seen.insert(offset) {
+                break;
+            }
+            let page = self.pager.get_page(&offset)?;
+            if page.node_type() != FREE_PAGE_TYPE {
+                break;
+            }
+            self.pager.recycle(offset);
+            next = page.next_free();
+        }
+        Ok(())
+    }
+
+    /// Chains the free pages together and records the first one in the header.
+    fn save_free_list(&mut self) {
+        let free = self.pager.free_pages();
+        for (i, offset) in free.iter().enumerate() {
+            self.pager.commit_page(
+                *offset,
+                Page::free(free.get(i + 1).copied(), self.pager.page_size()),
+            );
+        }
+        self.catalog
+            .set_free_list(free.first().copied(), free.len());
+        self.pager.commit_page(HEADER_PAGE, self.catalog.to_page());
+    }
+
+    /// Takes a shared lock and keeps it until the table is dropped. See [`Pager::pin_shared`].
+    pub(crate) fn pin(&mut self) -> io::Result<()> {
+        self.begin(Lock::Shared)?;
+        self.pager.pin_shared();
+        Ok(())
+    }
+
+    pub(crate) fn pager(&self) -> &Arc<Pager> {
+        &self.pager
+    }
+
+    pub(crate) fn trees(&self) -> &HashMap<String, BTree> {
+        &self.trees
+    }
+
+    /// Whether committing moves pages around, as full auto-vacuum does, or statements move roots,
+    /// as copying on write does.
+    pub(crate) fn commit_moves_pages(&self) -> bool {
+        self.catalog.auto_vacuum() == AutoVacuum::Full || self.catalog.copy_on_write()
+    }
+
+    pub fn find(&self, key: usize) -> Result<Result<Cursor, Cursor>, CorruptPage> {
+        self.trees[TABLE_NAME].find(key)
+    }
+
+    fn create_table(&mut self, name: &str) {
+        let tree = BTree::create(self.pager.clone());
+        self.catalog.add(name, tree.root());
+        self.pager.commit_page(HEADER_PAGE, self.catalog.to_page());
+        self.trees.insert(name.to_string(), tree);
     }
 
-    pub fn find(&self, key: usize) -> Result<Cursor, Cursor> {
-        self.btree.find(key)
+    fn execute_create_table(&mut self, name: &str) -> ExecuteResult {
+        if self.trees.contains_key(name) {
+            return ExecuteResult::QueryError(format!("table {name} already exists"));
+        }
+        if name.len() > MAX_TABLE_NAME {
+            return ExecuteResult::QueryError(format!("table name {name} is too long"));
+        }
+        if self.catalog.is_full() {
+            return ExecuteResult::QueryError(String::from("too many tables"));
+        }
+        self.create_table(name);
+        ExecuteResult::CreateTableSuccess
     }
 
-    fn execute_insert(&mut self, row: Row) -> ExecuteResult {
-        match self.find(row.id as usize) {
-            Ok(_duplicate_location) => ExecuteResult::DuplicateKey,
-            Err(cursor) => {
-                dbg!(&cursor);
+    fn execute_insert(&mut self, name: &str, row: Row) -> ExecuteResult {
+        let Some(tree) = self.trees.get_mut(name) else {
+            return ExecuteResult::QueryError(format!("no such table: {name}"));
+        };
+        match tree.find(row.id as usize) {
+            Err(corrupt) => ExecuteResult::Corrupt(corrupt),
+            Ok(Ok(_duplicate_location)) => ExecuteResult::DuplicateKey,
+            Ok(Err(cursor)) => {
                 if cursor.offset().0 == usize::MAX {
                     return ExecuteResult::TableFull;
                 }
-                if !self.btree.insert(row.id as usize, row) {
-                    return ExecuteResult::TableFull;
+                match tree.insert(row.id as usize, row) {
+                    Ok(true) => {}
+                    Ok(false) => return ExecuteResult::TableFull,
+                    Err(corrupt) => return ExecuteResult::Corrupt(corrupt),
                 }
+                self.follow_root(name);
                 ExecuteResult::InsertSuccess
             }
         }
     }
 
+    pub fn delete(&mut self, name: &str, id: u32) -> ExecuteResult {
+        if self.pager.read_only() {
+            return ExecuteResult::ReadOnly;
+        }
+        if let Err(result) = self.begin_statement(Lock::Reserved) {
+            return result;
+        }
+        let Some(tree) = self.trees.get_mut(name) else {
+            return ExecuteResult::QueryError(format!("no such table: {name}"));
+        };
+        let deleted = match tree.delete(id as usize) {
+            Ok(deleted) => deleted,
+            Err(corrupt) => {
+                self.forget();
+                return ExecuteResult::Corrupt(corrupt);
+            }
+        };
+        self.follow_root(name);
+        ExecuteResult::DeleteSuccess(deleted as usize)
+    }
+
+    /// Loads rows sorted by id into an empty table. See [`BTree::bulk_load`].
+    pub fn bulk_load(
+        &mut self,
+        name: &str,
+        rows: impl IntoIterator<Item = Row>,
+        fill_factor: f64,
+    ) -> ExecuteResult {
+        if self.pager.read_only() {
+            return ExecuteResult::ReadOnly;
+        }
+        if let Err(result) = self.begin_statement(Lock::Reserved) {
+            return result;
+        }
+        let Some(tree) = self.trees.get_mut(name) else {
+            return ExecuteResult::QueryError(format!("no such table: {name}"));
+        };
+        let result = tree.bulk_load(rows, fill_factor);
+        self.follow_root(name);
+        match result {
+            BulkLoadResult::Success(_) => ExecuteResult::InsertSuccess,
+            BulkLoadResult::NotEmpty => {
+                ExecuteResult::QueryError(format!("table {name} must be empty to bulk load"))
+            }
+            BulkLoadResult::DuplicateKey(_) => ExecuteResult::DuplicateKey,
+            BulkLoadResult::TableFull => ExecuteResult::TableFull,
+            BulkLoadResult::Unsorted(id) => {
+                ExecuteResult::QueryError(format!("rows must be sorted by id, but {id} is not"))
+            }
+            BulkLoadResult::Corrupt(corrupt) => {
+                self.forget();
+                ExecuteResult::Corrupt(corrupt)
+            }
+        }
+    }
+
+    /// Records where a table's root is after copying on write moved it.
+    fn follow_root(&mut self, name: &str) {
+        let root = self.trees[name].root();
+        if let Some(old) = self.catalog.root(name).filter(|&old| old != root) {
+            self.catalog.move_root(old, root);
+            self.pager.commit_page(HEADER_PAGE, self.catalog.to_page());
+        }
+    }
+
+    /// Keeps every table as it is now under `name`. The snapshot shares its pages with the
+    /// tables until they're changed.
+    fn execute_create_snapshot(&mut self, name: &str) -> ExecuteResult {
+        if !self.catalog.copy_on_write() {
+            return ExecuteResult::QueryError(String::from(
+                "snapshots need copy_on_write to be on",
+            ));
+        }
+        if self.catalog.snapshot(name).is_some() {
+            return ExecuteResult::QueryError(format!("snapshot {name} already exists"));
+        }
+        if name.len() > MAX_TABLE_NAME {
+            return ExecuteResult::QueryError(format!("snapshot name {name} is too long"));
+        }
+        if self.catalog.is_full() {
+            return ExecuteResult::QueryError(String::from("too many tables and snapshots"));
+        }
+        let page = self.pager.new_page();
+        self.pager
+            .commit_page(page, self.catalog.to_snapshot().to_page());
+        self.catalog.add_snapshot(name, page);
+        self.pager.commit_page(HEADER_PAGE, self.catalog.to_page());
+        // The tables' pages are the snapshot's now too, so even those written since the last
+        // commit get copied from here on
+        self.pager.seal();
+        match self.snapshot_pages() {
+            Ok(pages) => self.snapshot_pages = pages,
+            Err(corrupt) => return ExecuteResult::Corrupt(corrupt),
+        }
+        ExecuteResult::SnapshotSuccess
+    }
+
+    /// Forgets a snapshot, freeing the pages nothing else uses.
+    fn execute_drop_snapshot(&mut self, name: &str) -> ExecuteResult {
+        let Some(page) = self.catalog.remove_snapshot(name) else {
+            return ExecuteResult::QueryError(format!("no such snapshot: {name}"));
+        };
+        match self.drop_snapshot(page) {
+            Ok(()) => ExecuteResult::SnapshotSuccess,
+            Err(corrupt) => ExecuteResult::Corrupt(corrupt),
+        }
+    }
+
+    /// Frees the pages of a snapshot that's been taken out of the catalog.
+    fn drop_snapshot(&mut self, page: Offset) -> Result<(), CorruptPage> {
+        let mut dropped = vec![page];
+        for tree in self.snapshot_trees(page)?.values() {
+            dropped.extend(tree.pages()?);
+        }
+        self.snapshot_pages = self.snapshot_pages()?;
+        // Pages already retired get freed by the commit
+        let mut keep: HashSet<Offset> = self.pager.retired().into_iter().collect();
+        for tree in self.trees.values() {
+            keep.extend(tree.pages()?);
+        }
+        keep.extend(&self.snapshot_pages);
+        for offset in dropped {
+            if keep.insert(offset) {
+                self.pager.recycle(offset);
+            }
+        }
+        self.pager.commit_page(HEADER_PAGE, self.catalog.to_page());
+        Ok(())
+    }
+
+    fn execute_pragma(&mut self, name: &str, value: Option<&str>) -> ExecuteResult {
+        match (name, value) {
+            ("integrity_check", None) => {
+                let mut problems = self.integrity_check();
+                if problems.is_empty() {
+                    problems.push(String::from("ok"));
+                }
+                ExecuteResult::QuerySuccess(ResultSet {
+                    columns: vec![String::from(name)],
+                    rows: problems.into_iter().map(|p| vec![Value::Text(p)]).collect(),
+                })
+            }
+            ("integrity_check", Some(_)) => {
+                ExecuteResult::QueryError(String::from("integrity_check doesn't take a value"))
+            }
+            ("max_page_count", setting) => {
+                let max_page_count = match setting.map(str::parse::<usize>) {
+                    None => self.pager.max_page_count(),
+                    Some(Ok(max_page_count)) => self.set_max_page_count(max_page_count),
+                    Some(Err(_)) => {
+                        return ExecuteResult::QueryError(String::from(
+                            "max_page_count must be a number of pages",
+                        ))
+                    }
+                };
+                ExecuteResult::QuerySuccess(ResultSet {
+                    columns: vec![String::from(name)],
+                    rows: vec![vec![Value::Integer(max_page_count as i64)]],
+                })
+            }
+            ("mmap_size", setting) => {
+                let mmap_size = match setting.map(str::parse::<usize>) {
+                    None => self.pager.mmap_size(),
+                    Some(Ok(mmap_size)) => self.set_mmap_size(mmap_size),
+                    Some(Err(_)) => {
+                        return ExecuteResult::QueryError(String::from(
+                            "mmap_size must be a number of bytes",
+                        ))
+                    }
+                };
+                ExecuteResult::QuerySuccess(ResultSet {
+                    columns: vec![String::from(name)],
+                    rows: vec![vec![Value::Integer(mmap_size as i64)]],
+                })
+            }
+            ("busy_timeout", setting) => {
+                let busy_timeout = match setting.map(str::parse::<u64>) {
+                    None => self.busy_timeout(),
+                    Some(Ok(millis)) => {
+                        self.set_busy_timeout(Duration::from_millis(millis));
+                        self.busy_timeout()
+                    }
+                    Some(Err(_)) => {
+                        return ExecuteResult::QueryError(String::from(
+                            "busy_timeout must be a number of milliseconds",
+                        ))
+                    }
+                };
+                ExecuteResult::QuerySuccess(ResultSet {
+                    columns: vec![String::from(name)],
+                    rows: vec![vec![Value::Integer(busy_timeout.as_millis() as i64)]],
+                })
+            }
+            ("page_size", None) => ExecuteResult::QuerySuccess(ResultSet {
+                columns: vec![String::from(name)],
+                rows: vec![vec![Value::Integer(self.pager.page_size() as i64)]],
+            }),
+            ("page_size", Some(_)) => ExecuteResult::QueryError(String::from(
+                "page_size is chosen when a database is created",
+            )),
+            ("auto_vacuum", setting) => {
+                let auto_vacuum = match setting.map(str::to_lowercase).as_deref() {
+                    None => self.catalog.auto_vacuum(),
+                    Some("none" | "0") => AutoVacuum::None,
+                    Some("full" | "1") => AutoVacuum::Full,
+                    Some("incremental" | "2") => AutoVacuum::Incremental,
+                    Some(_) => {
+                        return ExecuteResult::QueryError(String::from(
+                            "auto_vacuum must be none, full or incremental",
+                        ))
+                    }
+                };
+                if let Err(why) = self.set_auto_vacuum(auto_vacuum) {
+                    return match corrupt_page(&why) {
+                        Some(corrupt) => ExecuteResult::Corrupt(corrupt),
+                        None => ExecuteResult::QueryError(format!("vacuum failed: {why}")),
+                    };
+                }
+                ExecuteResult::QuerySuccess(ResultSet {
+                    columns: vec![String::from(name)],
+                    rows: vec![vec![Value::Text(auto_vacuum.to_string())]],
+                })
+            }
+            ("incremental_vacuum", setting) => {
+                if !self.catalog.auto_vacuum().has_pointer_maps() {
+                    return ExecuteResult::QueryError(String::from(
+                        "incremental_vacuum needs auto_vacuum to be full or incremental",
+                    ));
+                }
+                let limit = match setting.map(str::parse::<usize>) {
+                    None | Some(Ok(0)) => usize::MAX,
+                    Some(Ok(limit)) => limit,
+                    Some(Err(_)) => {
+                        return ExecuteResult::QueryError(String::from(
+                            "incremental_vacuum takes a number of pages",
+                        ))
+                    }
+                };
+                let freed = match self.incremental_vacuum(limit) {
+                    Ok(freed) => freed,
+                    Err(why) if why.kind() == io::ErrorKind::WouldBlock => {
+                        return ExecuteResult::Busy
+                    }
+                    Err(why) => {
+                        return match corrupt_page(&why) {
+                            Some(corrupt) => ExecuteResult::Corrupt(corrupt),
+                            None => ExecuteResult::QueryError(format!("vacuum failed: {why}")),
+                        }
+                    }
+                };
+                ExecuteResult::QuerySuccess(ResultSet {
+                    columns: vec![String::from(name)],
+                    rows: vec![vec![Value::Integer(freed as i64)]],
+                })
+            }
+            ("page_checksums", setting) => {
+                let checksums = match setting.map(str::to_lowercase).as_deref() {
+                    None => self.catalog.checksums(),
+                    Some("on" | "1") => true,
+                    Some("off" | "0") => false,
+                    Some(_) => {
+                        return ExecuteResult::QueryError(String::from(
+                            "page_checksums must be on or off",
+                        ))
+                    }
+                };
+                if let Err(corrupt) = self.set_checksums(checksums) {
+                    return ExecuteResult::Corrupt(corrupt);
+                }
+                let setting = if checksums { "on" } else { "off" };
+                ExecuteResult::QuerySuccess(ResultSet {
+                    columns: vec![String::from(name)],
+                    rows: vec![vec![Value::Text(String::from(setting))]],
+                })
+            }
+            ("copy_on_write", setting) => {
+                let copy_on_write = match setting.map(str::to_lowercase).as_deref() {
+                    None => self.catalog.copy_on_write(),
+                    Some("on" | "1") => true,
+                    Some("off" | "0") => false,
+                    Some(_) => {
+                        return ExecuteResult::QueryError(String::from(
+                            "copy_on_write must be on or off",
+                        ))
+                    }
+                };
+                if let Err(why) = self.set_copy_on_write(copy_on_write) {
+                    return match corrupt_page(&why) {
+                        Some(corrupt) => ExecuteResult::Corrupt(corrupt),
+                        None => ExecuteResult::QueryError(why.to_string()),
+                    };
+                }
+                let setting = if copy_on_write { "on" } else { "off" };
+                ExecuteResult::QuerySuccess(ResultSet {
+                    columns: vec![String::from(name)],
+                    rows: vec![vec![Value::Text(String::from(setting))]],
+                })
+            }
+            _ => ExecuteResult::QueryError(format!("unknown pragma: {name}")),
+        }
+    }
+
+    /// Turning checksums on rewrites every page on the next commit, so each one gets a checksum.
+    /// Turning them off leaves the old checksums where they are, unchecked.
+    fn set_checksums(&mut self, checksums: bool) -> Result<(), CorruptPage> {
+        if checksums == self.catalog.checksums() {
+            return Ok(());
+        }
+        if checksums {
+            self.pager.rewrite_all()?;
+        }
+        self.catalog.set_checksums(checksums);
+        self.pager.set_checksums(checksums);
+        self.pager.commit_page(HEADER_PAGE, self.catalog.to_page());
+        Ok(())
+    }
+
+    fn execute_vacuum(&mut self, into: Option<&str>) -> ExecuteResult {
+        let result = match into {
+            Some(path) => self.vacuum_into(path),
+            None => self.vacuum(),
+        };
+        match result {
+            Ok(()) => ExecuteResult::VacuumSuccess,
+            Err(why) => match corrupt_page(&why) {
+                Some(corrupt) => ExecuteResult::Corrupt(corrupt),
+                None => ExecuteResult::QueryError(format!("vacuum failed: {why}")),
+            },
+        }
+    }
+
     fn execute_select(&self) -> ExecuteResult {
-        let mut rows = Vec::new();
-        let mut cursor = Cursor::start(&self.btree);
-        while !cursor.is_at_end_of_table() {
-            let row = cursor.value(&self.btree);
-            rows.push(row.clone());
-            self.btree.advance_cursor(&mut cursor);
+        match Scan::new(&self.trees[TABLE_NAME]).collect() {
+            Ok(rows) => ExecuteResult::SelectSuccess(rows),
+            Err(corrupt) => ExecuteResult::Corrupt(corrupt),
         }
-        ExecuteResult::SelectSuccess(rows)
     }
+
+    fn execute_query(&self, select: &Select) -> ExecuteResult {
+        let snapshot;
+        let trees = match &select.as_of {
+            None => &self.trees,
+            Some(name) => match self.catalog.snapshot(name) {
+                Some(page) => match self.snapshot_trees(page) {
+                    Ok(trees) => {
+                        snapshot = trees;
+                        &snapshot
+                    }
+                    Err(corrupt) => return ExecuteResult::Corrupt(corrupt),
+                },
+                None => return ExecuteResult::QueryError(format!("no such snapshot: {name}")),
+            },
+        };
+        match execute_select(select, trees, self.spill_threshold) {
+            Ok(results) => ExecuteResult::QuerySuccess(results),
+            Err(SelectError::Query(why)) => ExecuteResult::QueryError(why.to_string()),
+            Err(SelectError::Corrupt(corrupt)) => ExecuteResult::Corrupt(corrupt),
+        }
+    }
+}
+
+/// Reports why a database couldn't be opened, and exits.
+fn or_exit(table: io::Result<Table>) -> Table {
+    match table {
+        Ok(table) => table,
+        Err(why) => {
+            println!("Unable to open the database: {why}");
+            exit(-1);
+        }
+    }
+}
+
+/// The corrupt page an error is about, if it's about one.
+fn corrupt_page(why: &io::Error) -> Option<CorruptPage> {
+    why.get_ref()?.downcast_ref::<CorruptPage>().copied()
+}
+
+fn open_trees(pager: &Arc<Pager>, catalog: &Catalog) -> HashMap<String, BTree> {
+    catalog
+        .tables()
+        .iter()
+        .map(|(name, root)| (name.clone(), BTree::open(pager.clone(), *root)))
+        .collect()
 }
 
 #[cfg(test)]
 mod tests {
-    use std::fs::OpenOptions;
+    use std::io;
+    use std::time::Duration;
 
-    use crate::datastore::TABLE_MAX_ROWS;
-    use crate::page::Page;
+    use crate::btree::{CELL_KEY_SIZE, CELL_OFFSET, CELL_SIZE};
+    use crate::cursor::Scan;
+    use crate::datastore::{HEADER_PAGE, TABLE_NAME, USERNAME_OFFSET};
+    use crate::fixtures::{
+        insert, insert_row, open_test_db, pragma, row, select, statement, temp_path,
+    };
+    use crate::page::{crc32c, Page, DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE, MIN_PAGE_SIZE};
+    use crate::pager::{CorruptPage, Offset};
+    use crate::value::Value;
+    use crate::vfs::{DiskVfs, Lock, Vfs};
     use crate::{ExecuteResult, Row, Statement, StatementType, Table};
 
-    fn open_test_db() -> Table {
-        let test_db = OpenOptions::new()
-            .write(true)
-            .truncate(true)
-            .create(true)
-            .open("test.db")
-            .expect("test database");
-        test_db.sync_all().expect("sync changes to disk");
-        Table::open("test.db")
-    }
-
     #[test]
     fn serialize_tests() {
         let r = Row {
@@ -146,7 +1136,7 @@ mod tests {
             email: String::from("bbuford@example.com"),
         };
         let ser = r.serialize();
-        let de = Row::deserialize(&*ser);
+        let de = Row::deserialize(&ser).unwrap();
         assert_eq!(r.id, de.id);
         assert_eq!(r.username, de.username);
         assert_eq!(r.email, de.email);
@@ -157,7 +1147,7 @@ mod tests {
             email: String::from(""),
         };
         let ser = r.serialize();
-        let de = Row::deserialize(&*ser);
+        let de = Row::deserialize(&ser).unwrap();
         assert_eq!(r.id, de.id);
         assert_eq!(r.username, de.username);
         assert_eq!(r.email, de.email);
@@ -168,7 +1158,7 @@ mod tests {
             email: String::from("bbuford@example.com"),
         };
         let ser = r.serialize();
-        let de = Row::deserialize(&*ser);
+        let de = Row::deserialize(&ser).unwrap();
         assert_eq!(r.id, de.id);
         assert_ne!(r.username, de.username);
         assert_eq!(
@@ -180,7 +1170,7 @@ mod tests {
 
     #[test]
     fn page_insert_tests() {
-        let mut p = Page::new();
+        let mut p = Page::new(DEFAULT_PAGE_SIZE);
         let r = Row {
             id: 0,
             username: String::from("bbuford"),
@@ -188,12 +1178,12 @@ mod tests {
         };
         p.insert(r.clone(), 0);
         p.insert(r.clone(), 1);
-        let sel = p.select(0);
+        let sel = p.select(0).unwrap();
         assert_eq!(r.id, sel.id);
         assert_eq!(r.username, sel.username);
         assert_eq!(r.email, sel.email);
 
-        let sel = p.select(1);
+        let sel = p.select(1).unwrap();
         assert_eq!(r.id, sel.id);
         assert_eq!(r.username, sel.username);
         assert_eq!(r.email, sel.email);
@@ -218,10 +1208,7 @@ mod tests {
             ExecuteResult::InsertSuccess
         );
 
-        let statement = Statement {
-            statement_type: StatementType::Select,
-            row_to_insert: None,
-        };
+        let statement = select();
 
         let res = table.execute_statement(statement);
         assert!(matches!(res, ExecuteResult::SelectSuccess { .. }));
@@ -268,23 +1255,8 @@ mod tests {
     #[test]
     fn table_sorted_key_order() {
         let mut table = open_test_db();
-        for i in (0..12 as u32).rev() {
-            assert_eq!(
-                table.execute_statement(Statement {
-                    statement_type: StatementType::Insert,
-                    row_to_insert: Some(Row {
-                        id: i,
-                        username: String::from(format!("user{i}")),
-                        email: String::from(format!("user{i}@example.com")),
-                    }),
-                }),
-                ExecuteResult::InsertSuccess
-            );
-        }
-        if let ExecuteResult::SelectSuccess(rows) = table.execute_statement(Statement {
-            statement_type: StatementType::Select,
-            row_to_insert: None,
-        }) {
+        insert(&mut table, (0..12).rev());
+        if let ExecuteResult::SelectSuccess(rows) = table.execute_statement(select()) {
             for (idx, row) in rows.iter().enumerate() {
                 println!("{idx}");
                 assert_eq!(row.id, idx as u32);
@@ -293,4 +1265,668 @@ mod tests {
             panic!()
         }
     }
+
+    #[test]
+    fn tables_persist_in_the_catalog() {
+        let path = temp_path("catalog");
+        let admin = Row {
+            id: 7,
+            username: String::from("root"),
+            email: String::from("root@example.com"),
+        };
+
+        let mut table = Table::open(&path);
+        let create = |name: &str| Statement {
+            statement_type: StatementType::CreateTable(name.to_string()),
+            row_to_insert: None,
+        };
+        assert_eq!(
+            table.execute_statement(create("admins")),
+            ExecuteResult::CreateTableSuccess
+        );
+        assert!(matches!(
+            table.execute_statement(create("admins")),
+            ExecuteResult::QueryError(_)
+        ));
+        let insert = |name: &str| Statement {
+            statement_type: StatementType::InsertInto(name.to_string()),
+            row_to_insert: Some(admin.clone()),
+        };
+        assert_eq!(
+            table.execute_statement(insert("admins")),
+            ExecuteResult::InsertSuccess
+        );
+        assert!(matches!(
+            table.execute_statement(insert("guests")),
+            ExecuteResult::QueryError(_)
+        ));
+        table.close();
+
+        let mut table = Table::open(&path);
+        let query = crate::parser::parse_select("select username from admins").unwrap();
+        match table.execute_statement(Statement {
+            statement_type: StatementType::Query(Box::new(query)),
+            row_to_insert: None,
+        }) {
+            ExecuteResult::QuerySuccess(result) => {
+                assert_eq!(result.rows, vec![vec![Value::from("root")]])
+            }
+            other => panic!("{other:?}"),
+        }
+        // The default table is still there, and still empty
+        match table.execute_statement(select()) {
+            ExecuteResult::SelectSuccess(rows) => assert!(rows.is_empty()),
+            other => panic!("{other:?}"),
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn bulk_load_into_a_table() {
+        let mut table = open_test_db();
+        assert_eq!(
+            table.bulk_load("users", (0..500).map(row), 0.75),
+            ExecuteResult::InsertSuccess
+        );
+        assert!(matches!(
+            table.bulk_load("users", (500..501).map(row), 0.75),
+            ExecuteResult::QueryError(_)
+        ));
+        match table.execute_statement(select()) {
+            ExecuteResult::SelectSuccess(found) => assert!(found.into_iter().eq((0..500).map(row))),
+            other => panic!("{other:?}"),
+        }
+    }
+
+    #[test]
+    fn free_pages_survive_a_reopen() {
+        let path = temp_path("free");
+        let mut table = Table::open(&path);
+        // Building the tree leaves behind the page its top node started out on
+        assert_eq!(
+            table.bulk_load("users", (0..120).map(row), 1.0),
+            ExecuteResult::InsertSuccess
+        );
+        assert_eq!(table.pager.free_pages().len(), 1);
+        table.close();
+
+        let mut table = Table::open(&path);
+        assert_eq!(table.pager.free_pages().len(), 1);
+        assert_eq!(
+            pragma(&mut table, "pragma integrity_check"),
+            Ok(Value::from("ok"))
+        );
+        assert!(pragma(&mut table, "pragma page_count(3)").is_err());
+        table.close();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn checksums_catch_corruption() {
+        assert_eq!(crc32c(b"123456789"), 0xe3069283);
+
+        let path = temp_path("crc");
+        let mut table = Table::open(&path);
+        assert_eq!(
+            pragma(&mut table, "pragma page_checksums = on"),
+            Ok(Value::from("on"))
+        );
+        insert(&mut table, 0..30);
+        table.close();
+
+        let mut table = Table::open(&path);
+        assert!(table.integrity_check().is_empty());
+        assert_eq!(
+            pragma(&mut table, "pragma page_checksums"),
+            Ok(Value::from("on"))
+        );
+        drop(table);
+
+        // Flip a bit in the middle of the last leaf
+        let mut bytes = std::fs::read(&path).unwrap();
+        let last = bytes.len() / DEFAULT_PAGE_SIZE - 1;
+        bytes[last * DEFAULT_PAGE_SIZE + 100] ^= 1;
+        std::fs::write(&path, bytes).unwrap();
+        let mut table = Table::open(&path);
+        let problem = format!("Page {last} is corrupt");
+        assert_eq!(table.integrity_check(), vec![problem.clone()]);
+        let corrupt = ExecuteResult::Corrupt(CorruptPage(Offset(last)));
+        assert_eq!(table.execute_statement(select()), corrupt);
+        // An insert into the bad leaf fails too, and leaves nothing else changed
+        assert_eq!(table.execute_statement(insert_row(30)), corrupt);
+        assert_eq!(table.integrity_check(), vec![problem]);
+        table.close();
+
+        // A page of zeroes has no checksum either
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[last * DEFAULT_PAGE_SIZE..].fill(0);
+        std::fs::write(&path, &bytes).unwrap();
+        let mut table = Table::open_read_only(&path).unwrap();
+        assert_eq!(table.execute_statement(select()), corrupt);
+        drop(table);
+
+        // Nothing can be read without the header
+        bytes[100] ^= 1;
+        std::fs::write(&path, &bytes).unwrap();
+        let why = Table::open_read_only(&path).err().unwrap();
+        assert_eq!(why.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(why.to_string(), CorruptPage(HEADER_PAGE).to_string());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rows_that_arent_utf8_are_corrupt() {
+        let path = temp_path("utf8");
+        let mut table = Table::open(&path);
+        insert(&mut table, 0..30);
+        table.close();
+
+        // Without checksums, nothing notices until the row is decoded
+        let mut bytes = std::fs::read(&path).unwrap();
+        let last = bytes.len() / DEFAULT_PAGE_SIZE - 1;
+        bytes[last * DEFAULT_PAGE_SIZE + CELL_OFFSET + CELL_KEY_SIZE + USERNAME_OFFSET] = 0xff;
+        std::fs::write(&path, bytes).unwrap();
+        let mut table = Table::open(&path);
+        let corrupt = ExecuteResult::Corrupt(CorruptPage(Offset(last)));
+        assert_eq!(table.execute_statement(select()), corrupt);
+        assert_eq!(table.execute_statement(insert_row(30)), corrupt);
+        drop(table);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn inserts_stop_at_the_maximum_size() {
+        let mut table = open_test_db();
+        // The header and the root leaf, plus two pages for the root to split into
+        assert_eq!(
+            pragma(&mut table, "pragma max_page_count = 4"),
+            Ok(Value::Integer(4))
+        );
+        insert(&mut table, 1..=20);
+        // The right leaf is full, and splitting it needs a fifth page
+        assert_eq!(
+            table.execute_statement(insert_row(21)),
+            ExecuteResult::TableFull
+        );
+        assert_eq!(
+            table.execute_statement(insert_row(4)),
+            ExecuteResult::DuplicateKey
+        );
+        assert!(table.integrity_check().is_empty());
+
+        assert_eq!(table.set_max_page_count(1), 4);
+        table.set_max_page_count(5);
+        insert(&mut table, [21]);
+    }
+
+    #[test]
+    fn page_size_is_chosen_at_creation() {
+        for size in [MIN_PAGE_SIZE, MAX_PAGE_SIZE] {
+            let path = temp_path(&format!("size-{size}"));
+            let mut table = Table::open_with_page_size(&path, size);
+            insert(&mut table, 0..500);
+            table.close();
+            assert_eq!(std::fs::metadata(&path).unwrap().len() % size as u64, 0);
+
+            // Reopening goes by the header, not the page size asked for
+            let mut table = Table::open_with_page_size(&path, DEFAULT_PAGE_SIZE);
+            assert_eq!(
+                pragma(&mut table, "pragma page_size"),
+                Ok(Value::Integer(size as i64))
+            );
+            assert!(table.integrity_check().is_empty());
+            let ExecuteResult::SelectSuccess(rows) = table.execute_statement(select()) else {
+                panic!("select failed");
+            };
+            assert_eq!(rows.len(), 500);
+            assert!(pragma(&mut table, "pragma page_size = 1024").is_err());
+            table.close();
+            std::fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn vacuum_compacts_the_file() {
+        let (path, into) = (temp_path("vacuum"), temp_path("vacuum-into"));
+        let mut table = Table::open(&path);
+        // Out of order, so the leaves end up scattered through the file
+        insert(&mut table, (0..400).map(|i| i * 7 % 400));
+        for id in (0..400).filter(|id| id % 4 != 0) {
+            table.delete(TABLE_NAME, id);
+        }
+        table.create_table("logs");
+        assert_eq!(
+            table.bulk_load("logs", (0..50).map(row), 1.0),
+            ExecuteResult::InsertSuccess
+        );
+        table.commit().unwrap();
+        let before = std::fs::metadata(&path).unwrap().len();
+        let rows = |table: &Table, name: &str| {
+            Scan::new(&table.trees[name])
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap()
+        };
+        let (users, logs) = (rows(&table, TABLE_NAME), rows(&table, "logs"));
+
+        let vacuum_into = format!("vacuum into '{}'", into.display());
+        assert_eq!(
+            table.execute_statement(statement(&vacuum_into)),
+            ExecuteResult::VacuumSuccess
+        );
+        assert!(matches!(
+            table.execute_statement(statement(&vacuum_into)),
+            ExecuteResult::QueryError(_)
+        ));
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), before);
+        assert_eq!(
+            table.execute_statement(statement("vacuum")),
+            ExecuteResult::VacuumSuccess
+        );
+        assert!(std::fs::metadata(&path).unwrap().len() < before);
+        assert!(table.pager.free_pages().is_empty());
+        table.close();
+
+        // Both rebuilds come out the same but for the commit count in the header, with the
+        // leaves in key order through the file
+        let (vacuumed, copied) = (std::fs::read(&path).unwrap(), std::fs::read(&into).unwrap());
+        assert_eq!(vacuumed[DEFAULT_PAGE_SIZE..], copied[DEFAULT_PAGE_SIZE..]);
+        let header = |bytes: &[u8]| {
+            let mut header = bytes[..DEFAULT_PAGE_SIZE].to_vec();
+            header[36..44].fill(0);
+            header
+        };
+        assert_eq!(header(&vacuumed), header(&copied));
+        for file in [&path, &into] {
+            let table = Table::open(file);
+            assert!(table.integrity_check().is_empty());
+            assert_eq!(rows(&table, TABLE_NAME), users);
+            assert_eq!(rows(&table, "logs"), logs);
+            let tree = &table.trees[TABLE_NAME];
+            let mut cursor = tree.cursor_start().unwrap();
+            let mut leaves = Vec::new();
+            while !cursor.is_at_end_of_table() {
+                leaves.push(cursor.offset().0);
+                tree.advance_cursor(&mut cursor).unwrap();
+            }
+            assert!(leaves.windows(2).all(|pair| pair[0] <= pair[1]));
+        }
+        for file in [&path, &into] {
+            std::fs::remove_file(file).unwrap();
+        }
+    }
+
+    #[test]
+    fn vacuum_stops_at_a_corrupt_page() {
+        let (path, into) = (
+            temp_path("vacuum-corrupt"),
+            temp_path("vacuum-corrupt-into"),
+        );
+        let mut table = Table::open(&path);
+        insert(&mut table, 0..30);
+        table.close();
+
+        // A row that sorts before the one ahead of it in the last leaf
+        let mut bytes = std::fs::read(&path).unwrap();
+        let last = bytes.len() / DEFAULT_PAGE_SIZE - 1;
+        let id = last * DEFAULT_PAGE_SIZE + CELL_OFFSET + CELL_SIZE + CELL_KEY_SIZE;
+        bytes[id..id + 4].fill(0);
+        std::fs::write(&path, bytes).unwrap();
+        let mut table = Table::open(&path);
+        let corrupt = ExecuteResult::Corrupt(CorruptPage(Offset(last)));
+        let vacuum_into = format!("vacuum into '{}'", into.display());
+        assert_eq!(table.execute_statement(statement(&vacuum_into)), corrupt);
+        assert_eq!(table.execute_statement(statement("vacuum")), corrupt);
+        assert_eq!(table.execute_statement(select()), corrupt);
+        drop(table);
+        std::fs::remove_file(&path).unwrap();
+        let _ = std::fs::remove_file(&into);
+    }
+
+    #[test]
+    fn snapshots_keep_old_versions_of_tables() {
+        let path = temp_path("snapshot");
+        let run = |table: &mut Table, sql: &str| table.execute_statement(statement(sql));
+        let ids = |table: &mut Table, sql: &str| match table.prepare(sql).unwrap().execute(table) {
+            ExecuteResult::QuerySuccess(results) => results
+                .rows
+                .iter()
+                .map(|row| match row[0] {
+                    Value::Integer(id) => id as u32,
+                    _ => panic!("ids are integers"),
+                })
+                .collect::<Vec<_>>(),
+            other => panic!("{sql} failed: {other:?}"),
+        };
+
+        let mut table = Table::open(&path);
+        assert!(matches!(
+            run(&mut table, "snapshot create early"),
+            ExecuteResult::QueryError(_)
+        ));
+        assert!(matches!(
+            run(&mut table, "pragma copy_on_write = on"),
+            ExecuteResult::QuerySuccess(_)
+        ));
+        insert(&mut table, (0..300).map(|i| i * 7 % 300));
+        table.commit().unwrap();
+        assert_eq!(
+            run(&mut table, "snapshot create before"),
+            ExecuteResult::SnapshotSuccess
+        );
+        assert!(matches!(
+            run(&mut table, "snapshot create before"),
+            ExecuteResult::QueryError(_)
+        ));
+        insert(&mut table, 300..500);
+        for id in 0..100 {
+            table.delete(TABLE_NAME, id);
+        }
+        table.commit().unwrap();
+        assert!(table.integrity_check().is_empty());
+        assert!(matches!(
+            run(&mut table, "vacuum"),
+            ExecuteResult::QueryError(_)
+        ));
+        table.close();
+
+        // The snapshot outlives the connection that made it
+        let mut table = Table::open(&path);
+        let old = "select id from users as of snapshot before";
+        assert_eq!(ids(&mut table, old), (0..300).collect::<Vec<_>>());
+        assert_eq!(
+            ids(&mut table, "select id from users"),
+            (100..500).collect::<Vec<_>>()
+        );
+        assert!(matches!(
+            table
+                .prepare("select id from users as of snapshot nope")
+                .unwrap()
+                .execute(&mut table),
+            ExecuteResult::QueryError(_)
+        ));
+        assert!(table.integrity_check().is_empty());
+
+        let free = table.pager.free_pages().len();
+        assert_eq!(
+            run(&mut table, "snapshot drop before"),
+            ExecuteResult::SnapshotSuccess
+        );
+        assert!(table.pager.free_pages().len() > free);
+        table.commit().unwrap();
+        assert!(table.integrity_check().is_empty());
+        assert!(matches!(
+            table.prepare(old).unwrap().execute(&mut table),
+            ExecuteResult::QueryError(_)
+        ));
+
+        // Turning it off puts the parent and sibling pointers back
+        assert!(matches!(
+            run(&mut table, "pragma copy_on_write = off"),
+            ExecuteResult::QuerySuccess(_)
+        ));
+        table.close();
+        let table = Table::open(&path);
+        assert!(!table.pager.copy_on_write());
+        assert!(table.integrity_check().is_empty());
+        assert_eq!(
+            Scan::new(&table.trees[TABLE_NAME])
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap(),
+            (100..500).map(row).collect::<Vec<_>>()
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn read_only_databases_are_never_written() {
+        let path = temp_path("readonly");
+        let error = Table::open_read_only(&path).err().unwrap();
+        assert_eq!(error.kind(), std::io::ErrorKind::NotFound);
+        assert!(!path.exists());
+
+        let mut table = Table::open(&path);
+        insert(&mut table, 0..50);
+        table.close();
+        drop(table);
+        let before = std::fs::read(&path).unwrap();
+
+        let mut table = Table::open_read_only(&path).unwrap();
+        assert_eq!(
+            table.execute_statement(select()),
+            ExecuteResult::SelectSuccess((0..50).map(row).collect())
+        );
+        assert_eq!(
+            table.execute_statement(insert_row(50)),
+            ExecuteResult::ReadOnly
+        );
+        assert_eq!(table.delete(TABLE_NAME, 1), ExecuteResult::ReadOnly);
+        let mut sql_insert = table
+            .prepare("insert into users values (51, 'a', 'b')")
+            .unwrap();
+        assert_eq!(sql_insert.execute(&mut table), ExecuteResult::ReadOnly);
+        for sql in ["pragma page_checksums = on", "vacuum", "create table logs"] {
+            assert_eq!(
+                table.execute_statement(statement(sql)),
+                ExecuteResult::ReadOnly
+            );
+        }
+        assert!(table.vacuum().is_err());
+        assert!(table.integrity_check().is_empty());
+        table.close();
+        drop(table);
+
+        assert_eq!(std::fs::read(&path).unwrap(), before);
+        assert!(!DiskVfs::journal_path(&path).exists());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn mapped_pages_read_the_same_as_cached_ones() {
+        let path = temp_path("mmap");
+        let mut table = Table::open(&path);
+        insert(&mut table, 0..500);
+        table.commit().unwrap();
+        drop(table);
+
+        // Only the first few pages fit in the map, so the rest are read the usual way
+        let mut table = Table::open(&path);
+        assert_eq!(
+            pragma(&mut table, "pragma mmap_size = 32768"),
+            Ok(Value::Integer(32768))
+        );
+        assert_eq!(
+            Scan::new(&table.trees[TABLE_NAME])
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap(),
+            (0..500).map(row).collect::<Vec<_>>()
+        );
+
+        // Changed pages are read from the cache, and the map is made again after a commit
+        insert(&mut table, 500..800);
+        for id in (0..800).step_by(3) {
+            assert_eq!(
+                table.delete(TABLE_NAME, id),
+                ExecuteResult::DeleteSuccess(1)
+            );
+        }
+        let expected: Vec<Row> = (0..800).filter(|id| id % 3 != 0).map(row).collect();
+        assert_eq!(
+            Scan::new(&table.trees[TABLE_NAME])
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap(),
+            expected
+        );
+        table.commit().unwrap();
+        assert_eq!(
+            Scan::new(&table.trees[TABLE_NAME])
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap(),
+            expected
+        );
+        assert!(table.integrity_check().is_empty());
+        table.set_mmap_size(0);
+        assert_eq!(
+            Scan::new(&table.trees[TABLE_NAME])
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap(),
+            expected
+        );
+        table.close();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn auto_vacuum_gives_back_free_pages() {
+        let path = temp_path("autovac");
+        let mut table = Table::open(&path);
+        insert(&mut table, (0..300).map(|i| i * 7 % 300));
+        assert_eq!(
+            pragma(&mut table, "pragma auto_vacuum"),
+            Ok(Value::Text(String::from("none")))
+        );
+        assert!(pragma(&mut table, "pragma incremental_vacuum").is_err());
+
+        // Switching to it rebuilds the file with pointer maps
+        assert_eq!(
+            pragma(&mut table, "pragma auto_vacuum = incremental"),
+            Ok(Value::Text(String::from("incremental")))
+        );
+        assert!(table.pager.pointer_maps());
+        assert!(table.integrity_check().is_empty());
+
+        // A bulk load leaves behind the page its top node started on
+        table.create_table("logs");
+        assert_eq!(
+            table.bulk_load("logs", (0..200).map(row), 1.0),
+            ExecuteResult::InsertSuccess
+        );
+        assert_eq!(table.pager.free_count(), 1);
+        let pages = table.pager.num_pages();
+        assert_eq!(
+            pragma(&mut table, "pragma incremental_vacuum(5)"),
+            Ok(Value::Integer(1))
+        );
+        assert_eq!(table.pager.num_pages(), pages - 1);
+        assert!(table.integrity_check().is_empty());
+
+        // And one that fails gives back every page it took, which full mode hands back on commit
+        table.create_table("events");
+        let unsorted = (0..500).chain([1]).map(row);
+        assert!(matches!(
+            table.bulk_load("events", unsorted, 1.0),
+            ExecuteResult::QueryError(_)
+        ));
+        assert!(table.pager.free_count() > 30);
+        assert_eq!(
+            pragma(&mut table, "pragma auto_vacuum = full"),
+            Ok(Value::Text(String::from("full")))
+        );
+        table.commit().unwrap();
+        assert_eq!(table.pager.free_count(), 0);
+        let size = std::fs::metadata(&path).unwrap().len();
+        assert_eq!(size, (table.pager.num_pages() * DEFAULT_PAGE_SIZE) as u64);
+        table.close();
+
+        let mut table = Table::open(&path);
+        assert_eq!(
+            pragma(&mut table, "pragma auto_vacuum"),
+            Ok(Value::Text(String::from("full")))
+        );
+        assert!(table.integrity_check().is_empty());
+        assert_eq!(
+            pragma(&mut table, "pragma auto_vacuum = none"),
+            Ok(Value::Text(String::from("none")))
+        );
+        assert!(!table.pager.pointer_maps());
+        assert!(table.integrity_check().is_empty());
+        assert_eq!(Scan::new(&table.trees[TABLE_NAME]).count(), 300);
+        assert_eq!(Scan::new(&table.trees["logs"]).count(), 200);
+        table.close();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn incremental_vacuum_is_busy_while_another_connection_writes() {
+        let path = temp_path("vacuum-busy");
+        let mut a = Table::open(&path);
+        insert(&mut a, 0..30);
+        assert_eq!(
+            pragma(&mut a, "pragma auto_vacuum = incremental"),
+            Ok(Value::Text(String::from("incremental")))
+        );
+        a.commit().unwrap();
+        a.set_busy_timeout(Duration::ZERO);
+
+        // b holds the reserved lock until it commits
+        let mut b = Table::open(&path);
+        insert(&mut b, [30]);
+        let error = a.incremental_vacuum(5).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(
+            a.execute_statement(statement("pragma incremental_vacuum(5)")),
+            ExecuteResult::Busy
+        );
+        b.commit().unwrap();
+        assert_eq!(
+            pragma(&mut a, "pragma incremental_vacuum(5)"),
+            Ok(Value::Integer(0))
+        );
+        a.close();
+        b.close();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn connections_take_turns_writing() {
+        let path = temp_path("locks");
+        let insert = |table: &mut Table, id: u32| table.execute_statement(insert_row(id));
+        let ids = |table: &mut Table| {
+            let ExecuteResult::SelectSuccess(rows) = table.execute_statement(select()) else {
+                panic!("the select failed");
+            };
+            rows.into_iter().map(|row| row.id).collect::<Vec<_>>()
+        };
+        let mut a = Table::open(&path);
+        let mut b = Table::open(&path);
+        assert_eq!(
+            pragma(&mut b, "pragma busy_timeout = 0"),
+            Ok(Value::Integer(0))
+        );
+        a.set_busy_timeout(Duration::ZERO);
+
+        // a's changes stay its own until it commits, and nobody else can write meanwhile
+        assert_eq!(insert(&mut a, 1), ExecuteResult::InsertSuccess);
+        assert_eq!(ids(&mut a), vec![1]);
+        assert_eq!(ids(&mut b), Vec::<u32>::new());
+        assert_eq!(insert(&mut b, 2), ExecuteResult::Busy);
+        a.commit().unwrap();
+        assert_eq!(ids(&mut b), vec![1]);
+
+        // A writer can't commit while someone is reading, but keeps its changes to try again
+        assert_eq!(insert(&mut b, 2), ExecuteResult::InsertSuccess);
+        assert_eq!(insert(&mut a, 3), ExecuteResult::Busy);
+        let mut reader = DiskVfs::open(&path).unwrap();
+        assert!(reader.lock(Lock::Shared).unwrap());
+        let error = b.commit().unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::WouldBlock);
+        assert!(reader.lock(Lock::Unlocked).unwrap());
+        b.commit().unwrap();
+        assert_eq!(ids(&mut a), vec![1, 2]);
+
+        // Or waits for the reader to finish, with a busy timeout
+        assert!(reader.lock(Lock::Shared).unwrap());
+        let finish_reading = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            reader.lock(Lock::Unlocked).unwrap();
+        });
+        a.set_busy_timeout(Duration::from_secs(10));
+        assert_eq!(insert(&mut a, 3), ExecuteResult::InsertSuccess);
+        a.commit().unwrap();
+        finish_reading.join().unwrap();
+        assert_eq!(ids(&mut b), vec![1, 2, 3]);
+        a.close();
+        b.close();
+        assert!(Table::open(&path).integrity_check().is_empty());
+        std::fs::remove_file(&path).unwrap();
+    }
 }
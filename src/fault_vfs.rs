@@ -0,0 +1,348 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use crate::datastore::{ExecuteResult, Row, Table, TABLE_NAME};
+use crate::vfs::{read_from, write_to, Lock, Vfs};
+
+/// Something that goes wrong at a numbered I/O operation. Writes, syncs and truncates are
+/// counted; reads can't change what's on disk, so they aren't.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fault {
+    /// The nth write fails and the process dies, but the machine keeps running.
+    FailWrite(usize),
+    /// The power goes out during the nth write, after only the first half of it made it to
+    /// disk.
+    TornWrite(usize),
+    /// The nth sync reports success without making anything durable.
+    DropSync(usize),
+    /// The power goes out just before the nth operation of any kind.
+    PowerOff(usize),
+}
+
+#[derive(Debug, Default)]
+struct File {
+    /// What the process sees, including writes that haven't been synced.
+    current: Vec<u8>,
+    /// What survives a power failure.
+    durable: Vec<u8>,
+}
+
+/// A machine whose disk holds any number of files, and that fails in the ways real ones do.
+#[derive(Debug, Default)]
+pub struct Machine {
+    files: HashMap<String, File>,
+    fault: Option<Fault>,
+    /// Whether the fault has happened yet.
+    tripped: bool,
+    powered_off: bool,
+    /// Whether databases get a journal, which is only turned off to show what goes wrong.
+    journaled: bool,
+    ops: usize,
+    writes: usize,
+    syncs: usize,
+}
+
+impl Machine {
+    pub fn new(fault: Option<Fault>) -> Arc<Mutex<Self>> {
+        Arc::new(Mutex::new(Self {
+            fault,
+            journaled: true,
+            ..Self::default()
+        }))
+    }
+
+    /// Counts an operation, failing it if the power is out or goes out now.
+    fn begin(&mut self) -> io::Result<()> {
+        if self.powered_off {
+            return Err(io::Error::other("the power is out"));
+        }
+        let op = self.ops;
+        self.ops += 1;
+        if self.fault == Some(Fault::PowerOff(op)) {
+            self.tripped = true;
+            self.power_off();
+            return Err(io::Error::other("the power went out"));
+        }
+        Ok(())
+    }
+
+    /// Loses every write that wasn't synced.
+    pub fn power_off(&mut self) {
+        for file in self.files.values_mut() {
+            file.current = file.durable.clone();
+        }
+        self.powered_off = true;
+    }
+
+    /// Starts over with whatever is on disk, and no more faults. If the power stayed on, the
+    /// operating system finishes writing everything the process handed it.
+    pub fn reboot(&mut self) {
+        for file in self.files.values_mut() {
+            if self.powered_off {
+                file.current = file.durable.clone();
+            } else {
+                file.durable = file.current.clone();
+            }
+        }
+        self.powered_off = false;
+        self.fault = None;
+    }
+
+    fn file(&mut self, name: &str) -> &mut File {
+        self.files.entry(name.to_string()).or_default()
+    }
+}
+
+/// A file on a [`Machine`].
+#[derive(Debug)]
+pub struct FaultVfs {
+    machine: Arc<Mutex<Machine>>,
+    name: String,
+}
+
+impl FaultVfs {
+    pub fn new(machine: &Arc<Mutex<Machine>>, name: &str) -> Self {
+        Self {
+            machine: machine.clone(),
+            name: name.to_string(),
+        }
+    }
+}
+
+impl Vfs for FaultVfs {
+    fn size(&mut self) -> io::Result<u64> {
+        Ok(self.machine.lock().unwrap().file(&self.name).current.len() as u64)
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let mut machine = self.machine.lock().unwrap();
+        read_from(&machine.file(&self.name).current, offset, buf);
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        let mut machine = self.machine.lock().unwrap();
+        machine.begin()?;
+        let write = machine.writes;
+        machine.writes += 1;
+        match machine.fault {
+            Some(Fault::FailWrite(n)) if n == write => {
+                machine.tripped = true;
+                return Err(io::Error::other("the write failed"));
+            }
+            Some(Fault::TornWrite(n)) if n == write => {
+                machine.tripped = true;
+                let durable = &mut machine.file(&self.name).durable;
+                write_to(durable, offset, &buf[..buf.len() / 2]);
+                machine.power_off();
+                return Err(io::Error::other("the power went out"));
+            }
+            _ => {}
+        }
+        write_to(&mut machine.file(&self.name).current, offset, buf);
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        let mut machine = self.machine.lock().unwrap();
+        machine.begin()?;
+        let sync = machine.syncs;
+        machine.syncs += 1;
+        if machine.fault == Some(Fault::DropSync(sync)) {
+            machine.tripped = true;
+            return Ok(());
+        }
+        let file = machine.file(&self.name);
+        file.durable = file.current.clone();
+        Ok(())
+    }
+
+    fn truncate(&mut self, size: u64) -> io::Result<()> {
+        let mut machine = self.machine.lock().unwrap();
+        machine.begin()?;
+        let file = machine.file(&self.name);
+        file.current.resize(size as usize, 0);
+        Ok(())
+    }
+
+    fn lock(&mut self, _lock: Lock) -> io::Result<bool> {
+        Ok(true)
+    }
+
+    fn journal(&self) -> io::Result<Option<Box<dyn Vfs>>> {
+        if !self.machine.lock().unwrap().journaled {
+            return Ok(None);
+        }
+        let journal = FaultVfs::new(&self.machine, &format!("{}-journal", self.name));
+        Ok(Some(Box::new(journal)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::btree_map::Entry;
+
+    use super::*;
+    use crate::fixtures::{insert, row, select};
+
+    const TRANSACTIONS: usize = 5;
+    const OPERATIONS_PER_TRANSACTION: usize = 30;
+    const KEYS: u64 = 300;
+    /// The transaction that vacuums instead of just committing, which shrinks the file.
+    const VACUUM: usize = 3;
+
+    /// xorshift64*, so that every run of a seed does the same thing.
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, n: u64) -> u64 {
+            self.0 ^= self.0 >> 12;
+            self.0 ^= self.0 << 25;
+            self.0 ^= self.0 >> 27;
+            self.0.wrapping_mul(0x2545f4914f6cdd1d) % n
+        }
+    }
+
+    type Rows = BTreeMap<u32, Row>;
+
+    fn open(machine: &Arc<Mutex<Machine>>) -> Table {
+        Table::with_vfs(Box::new(FaultVfs::new(machine, "test.db")))
+    }
+
+    struct Outcome {
+        /// The rows after each commit that succeeded, starting from the empty database.
+        committed: Vec<Rows>,
+        /// The rows of the commit that failed, if one did.
+        in_flight: Option<Rows>,
+        /// Which commit the fault happened during, counting the empty database as commit 0.
+        faulty_commit: Option<usize>,
+    }
+
+    /// Runs random inserts and deletes, committing every so often, until they're done or a
+    /// commit fails.
+    fn workload(machine: &Arc<Mutex<Machine>>, seed: u64) -> Outcome {
+        // Creating the database exits the process if it fails, as opening one does, so the fault
+        // waits until it's created
+        let fault = machine.lock().unwrap().fault.take();
+        let mut table = open(machine);
+        machine.lock().unwrap().fault = fault;
+        let mut rng = Rng(seed);
+        let mut rows = Rows::new();
+        let mut outcome = Outcome {
+            committed: vec![Rows::new()],
+            in_flight: None,
+            faulty_commit: None,
+        };
+        for transaction in 0..TRANSACTIONS {
+            for _ in 0..OPERATIONS_PER_TRANSACTION {
+                let id = rng.below(KEYS) as u32;
+                if rng.below(3) == 0 {
+                    table.delete(TABLE_NAME, id);
+                    rows.remove(&id);
+                } else if let Entry::Vacant(entry) = rows.entry(id) {
+                    insert(&mut table, [id]);
+                    entry.insert(row(id));
+                }
+            }
+            let tripped = machine.lock().unwrap().tripped;
+            let result = if transaction == VACUUM {
+                table.vacuum()
+            } else {
+                table.commit()
+            };
+            if !tripped && machine.lock().unwrap().tripped {
+                outcome.faulty_commit = Some(outcome.committed.len());
+            }
+            match result {
+                Ok(()) => outcome.committed.push(rows.clone()),
+                Err(_) => {
+                    outcome.in_flight = Some(rows);
+                    break;
+                }
+            }
+        }
+        outcome
+    }
+
+    /// Runs a workload with a fault, reboots, and checks the database is intact and holds what
+    /// was committed.
+    fn crash(seed: u64, fault: Fault, journaled: bool) -> Result<(), String> {
+        let machine = Machine::new(Some(fault));
+        machine.lock().unwrap().journaled = journaled;
+        let outcome = workload(&machine, seed);
+        let Some(faulty_commit) = outcome.faulty_commit else {
+            return Ok(());
+        };
+        if let Fault::DropSync(_) = fault {
+            machine.lock().unwrap().power_off();
+        }
+        machine.lock().unwrap().reboot();
+
+        // A commit that finished is durable, and one that didn't may or may not be. A commit
+        // that lost a sync may have been undone, along with any later ones.
+        let mut expected: Vec<&Rows> = match fault {
+            Fault::DropSync(_) => outcome.committed[faulty_commit - 1..].iter().collect(),
+            _ => vec![outcome.committed.last().unwrap()],
+        };
+        expected.extend(outcome.in_flight.as_ref());
+
+        let mut table = open(&machine);
+        let problems = table.integrity_check();
+        if !problems.is_empty() {
+            return Err(format!("{fault:?}: {problems:?}"));
+        }
+        let ExecuteResult::SelectSuccess(found) = table.execute_statement(select()) else {
+            return Err(format!("{fault:?}: the select failed"));
+        };
+        let found: Rows = found.into_iter().map(|row| (row.id, row)).collect();
+        if !expected.contains(&&found) {
+            return Err(format!(
+                "{fault:?}: found {} rows, which don't match any state that was committed",
+                found.len()
+            ));
+        }
+        Ok(())
+    }
+
+    /// Crashes a workload at every I/O operation it does, in every way, returning what went
+    /// wrong.
+    fn crash_everywhere(seed: u64, journaled: bool) -> Vec<String> {
+        let machine = Machine::new(None);
+        machine.lock().unwrap().journaled = journaled;
+        workload(&machine, seed);
+        let (ops, writes, syncs) = {
+            let machine = machine.lock().unwrap();
+            (machine.ops, machine.writes, machine.syncs)
+        };
+
+        let faults = (0..writes)
+            .flat_map(|n| [Fault::FailWrite(n), Fault::TornWrite(n)])
+            .chain((0..syncs).map(Fault::DropSync))
+            .chain((0..ops).map(Fault::PowerOff));
+        faults
+            .filter_map(|fault| {
+                // A database broken badly enough panics on open
+                let crashed = std::panic::catch_unwind(|| crash(seed, fault, journaled));
+                match crashed {
+                    Ok(result) => result.err(),
+                    Err(_) => Some(format!("{fault:?}: opening the database panicked")),
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn commits_survive_a_crash_anywhere() {
+        for seed in [1, 0x5eed] {
+            let problems = crash_everywhere(seed, true);
+            assert!(problems.is_empty(), "seed {seed}: {problems:#?}");
+        }
+    }
+
+    #[test]
+    fn writing_pages_in_place_does_not() {
+        // Without the journal, commits write straight over the database
+        assert!(!crash_everywhere(1, false).is_empty());
+    }
+}
@@ -0,0 +1,75 @@
+//! Helpers the tests share.
+
+use std::path::PathBuf;
+
+use crate::value::Value;
+use crate::vfs::DiskVfs;
+use crate::{
+    prepare_statement, ExecuteResult, PrepareResult, Row, Statement, StatementType, Table,
+};
+
+/// A database kept in memory.
+pub fn open_test_db() -> Table {
+    Table::open(":memory:")
+}
+
+/// A row whose username and email are made from its id.
+pub fn row(id: u32) -> Row {
+    Row {
+        id,
+        username: format!("user{id}"),
+        email: format!("user{id}@example.com"),
+    }
+}
+
+/// A statement that inserts [`row`]`(id)` into the default table.
+pub fn insert_row(id: u32) -> Statement {
+    Statement {
+        statement_type: StatementType::Insert,
+        row_to_insert: Some(row(id)),
+    }
+}
+
+/// Inserts a row for each id into the default table, every one of which has to go in.
+pub fn insert(table: &mut Table, ids: impl IntoIterator<Item = u32>) {
+    for id in ids {
+        assert_eq!(
+            table.execute_statement(insert_row(id)),
+            ExecuteResult::InsertSuccess
+        );
+    }
+}
+
+/// A statement that selects every row of the default table.
+pub fn select() -> Statement {
+    Statement {
+        statement_type: StatementType::Select,
+        row_to_insert: None,
+    }
+}
+
+/// Prepares a statement that takes no parameters.
+pub fn statement(sql: &str) -> Statement {
+    let PrepareResult::Success(statement) = prepare_statement(sql) else {
+        panic!("{sql} didn't prepare");
+    };
+    statement
+}
+
+/// Executes a pragma, returning the value it results in or the error it fails with.
+pub fn pragma(table: &mut Table, sql: &str) -> Result<Value, String> {
+    match table.execute_statement(statement(sql)) {
+        ExecuteResult::QuerySuccess(results) => Ok(results.rows[0][0].clone()),
+        ExecuteResult::QueryError(why) => Err(why),
+        other => panic!("{sql} failed: {other:?}"),
+    }
+}
+
+/// A path in the temp directory for a test's database, with anything an earlier run left there
+/// removed.
+pub fn temp_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("tarsier-{name}-{}.db", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let _ = std::fs::remove_file(DiskVfs::journal_path(&path));
+    path
+}
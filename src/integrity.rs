@@ -0,0 +1,425 @@
+use std::collections::HashSet;
+
+use crate::btree::{CELL_OFFSET, CELL_SIZE};
+use crate::catalog::Catalog;
+use crate::node::{max_internal_separators, max_leaf_cells, NodeView};
+use crate::page::Page;
+use crate::pager::{Offset, Pager};
+use crate::pointer_map::{self, PageType};
+
+/// The header is always the first page.
+const HEADER_PAGE: Offset = Offset(0);
+
+/// Walks every table's B-tree, every snapshot's and the free list, and describes each problem
+/// found. An empty list means the database is consistent.
+pub fn check(pager: &Pager, catalog: &Catalog) -> Vec<String> {
+    let mut check = Check {
+        pager,
+        problems: Vec::new(),
+        seen: HashSet::from([HEADER_PAGE]),
+        version: HashSet::new(),
+        copy_on_write: catalog.copy_on_write(),
+        leaves: Vec::new(),
+        leaf_depth: None,
+        unreadable: false,
+    };
+    if pager.pointer_maps() {
+        check.seen.extend(
+            (0..pager.num_pages())
+                .filter(|&page| pointer_map::is_map_page(page, pager.page_size()))
+                .map(Offset),
+        );
+    }
+    for (name, root) in catalog.tables() {
+        check.table(name, *root);
+    }
+    for (name, page) in catalog.snapshots() {
+        check.snapshot(name, *page);
+    }
+    check.free_list();
+    check.problems
+}
+
+struct Check<'a> {
+    pager: &'a Pager,
+    problems: Vec<String>,
+    /// Every page reached so far, across all tables and snapshots.
+    seen: HashSet<Offset>,
+    /// The pages reached so far in the version of the database being checked: its latest, or a
+    /// snapshot. Versions share pages, but no page appears twice in one.
+    version: HashSet<Offset>,
+    /// Whether parent and sibling pointers are left out of date, as copying on write leaves them.
+    copy_on_write: bool,
+    /// The leaves of the current table in key order, with their sibling pointers.
+    leaves: Vec<(Offset, Option<Offset>, Option<Offset>)>,
+    leaf_depth: Option<usize>,
+    /// Whether a page of the current table couldn't be read, leaving a gap in its leaves.
+    unreadable: bool,
+}
+
+impl Check<'_> {
+    fn table(&mut self, name: &str, root: Offset) {
+        self.leaves.clear();
+        self.leaf_depth = None;
+        self.unreadable = false;
+        self.node(root, None, 0, None, None);
+        if self.unreadable || self.copy_on_write {
+            return;
+        }
+
+        for (i, &(offset, last, next)) in self.leaves.iter().enumerate() {
+            let expected_last = i.checked_sub(1).map(|i| self.leaves[i].0);
+            let expected_next = self.leaves.get(i + 1).map(|leaf| leaf.0);
+            if last != expected_last {
+                self.problems.push(format!(
+                    "Page {} of table {name} links to {} as its previous leaf instead of {}",
+                    offset.0,
+                    describe(last),
+                    describe(expected_last)
+                ));
+            }
+            if next != expected_next {
+                self.problems.push(format!(
+                    "Page {} of table {name} links to {} as its next leaf instead of {}",
+                    offset.0,
+                    describe(next),
+                    describe(expected_next)
+                ));
+            }
+        }
+    }
+
+    /// Checks the subtree at `offset`, whose keys must all be at least `lower` and less than
+    /// `upper`.
+    fn node(
+        &mut self,
+        offset: Offset,
+        parent: Option<Offset>,
+        depth: usize,
+        lower: Option<usize>,
+        upper: Option<usize>,
+    ) {
+        let page_number = offset.0;
+        if page_number >= self.pager.num_pages() {
+            self.problems.push(format!(
+                "Page {page_number} is past the end of the file, which has {} pages",
+                self.pager.num_pages()
+            ));
+            return;
+        }
+        if !self.version.insert(offset) {
+            self.problems
+                .push(format!("Page {page_number} is reachable more than once"));
+            return;
+        }
+        if !self.seen.insert(offset) {
+            // A snapshot sharing a page with a later version, which checked it already
+            return;
+        }
+        let page = match self.pager.get_page(&offset) {
+            Ok(page) => page,
+            Err(why) => {
+                self.problems.push(why.to_string());
+                self.unreadable = true;
+                return;
+            }
+        };
+        if !self.counts_fit(&page, page_number) {
+            return;
+        }
+
+        match (page.is_root_node(), parent) {
+            (true, Some(_)) => self.problems.push(format!(
+                "Page {page_number} is marked as a root but has a parent"
+            )),
+            (false, None) => self.problems.push(format!(
+                "Page {page_number} is a root but isn't marked as one"
+            )),
+            (false, Some(parent))
+                if !self.copy_on_write && page.parent_offset() != Some(parent) =>
+            {
+                self.problems.push(format!(
+                    "Page {page_number} points to page {} as its parent instead of page {}",
+                    page.parent_offset().unwrap().0,
+                    parent.0
+                ))
+            }
+            _ => {}
+        }
+        if self.pager.pointer_maps() {
+            let page_type = match (parent, page.node_type()) {
+                (None, _) => PageType::Root,
+                (Some(_), 0) => PageType::Leaf,
+                (Some(_), _) => PageType::Internal,
+            };
+            self.pointer_map_entry(offset, page_type, parent);
+        }
+
+        let node = NodeView::new(page, offset);
+        if node.is_leaf() {
+            let keys: Vec<usize> = (0..node.num_cells()).map(|cell| node.key(cell)).collect();
+            self.keys(page_number, &keys, lower, upper, "key");
+            match self.leaf_depth {
+                None => self.leaf_depth = Some(depth),
+                Some(expected) if expected != depth => self.problems.push(format!(
+                    "Page {page_number} is a leaf at depth {depth}, but other leaves are at depth {expected}"
+                )),
+                _ => {}
+            }
+            self.leaves
+                .push((offset, node.last_leaf(), node.next_leaf()));
+        } else {
+            let separators: Vec<usize> = (0..node.num_separators())
+                .map(|slot| node.separator(slot))
+                .collect();
+            self.keys(page_number, &separators, lower, upper, "separator");
+            for (i, child) in node.children().enumerate() {
+                let lower = if i == 0 {
+                    lower
+                } else {
+                    Some(separators[i - 1])
+                };
+                let upper = separators.get(i).copied().or(upper);
+                self.node(child, Some(offset), depth + 1, lower, upper);
+            }
+        }
+    }
+
+    /// Checks a snapshot's catalog and the trees it keeps alive.
+    fn snapshot(&mut self, name: &str, page: Offset) {
+        if page.0 >= self.pager.num_pages() || !self.seen.insert(page) {
+            self.problems.push(format!(
+                "Snapshot {name} is kept on page {}, which is past the end of the file or in use",
+                page.0
+            ));
+            return;
+        }
+        let catalog = match self.pager.get_page(&page) {
+            Ok(page) => Catalog::load(&page),
+            Err(why) => {
+                self.problems.push(why.to_string());
+                return;
+            }
+        };
+        let Some(catalog) = catalog else {
+            self.problems.push(format!(
+                "Page {} doesn't hold snapshot {name}'s catalog",
+                page.0
+            ));
+            return;
+        };
+        self.version.clear();
+        for (table, root) in catalog.tables() {
+            self.table(&format!("{table} as of snapshot {name}"), *root);
+        }
+    }
+
+    /// Checks the counts stored in a page before it gets decoded, since decoding trusts them.
+    fn counts_fit(&mut self, page: &Page, page_number: usize) -> bool {
+        let num_cells = page.num_cells();
+        match page.node_type() {
+            0 => {
+                let max_cells = max_leaf_cells(page.size());
+                if num_cells > max_cells {
+                    self.problems.push(format!(
+                        "Page {page_number} claims {num_cells} cells but a leaf holds at most {max_cells}"
+                    ));
+                    return false;
+                }
+                let unused =
+                    CELL_OFFSET + num_cells * CELL_SIZE..CELL_OFFSET + max_cells * CELL_SIZE;
+                if page.as_bytes()[unused].iter().any(|&b| b != 0) {
+                    self.problems.push(format!(
+                        "Page {page_number} holds cells past its cell count of {num_cells}"
+                    ));
+                }
+                true
+            }
+            1 => {
+                let separators = page.separator_count();
+                let max_separators = max_internal_separators(page.size());
+                if separators > max_separators {
+                    self.problems.push(format!(
+                        "Page {page_number} claims {separators} separators but an internal node holds at most {max_separators}"
+                    ));
+                    return false;
+                }
+                if separators == 0 {
+                    self.problems.push(format!(
+                        "Page {page_number} is an internal node without separators"
+                    ));
+                    return false;
+                }
+                if num_cells != separators {
+                    self.problems.push(format!(
+                        "Page {page_number} has {separators} separators but a cell count of {num_cells}"
+                    ));
+                }
+                true
+            }
+            other => {
+                self.problems.push(format!(
+                    "Page {page_number} has an unknown node type {other}"
+                ));
+                false
+            }
+        }
+    }
+
+    /// Checks that keys are strictly increasing and within their parent's bounds.
+    fn keys(
+        &mut self,
+        page_number: usize,
+        keys: &[usize],
+        lower: Option<usize>,
+        upper: Option<usize>,
+        what: &str,
+    ) {
+        for pair in keys.windows(2) {
+            if pair[0] >= pair[1] {
+                self.problems.push(format!(
+                    "Page {page_number} has {what} {} before {what} {}",
+                    pair[0], pair[1]
+                ));
+            }
+        }
+        for &key in keys {
+            if lower.is_some_and(|lower| key < lower) {
+                self.problems.push(format!(
+                    "Page {page_number} has {what} {key} below its lower bound of {}",
+                    lower.unwrap()
+                ));
+            }
+            if upper.is_some_and(|upper| key >= upper) {
+                self.problems.push(format!(
+                    "Page {page_number} has {what} {key} at or above its upper bound of {}",
+                    upper.unwrap()
+                ));
+            }
+        }
+    }
+
+    /// Checks that a page's pointer map entry says what the page really is.
+    fn pointer_map_entry(&mut self, offset: Offset, page_type: PageType, parent: Option<Offset>) {
+        match self.pager.pointer_map_entry(offset) {
+            Some(entry) if entry == (page_type, parent) => {}
+            Some((mapped_type, mapped_parent)) => self.problems.push(format!(
+                "Page {}'s pointer map entry says it's {mapped_type} under {} instead of {page_type} under {}",
+                offset.0,
+                describe(mapped_parent),
+                describe(parent)
+            )),
+            None => self
+                .problems
+                .push(format!("Page {} has no pointer map entry", offset.0)),
+        }
+    }
+
+    /// Every page not in a tree should be on the free list, exactly once.
+    fn free_list(&mut self) {
+        let mut free = HashSet::new();
+        for offset in self.pager.free_pages() {
+            if self.seen.contains(&offset) {
+                self.problems
+                    .push(format!("Page {} is on the free list but in use", offset.0));
+            } else if !free.insert(offset) {
+                self.problems.push(format!(
+                    "Page {} is on the free list more than once",
+                    offset.0
+                ));
+            } else if self.pager.pointer_maps() {
+                self.pointer_map_entry(offset, PageType::Free, None);
+            }
+        }
+        for page_number in 0..self.pager.num_pages() {
+            let offset = Offset(page_number);
+            if !self.seen.contains(&offset) && !free.contains(&offset) {
+                self.problems
+                    .push(format!("Page {page_number} is never used"));
+            }
+        }
+    }
+}
+
+fn describe(offset: Option<Offset>) -> String {
+    match offset {
+        Some(offset) => format!("page {}", offset.0),
+        None => String::from("nothing"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::btree::BTree;
+    use crate::catalog::Catalog;
+    use crate::integrity::check;
+    use crate::page::Page;
+    use crate::pager::{Offset, Pager};
+    use crate::Row;
+
+    fn tree(ids: impl Iterator<Item = usize>) -> (Arc<Pager>, Catalog, BTree) {
+        let pager = Arc::new(Pager::open(":memory:"));
+        pager.commit_page(Offset(0), Page::new(pager.page_size()));
+        let mut tree = BTree::create(pager.clone());
+        let mut catalog = Catalog::new(pager.page_size());
+        catalog.add("users", tree.root());
+        for id in ids {
+            let row = Row {
+                id: id as u32,
+                username: format!("user{id}"),
+                email: format!("user{id}@example.com"),
+            };
+            assert!(tree.insert(id, row).unwrap());
+        }
+        (pager, catalog, tree)
+    }
+
+    fn children(pager: &Pager, offset: Offset) -> Vec<Offset> {
+        pager.view(&offset).unwrap().children().collect()
+    }
+
+    #[test]
+    fn internal_splits_stay_consistent() {
+        let (pager, catalog, tree) = tree(0..3000);
+        // Enough leaves that the root's first child had to split too
+        let first = children(&pager, tree.root())[0];
+        assert!(!children(&pager, first).is_empty());
+        assert_eq!(check(&pager, &catalog), Vec::<String>::new());
+        for id in 0..3000 {
+            assert!(tree.find(id).unwrap().is_ok(), "{id} is missing");
+        }
+    }
+
+    #[test]
+    fn problems_name_the_page() {
+        let (pager, catalog, tree) = tree(0..40);
+        let leaves = children(&pager, tree.root());
+
+        // A key that sorts after the next leaf's keys
+        let mut page = pager.get_page(&leaves[0]).unwrap();
+        page.set_cell(0, 1000, &Row::deserialize(&[0; 291]).unwrap());
+        pager.commit_page(leaves[0], page);
+        // A leaf that has lost track of its right neighbour
+        let mut leaf = pager.get(&leaves[1]).unwrap();
+        leaf.set_next_leaf(None);
+        pager.commit(&leaf);
+        let unused = pager.new_page();
+        pager.recycle(leaves[2]);
+
+        let problems = check(&pager, &catalog);
+        let (a, b, c) = (leaves[0].0, leaves[1].0, leaves[2].0);
+        for expected in [
+            format!("Page {a} has key 1000 before key 1"),
+            format!("Page {a} has key 1000 at or above its upper bound of 7"),
+            format!(
+                "Page {b} of table users links to nothing as its next leaf instead of page {c}"
+            ),
+            format!("Page {c} is on the free list but in use"),
+            format!("Page {} is never used", unused.0),
+        ] {
+            assert!(problems.contains(&expected), "{expected} in {problems:?}");
+        }
+    }
+}
@@ -0,0 +1,147 @@
+use std::io;
+
+use crate::page::{is_valid_page_size, Page};
+use crate::pager::Offset;
+use crate::vfs::Vfs;
+
+/// Commits go through a redo journal kept next to the database. The pages a commit changes are
+/// written to the journal and synced before any of them is written to the database, so a crash
+/// part way through writing the database can be finished by replaying the journal.
+///
+/// The journal's first page is its header: a magic string, how many pages it holds, a checksum of
+/// everything after the header, the page size, and how many pages the database has once the
+/// commit is done, so that commits can shrink it too. Then come index pages listing which page of
+/// the database each journaled page belongs to, and then the pages themselves. The header is
+/// written last, and a journal whose checksum doesn't match was torn by a crash before its commit
+/// finished, so it's ignored.
+const MAGIC: &[u8; 8] = b"tarsierj";
+const COUNT_OFFSET: usize = 8;
+const CHECKSUM_OFFSET: usize = 16;
+const PAGE_SIZE_OFFSET: usize = 24;
+const DB_PAGES_OFFSET: usize = 28;
+/// The part of the header page that's used, which is all that's read back before the page size
+/// is known.
+const HEADER_SIZE: usize = 36;
+const ENTRY_SIZE: usize = 8;
+
+pub fn write(
+    journal: &mut dyn Vfs,
+    pages: &[(Offset, &Page)],
+    page_size: usize,
+    db_pages: usize,
+) -> io::Result<()> {
+    let entries_per_index_page = page_size / ENTRY_SIZE;
+    let index_pages = pages.len().div_ceil(entries_per_index_page);
+    let at = |page: usize| (page * page_size) as u64;
+    let mut checksum = Checksum::new();
+    for (i, chunk) in pages.chunks(entries_per_index_page).enumerate() {
+        let mut index = vec![0; page_size];
+        for (entry, (offset, _)) in index.chunks_mut(ENTRY_SIZE).zip(chunk) {
+            entry.copy_from_slice(&(offset.0 as u64).to_le_bytes());
+        }
+        checksum.update(&index);
+        journal.write_at(at(1 + i), &index)?;
+    }
+    for (i, (_, page)) in pages.iter().enumerate() {
+        checksum.update(page.as_bytes());
+        journal.write_at(at(1 + index_pages + i), page.as_bytes())?;
+    }
+
+    let mut header = vec![0; page_size];
+    header[..MAGIC.len()].copy_from_slice(MAGIC);
+    header[COUNT_OFFSET..COUNT_OFFSET + 4].copy_from_slice(&(pages.len() as u32).to_le_bytes());
+    header[CHECKSUM_OFFSET..CHECKSUM_OFFSET + 8].copy_from_slice(&checksum.0.to_le_bytes());
+    header[PAGE_SIZE_OFFSET..PAGE_SIZE_OFFSET + 4]
+        .copy_from_slice(&(page_size as u32).to_le_bytes());
+    header[DB_PAGES_OFFSET..DB_PAGES_OFFSET + 8].copy_from_slice(&(db_pages as u64).to_le_bytes());
+    journal.write_at(0, &header)?;
+    journal.sync()
+}
+
+/// Copies a complete journal's pages into the database and cuts it down to size, and returns how
+/// many pages there were. A missing or torn journal copies nothing.
+pub fn replay(journal: &mut dyn Vfs, db: &mut dyn Vfs) -> io::Result<usize> {
+    let journal_size = journal.size()?;
+    if journal_size == 0 {
+        return Ok(0);
+    }
+    let mut header = [0; HEADER_SIZE];
+    journal.read_at(0, &mut header)?;
+    if &header[..MAGIC.len()] != MAGIC {
+        return Ok(0);
+    }
+    let read_u32 = |at: usize| u32::from_le_bytes(header[at..at + 4].try_into().unwrap());
+    let count = read_u32(COUNT_OFFSET) as usize;
+    let page_size = read_u32(PAGE_SIZE_OFFSET) as usize;
+    let read_u64 = |at: usize| u64::from_le_bytes(header[at..at + 8].try_into().unwrap());
+    let expected = read_u64(CHECKSUM_OFFSET);
+    let db_pages = read_u64(DB_PAGES_OFFSET) as usize;
+    if !is_valid_page_size(page_size) {
+        return Ok(0);
+    }
+    let index_pages = count.div_ceil(page_size / ENTRY_SIZE);
+    let at = |page: usize| (page * page_size) as u64;
+    if journal_size < at(1 + index_pages + count) {
+        return Ok(0);
+    }
+
+    let mut checksum = Checksum::new();
+    let mut targets = Vec::with_capacity(count);
+    for i in 0..index_pages {
+        let mut index = vec![0; page_size];
+        journal.read_at(at(1 + i), &mut index)?;
+        checksum.update(&index);
+        targets.extend(
+            index
+                .chunks(ENTRY_SIZE)
+                .map(|entry| u64::from_le_bytes(entry.try_into().unwrap()) as usize),
+        );
+    }
+    let mut pages = Vec::with_capacity(count);
+    for i in 0..count {
+        let mut page = vec![0; page_size];
+        journal.read_at(at(1 + index_pages + i), &mut page)?;
+        checksum.update(&page);
+        pages.push(page);
+    }
+    if checksum.0 != expected {
+        return Ok(0);
+    }
+
+    for (target, page) in targets.into_iter().zip(pages.iter()) {
+        db.write_at(at(target), page)?;
+    }
+    shrink(db, at(db_pages))?;
+    db.sync()?;
+    Ok(count)
+}
+
+/// Cuts a file down to `size` bytes, if it's any bigger.
+pub fn shrink(file: &mut dyn Vfs, size: u64) -> io::Result<()> {
+    if file.size()? > size {
+        file.truncate(size)?;
+    }
+    Ok(())
+}
+
+/// Empties the journal once its commit is safely in the database.
+pub fn clear(journal: &mut dyn Vfs) -> io::Result<()> {
+    journal.truncate(0)?;
+    journal.sync()
+}
+
+/// 64-bit FNV-1a.
+struct Checksum(u64);
+
+impl Checksum {
+    fn new() -> Self {
+        Self(0xcbf29ce484222325)
+    }
+
+    fn update(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 ^= byte as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+}
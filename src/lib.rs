@@ -0,0 +1,205 @@
+use regex::Regex;
+
+pub use crate::backup::Backup;
+pub use crate::datastore::{ExecuteResult, Row, Table};
+use crate::parser::Select;
+pub use crate::pool::Pool;
+pub use crate::statement::{PreparedStatement, StepResult};
+pub use crate::value::Value;
+
+mod aggregate;
+pub mod backup;
+mod btree;
+mod bulk_load;
+mod catalog;
+mod cursor;
+pub mod datastore;
+#[cfg(test)]
+mod fault_vfs;
+#[cfg(test)]
+mod fixtures;
+mod integrity;
+mod journal;
+mod node;
+mod node_type;
+mod page;
+mod pager;
+pub mod parser;
+mod pointer_map;
+pub mod pool;
+pub mod query;
+mod sort;
+mod spill;
+pub mod statement;
+pub mod trace;
+pub mod value;
+pub mod vfs;
+mod visualize;
+
+pub enum PrepareResult {
+    Success(Statement),
+    /// A statement that may take parameters, to be bound before it's executed.
+    Prepared(PreparedStatement),
+    UnrecognizedStatement,
+    SyntaxError,
+    ParseError(String),
+    NegativeId,
+    StringTooLong,
+}
+
+pub enum StatementType {
+    Insert,
+    InsertInto(String),
+    Select,
+    Query(Box<Select>),
+    CreateTable(String),
+    /// `PRAGMA name`, `PRAGMA name = value` or `PRAGMA name(value)`.
+    Pragma(String, Option<String>),
+    /// `VACUUM`, or `VACUUM INTO 'path'` to write the compacted database somewhere else.
+    Vacuum(Option<String>),
+    /// `SNAPSHOT CREATE name`, keeping the tables as they are now for `AS OF SNAPSHOT` queries.
+    CreateSnapshot(String),
+    /// `SNAPSHOT DROP name`, freeing the pages only that snapshot used.
+    DropSnapshot(String),
+}
+
+impl StatementType {
+    /// A short name for the kind of statement, for trace events.
+    fn kind(&self) -> &'static str {
+        match self {
+            StatementType::Insert | StatementType::InsertInto(_) => "insert",
+            StatementType::Select | StatementType::Query(_) => "select",
+            StatementType::CreateTable(_) => "create_table",
+            StatementType::Pragma(..) => "pragma",
+            StatementType::Vacuum(_) => "vacuum",
+            StatementType::CreateSnapshot(_) | StatementType::DropSnapshot(_) => "snapshot",
+        }
+    }
+
+    /// Whether the statement changes the file, and so needs the reserved lock.
+    fn writes(&self) -> bool {
+        match self {
+            StatementType::Insert
+            | StatementType::InsertInto(_)
+            | StatementType::CreateTable(_)
+            | StatementType::Vacuum(None)
+            | StatementType::CreateSnapshot(_)
+            | StatementType::DropSnapshot(_) => true,
+            StatementType::Select | StatementType::Query(_) | StatementType::Vacuum(Some(_)) => {
+                false
+            }
+            StatementType::Pragma(name, value) => match name.as_str() {
+                "incremental_vacuum" => true,
+                "auto_vacuum" | "page_checksums" | "copy_on_write" => value.is_some(),
+                _ => false,
+            },
+        }
+    }
+}
+
+pub struct Statement {
+    statement_type: StatementType,
+    row_to_insert: Option<Row>,
+}
+
+/// Prepares one line of input. SQL statements, which may have parameters, come back as
+/// `PrepareResult::Prepared`.
+pub fn prepare_statement(statement: &str) -> PrepareResult {
+    let sql_insert = Regex::new(r"^insert into \w+ values\b").unwrap();
+    if sql_insert.is_match(statement)
+        || (statement.starts_with("select") && statement.trim() != "select")
+    {
+        match PreparedStatement::new(statement) {
+            Ok(stmt) => PrepareResult::Prepared(stmt),
+            Err(why) => PrepareResult::ParseError(why.to_string()),
+        }
+    } else if statement.starts_with("insert") {
+        let re = Regex::new(r"^insert (?:into (\w+) )?(-?\d+) (\w+) ([\w@\.]+)").unwrap();
+        match re.captures(statement) {
+            Some(cap) => {
+                let id: u32 = if let Ok(i) = cap.get(2).unwrap().as_str().parse() {
+                    i
+                } else {
+                    return PrepareResult::NegativeId;
+                };
+
+                let username = cap.get(3).unwrap().as_str().to_string();
+                if username.len() > 32 {
+                    return PrepareResult::StringTooLong;
+                }
+
+                let email = cap.get(4).unwrap().as_str().to_string();
+                if email.len() > 255 {
+                    return PrepareResult::StringTooLong;
+                }
+
+                let statement_type = match cap.get(1) {
+                    Some(table) => StatementType::InsertInto(table.as_str().to_lowercase()),
+                    None => StatementType::Insert,
+                };
+                PrepareResult::Success(Statement {
+                    statement_type,
+                    row_to_insert: Some(Row {
+                        id,
+                        username,
+                        email,
+                    }),
+                })
+            }
+            None => PrepareResult::SyntaxError,
+        }
+    } else if statement.starts_with("create") {
+        let re = Regex::new(r"^create table (\w+)\s*$").unwrap();
+        match re.captures(statement) {
+            Some(cap) => PrepareResult::Success(Statement {
+                statement_type: StatementType::CreateTable(cap[1].to_lowercase()),
+                row_to_insert: None,
+            }),
+            None => PrepareResult::SyntaxError,
+        }
+    } else if statement.starts_with("pragma") {
+        let re = Regex::new(r"^pragma (\w+)\s*(?:=\s*(\w+)|\(\s*(\w+)\s*\))?\s*;?\s*$").unwrap();
+        match re.captures(statement) {
+            Some(cap) => PrepareResult::Success(Statement {
+                statement_type: StatementType::Pragma(
+                    cap[1].to_lowercase(),
+                    cap.get(2).or(cap.get(3)).map(|v| v.as_str().to_string()),
+                ),
+                row_to_insert: None,
+            }),
+            None => PrepareResult::SyntaxError,
+        }
+    } else if statement.starts_with("vacuum") {
+        let re = Regex::new(r"^vacuum(?: into '([^']+)')?\s*;?\s*$").unwrap();
+        match re.captures(statement) {
+            Some(cap) => PrepareResult::Success(Statement {
+                statement_type: StatementType::Vacuum(cap.get(1).map(|p| p.as_str().to_string())),
+                row_to_insert: None,
+            }),
+            None => PrepareResult::SyntaxError,
+        }
+    } else if statement.starts_with("snapshot") {
+        let re = Regex::new(r"^snapshot (create|drop) (\w+)\s*;?\s*$").unwrap();
+        match re.captures(statement) {
+            Some(cap) => {
+                let name = cap[2].to_lowercase();
+                PrepareResult::Success(Statement {
+                    statement_type: if &cap[1] == "create" {
+                        StatementType::CreateSnapshot(name)
+                    } else {
+                        StatementType::DropSnapshot(name)
+                    },
+                    row_to_insert: None,
+                })
+            }
+            None => PrepareResult::SyntaxError,
+        }
+    } else if statement.trim() == "select" {
+        PrepareResult::Success(Statement {
+            statement_type: StatementType::Select,
+            row_to_insert: None,
+        })
+    } else {
+        PrepareResult::UnrecognizedStatement
+    }
+}
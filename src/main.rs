@@ -1,53 +1,35 @@
+use std::collections::BTreeMap;
 use std::io;
 use std::io::Write;
 use std::process::exit;
 
-use regex::Regex;
-
-use crate::datastore::{ExecuteResult, Row, Table};
-
-mod btree;
-mod cursor;
-mod datastore;
-mod fetchable;
-mod node;
-mod node_type;
-mod pager;
-mod page;
+use tarsier::parser::{parse_select, SelectItem};
+use tarsier::query::evaluate_constant;
+use tarsier::trace;
+use tarsier::{prepare_statement, ExecuteResult, PrepareResult, PreparedStatement, Table, Value};
 
 enum MetaCommand {
     Success,
     UnrecognizedCommand,
 }
 
-enum PrepareResult {
-    Success(Statement),
-    UnrecognizedStatement,
-    SyntaxError,
-    NegativeId,
-    StringTooLong,
-}
+/// Parameter values set with `.param set`, keyed by `:name` or `?N`.
+type Parameters = BTreeMap<String, Value>;
 
-pub enum StatementType {
-    Insert,
-    Select,
-}
-
-pub struct Statement {
-    statement_type: StatementType,
-    row_to_insert: Option<Row>,
-}
+/// The file opened when none is named on the command line.
+const DEFAULT_FILE: &str = "db.db";
 
 fn main() {
     let mut input = String::new();
-    let mut table = Table::open("db.db");
+    let mut table = open_table();
+    let mut parameters = Parameters::new();
     loop {
         print!("db> ");
         io::stdout().flush().unwrap();
         match io::stdin().read_line(&mut input) {
             Ok(_) => {
                 if input.starts_with(".") {
-                    match do_meta_command(&input, &mut table) {
+                    match do_meta_command(&input, &mut table, &mut parameters) {
                         MetaCommand::UnrecognizedCommand => {
                             input.pop();
                             println!("Unrecognized command: {}", input);
@@ -56,18 +38,15 @@ fn main() {
                     }
                 } else {
                     match prepare_statement(&input) {
-                        PrepareResult::Success(stmt) => match table.execute_statement(stmt) {
-                            ExecuteResult::InsertSuccess => println!("SUCCESS"),
-                            ExecuteResult::SelectSuccess(results) => {
-                                for row in results {
-                                    println!("{}", row);
-                                }
-                            }
-                            ExecuteResult::TableFull => println!("ERROR: TABLE IS FULL"),
-                            ExecuteResult::DuplicateKey => {
-                                println!("ERROR: DUPLICATE PRIMARY KEYS NOT ALLOWED")
-                            }
-                        },
+                        PrepareResult::Success(stmt) => {
+                            print_result(table.execute_statement(stmt));
+                            commit(&mut table);
+                        }
+                        PrepareResult::Prepared(mut stmt) => {
+                            bind_parameters(&mut stmt, &parameters);
+                            print_result(stmt.execute(&mut table));
+                            commit(&mut table);
+                        }
                         PrepareResult::UnrecognizedStatement => {
                             input.pop();
                             println!("Unrecognized statement: {}", input);
@@ -76,6 +55,7 @@ fn main() {
                             input.pop();
                             println!("SYNTAX ERROR: Could not parse statement");
                         }
+                        PrepareResult::ParseError(why) => println!("SYNTAX ERROR: {why}"),
                         PrepareResult::StringTooLong => {
                             input.pop();
                             println!("String is too long!");
@@ -93,62 +73,198 @@ fn main() {
     }
 }
 
-fn do_meta_command(command: &String, table: &mut Table) -> MetaCommand {
+fn print_result(result: ExecuteResult) {
+    match result {
+        ExecuteResult::InsertSuccess
+        | ExecuteResult::DeleteSuccess(_)
+        | ExecuteResult::CreateTableSuccess
+        | ExecuteResult::VacuumSuccess
+        | ExecuteResult::SnapshotSuccess => println!("SUCCESS"),
+        ExecuteResult::SelectSuccess(results) => {
+            for row in results {
+                println!("{}", row);
+            }
+        }
+        ExecuteResult::QuerySuccess(results) => {
+            for row in results.rows {
+                let values: Vec<String> = row.iter().map(|v| v.to_string()).collect();
+                println!("{}", values.join("|"));
+            }
+        }
+        ExecuteResult::QueryError(why) => println!("ERROR: {why}"),
+        ExecuteResult::TableFull => println!("ERROR: TABLE IS FULL"),
+        ExecuteResult::DuplicateKey => println!("ERROR: DUPLICATE PRIMARY KEYS NOT ALLOWED"),
+        ExecuteResult::Busy => println!("ERROR: DATABASE IS LOCKED"),
+        ExecuteResult::ReadOnly => println!("ERROR: DATABASE IS READ-ONLY"),
+        ExecuteResult::Corrupt(corrupt) => println!("ERROR: {corrupt}"),
+    }
+}
+
+/// Commits each statement as soon as it's done, so the lock a write takes isn't held until
+/// `.exit`. Reads leave nothing to commit.
+fn commit(table: &mut Table) {
+    if let Err(why) = table.commit() {
+        println!("ERROR: Unable to commit: {why}");
+    }
+}
+
+fn bind_parameters(stmt: &mut PreparedStatement, parameters: &Parameters) {
+    for index in 1..=stmt.parameter_count() {
+        let key = match stmt.parameter_name(index) {
+            Some(name) => name.to_string(),
+            None => format!("?{index}"),
+        };
+        if let Some(value) = parameters.get(&key) {
+            stmt.bind(index, value.clone()).unwrap();
+        }
+    }
+}
+
+/// Opens the database named on the command line, or `db.db`. `--readonly` opens an existing
+/// file without ever writing to it.
+fn open_table() -> Table {
+    let mut read_only = false;
+    let mut path = None;
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--readonly" => read_only = true,
+            _ if arg.starts_with("--") || path.is_some() => {
+                println!("Usage: tarsier [--readonly] [FILE]");
+                exit(-1);
+            }
+            _ => path = Some(arg),
+        }
+    }
+    let path = path.unwrap_or_else(|| String::from(DEFAULT_FILE));
+    if !read_only {
+        return Table::open(path);
+    }
+    match Table::open_read_only(&path) {
+        Ok(table) => table,
+        Err(why) => {
+            println!("Unable to open {path}: {why}");
+            exit(-1);
+        }
+    }
+}
+
+fn do_meta_command(command: &str, table: &mut Table, parameters: &mut Parameters) -> MetaCommand {
     if command.starts_with(".exit") {
         table.close();
         exit(0);
+    } else if command.starts_with(".check") {
+        let problems = table.integrity_check();
+        if problems.is_empty() {
+            println!("ok");
+        }
+        for problem in problems {
+            println!("{problem}");
+        }
+        MetaCommand::Success
+    } else if command.starts_with(".backup") {
+        do_backup_command(command, table)
+    } else if command.starts_with(".btree") {
+        do_btree_command(command, table)
+    } else if command.starts_with(".trace") {
+        do_trace_command(command)
+    } else if command.starts_with(".param") {
+        do_param_command(command, parameters)
     } else {
         MetaCommand::UnrecognizedCommand
     }
 }
 
-fn prepare_statement(statement: &String) -> PrepareResult {
-    if statement.starts_with("insert") {
-        let re = Regex::new(r"^insert (-?\d+) (\w+) ([\w@\.]+)").unwrap();
-        match re.captures(statement) {
-            Some(cap) => {
-                let id: u32 = if let Ok(i) = cap.get(1).unwrap().as_str().parse() {
-                    i
-                } else {
-                    return PrepareResult::NegativeId;
-                };
+/// `.backup FILE` commits, then copies the database to FILE. A backup only holds what's been
+/// committed.
+fn do_backup_command(command: &str, table: &mut Table) -> MetaCommand {
+    let words: Vec<&str> = command.split_whitespace().skip(1).collect();
+    let [path] = words.as_slice() else {
+        return MetaCommand::UnrecognizedCommand;
+    };
+    if let Err(why) = table.commit() {
+        println!("Unable to commit before backing up: {why}");
+    } else if let Err(why) = table.backup(path) {
+        println!("Unable to back up to {path}: {why}");
+    }
+    MetaCommand::Success
+}
 
-                let username: String =
-                    if let Ok(user) = cap.get(2).unwrap().as_str().parse::<String>() {
-                        if user.len() > 32 {
-                            return PrepareResult::StringTooLong;
-                        }
-                        user
-                    } else {
-                        return PrepareResult::SyntaxError;
-                    };
-
-                let email: String = if let Ok(e) = cap.get(3).unwrap().as_str().parse::<String>() {
-                    if e.len() > 255 {
-                        return PrepareResult::StringTooLong;
-                    }
-                    e
-                } else {
-                    return PrepareResult::SyntaxError;
-                };
-
-                PrepareResult::Success(Statement {
-                    statement_type: StatementType::Insert,
-                    row_to_insert: Some(Row {
-                        id,
-                        username,
-                        email,
-                    }),
-                })
+/// `.btree` prints every table's B-tree as an outline, and `.btree dot FILE` writes it to a
+/// Graphviz DOT file.
+fn do_btree_command(command: &str, table: &Table) -> MetaCommand {
+    let words: Vec<&str> = command.split_whitespace().skip(1).collect();
+    match words.as_slice() {
+        [] => print!("{}", table.btree_outline()),
+        ["dot", path] => {
+            if let Err(why) = std::fs::write(path, table.btree_dot()) {
+                println!("Unable to write {path}: {why}");
             }
-            None => PrepareResult::SyntaxError,
         }
-    } else if statement.starts_with("select") {
-        PrepareResult::Success(Statement {
-            statement_type: StatementType::Select,
-            row_to_insert: None,
-        })
+        _ => return MetaCommand::UnrecognizedCommand,
+    }
+    MetaCommand::Success
+}
+
+/// `.trace on`, `.trace off` or `.trace LEVEL` sets how much goes to stderr, and `.trace` shows
+/// the current setting.
+fn do_trace_command(command: &str) -> MetaCommand {
+    let words: Vec<&str> = command.split_whitespace().skip(1).collect();
+    match words.as_slice() {
+        [] => match trace::level() {
+            Some(level) => println!("{level}"),
+            None => println!("off"),
+        },
+        ["off"] => trace::set_level(None),
+        [level] => match level.parse() {
+            Ok(level) => trace::set_level(Some(level)),
+            Err(()) => return MetaCommand::UnrecognizedCommand,
+        },
+        _ => return MetaCommand::UnrecognizedCommand,
+    }
+    MetaCommand::Success
+}
+
+/// `.param set KEY VALUE`, `.param unset KEY`, `.param list` and `.param clear`. Keys are `:name`
+/// or `?N`, and values are SQL literals, or text if they don't parse as one.
+fn do_param_command(command: &str, parameters: &mut Parameters) -> MetaCommand {
+    let mut words = command.split_whitespace().skip(1);
+    match (words.next(), words.next()) {
+        (Some("set"), Some(key)) => {
+            let value: Vec<&str> = words.collect();
+            if value.is_empty() {
+                return MetaCommand::UnrecognizedCommand;
+            }
+            let value = value.join(" ");
+            parameters.insert(parameter_key(key), parse_value(&value));
+        }
+        (Some("unset"), Some(key)) => {
+            parameters.remove(&parameter_key(key));
+        }
+        (Some("list"), None) => {
+            for (key, value) in parameters.iter() {
+                println!("{key} {value}");
+            }
+        }
+        (Some("clear"), None) => parameters.clear(),
+        _ => return MetaCommand::UnrecognizedCommand,
+    }
+    MetaCommand::Success
+}
+
+fn parameter_key(key: &str) -> String {
+    if key.starts_with(':') || key.starts_with('?') {
+        key.to_string()
     } else {
-        PrepareResult::UnrecognizedStatement
+        format!(":{key}")
     }
 }
+
+fn parse_value(value: &str) -> Value {
+    let literal = parse_select(&format!("select {value}"))
+        .ok()
+        .and_then(|select| match select.items.as_slice() {
+            [SelectItem::Expr { expr, .. }] => evaluate_constant(expr).ok(),
+            _ => None,
+        });
+    literal.unwrap_or_else(|| Value::Text(value.to_string()))
+}
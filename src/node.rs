@@ -1,11 +1,22 @@
+use std::cmp::Ordering;
 use std::fmt::Debug;
 
+use crate::btree::{CELL_OFFSET, CELL_SIZE};
 use crate::cursor::Cursor;
 use crate::node_type::{InternalNode, KeyValuePair, LeafNode, NodeType};
-use crate::pager::{HasOffset, Offset};
+use crate::page::{Page, CHECKSUM_SIZE, INTERNAL_CHILDREN_OFFSET, INTERNAL_CHILD_SIZE};
+use crate::pager::{CorruptPage, HasOffset, Offset, PageBytes};
+use crate::Row;
 
-pub const MAX_INTERNAL_NODES: usize = 511;
-pub const MAX_LEAF_NODES: usize = 12;
+/// How many cells fit in a leaf on a page of `page_size` bytes.
+pub fn max_leaf_cells(page_size: usize) -> usize {
+    (page_size - CHECKSUM_SIZE - CELL_OFFSET) / CELL_SIZE
+}
+
+/// How many separators fit in an internal node on a page of `page_size` bytes.
+pub fn max_internal_separators(page_size: usize) -> usize {
+    (page_size - CHECKSUM_SIZE - INTERNAL_CHILDREN_OFFSET) / INTERNAL_CHILD_SIZE
+}
 
 #[derive(Debug, Clone)]
 pub enum InsertResult<K, V> {
@@ -87,7 +98,8 @@ impl<K: Ord + Clone, V: Debug> Node<K, V> {
         None
     }
 
-    pub fn insert_leaf(&mut self, key: K, value: V) -> InsertResult<K, V>
+    /// Inserts into a leaf, splitting it once it holds more than `max_cells`.
+    pub fn insert_leaf(&mut self, key: K, value: V, max_cells: usize) -> InsertResult<K, V>
     where
         K: Ord + Debug,
         V: Debug,
@@ -102,7 +114,7 @@ impl<K: Ord + Clone, V: Debug> Node<K, V> {
             };
             children.insert(location, KeyValuePair { key, value });
             self.num_cells += 1;
-            return if self.num_cells <= MAX_LEAF_NODES {
+            if self.num_cells <= max_cells {
                 InsertResult::Success
             } else {
                 let upper = children.split_off((children.len() / 2) - 1);
@@ -112,7 +124,7 @@ impl<K: Ord + Clone, V: Debug> Node<K, V> {
                     separator: new_node.smallest_key().unwrap(),
                     tree: new_node,
                 })
-            };
+            }
         } else {
             panic!()
         }
@@ -124,34 +136,19 @@ impl<K: Ord + Clone, V: Debug> Node<K, V> {
     where
         K: Debug,
     {
-        dbg!(key);
         match &self.node_type {
             NodeType::Leaf(LeafNode {
                 children,
                 next_leaf,
                 ..
-            }) => {
-                dbg!(children);
-
-                match children.binary_search_by_key(&key, |pair| dbg!(&pair.key)) {
-                    Ok(index) => Ok(Cursor::new(
-                        self.offset,
-                        index,
-                        next_leaf.is_none() && index == self.num_cells - 1,
-                    )),
-                    Err(index) => {
-                        if index > MAX_LEAF_NODES && next_leaf.is_some() {
-                            Err(Cursor::new(next_leaf.unwrap(), 0, false))
-                        } else {
-                            Err(Cursor::new(
-                                self.offset,
-                                index,
-                                next_leaf.is_none() && index == self.num_cells,
-                            ))
-                        }
-                    }
-                }
-            }
+            }) => match children.binary_search_by_key(&key, |pair| &pair.key) {
+                Ok(index) => Ok(Cursor::new(self.offset, index, false)),
+                Err(index) => Err(Cursor::new(
+                    self.offset,
+                    index,
+                    next_leaf.is_none() && index == self.num_cells,
+                )),
+            },
             NodeType::Internal(..) => {
                 panic!()
             }
@@ -167,7 +164,7 @@ impl<K: Ord + Clone, V: Debug> Node<K, V> {
             let mut new_node = Node::leaf_with_children(upper);
             new_node.offset = new_page;
             self.num_cells = children.len();
-            return new_node;
+            new_node
         } else {
             panic!()
         }
@@ -200,13 +197,8 @@ impl<K: Ord + Clone, V: Debug> Node<K, V> {
                     panic!("Duplicate key");
                 }
                 Err(index) => {
-                    if index > MAX_INTERNAL_NODES {
-                        println!("Error: Trying to insert more internal children than can be stored by one node ({})!", index);
-                        panic!();
-                    } else {
-                        separators.insert(index, key);
-                        children.insert(index + 1, right)
-                    }
+                    separators.insert(index, key);
+                    children.insert(index + 1, right)
                 }
             }
             return true;
@@ -214,38 +206,38 @@ impl<K: Ord + Clone, V: Debug> Node<K, V> {
         false
     }
     pub fn set_last_leaf(&mut self, last: Option<Offset>) -> Option<Offset> {
-        if let NodeType::Leaf(LeafNode { mut last_leaf, .. }) = self.node_type {
-            match last {
-                Some(o) => last_leaf.replace(o),
-                None => last_leaf.take(),
-            }
+        if let NodeType::Leaf(LeafNode {
+            ref mut last_leaf, ..
+        }) = self.node_type
+        {
+            std::mem::replace(last_leaf, last)
         } else {
             panic!("Called on a non-leaf node!")
         }
     }
 
     pub fn set_next_leaf(&mut self, next: Option<Offset>) -> Option<Offset> {
-        if let NodeType::Leaf(LeafNode { mut next_leaf, .. }) = self.node_type {
-            match next {
-                Some(o) => next_leaf.replace(o),
-                None => next_leaf.take(),
-            }
+        if let NodeType::Leaf(LeafNode {
+            ref mut next_leaf, ..
+        }) = self.node_type
+        {
+            std::mem::replace(next_leaf, next)
         } else {
             panic!("Called on a non-leaf node!")
         }
     }
 
-    pub fn get_next_leaf(&mut self) -> Option<Offset> {
-        if let NodeType::Leaf(LeafNode { mut next_leaf, .. }) = self.node_type {
-            next_leaf.clone()
+    pub fn get_next_leaf(&self) -> Option<Offset> {
+        if let NodeType::Leaf(LeafNode { next_leaf, .. }) = self.node_type {
+            next_leaf
         } else {
             panic!("Called on a non-leaf node!")
         }
     }
 
-    pub fn get_last_leaf(&mut self) -> Option<Offset> {
-        if let NodeType::Leaf(LeafNode { mut last_leaf, .. }) = self.node_type {
-            last_leaf.clone()
+    pub fn get_last_leaf(&self) -> Option<Offset> {
+        if let NodeType::Leaf(LeafNode { last_leaf, .. }) = self.node_type {
+            last_leaf
         } else {
             panic!("Called on a non-leaf node!")
         }
@@ -258,20 +250,206 @@ impl<K, V> HasOffset for Node<K, V> {
     }
 }
 
+/// A node read in place from its page. Keys and child pointers are read straight from the page's
+/// bytes, and a row is only decoded when it's asked for. Reading never needs more than this;
+/// a [`Node`] is only decoded to be changed and committed.
+#[derive(Debug, Clone)]
+pub struct NodeView<B = PageBytes> {
+    page: Page<B>,
+    offset: Offset,
+}
+
+impl<B: AsRef<[u8]>> NodeView<B> {
+    pub fn new(page: Page<B>, offset: Offset) -> Self {
+        Self { page, offset }
+    }
+
+    pub fn is_leaf(&self) -> bool {
+        self.page.node_type() == 0
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.page.is_root_node()
+    }
+
+    pub fn parent_offset(&self) -> Option<Offset> {
+        (!self.is_root())
+            .then(|| self.page.parent_offset())
+            .flatten()
+    }
+
+    /// How many cells a leaf has.
+    pub fn num_cells(&self) -> usize {
+        self.page.num_cells()
+    }
+
+    pub fn key(&self, cell_num: usize) -> usize {
+        self.page.cell_key(cell_num)
+    }
+
+    /// Decodes the row in one of a leaf's cells. Fails if the row's bytes are damaged.
+    pub fn value(&self, cell_num: usize) -> Result<Row, CorruptPage> {
+        self.page
+            .cell_value(cell_num)
+            .map_err(|_| CorruptPage(self.offset))
+    }
+
+    /// The cell holding `key` in a leaf, or the one it would go in.
+    pub fn search(&self, key: usize) -> Result<usize, usize> {
+        binary_search(self.num_cells(), key, |cell| self.key(cell))
+    }
+
+    pub fn next_leaf(&self) -> Option<Offset> {
+        self.page.next_leaf()
+    }
+
+    pub fn last_leaf(&self) -> Option<Offset> {
+        self.page.last_leaf()
+    }
+
+    /// How many separators an internal node has. It has one more child.
+    pub fn num_separators(&self) -> usize {
+        self.page.separator_count()
+    }
+
+    pub fn separator(&self, index: usize) -> usize {
+        self.page.internal_child(index).1
+    }
+
+    pub fn child(&self, index: usize) -> Offset {
+        match index {
+            0 => self.page.internal_child(0).0,
+            index => self.page.internal_child(index - 1).2,
+        }
+    }
+
+    /// Which of an internal node's children `key` is under.
+    pub fn child_index(&self, key: usize) -> usize {
+        match binary_search(self.num_separators(), key, |slot| self.separator(slot)) {
+            Ok(index) => index + 1,
+            Err(index) => index,
+        }
+    }
+
+    /// Every child of an internal node, in order.
+    pub fn children(&self) -> impl Iterator<Item = Offset> + '_ {
+        let children = if self.is_leaf() {
+            0
+        } else {
+            self.num_separators() + 1
+        };
+        (0..children).map(|index| self.child(index))
+    }
+
+    /// Decodes the whole node, to change it.
+    pub fn to_node(&self) -> Result<Node<usize, Row>, CorruptPage> {
+        let mut node = Node::try_from(&self.page).map_err(|_| CorruptPage(self.offset))?;
+        node.offset = self.offset;
+        Ok(node)
+    }
+}
+
+impl<B> HasOffset for NodeView<B> {
+    fn offset(&self) -> Offset {
+        self.offset
+    }
+}
+
+/// [`slice::binary_search`] over `len` keys read by `key_at`.
+fn binary_search(len: usize, key: usize, key_at: impl Fn(usize) -> usize) -> Result<usize, usize> {
+    let (mut low, mut high) = (0, len);
+    while low < high {
+        let mid = low + (high - low) / 2;
+        match key_at(mid).cmp(&key) {
+            Ordering::Equal => return Ok(mid),
+            Ordering::Less => low = mid + 1,
+            Ordering::Greater => high = mid,
+        }
+    }
+    Err(low)
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::node::{InsertResult, Node, MAX_LEAF_NODES};
+    use crate::node::{max_internal_separators, max_leaf_cells, InsertResult, Node, NodeView};
+    use crate::node_type::KeyValuePair;
+    use crate::page::{Page, DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE, MIN_PAGE_SIZE};
+    use crate::pager::Offset;
+    use crate::Row;
 
     #[test]
     fn test_leaf_inserts() {
+        let max_cells = max_leaf_cells(DEFAULT_PAGE_SIZE);
         let mut n: Node<usize, usize> = Node::leaf();
-        for i in 0..MAX_LEAF_NODES {
-            assert!(matches!(n.insert_leaf(i, i), InsertResult::Success));
+        for i in 0..max_cells {
+            assert!(matches!(
+                n.insert_leaf(i, i, max_cells),
+                InsertResult::Success
+            ));
         }
-        assert!(matches!(n.insert_leaf(0, 0), InsertResult::DuplicateKey));
         assert!(matches!(
-            n.insert_leaf(MAX_LEAF_NODES + 1, 0),
+            n.insert_leaf(0, 0, max_cells),
+            InsertResult::DuplicateKey
+        ));
+        assert!(matches!(
+            n.insert_leaf(max_cells + 1, 0, max_cells),
             InsertResult::ParentSplit(..)
         ));
     }
+
+    #[test]
+    fn capacity_follows_the_page_size() {
+        assert_eq!(max_leaf_cells(MIN_PAGE_SIZE), 1);
+        assert_eq!(max_leaf_cells(DEFAULT_PAGE_SIZE), 13);
+        assert_eq!(max_leaf_cells(MAX_PAGE_SIZE), 222);
+        assert_eq!(max_internal_separators(MIN_PAGE_SIZE), 24);
+        assert_eq!(max_internal_separators(DEFAULT_PAGE_SIZE), 203);
+        assert_eq!(max_internal_separators(MAX_PAGE_SIZE), 3275);
+    }
+
+    #[test]
+    fn views_read_what_nodes_decode() {
+        let row = |id: u32| Row {
+            id,
+            username: format!("user{id}"),
+            email: format!("user{id}@example.com"),
+        };
+        let pairs = [2, 4, 6].map(|id| KeyValuePair {
+            key: id as usize,
+            value: row(id),
+        });
+        let mut leaf = Node::leaf_with_children(pairs.to_vec());
+        leaf.parent_offset = Some(Offset(3));
+        leaf.set_next_leaf(Some(Offset(5)));
+        let page = Page::from_node(&leaf, DEFAULT_PAGE_SIZE).unwrap();
+        let view = NodeView::new(Page::borrowed(page.as_bytes()), Offset(4));
+        assert!(view.is_leaf() && !view.is_root());
+        assert_eq!(view.parent_offset(), Some(Offset(3)));
+        assert_eq!(view.next_leaf(), Some(Offset(5)));
+        assert_eq!(view.last_leaf(), None);
+        assert_eq!(view.num_cells(), 3);
+        assert_eq!((view.key(1), view.value(1).unwrap()), (4, row(4)));
+        assert_eq!(view.search(6), Ok(2));
+        assert_eq!(view.search(5), Err(2));
+        assert_eq!(view.search(7), Err(3));
+        assert_eq!(view.children().count(), 0);
+        assert_eq!(view.to_node().unwrap().offset, Offset(4));
+
+        let mut internal =
+            Node::internal_with_separators(vec![10, 20], vec![Offset(7), Offset(8), Offset(9)]);
+        internal.is_root = true;
+        let page = Page::from_node(&internal, DEFAULT_PAGE_SIZE).unwrap();
+        let view = NodeView::new(Page::borrowed(page.as_bytes()), Offset(1));
+        assert!(!view.is_leaf() && view.is_root());
+        assert_eq!(view.parent_offset(), None);
+        assert_eq!(view.num_separators(), 2);
+        assert_eq!(view.separator(1), 20);
+        assert_eq!(
+            view.children().collect::<Vec<_>>(),
+            vec![Offset(7), Offset(8), Offset(9)]
+        );
+        assert_eq!(view.child_index(5), 0);
+        assert_eq!(view.child_index(10), 1);
+        assert_eq!(view.child_index(25), 2);
+    }
 }
@@ -1,51 +1,103 @@
 use std::fmt::{Debug, Formatter};
 use std::io::Write;
+use std::str::Utf8Error;
 
 use crate::btree::{
-    CELL_KEY_SIZE, CELL_OFFSET, CELL_SIZE, CELL_VALUE_SIZE, IS_ROOT_OFFSET, NODE_TYPE_OFFSET,
-    NUM_CELLS_OFFSET, PARENT_OFFSET,
+    CELL_KEY_SIZE, CELL_OFFSET, CELL_SIZE, CELL_VALUE_SIZE, IS_ROOT_OFFSET, LAST_LEAF_OFFSET,
+    NEXT_LEAF_OFFSET, NODE_TYPE_OFFSET, NUM_CELLS_OFFSET, PARENT_OFFSET,
 };
 use crate::datastore::ROW_SIZE;
-use crate::node::Node;
+use crate::node::{max_internal_separators, max_leaf_cells, Node};
 use crate::node_type::{InternalNode, KeyValuePair, LeafNode, NodeType};
 use crate::pager::Offset;
 use crate::Row;
 
-pub const PAGE_SIZE: usize = 4096;
-pub const TABLE_MAX_PAGES: usize = 100;
-pub const RIGHTMOST_CHILD_OFFSET: usize = 10;
-pub const INTERNAL_CHILDREN_OFFSET: usize = RIGHTMOST_CHILD_OFFSET + 4;
-pub const INTERNAL_CHILD_SIZE: usize = 12;
+/// The file format. Every number is stored little-endian, whatever the machine, so a database can
+/// move between architectures. Page numbers are `u64`s, and keys and counts are `u32`s.
+///
+/// The first page is the header, laid out in `catalog.rs`. Every other page starts with a node
+/// type byte at offset 0: 0 for a leaf, 1 for an internal node, 2 for a free page.
+///
+/// | Offset | Leaf                     | Internal                        | Free           |
+/// |--------|--------------------------|---------------------------------|----------------|
+/// | 1      | 1 if it's a root         | 1 if it's a root                |                |
+/// | 2      | parent page              | parent page                     |                |
+/// | 10     | cell count               | separator count                 |                |
+/// | 14     | next leaf, or 0          | separator count                 | next free page |
+/// | 18     |                          | slots of (left, key, right)     |                |
+/// | 22     | previous leaf, or 0      |                                 |                |
+/// | 30     | cells of (key, row)      |                                 |                |
+///
+/// A leaf cell is 295 bytes: the key, then the row as `Row::serialize` lays it out. Neighbouring
+/// internal slots share a child, so slot i's right page is slot i + 1's left page. The last
+/// [`CHECKSUM_SIZE`] bytes of each page are a CRC32C of the rest of it, when checksums are on.
+///
+/// Every page of a database is the same size, a power of two between [`MIN_PAGE_SIZE`] and
+/// [`MAX_PAGE_SIZE`] chosen when it's created and recorded in the header.
+pub const DEFAULT_PAGE_SIZE: usize = 4096;
+pub const MIN_PAGE_SIZE: usize = 512;
+pub const MAX_PAGE_SIZE: usize = 65536;
+/// The last bytes of every page are reserved for a checksum of the rest, whether or not the
+/// database keeps checksums, so they can be turned on without moving anything.
+pub const CHECKSUM_SIZE: usize = 4;
+pub const PAGE_NUMBER_SIZE: usize = 8;
+pub const SEPARATOR_COUNT_OFFSET: usize = 14;
+pub const INTERNAL_CHILDREN_OFFSET: usize = SEPARATOR_COUNT_OFFSET + 4;
+pub const INTERNAL_CHILD_SIZE: usize = 2 * PAGE_NUMBER_SIZE + 4;
+/// Node type of a page on the free list. Free pages point to the next one where a leaf points to
+/// its next sibling.
+pub const FREE_PAGE_TYPE: u8 = 2;
 
-pub struct Page(Box<Box<[u8; PAGE_SIZE as usize]>>);
+/// Whether a database can have pages of `page_size` bytes.
+pub fn is_valid_page_size(page_size: usize) -> bool {
+    (MIN_PAGE_SIZE..=MAX_PAGE_SIZE).contains(&page_size) && page_size.is_power_of_two()
+}
+
+/// A page's bytes, owned unless they're borrowed from somewhere like the cache or a memory map.
+#[derive(Clone)]
+pub struct Page<B = Box<[u8]>>(B);
 
 impl Page {
-    pub fn new() -> Self {
-        Self(Box::new(Box::new([0 as u8; PAGE_SIZE as usize])))
+    pub fn new(page_size: usize) -> Self {
+        Self(vec![0; page_size].into_boxed_slice())
     }
 
-    pub fn load(p: Box<[u8; PAGE_SIZE]>) -> Self {
-        Self(Box::new(p))
+    pub fn load(p: Box<[u8]>) -> Self {
+        Self(p)
     }
 
     pub fn insert(&mut self, row: Row, slot: usize) {
         let min = slot * ROW_SIZE;
         let max = min + ROW_SIZE;
-        self.0[min..max].swap_with_slice(&mut *row.serialize());
+        self.0[min..max].swap_with_slice(&mut row.serialize());
     }
 
-    pub fn select(&self, slot: usize) -> Row {
-        let min = slot * ROW_SIZE;
-        let max = min + ROW_SIZE;
-        Row::deserialize(&self.0[min..max])
+    pub fn as_bytes_mut(&mut self) -> &mut [u8] {
+        &mut self.0
     }
 
-    pub fn write(&self, mut writer: impl Write) -> std::io::Result<usize> {
-        writer.write(&self.0[0..PAGE_SIZE])
+    /// Stores a checksum of the page's contents in its reserved bytes.
+    pub fn stamp_checksum(&mut self) {
+        let usable = self.usable_size();
+        let checksum = crc32c(&self.0[..usable]);
+        self.set_u32_at(usable, checksum);
     }
 
-    pub fn is_root_node(&self) -> bool {
-        self.0[IS_ROOT_OFFSET] == 1
+    /// A page for the free list, linking to the next free page.
+    pub fn free(next: Option<Offset>, page_size: usize) -> Self {
+        let mut page = Page::new(page_size);
+        page.0[NODE_TYPE_OFFSET] = FREE_PAGE_TYPE;
+        page.set_next_leaf(next);
+        page
+    }
+
+    fn set_u32_at(&mut self, at: usize, value: u32) {
+        self.0[at..at + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn set_page_number_at(&mut self, at: usize, page: Option<Offset>) {
+        let page = page.map_or(0, |o| o.0 as u64);
+        self.0[at..at + PAGE_NUMBER_SIZE].copy_from_slice(&page.to_le_bytes());
     }
 
     pub fn set_root_node(&mut self, is_root_node: bool) {
@@ -56,74 +108,193 @@ impl Page {
         }
     }
 
-    pub fn parent_offset(&self) -> Option<Offset> {
-        Some(Offset(
-            u32::from_ne_bytes(self.0[PARENT_OFFSET..PARENT_OFFSET + 4].try_into().unwrap())
-                as usize,
-        ))
-    }
-
     pub fn set_parent_offset(&mut self, parent_offset: Option<Offset>) {
-        if let Some(offset) = parent_offset {
-            self.0[PARENT_OFFSET..PARENT_OFFSET + 4]
-                .swap_with_slice(&mut (offset.0 as u32).to_ne_bytes())
+        if parent_offset.is_some() {
+            self.set_page_number_at(PARENT_OFFSET, parent_offset)
         }
     }
 
-    pub fn num_cells(&self) -> usize {
-        u32::from_ne_bytes(
-            self.0[NUM_CELLS_OFFSET..NUM_CELLS_OFFSET + 4]
-                .try_into()
-                .unwrap(),
-        ) as usize
+    pub fn set_num_cells(&mut self, num_cells: usize) {
+        self.set_u32_at(NUM_CELLS_OFFSET, num_cells as u32)
     }
 
-    pub fn set_num_cells(&mut self, num_cells: usize) {
-        self.0[NUM_CELLS_OFFSET..NUM_CELLS_OFFSET + 4]
-            .swap_with_slice(&mut (num_cells as u32).to_ne_bytes());
+    pub fn set_next_leaf(&mut self, next_leaf: Option<Offset>) {
+        self.set_page_number_at(NEXT_LEAF_OFFSET, next_leaf)
     }
 
-    pub fn rightmost_child(&self) -> usize {
-        u32::from_ne_bytes(
-            self.0[RIGHTMOST_CHILD_OFFSET..RIGHTMOST_CHILD_OFFSET + 4]
-                .try_into()
-                .unwrap(),
-        ) as usize
+    pub fn set_last_leaf(&mut self, last_leaf: Option<Offset>) {
+        self.set_page_number_at(LAST_LEAF_OFFSET, last_leaf)
     }
 
-    pub fn set_rightmost_child(&mut self, rightmost_child: usize) {
-        self.0[RIGHTMOST_CHILD_OFFSET..RIGHTMOST_CHILD_OFFSET + 4]
-            .swap_with_slice(&mut (rightmost_child as u32).to_ne_bytes());
+    pub fn set_separator_count(&mut self, separator_count: usize) {
+        self.set_u32_at(SEPARATOR_COUNT_OFFSET, separator_count as u32)
     }
 
     pub fn set_internal_child(&mut self, slot: usize, key: usize, left: Offset, right: Offset) {
         let child_left = INTERNAL_CHILDREN_OFFSET + (slot * INTERNAL_CHILD_SIZE);
-        let child_key = child_left + 4;
+        let child_key = child_left + PAGE_NUMBER_SIZE;
         let child_right = child_key + 4;
-        self.0[child_left..child_left + 4].swap_with_slice(&mut (left.0 as u32).to_ne_bytes());
-        self.0[child_key..child_key + 4].swap_with_slice(&mut (key as u32).to_ne_bytes());
-        self.0[child_right..child_right + 4].swap_with_slice(&mut (right.0 as u32).to_ne_bytes());
+        self.set_page_number_at(child_left, Some(left));
+        self.set_u32_at(child_key, key as u32);
+        self.set_page_number_at(child_right, Some(right));
     }
 
     pub fn set_cell(&mut self, cell_num: usize, key: usize, value: &Row) {
         let cell_key = CELL_OFFSET + (cell_num * CELL_SIZE);
         let cell_val = cell_key + CELL_KEY_SIZE;
-        self.0[cell_key..cell_key + 4].swap_with_slice(&mut (key as u32).to_ne_bytes());
-        self.0[cell_val..cell_val + CELL_VALUE_SIZE].swap_with_slice(&mut *value.serialize());
+        self.set_u32_at(cell_key, key as u32);
+        self.0[cell_val..cell_val + CELL_VALUE_SIZE].swap_with_slice(&mut value.serialize());
     }
 }
 
-impl Debug for Page {
+impl<B: AsRef<[u8]>> Page<B> {
+    /// A page read in place from bytes kept somewhere else, like the cache or a memory map,
+    /// without copying them.
+    pub fn borrowed(bytes: B) -> Self {
+        Self(bytes)
+    }
+
+    pub fn size(&self) -> usize {
+        self.as_bytes().len()
+    }
+
+    /// The bytes before the checksum.
+    pub fn usable_size(&self) -> usize {
+        self.size() - CHECKSUM_SIZE
+    }
+
+    pub fn select(&self, slot: usize) -> Result<Row, Utf8Error> {
+        let min = slot * ROW_SIZE;
+        let max = min + ROW_SIZE;
+        Row::deserialize(&self.as_bytes()[min..max])
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        self.0.as_ref()
+    }
+
+    pub fn write(&self, mut writer: impl Write) -> std::io::Result<usize> {
+        writer.write(self.as_bytes())
+    }
+
+    fn stored_checksum(&self) -> u32 {
+        self.u32_at(self.usable_size())
+    }
+
+    /// Whether the page's reserved bytes hold a checksum of its contents.
+    pub fn checksum_matches(&self) -> bool {
+        self.stored_checksum() == crc32c(&self.as_bytes()[..self.usable_size()])
+    }
+
+    pub fn node_type(&self) -> u8 {
+        self.as_bytes()[NODE_TYPE_OFFSET]
+    }
+
+    pub fn next_free(&self) -> Option<Offset> {
+        self.next_leaf()
+    }
+
+    fn u32_at(&self, at: usize) -> u32 {
+        u32::from_le_bytes(self.as_bytes()[at..at + 4].try_into().unwrap())
+    }
+
+    /// Page numbers are stored as `u64`s, with 0 meaning "no page". Page 0 is always the header,
+    /// so it's never a node's parent, sibling or child.
+    fn page_number_at(&self, at: usize) -> Option<Offset> {
+        let bytes = &self.as_bytes()[at..at + PAGE_NUMBER_SIZE];
+        match u64::from_le_bytes(bytes.try_into().unwrap()) {
+            0 => None,
+            page => Some(Offset(page as usize)),
+        }
+    }
+
+    pub fn is_root_node(&self) -> bool {
+        self.as_bytes()[IS_ROOT_OFFSET] == 1
+    }
+
+    pub fn parent_offset(&self) -> Option<Offset> {
+        Some(self.page_number_at(PARENT_OFFSET).unwrap_or(Offset(0)))
+    }
+
+    pub fn num_cells(&self) -> usize {
+        self.u32_at(NUM_CELLS_OFFSET) as usize
+    }
+
+    pub fn next_leaf(&self) -> Option<Offset> {
+        self.page_number_at(NEXT_LEAF_OFFSET)
+    }
+
+    pub fn last_leaf(&self) -> Option<Offset> {
+        self.page_number_at(LAST_LEAF_OFFSET)
+    }
+
+    pub fn separator_count(&self) -> usize {
+        self.u32_at(SEPARATOR_COUNT_OFFSET) as usize
+    }
+
+    /// The left child, separator and right child in an internal node's slot.
+    pub fn internal_child(&self, slot: usize) -> (Offset, usize, Offset) {
+        let child_left = INTERNAL_CHILDREN_OFFSET + (slot * INTERNAL_CHILD_SIZE);
+        let child_key = child_left + PAGE_NUMBER_SIZE;
+        let child_right = child_key + 4;
+        let child = |at| self.page_number_at(at).unwrap_or(Offset(0));
+        (
+            child(child_left),
+            self.u32_at(child_key) as usize,
+            child(child_right),
+        )
+    }
+
+    pub fn cell_key(&self, cell_num: usize) -> usize {
+        self.u32_at(CELL_OFFSET + (cell_num * CELL_SIZE)) as usize
+    }
+
+    pub fn cell_value(&self, cell_num: usize) -> Result<Row, Utf8Error> {
+        let at = CELL_OFFSET + (cell_num * CELL_SIZE) + CELL_KEY_SIZE;
+        Row::deserialize(&self.as_bytes()[at..at + CELL_VALUE_SIZE])
+    }
+}
+
+/// CRC32C (Castagnoli), one byte at a time from a table built at compile time.
+const CRC32C_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0x82f63b78
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+pub fn crc32c(bytes: &[u8]) -> u32 {
+    let mut crc = !0_u32;
+    for &byte in bytes {
+        crc = CRC32C_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+impl<B> Debug for Page<B> {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         write!(f, "Page (\n\t0: [*OMITTED*]\n)")
     }
 }
 
-impl TryFrom<&Page> for Node<usize, Row> {
+impl<B: AsRef<[u8]>> TryFrom<&Page<B>> for Node<usize, Row> {
     type Error = ();
 
-    fn try_from(value: &Page) -> Result<Self, Self::Error> {
-        let mut node = if value.0[NODE_TYPE_OFFSET] == 0 {
+    fn try_from(value: &Page<B>) -> Result<Self, Self::Error> {
+        let mut node = if value.node_type() == 0 {
             Node::leaf()
         } else {
             Node::internal()
@@ -136,42 +307,33 @@ impl TryFrom<&Page> for Node<usize, Row> {
 
         match node.node_type {
             NodeType::Leaf(LeafNode {
-                ref mut children, ..
+                ref mut children,
+                ref mut last_leaf,
+                ref mut next_leaf,
             }) => {
-                for i in 0..12 as usize {
-                    if i == node.num_cells {
-                        break;
-                    }
-                    let cell_key = CELL_OFFSET + (i * CELL_SIZE);
-                    let cell_val = cell_key + CELL_KEY_SIZE;
-                    let key =
-                        u32::from_ne_bytes(value.0[cell_key..cell_key + 4].try_into().unwrap())
-                            as usize;
-                    let value = Row::deserialize(&value.0[cell_val..cell_val + CELL_VALUE_SIZE]);
-                    children.push(KeyValuePair { key, value });
+                *last_leaf = value.last_leaf();
+                *next_leaf = value.next_leaf();
+                for i in 0..node.num_cells {
+                    children.push(KeyValuePair {
+                        key: value.cell_key(i),
+                        value: value.cell_value(i).map_err(|_| ())?,
+                    });
                 }
             }
             NodeType::Internal(InternalNode {
                 ref mut separators,
                 ref mut children,
             }) => {
-                let rightmost = value.rightmost_child();
-                for slot in 0..rightmost {
-                    let child_left = INTERNAL_CHILDREN_OFFSET + (slot * INTERNAL_CHILD_SIZE);
-                    let child_key = child_left + 4;
-                    let child_right = child_key + 4;
-
-                    let left =
-                        u32::from_ne_bytes(value.0[child_left..child_left + 4].try_into().unwrap())
-                            as usize;
-
-                    let right = u32::from_ne_bytes(
-                        value.0[child_right..child_right + 4].try_into().unwrap(),
-                    ) as usize;
-
-                    separators.push(child_key);
-                    children.insert(slot, Offset(left));
-                    children.insert(slot + 1, Offset(right));
+                let separator_count = value.separator_count();
+                for slot in 0..separator_count {
+                    let (left, key, right) = value.internal_child(slot);
+                    // Neighbouring slots share a child, so only the first slot contributes its
+                    // left pointer.
+                    if slot == 0 {
+                        children.push(left);
+                    }
+                    separators.push(key);
+                    children.push(right);
                 }
             }
         }
@@ -180,22 +342,36 @@ impl TryFrom<&Page> for Node<usize, Row> {
     }
 }
 
-impl TryFrom<&Node<usize, Row>> for Page {
-    type Error = ();
-
-    fn try_from(value: &Node<usize, Row>) -> Result<Self, Self::Error> {
-        let mut page = Page::new();
+impl Page {
+    /// Encodes a node on a page of `page_size` bytes, or returns `None` if it doesn't fit.
+    pub fn from_node(value: &Node<usize, Row>, page_size: usize) -> Option<Self> {
+        let fits = match value.node_type {
+            NodeType::Leaf(LeafNode { ref children, .. }) => {
+                children.len() <= max_leaf_cells(page_size)
+            }
+            NodeType::Internal(InternalNode { ref separators, .. }) => {
+                separators.len() <= max_internal_separators(page_size)
+            }
+        };
+        if !fits {
+            return None;
+        }
+        let mut page = Page::new(page_size);
         page.set_root_node(value.is_root);
         page.set_parent_offset(value.parent_offset);
         page.set_num_cells(value.num_cells);
 
         match value.node_type {
-            NodeType::Leaf(LeafNode { ref children, .. }) => {
+            NodeType::Leaf(LeafNode {
+                ref children,
+                last_leaf,
+                next_leaf,
+            }) => {
                 page.0[NODE_TYPE_OFFSET] = 0;
-                let mut i = 0;
-                for KeyValuePair { key, value } in children {
+                page.set_last_leaf(last_leaf);
+                page.set_next_leaf(next_leaf);
+                for (i, KeyValuePair { key, value }) in children.iter().enumerate() {
                     page.set_cell(i, *key, value);
-                    i += 1;
                 }
             }
             NodeType::Internal(InternalNode {
@@ -203,18 +379,161 @@ impl TryFrom<&Node<usize, Row>> for Page {
                 ref children,
             }) => {
                 page.0[NODE_TYPE_OFFSET] = 1;
-                page.set_rightmost_child(children.len() - 1);
+                page.set_num_cells(separators.len());
+                page.set_separator_count(separators.len());
                 for (slot, ((&key, left), right)) in separators
                     .iter()
                     .zip(children.iter())
                     .zip(children.iter().skip(1))
                     .enumerate()
                 {
-                    page.set_internal_child(slot, key, left.clone(), right.clone())
+                    page.set_internal_child(slot, key, *left, *right)
                 }
             }
         }
 
-        Ok(page)
+        Some(page)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::fixtures::{insert, statement, temp_path};
+    use crate::node::Node;
+    use crate::page::{crc32c, Page, CHECKSUM_SIZE, DEFAULT_PAGE_SIZE};
+    use crate::pager::Offset;
+    use crate::Table;
+
+    /// `build_golden` run when the format was last changed on purpose. Change it again and this
+    /// file has to be rebuilt, and the test below updated to match.
+    const GOLDEN: &[u8] = include_bytes!("../testdata/golden.db");
+    const USABLE_SIZE: usize = DEFAULT_PAGE_SIZE - CHECKSUM_SIZE;
+
+    /// A database with checksums on and 14 rows in `users`, one more than fits in a leaf.
+    fn build_golden(path: &Path) {
+        let _ = std::fs::remove_file(path);
+        let mut table = Table::open(path);
+        table.execute_statement(statement("pragma page_checksums = on"));
+        insert(&mut table, 1..=14);
+        table.close();
+    }
+
+    fn u32_at(page: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(page[at..at + 4].try_into().unwrap())
+    }
+
+    fn u64_at(page: &[u8], at: usize) -> u64 {
+        u64::from_le_bytes(page[at..at + 8].try_into().unwrap())
+    }
+
+    /// Checks one leaf cell: its key, and the row's id, username and email.
+    fn check_cell(page: &[u8], cell: usize, id: u32) {
+        let at = 30 + cell * 295;
+        assert_eq!(u32_at(page, at), id, "key of cell {cell}");
+        assert_eq!(u32_at(page, at + 4), id, "id of cell {cell}");
+        let username = format!("user{id}");
+        assert_eq!(&page[at + 8..at + 8 + username.len()], username.as_bytes());
+        assert!(page[at + 8 + username.len()..at + 40]
+            .iter()
+            .all(|&b| b == 0));
+        let email = format!("user{id}@example.com");
+        assert_eq!(&page[at + 40..at + 40 + email.len()], email.as_bytes());
+        assert!(page[at + 40 + email.len()..at + 295]
+            .iter()
+            .all(|&b| b == 0));
+    }
+
+    #[test]
+    fn golden_file_matches_the_format() {
+        let pages: Vec<&[u8]> = GOLDEN.chunks(DEFAULT_PAGE_SIZE).collect();
+        assert_eq!(pages.len(), 4);
+        for (i, page) in pages.iter().enumerate() {
+            assert_eq!(
+                u32_at(page, USABLE_SIZE),
+                crc32c(&page[..USABLE_SIZE]),
+                "checksum of page {i}"
+            );
+        }
+
+        // The header: magic, the page size, one table, checksums on, an empty free list, two
+        // commits (creating the file and closing it), then `users` at page 1
+        let header = pages[0];
+        assert_eq!(&header[0..8], b"tarsier\0");
+        assert_eq!(u32_at(header, 8), 4096);
+        assert_eq!(u32_at(header, 12), 1);
+        assert_eq!(u32_at(header, 16), 1);
+        assert_eq!(u64_at(header, 20), 0);
+        assert_eq!(u64_at(header, 28), 0);
+        assert_eq!(u64_at(header, 36), 2);
+        assert_eq!(&header[44..49], b"users");
+        assert!(header[49..76].iter().all(|&b| b == 0));
+        assert_eq!(u64_at(header, 76), 1);
+        assert!(header[84..USABLE_SIZE].iter().all(|&b| b == 0));
+
+        // The root: an internal node with one separator, 8, between pages 3 and 2
+        let root = pages[1];
+        assert_eq!(&root[0..2], [1, 1]);
+        assert_eq!(u64_at(root, 2), 0);
+        assert_eq!(u32_at(root, 10), 1);
+        assert_eq!(u32_at(root, 14), 1);
+        assert_eq!(u64_at(root, 18), 3);
+        assert_eq!(u32_at(root, 26), 8);
+        assert_eq!(u64_at(root, 30), 2);
+        assert!(root[38..USABLE_SIZE].iter().all(|&b| b == 0));
+
+        // The right leaf, split off the left one, holds 8 to 14
+        let right = pages[2];
+        assert_eq!(&right[0..2], [0, 0]);
+        assert_eq!(u64_at(right, 2), 1);
+        assert_eq!(u32_at(right, 10), 7);
+        assert_eq!(u64_at(right, 14), 0);
+        assert_eq!(u64_at(right, 22), 3);
+        for (cell, id) in (8..=14).enumerate() {
+            check_cell(right, cell, id);
+        }
+
+        // The left leaf holds 1 to 7
+        let left = pages[3];
+        assert_eq!(&left[0..2], [0, 0]);
+        assert_eq!(u64_at(left, 2), 1);
+        assert_eq!(u32_at(left, 10), 7);
+        assert_eq!(u64_at(left, 14), 2);
+        assert_eq!(u64_at(left, 22), 0);
+        for (cell, id) in (1..=7).enumerate() {
+            check_cell(left, cell, id);
+        }
+
+        // And the code still writes exactly this
+        let path = temp_path("golden");
+        build_golden(&path);
+        let built = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(built == GOLDEN, "the file format changed");
+    }
+
+    #[test]
+    fn page_numbers_past_32_bits() {
+        let far = |n: usize| Offset((1 << 40) + n);
+        let mut internal =
+            Node::internal_with_separators(vec![10, 20], vec![far(1), far(2), far(3)]);
+        internal.parent_offset = Some(far(0));
+        let page = Page::from_node(&internal, DEFAULT_PAGE_SIZE).unwrap();
+        let decoded = Node::try_from(&page).unwrap();
+        assert_eq!(decoded.parent_offset, Some(far(0)));
+        assert_eq!(
+            Page::from_node(&decoded, DEFAULT_PAGE_SIZE)
+                .unwrap()
+                .as_bytes(),
+            page.as_bytes()
+        );
+
+        let mut leaf = Node::leaf();
+        leaf.set_next_leaf(Some(far(4)));
+        leaf.set_last_leaf(Some(far(5)));
+        let decoded = Node::try_from(&Page::from_node(&leaf, DEFAULT_PAGE_SIZE).unwrap()).unwrap();
+        assert_eq!(decoded.get_next_leaf(), Some(far(4)));
+        assert_eq!(decoded.get_last_leaf(), Some(far(5)));
     }
 }
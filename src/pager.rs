@@ -1,15 +1,26 @@
-use std::cell::{Cell, RefCell};
 use std::cmp::Reverse;
-use std::collections::{BinaryHeap, HashMap};
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap, HashSet};
 use std::fmt::{Debug, Display, Formatter};
-use std::fs::{File, OpenOptions};
-use std::io::{Read, Seek, SeekFrom, Write};
-use std::ops::Deref;
+use std::io;
+use std::ops::Range;
 use std::path::Path;
 use std::process::exit;
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
 
-use crate::node::Node;
-use crate::page::{Page, PAGE_SIZE};
+use memmap2::Mmap;
+
+use crate::catalog;
+use crate::journal;
+use crate::node::{Node, NodeView};
+use crate::node_type::NodeType;
+use crate::page::{Page, DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE};
+use crate::pointer_map::{self, PageType};
+use crate::trace::{event, Level};
+use crate::vfs::{DiskVfs, Lock, MemoryVfs, Vfs, MEMORY};
 use crate::Row;
 
 #[derive(Debug, Eq, Hash, PartialEq, PartialOrd, Ord, Copy, Clone)]
@@ -21,36 +32,223 @@ impl Display for Offset {
     }
 }
 
+/// Page numbers are 64 bits, so by default a file can have as many of the largest pages as there
+/// are byte offsets to put them at.
+pub const DEFAULT_MAX_PAGE_COUNT: usize = usize::MAX / MAX_PAGE_SIZE;
+
+/// A page's old versions, oldest first, each with the last commit it was current for.
+type Versions = Vec<(u64, Arc<Page>)>;
+
+/// The bytes of a page that a [`NodeView`] reads, shared with the cache or the memory map rather
+/// than copied out of it.
+#[derive(Debug, Clone)]
+pub enum PageBytes {
+    Cached(Arc<Page>),
+    /// A page's range of a map of the file. A page is only read from the map while it's latched
+    /// and isn't in the cache, so the writer can't change it, and the file is only cut short
+    /// past pages nothing reads any more. A map the pager has replaced lives on until the last
+    /// view of it is dropped.
+    Mapped(Arc<Mmap>, Range<usize>),
+}
+
+impl AsRef<[u8]> for PageBytes {
+    fn as_ref(&self) -> &[u8] {
+        match self {
+            PageBytes::Cached(page) => page.as_bytes(),
+            PageBytes::Mapped(map, range) => &map[range.clone()],
+        }
+    }
+}
+
+/// A page whose contents can't be trusted: its checksum doesn't match what was read back, or it
+/// doesn't hold what the pages pointing to it say it does.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CorruptPage(pub Offset);
+
+impl Display for CorruptPage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Page {} is corrupt", self.0 .0)
+    }
+}
+
+impl std::error::Error for CorruptPage {}
+
+impl From<CorruptPage> for io::Error {
+    fn from(corrupt: CorruptPage) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, corrupt)
+    }
+}
+
+/// How long to wait for another connection to let go of a lock before giving up, by default.
+pub const DEFAULT_BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+/// The longest to sleep between attempts at a lock.
+const MAX_BUSY_SLEEP: Duration = Duration::from_millis(100);
+
+/// Another connection held on to a lock for longer than the busy timeout.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Busy;
+
+impl Display for Busy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "database is locked")
+    }
+}
+
+impl From<Busy> for io::Error {
+    fn from(busy: Busy) -> Self {
+        io::Error::new(io::ErrorKind::WouldBlock, busy.to_string())
+    }
+}
+
+/// Who holds a page's latch: any number of readers, or the writer.
+#[derive(Debug, Default)]
+struct Latch {
+    readers: usize,
+    writer: bool,
+}
+
+/// A latch on a page, let go when it's dropped. Readers hold one on each page they read from, and
+/// the writer one on each page it's about to change, so a reader never sees a page half way
+/// through a change that spans several. A node view keeps the version of the page it was made
+/// from, so reading a single node is safe without one.
+#[derive(Debug)]
+pub struct PageLatch<'a> {
+    pager: &'a Pager,
+    offset: Offset,
+    exclusive: bool,
+}
+
+impl Drop for PageLatch<'_> {
+    fn drop(&mut self) {
+        let mut latches = self.pager.latches.lock().unwrap();
+        let latch = latches.get_mut(&self.offset).unwrap();
+        if self.exclusive {
+            latch.writer = false;
+        } else {
+            latch.readers -= 1;
+        }
+        if latch.readers == 0 && !latch.writer {
+            latches.remove(&self.offset);
+        }
+        self.pager.latch_released.notify_all();
+    }
+}
+
 #[derive(Debug)]
 pub struct Pager {
-    file: RefCell<File>,
-    num_pages: Cell<usize>,
-    cache: RefCell<HashMap<Offset, Page>>,
-    free_pages: RefCell<BinaryHeap<Reverse<Offset>>>,
+    vfs: Mutex<Box<dyn Vfs>>,
+    /// Opened the first time it's needed, so scratch files never get one.
+    journal: Mutex<Option<Box<dyn Vfs>>>,
+    /// Read from the header, or chosen by whoever opened the file if it's new.
+    page_size: AtomicUsize,
+    num_pages: AtomicUsize,
+    /// Shared with the node views reading them, so a page that's changed while one is being read
+    /// is copied first.
+    cache: RwLock<HashMap<Offset, Arc<Page>>>,
+    /// Pages changed since the last flush.
+    dirty: Mutex<BTreeSet<Offset>>,
+    free_pages: Mutex<BinaryHeap<Reverse<Offset>>>,
+    /// Whether pages are checksummed as they're written and checked as they're read.
+    checksums: AtomicBool,
+    /// The most pages the file may grow to.
+    max_page_count: AtomicUsize,
+    /// Whether the file has pointer maps, which have to be kept up to date as pages change.
+    pointer_maps: AtomicBool,
+    /// A pointer map page that couldn't be updated because it's corrupt, which keeps the changes
+    /// from being flushed.
+    corrupt_map: Mutex<Option<CorruptPage>>,
+    /// Whether the file can only be read, so changes can't be flushed.
+    read_only: bool,
+    /// How much of the file, in bytes, pages may be read from a memory map of it. 0 reads every
+    /// page through the VFS.
+    mmap_size: AtomicUsize,
+    /// The start of the file mapped into memory, made when a page is first read from it and
+    /// dropped whenever the file may have changed size. Views share it rather than copying pages
+    /// out of it.
+    map: RwLock<Option<Arc<Mmap>>>,
+    /// Whether trees copy the pages an earlier commit wrote instead of changing them.
+    copy_on_write: AtomicBool,
+    /// Pages handed out since the last commit, which trees may change in place even when copying
+    /// on write.
+    fresh: Mutex<HashSet<Offset>>,
+    /// Pages that copies have replaced since the last commit, to be freed when it's made.
+    retired: Mutex<Vec<Offset>>,
+    lock: Mutex<Lock>,
+    /// Whether the lock never drops below shared.
+    pinned: AtomicBool,
+    busy_timeout: Mutex<Duration>,
+    /// Who holds each page's latch. Pages nobody has latched aren't in it.
+    latches: Mutex<HashMap<Offset, Latch>>,
+    /// Signalled whenever a latch is let go.
+    latch_released: Condvar,
+    /// How many times the pager has flushed, which numbers the versions snapshots read.
+    commits: AtomicU64,
+    /// Whether pages keep their old versions for snapshots.
+    keep_versions: AtomicBool,
+    /// Old versions of the pages changed since a snapshot could have been taken.
+    versions: Mutex<HashMap<Offset, Versions>>,
+    /// How many snapshots read as of each commit.
+    snapshots: Mutex<BTreeMap<u64, usize>>,
+}
+
+/// The database as it was at a commit, which stays readable while the writer commits more. Its
+/// old pages are kept until it's dropped.
+#[derive(Debug)]
+pub struct Snapshot {
+    pager: Arc<Pager>,
+    commit: u64,
+}
+
+impl Snapshot {
+    /// Reads the node a page held as of the snapshot. Fails if there's no version of it to read,
+    /// as when a corrupt page points past the end of the file.
+    pub fn view(&self, page: &Offset) -> Result<NodeView, CorruptPage> {
+        // Read the current page before looking for an old version: the writer keeps the old
+        // version before changing the page, so if there's none yet, this read is of it
+        let current = self.pager.peek(page);
+        let versions = self.pager.versions.lock().unwrap();
+        let old = versions.get(page).and_then(|chain| {
+            chain
+                .iter()
+                .find(|(until, _)| *until >= self.commit)
+                .map(|(_, page)| page.clone())
+        });
+        let Some(raw) = old.or(current) else {
+            return Err(CorruptPage(*page));
+        };
+        Ok(NodeView::new(Page::borrowed(PageBytes::Cached(raw)), *page))
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        {
+            let mut snapshots = self.pager.snapshots.lock().unwrap();
+            let readers = snapshots.get_mut(&self.commit).unwrap();
+            *readers -= 1;
+            if *readers == 0 {
+                snapshots.remove(&self.commit);
+            }
+        }
+        self.pager.collect_versions();
+    }
 }
 
 impl Pager {
+    /// Opens a database file, or a fresh in-memory database for `:memory:`.
     pub fn open(filename: impl AsRef<Path>) -> Self {
-        let file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(filename);
-        match file {
-            Ok(file) => {
-                let file_length = file.metadata().expect("Metadata for DB open").len();
-                if file_length % PAGE_SIZE as u64 != 0 {
-                    println!("DB file is not a whole number of pages. CORRUPT FILE.");
-                    panic!();
-                }
-                let num_pages = Cell::new(file_length as usize / PAGE_SIZE);
-                Self {
-                    file: RefCell::new(file),
-                    num_pages,
-                    cache: RefCell::new(HashMap::new()),
-                    free_pages: RefCell::new(BinaryHeap::new()),
-                }
-            }
+        Self::open_with_page_size(filename, DEFAULT_PAGE_SIZE)
+    }
+
+    /// Opens a database file, which gets pages of `page_size` bytes if it's new.
+    pub fn open_with_page_size(filename: impl AsRef<Path>, page_size: usize) -> Self {
+        let pager = if filename.as_ref() == Path::new(MEMORY) {
+            Self::with_vfs(Box::new(MemoryVfs::new()), page_size)
+        } else {
+            DiskVfs::open(filename).and_then(|vfs| Self::with_vfs(Box::new(vfs), page_size))
+        };
+        match pager {
+            Ok(pager) => pager,
             Err(why) => {
                 println!("Unable to open file: {why}");
                 exit(-1);
@@ -58,112 +256,807 @@ impl Pager {
         }
     }
 
-    pub fn new_page(&self) -> Offset {
-        return if let Ok(mut pq) = self.free_pages.try_borrow_mut() {
-            if pq.is_empty() {
-                self.num_pages.set(self.num_pages.get() + 1);
-                Offset(self.num_pages.get())
-            } else {
-                pq.pop().unwrap().0
+    /// Opens a database kept in some other storage, which gets pages of `page_size` bytes if it's
+    /// new. Fails if the storage can't be measured.
+    pub fn with_vfs(mut vfs: Box<dyn Vfs>, page_size: usize) -> io::Result<Self> {
+        let page_size = AtomicUsize::new(page_size);
+        let num_pages = AtomicUsize::new(0);
+        Self::measure(vfs.as_mut(), &page_size, &num_pages)?;
+        Ok(Self {
+            read_only: vfs.read_only(),
+            mmap_size: AtomicUsize::new(0),
+            map: RwLock::new(None),
+            vfs: Mutex::new(vfs),
+            journal: Mutex::new(None),
+            page_size,
+            num_pages,
+            cache: RwLock::new(HashMap::new()),
+            dirty: Mutex::new(BTreeSet::new()),
+            free_pages: Mutex::new(BinaryHeap::new()),
+            checksums: AtomicBool::new(false),
+            max_page_count: AtomicUsize::new(DEFAULT_MAX_PAGE_COUNT),
+            pointer_maps: AtomicBool::new(false),
+            corrupt_map: Mutex::new(None),
+            copy_on_write: AtomicBool::new(false),
+            fresh: Mutex::new(HashSet::new()),
+            retired: Mutex::new(Vec::new()),
+            lock: Mutex::new(Lock::Unlocked),
+            pinned: AtomicBool::new(false),
+            busy_timeout: Mutex::new(DEFAULT_BUSY_TIMEOUT),
+            latches: Mutex::new(HashMap::new()),
+            latch_released: Condvar::new(),
+            commits: AtomicU64::new(0),
+            keep_versions: AtomicBool::new(false),
+            versions: Mutex::new(HashMap::new()),
+            snapshots: Mutex::new(BTreeMap::new()),
+        })
+    }
+
+    /// Finishes a commit that a crash interrupted, by replaying its journal, and counts the pages
+    /// again if it did. Must be called before any page is read.
+    pub fn recover(&self) -> io::Result<()> {
+        let mut vfs = self.vfs.lock().unwrap();
+        let mut journal = self.journal.lock().unwrap();
+        if journal.is_none() {
+            *journal = vfs.journal()?;
+        }
+        if let Some(journal) = journal.as_mut() {
+            let replayed = journal::replay(journal.as_mut(), vfs.as_mut())?;
+            if replayed > 0 {
+                event!(Level::Info, "journal_replay", pages = replayed);
+                journal::clear(journal.as_mut())?;
+                let measured = Self::measure(vfs.as_mut(), &self.page_size, &self.num_pages);
+                drop(vfs);
+                self.unmap();
+                return measured;
             }
+        }
+        Ok(())
+    }
+
+    /// Reads the page size out of the header, if the file has one yet, and counts its pages. The
+    /// header can be torn until the journal is replayed, so a bad one is left for the catalog to
+    /// complain about.
+    fn measure(
+        vfs: &mut dyn Vfs,
+        page_size: &AtomicUsize,
+        num_pages: &AtomicUsize,
+    ) -> io::Result<()> {
+        let size = vfs.size()?;
+        if size > 0 {
+            let mut header = [0; catalog::PAGE_SIZE_END];
+            vfs.read_at(0, &mut header)?;
+            if let Some(from_header) = catalog::page_size(&header) {
+                page_size.store(from_header, Relaxed);
+            }
+        }
+        // A torn write can leave part of a page past the end, which the journal replaces
+        num_pages.store((size / page_size.load(Relaxed) as u64) as usize, Relaxed);
+        Ok(())
+    }
+
+    pub fn page_size(&self) -> usize {
+        self.page_size.load(Relaxed)
+    }
+
+    pub fn lock_level(&self) -> Lock {
+        *self.lock.lock().unwrap()
+    }
+
+    /// Takes a stronger lock, one level at a time. If another connection holds a level that's
+    /// needed, this goes back to the lock it started with and tries again until the busy timeout
+    /// runs out, so it never holds on to part of what it asked for while it waits.
+    pub fn lock(&self, lock: Lock) -> Result<(), Busy> {
+        let held = *self.lock.lock().unwrap();
+        let start = Instant::now();
+        let mut pause = Duration::from_millis(1);
+        while !self.try_lock(lock) {
+            self.unlock(held);
+            let waited = start.elapsed();
+            if waited >= *self.busy_timeout.lock().unwrap() {
+                event!(Level::Info, "busy", lock = lock);
+                return Err(Busy);
+            }
+            sleep(pause.min(*self.busy_timeout.lock().unwrap() - waited));
+            pause = (pause * 2).min(MAX_BUSY_SLEEP);
+        }
+        Ok(())
+    }
+
+    /// Takes a lock without waiting, returning whether it was granted. A refused lock can leave a
+    /// weaker one held.
+    pub fn try_lock(&self, lock: Lock) -> bool {
+        for level in [Lock::Shared, Lock::Reserved, Lock::Exclusive] {
+            if level <= *self.lock.lock().unwrap() || level > lock {
+                continue;
+            }
+            match self.vfs.lock().unwrap().lock(level) {
+                Ok(true) => *self.lock.lock().unwrap() = level,
+                Ok(false) => return false,
+                Err(why) => {
+                    println!("Unable to lock file: {why}");
+                    exit(-1);
+                }
+            }
+        }
+        true
+    }
+
+    /// Drops to a weaker lock, which never has to wait. A pinned lock stays shared at least.
+    pub fn unlock(&self, lock: Lock) {
+        let lock = if self.pinned.load(Relaxed) {
+            lock.max(Lock::Shared)
         } else {
-            self.num_pages.set(self.num_pages.get() + 1);
-            Offset(self.num_pages.get())
+            lock
         };
+        if lock >= *self.lock.lock().unwrap() {
+            return;
+        }
+        if let Err(why) = self.vfs.lock().unwrap().lock(lock) {
+            println!("Unable to unlock file: {why}");
+            exit(-1);
+        }
+        *self.lock.lock().unwrap() = lock;
+        if lock == Lock::Unlocked {
+            // Another connection may delete the journal now, so it's opened again when needed
+            *self.journal.lock().unwrap() = None;
+        }
     }
 
-    pub fn recycle(&mut self, offset: Offset) {
-        self.free_pages.borrow_mut().push(Reverse(offset));
-    }
-
-    pub fn get(&self, page: &Offset) -> Node<usize, Row> {
-        if self.cache.borrow().get(page).is_none() {
-            if page.0 < self.num_pages.get() {
-                self.file
-                    .borrow_mut()
-                    .seek(SeekFrom::Start((page.0 * PAGE_SIZE) as u64))
-                    .expect("Unable to seek to location in file.");
-                let mut page_raw = Box::new([0 as u8; PAGE_SIZE]);
-                match self.file.borrow_mut().read(page_raw.as_mut()) {
-                    Ok(_bytes_read) => self
-                        .cache
-                        .borrow_mut()
-                        .insert(page.clone(), Page::load(page_raw)),
-                    Err(why) => {
-                        println!("Unable to read file: {why}");
-                        exit(-1);
-                    }
-                };
-            } else {
-                self.cache.borrow_mut().insert(page.clone(), Page::new());
-                self.num_pages.set(self.num_pages.get() + 1);
+    /// Deletes the journal if it's empty, when the connection is done. Needs the exclusive lock.
+    pub fn remove_journal(&self) -> io::Result<()> {
+        *self.journal.lock().unwrap() = None;
+        self.vfs.lock().unwrap().remove_journal()
+    }
+
+    /// Keeps the shared lock that's held now until the pager is dropped, so nobody else can
+    /// commit while this process caches pages for several connections.
+    pub fn pin_shared(&self) {
+        assert!(*self.lock.lock().unwrap() >= Lock::Shared);
+        self.pinned.store(true, Relaxed);
+    }
+
+    /// Latches a page for reading, waiting while the writer has it.
+    pub fn latch_shared(&self, offset: Offset) -> PageLatch<'_> {
+        let mut latches = self.latches.lock().unwrap();
+        while latches.get(&offset).is_some_and(|latch| latch.writer) {
+            latches = self.latch_released.wait(latches).unwrap();
+        }
+        latches.entry(offset).or_default().readers += 1;
+        PageLatch {
+            pager: self,
+            offset,
+            exclusive: false,
+        }
+    }
+
+    /// Latches a page for changing it, waiting for its readers to finish.
+    pub fn latch_exclusive(&self, offset: Offset) -> PageLatch<'_> {
+        let mut latches = self.latches.lock().unwrap();
+        while latches
+            .get(&offset)
+            .is_some_and(|latch| latch.writer || latch.readers > 0)
+        {
+            latches = self.latch_released.wait(latches).unwrap();
+        }
+        latches.entry(offset).or_default().writer = true;
+        PageLatch {
+            pager: self,
+            offset,
+            exclusive: true,
+        }
+    }
+
+    /// Keeps the old version of every page the writer changes from here on, for as long as a
+    /// snapshot might read it.
+    pub fn keep_versions(&self) {
+        self.keep_versions.store(true, Relaxed);
+    }
+
+    /// Takes a snapshot of the database as of the last commit. Only changes made since
+    /// [`Pager::keep_versions`] was called can be seen past.
+    pub fn snapshot(self: &Arc<Self>) -> Snapshot {
+        let mut snapshots = self.snapshots.lock().unwrap();
+        let commit = self.commits.load(Relaxed);
+        *snapshots.entry(commit).or_default() += 1;
+        Snapshot {
+            pager: self.clone(),
+            commit,
+        }
+    }
+
+    /// How many old versions of pages are kept for snapshots.
+    pub fn kept_versions(&self) -> usize {
+        self.versions.lock().unwrap().values().map(Vec::len).sum()
+    }
+
+    /// Keeps the version of a page that was current at the last commit, if it hasn't been kept
+    /// already, before the writer changes it.
+    fn keep_version(&self, offset: Offset) {
+        if !self.keep_versions.load(Relaxed) {
+            return;
+        }
+        let commit = self.commits.load(Relaxed);
+        if let Some(chain) = self.versions.lock().unwrap().get(&offset) {
+            if chain.last().is_some_and(|(until, _)| *until == commit) {
+                return;
             }
         }
+        // Pages past the end of the file are new since the last commit, so no snapshot reads them
+        let cached = self.cache.read().unwrap().get(&offset).cloned();
+        let in_file = || -> io::Result<bool> {
+            let size = self.vfs.lock().unwrap().size()?;
+            Ok(self.position(&offset) < size)
+        };
+        let page = match cached {
+            Some(page) => page,
+            None if in_file().unwrap_or(false) => Arc::new(self.read_page(&offset)),
+            None => return,
+        };
+        let mut versions = self.versions.lock().unwrap();
+        versions.entry(offset).or_default().push((commit, page));
+    }
+
+    /// Drops the old versions that no snapshot can read any more.
+    fn collect_versions(&self) {
+        let snapshots = self.snapshots.lock().unwrap();
+        let oldest = match snapshots.keys().next() {
+            Some(commit) => *commit,
+            None => self.commits.load(Relaxed),
+        };
+        self.versions.lock().unwrap().retain(|_, chain| {
+            chain.retain(|(until, _)| *until >= oldest);
+            !chain.is_empty()
+        });
+    }
+
+    /// A copy of a page as it is now, read around the cache so nothing is added to it. `None` if
+    /// it's past the end of the file.
+    fn peek(&self, page: &Offset) -> Option<Arc<Page>> {
+        if let Some(page) = self.cache.read().unwrap().get(page) {
+            return Some(page.clone());
+        }
+        (page.0 < self.num_pages.load(Relaxed)).then(|| Arc::new(self.read_page(page)))
+    }
+
+    pub fn busy_timeout(&self) -> Duration {
+        *self.busy_timeout.lock().unwrap()
+    }
+
+    /// Sets how long [`Pager::lock`] waits for a lock that another connection holds.
+    pub fn set_busy_timeout(&self, busy_timeout: Duration) {
+        *self.busy_timeout.lock().unwrap() = busy_timeout;
+    }
+
+    /// Whether any page has changed since the last flush.
+    pub fn has_changes(&self) -> bool {
+        !self.dirty.lock().unwrap().is_empty()
+    }
+
+    /// Drops every cached page and the free list, and counts the file's pages again, after
+    /// another connection has changed it. Changes that weren't flushed are lost.
+    pub fn forget(&self) -> io::Result<()> {
+        self.unmap();
+        self.cache.write().unwrap().clear();
+        self.dirty.lock().unwrap().clear();
+        *self.corrupt_map.lock().unwrap() = None;
+        self.free_pages.lock().unwrap().clear();
+        self.fresh.lock().unwrap().clear();
+        self.retired.lock().unwrap().clear();
+        Self::measure(
+            self.vfs.lock().unwrap().as_mut(),
+            &self.page_size,
+            &self.num_pages,
+        )
+    }
 
-        let mut node = Node::try_from(self.cache.borrow().get(&page).unwrap()).unwrap();
-        node.offset = page.clone();
-        node
+    /// Turns checksums on or off from here on. Pages already in the cache aren't checked again.
+    pub fn set_checksums(&self, checksums: bool) {
+        self.checksums.store(checksums, Relaxed);
     }
 
-    pub fn commit(&mut self, n: &Node<usize, Row>) {
-        match n.try_into() {
-            Ok(new_page) => {
-                if n.offset().0 > self.num_pages.get() {
-                    self.num_pages.set(n.offset().0 + 1);
+    /// Marks every page as changed, so the next flush writes all of them out again.
+    pub fn rewrite_all(&self) -> Result<(), CorruptPage> {
+        for page in 0..self.num_pages.load(Relaxed) {
+            self.load(&Offset(page))?;
+            self.dirty.lock().unwrap().insert(Offset(page));
+        }
+        Ok(())
+    }
+
+    pub fn max_page_count(&self) -> usize {
+        self.max_page_count.load(Relaxed)
+    }
+
+    /// Caps how many pages the file may grow to, but never below the pages it already has.
+    /// Returns the cap that was set.
+    pub fn set_max_page_count(&self, max_page_count: usize) -> usize {
+        let max_page_count = max_page_count.max(self.num_pages.load(Relaxed));
+        self.max_page_count.store(max_page_count, Relaxed);
+        max_page_count
+    }
+
+    /// How many more pages [`Pager::new_page`] can hand out before the file reaches its maximum
+    /// size, counting the free ones it reuses first.
+    pub fn pages_left(&self) -> usize {
+        let unallocated = self
+            .max_page_count
+            .load(Relaxed)
+            .saturating_sub(self.num_pages.load(Relaxed));
+        unallocated.saturating_add(self.free_pages.lock().unwrap().len())
+    }
+
+    pub fn new_page(&self) -> Offset {
+        let offset = self.allocate();
+        self.fresh.lock().unwrap().insert(offset);
+        offset
+    }
+
+    fn allocate(&self) -> Offset {
+        if let Some(Reverse(offset)) = self.free_pages.lock().unwrap().pop() {
+            return offset;
+        }
+        let mut offset = Offset(self.num_pages.load(Relaxed));
+        if self.pointer_maps.load(Relaxed) && pointer_map::is_map_page(offset.0, self.page_size()) {
+            self.commit_page(offset, Page::new(self.page_size()));
+            offset = Offset(offset.0 + 1);
+        }
+        self.num_pages.store(offset.0 + 1, Relaxed);
+        offset
+    }
+
+    pub fn recycle(&self, offset: Offset) {
+        self.free_pages.lock().unwrap().push(Reverse(offset));
+        if self.pointer_maps.load(Relaxed) {
+            self.set_pointer_map_entry(offset, PageType::Free, None);
+        }
+    }
+
+    pub fn free_count(&self) -> usize {
+        self.free_pages.lock().unwrap().len()
+    }
+
+    /// Takes a particular page off the free list, returning whether it was on it.
+    pub fn take_free(&self, offset: Offset) -> bool {
+        let mut free_pages = self.free_pages.lock().unwrap();
+        let before = free_pages.len();
+        free_pages.retain(|Reverse(free)| *free != offset);
+        free_pages.len() < before
+    }
+
+    pub fn pointer_maps(&self) -> bool {
+        self.pointer_maps.load(Relaxed)
+    }
+
+    /// Starts or stops keeping pointer maps up to date. The file's layout has to match already.
+    pub fn set_pointer_maps(&self, pointer_maps: bool) {
+        self.pointer_maps.store(pointer_maps, Relaxed);
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    pub fn mmap_size(&self) -> usize {
+        self.mmap_size.load(Relaxed)
+    }
+
+    /// Sets how much of the file, in bytes, pages may be read from a memory map of it instead of
+    /// being copied into the cache, or 0 to read every page through the VFS. Pages past the limit,
+    /// and pages in storage that can't be mapped, are read the usual way. Writes always go
+    /// through the cache.
+    pub fn set_mmap_size(&self, mmap_size: usize) {
+        self.mmap_size.store(mmap_size, Relaxed);
+        self.unmap();
+    }
+
+    fn unmap(&self) {
+        *self.map.write().unwrap() = None;
+    }
+
+    /// Maps as much of the file as the mmap size allows, in whole pages.
+    fn remap(&self) {
+        let mut map = self.map.write().unwrap();
+        let mut vfs = self.vfs.lock().unwrap();
+        let size = vfs.size().unwrap_or(0).min(self.mmap_size() as u64) as usize;
+        let len = size - size % self.page_size();
+        *map = match len {
+            0 => None,
+            len => vfs.map(len).ok().flatten().map(Arc::new),
+        };
+    }
+
+    /// Reads a page straight from the memory map, if it's in the mapped part of the file and the
+    /// cache doesn't hold a newer copy of it. Without a lock, another connection could cut the
+    /// file short while it's read, so the map is only read under one.
+    fn view_mapped(&self, page: &Offset) -> Result<Option<NodeView>, CorruptPage> {
+        let start = self.position(page) as usize;
+        let end = start + self.page_size();
+        if end > self.mmap_size()
+            || page.0 >= self.num_pages.load(Relaxed)
+            || self.cache.read().unwrap().contains_key(page)
+            || self.lock_level() == Lock::Unlocked
+        {
+            return Ok(None);
+        }
+        if self
+            .map
+            .read()
+            .unwrap()
+            .as_ref()
+            .is_none_or(|map| map.len() < end)
+        {
+            self.remap();
+        }
+        let Some(map) = self.map.read().unwrap().clone() else {
+            return Ok(None);
+        };
+        if map.len() < end {
+            return Ok(None);
+        }
+        let raw = Page::borrowed(PageBytes::Mapped(map, start..end));
+        if self.checksums.load(Relaxed) && !raw.checksum_matches() {
+            return Err(CorruptPage(*page));
+        }
+        event!(Level::Trace, "mmap_read", page = page.0);
+        Ok(Some(NodeView::new(raw, *page)))
+    }
+
+    pub fn copy_on_write(&self) -> bool {
+        self.copy_on_write.load(Relaxed)
+    }
+
+    /// Starts or stops copying pages on write. Trees stop keeping their parent and sibling
+    /// pointers up to date while it's on, since copying a page would mean copying every page that
+    /// points to it.
+    pub fn set_copy_on_write(&self, copy_on_write: bool) {
+        self.copy_on_write.store(copy_on_write, Relaxed);
+    }
+
+    /// Whether a page was handed out since the last commit, so nothing else can be sharing it.
+    pub fn is_fresh(&self, offset: Offset) -> bool {
+        self.fresh.lock().unwrap().contains(&offset)
+    }
+
+    /// Treats every page as written by an earlier commit from here on, as taking a snapshot
+    /// does, so that pages the snapshot shares get copied too.
+    pub fn seal(&self) {
+        self.fresh.lock().unwrap().clear();
+    }
+
+    /// Records that a copy has replaced a page, which can be freed once the copy is committed.
+    pub fn retire(&self, offset: Offset) {
+        self.retired.lock().unwrap().push(offset);
+    }
+
+    /// The pages copies have replaced since the last commit.
+    pub fn retired(&self) -> Vec<Offset> {
+        self.retired.lock().unwrap().clone()
+    }
+
+    /// Takes the pages copies have replaced, to free them as part of the commit.
+    pub fn take_retired(&self) -> Vec<Offset> {
+        std::mem::take(&mut *self.retired.lock().unwrap())
+    }
+
+    /// What a page's pointer map entry says it is, and its parent. `None` if it doesn't have one.
+    pub fn pointer_map_entry(&self, offset: Offset) -> Option<(PageType, Option<Offset>)> {
+        if offset.0 <= 1 || pointer_map::is_map_page(offset.0, self.page_size()) {
+            return None;
+        }
+        let (map, at) = pointer_map::entry_location(offset.0, self.page_size());
+        self.load(&map).ok()?;
+        pointer_map::decode(&self.cache.read().unwrap()[&map].as_bytes()[at..])
+    }
+
+    fn set_pointer_map_entry(&self, offset: Offset, page_type: PageType, parent: Option<Offset>) {
+        let (map, at) = pointer_map::entry_location(offset.0, self.page_size());
+        if let Err(why) = self.load(&map) {
+            self.corrupt_map.lock().unwrap().get_or_insert(why);
+            return;
+        }
+        let entry = pointer_map::encode(page_type, parent);
+        let mut cache = self.cache.write().unwrap();
+        let page = Arc::make_mut(cache.get_mut(&map).unwrap());
+        let bytes = &mut page.as_bytes_mut()[at..at + entry.len()];
+        if bytes != entry {
+            bytes.copy_from_slice(&entry);
+            self.dirty.lock().unwrap().insert(map);
+        }
+    }
+
+    /// Cuts the file down to its first `num_pages` pages on the next flush, forgetting anything
+    /// past them.
+    pub fn truncate(&self, num_pages: usize) {
+        if num_pages >= self.num_pages.load(Relaxed) {
+            return;
+        }
+        for page in num_pages..self.num_pages.load(Relaxed) {
+            self.keep_version(Offset(page));
+        }
+        self.num_pages.store(num_pages, Relaxed);
+        let past_the_end = |offset: &Offset| offset.0 >= num_pages;
+        self.cache
+            .write()
+            .unwrap()
+            .retain(|offset, _| !past_the_end(offset));
+        self.dirty
+            .lock()
+            .unwrap()
+            .retain(|offset| !past_the_end(offset));
+        self.free_pages
+            .lock()
+            .unwrap()
+            .retain(|Reverse(offset)| !past_the_end(offset));
+    }
+
+    /// Replaces every page with the pages of another file, as of the next flush. None of them are
+    /// free afterwards.
+    pub fn copy_from(&self, source: &Pager) -> Result<(), CorruptPage> {
+        assert_eq!(source.page_size(), self.page_size());
+        self.free_pages.lock().unwrap().clear();
+        for page in 0..source.num_pages() {
+            let offset = Offset(page);
+            self.commit_page(offset, source.get_page(&offset)?);
+        }
+        self.truncate(source.num_pages());
+        Ok(())
+    }
+
+    /// The pages waiting to be reused, lowest first.
+    pub fn free_pages(&self) -> Vec<Offset> {
+        let mut pages: Vec<Offset> = self
+            .free_pages
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|Reverse(offset)| *offset)
+            .collect();
+        pages.sort();
+        pages
+    }
+
+    fn load(&self, page: &Offset) -> Result<(), CorruptPage> {
+        if self.cache.read().unwrap().get(page).is_some() {
+            event!(Level::Trace, "cache_hit", page = page.0);
+        } else if page.0 < self.num_pages.load(Relaxed) {
+            event!(Level::Debug, "page_read", page = page.0);
+            let page_raw = self.read_page(page);
+            if self.checksums.load(Relaxed) && !page_raw.checksum_matches() {
+                return Err(CorruptPage(*page));
+            }
+            // The writer may have committed a newer copy while this one was being read
+            self.cache
+                .write()
+                .unwrap()
+                .entry(*page)
+                .or_insert(Arc::new(page_raw));
+        } else {
+            self.cache
+                .write()
+                .unwrap()
+                .entry(*page)
+                .or_insert_with(|| Arc::new(Page::new(self.page_size())));
+            self.dirty.lock().unwrap().insert(*page);
+            self.num_pages.fetch_add(1, Relaxed);
+        }
+        Ok(())
+    }
+
+    /// Reads the node on a page in place. A corrupt page is an error, naming the page, rather
+    /// than being read as garbage.
+    pub fn view(&self, page: &Offset) -> Result<NodeView, CorruptPage> {
+        if let Some(view) = self.view_mapped(page)? {
+            return Ok(view);
+        }
+        self.load(page)?;
+        let bytes = PageBytes::Cached(self.cache.read().unwrap()[page].clone());
+        Ok(NodeView::new(Page::borrowed(bytes), *page))
+    }
+
+    /// Decodes the node on a page, to change it.
+    pub fn get(&self, page: &Offset) -> Result<Node<usize, Row>, CorruptPage> {
+        self.view(page)?.to_node()
+    }
+
+    /// Returns a copy of a page that doesn't hold a B-tree node, like the database header, or
+    /// which page is corrupt.
+    pub fn get_page(&self, page: &Offset) -> Result<Page, CorruptPage> {
+        self.load(page)?;
+        Ok(Page::clone(&self.cache.read().unwrap()[page]))
+    }
+
+    pub fn commit_page(&self, offset: Offset, page: Page) {
+        event!(Level::Debug, "page_commit", page = offset.0);
+        if offset.0 >= self.num_pages.load(Relaxed) {
+            self.num_pages.store(offset.0 + 1, Relaxed);
+        }
+        self.keep_version(offset);
+        self.cache.write().unwrap().insert(offset, Arc::new(page));
+        self.dirty.lock().unwrap().insert(offset);
+    }
+
+    pub fn commit(&self, n: &Node<usize, Row>) {
+        match Page::from_node(n, self.page_size()) {
+            Some(new_page) => {
+                self.commit_page(n.offset(), new_page);
+                if self.pointer_maps.load(Relaxed) {
+                    let page_type = match (n.parent_offset, &n.node_type) {
+                        (None, _) => PageType::Root,
+                        (Some(_), NodeType::Internal(..)) => PageType::Internal,
+                        (Some(_), NodeType::Leaf(..)) => PageType::Leaf,
+                    };
+                    self.set_pointer_map_entry(n.offset(), page_type, n.parent_offset);
                 }
-                dbg!(n.offset());
-                self.cache.borrow_mut().insert(n.offset(), new_page);
             }
-            Err(_) => {
+            None => {
                 println!("Unable to commit page {}", n.offset());
                 exit(-1);
             }
         }
     }
 
-    pub fn close(&mut self) {
-        for i in 0..self.num_pages.get() {
-            let map = self.cache.get_mut();
-            let offset = Offset(i);
-            let page = map.get_mut(&offset);
-            self.file
-                .borrow_mut()
-                .seek(SeekFrom::Start(0))
-                .expect("Seeking start of the file");
-            match page.map(|page| page.write(self.file.borrow_mut().deref())) {
-                Some(Ok(bytes_written)) => {
-                    if i < self.num_pages.get() - 1 {
-                        self.file
-                            .borrow_mut()
-                            .seek(SeekFrom::Current(
-                                (PAGE_SIZE as usize - bytes_written) as i64,
-                            ))
-                            .expect("seeking up to the next page offset");
-                    }
-                }
-                Some(Err(why)) => {
-                    println!("Unable to write page to file because: {why}");
-                    exit(-1);
-                }
-                None => {
-                    self.file
-                        .borrow_mut()
-                        .seek(SeekFrom::Current(PAGE_SIZE as i64))
-                        .expect("Page size seek forward");
+    /// Writes every page changed since the last flush, as one atomic commit: the changed pages go
+    /// to the journal first, and then to the database.
+    pub fn flush(&self) -> io::Result<()> {
+        if let Some(corrupt) = *self.corrupt_map.lock().unwrap() {
+            return Err(corrupt.into());
+        }
+        let mut dirty = self.dirty.lock().unwrap();
+        if self.read_only && !dirty.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "the database is read-only",
+            ));
+        } else if self.read_only {
+            return Ok(());
+        }
+        let size = self.position(&Offset(self.num_pages.load(Relaxed)));
+        if dirty.is_empty() && self.vfs.lock().unwrap().size()? <= size {
+            return Ok(());
+        }
+        if self.checksums.load(Relaxed) {
+            let mut cache = self.cache.write().unwrap();
+            for offset in dirty.iter() {
+                Arc::make_mut(cache.get_mut(offset).unwrap()).stamp_checksum();
+            }
+        }
+        let cache = self.cache.read().unwrap();
+        let pages: Vec<(Offset, &Page)> = dirty
+            .iter()
+            .map(|offset| (*offset, &*cache[offset]))
+            .collect();
+        let mut vfs = self.vfs.lock().unwrap();
+        let mut journal = self.journal.lock().unwrap();
+        if journal.is_none() {
+            *journal = vfs.journal()?;
+        }
+        if let Some(journal) = journal.as_mut() {
+            journal::write(
+                journal.as_mut(),
+                &pages,
+                self.page_size(),
+                self.num_pages.load(Relaxed),
+            )?;
+        }
+        let file_pages = (vfs.size()? / self.page_size() as u64) as usize;
+        for (offset, page) in &pages {
+            event!(Level::Debug, "page_write", page = offset.0);
+            vfs.write_at(self.position(offset), page.as_bytes())?;
+        }
+        // Pages handed out but never written would otherwise be left as zeroes, without a checksum
+        if self.checksums.load(Relaxed) {
+            let mut blank = Page::new(self.page_size());
+            blank.stamp_checksum();
+            for offset in (file_pages..self.num_pages.load(Relaxed)).map(Offset) {
+                if !dirty.contains(&offset) {
+                    vfs.write_at(self.position(&offset), blank.as_bytes())?;
                 }
             }
         }
-        self.file
-            .borrow_mut()
-            .flush()
-            .expect("Flushing writes to file")
+        journal::shrink(vfs.as_mut(), size)?;
+        vfs.sync()?;
+        if let Some(journal) = journal.as_mut() {
+            journal::clear(journal.as_mut())?;
+        }
+        dirty.clear();
+        self.fresh.lock().unwrap().clear();
+        drop((cache, vfs, journal));
+        // The file may have changed size under the map, so the next read makes a new one
+        self.unmap();
+        self.commits.fetch_add(1, Relaxed);
+        self.collect_versions();
+        Ok(())
+    }
+
+    pub fn close(&self) {
+        if let Err(why) = self.flush() {
+            println!("Unable to write page to file because: {why}");
+            exit(-1);
+        }
+    }
+
+    /// Reads a page straight from the file, bypassing the page cache. Used for scratch data that
+    /// should never be held in memory, like spilled sort runs.
+    pub fn read_page(&self, offset: &Offset) -> Page {
+        let mut page_raw = vec![0_u8; self.page_size()].into_boxed_slice();
+        if let Err(why) = self
+            .vfs
+            .lock()
+            .unwrap()
+            .read_at(self.position(offset), &mut page_raw)
+        {
+            println!("Unable to read file: {why}");
+            exit(-1);
+        }
+        Page::load(page_raw)
+    }
+
+    /// Writes a page straight to the file, bypassing the page cache.
+    pub fn write_page(&self, offset: &Offset, page: &Page) {
+        let position = self.position(offset);
+        if let Err(why) = self.vfs.lock().unwrap().write_at(position, page.as_bytes()) {
+            println!("Unable to write page to file because: {why}");
+            exit(-1);
+        }
     }
 
     pub fn num_pages(&self) -> usize {
-        self.num_pages.get()
+        self.num_pages.load(Relaxed)
+    }
+
+    /// How many pages the file itself holds, leaving out any added since the last flush.
+    pub fn file_pages(&self) -> io::Result<usize> {
+        let size = self.vfs.lock().unwrap().size()?;
+        Ok((size / self.page_size() as u64) as usize)
+    }
+
+    /// Where a page starts in the file.
+    fn position(&self, offset: &Offset) -> u64 {
+        offset.0 as u64 * self.page_size() as u64
     }
 }
 
 pub trait HasOffset {
     fn offset(&self) -> Offset;
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::fixtures::{insert, temp_path};
+    use crate::pager::Offset;
+    use crate::vfs::Lock;
+    use crate::Table;
+
+    #[test]
+    fn views_keep_the_map_they_were_read_from() {
+        let path = temp_path("mapped-view");
+        let mut table = Table::open(&path);
+        insert(&mut table, 0..100);
+        table.close();
+
+        let table = Table::open(&path);
+        let pager = table.pager();
+        pager.set_mmap_size(1 << 20);
+        pager.lock(Lock::Shared).unwrap();
+        let page = Offset(2);
+        let view = pager.view(&page).unwrap();
+        let map = pager.map.read().unwrap().clone().unwrap();
+        assert!(!pager.cache.read().unwrap().contains_key(&page));
+        assert_eq!(Arc::strong_count(&map), 3);
+
+        // Unmapping leaves the map to the view, which still reads what the pager does
+        pager.set_mmap_size(0);
+        drop(map);
+        let cells = view.num_cells();
+        let expected = pager.view(&page).unwrap();
+        assert_eq!(cells, expected.num_cells());
+        assert_eq!(view.key(0), expected.key(0));
+        drop(view);
+        pager.unlock(Lock::Unlocked);
+        drop(table);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,839 @@
+use std::fmt::{Display, Formatter};
+
+use crate::value::Value;
+
+/// Words that can't be used as a bare column alias, because they start the next clause.
+const RESERVED: [&str; 24] = [
+    "select", "from", "where", "group", "by", "having", "order", "limit", "offset", "asc", "desc",
+    "as", "and", "or", "not", "null", "is", "like", "join", "inner", "left", "outer", "cross",
+    "on",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError(pub String);
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColumnRef {
+    pub table: Option<String>,
+    pub name: String,
+}
+
+impl Display for ColumnRef {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.table {
+            Some(table) => write!(f, "{}.{}", table, self.name),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnaryOp {
+    Not,
+    Negate,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryOp {
+    Or,
+    And,
+    Eq,
+    NotEq,
+    Lt,
+    LtEq,
+    Gt,
+    GtEq,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Modulo,
+    Concat,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Literal(Value),
+    Column(ColumnRef),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+    IsNull {
+        expr: Box<Expr>,
+        negated: bool,
+    },
+    Like {
+        expr: Box<Expr>,
+        pattern: Box<Expr>,
+        negated: bool,
+    },
+    /// A `?` or `:name` placeholder, numbered from 0 in the order parameters first appear.
+    Parameter(usize),
+    /// A function call, with the name lowercased. `count(*)` is the only call that takes a star.
+    Function {
+        name: String,
+        args: Vec<Expr>,
+    },
+    CountStar,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SelectItem {
+    Wildcard,
+    /// An output column. `name` is the alias, or the expression text as it was written.
+    Expr {
+        expr: Expr,
+        name: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TableRef {
+    pub name: String,
+    pub alias: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JoinKind {
+    Inner,
+    /// `LEFT [OUTER] JOIN`: outer rows without a match are kept, padded with NULLs.
+    Left,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Join {
+    pub kind: JoinKind,
+    pub table: TableRef,
+    /// The `ON` condition. A join without one pairs every row with every row.
+    pub on: Option<Expr>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OrderingTerm {
+    pub expr: Expr,
+    pub descending: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Select {
+    pub items: Vec<SelectItem>,
+    pub from: Option<TableRef>,
+    /// Tables joined onto `from`, left to right.
+    pub joins: Vec<Join>,
+    pub where_clause: Option<Expr>,
+    pub group_by: Vec<Expr>,
+    pub having: Option<Expr>,
+    pub order_by: Vec<OrderingTerm>,
+    pub limit: Option<Expr>,
+    pub offset: Option<Expr>,
+    /// The snapshot named by a trailing `AS OF SNAPSHOT name`, to read from instead of the latest
+    /// version of every table.
+    pub as_of: Option<String>,
+}
+
+/// `INSERT INTO table VALUES (...)`, with one value for every column.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Insert {
+    pub table: String,
+    pub values: Vec<Expr>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Select(Box<Select>),
+    Insert(Insert),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Integer(i64),
+    Real(f64),
+    Text(String),
+    Symbol(&'static str),
+    /// `?`, or `:name` with its colon.
+    Parameter(Option<String>),
+    Eof,
+}
+
+/// Two-character symbols come first so that `<=` isn't read as `<` followed by `=`.
+const SYMBOLS: [&str; 18] = [
+    "<=", ">=", "<>", "!=", "==", "||", "(", ")", ",", ".", "*", "+", "-", "/", "%", "=", "<", ">",
+];
+
+fn tokenize(input: &str) -> Result<Vec<(Token, usize, usize)>, ParseError> {
+    let bytes = input.as_bytes();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let c = bytes[i];
+        let start = i;
+        if c.is_ascii_whitespace() || c == b';' {
+            i += 1;
+            continue;
+        } else if c.is_ascii_alphabetic() || c == b'_' {
+            while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_') {
+                i += 1;
+            }
+            tokens.push((Token::Ident(input[start..i].to_string()), start, i));
+        } else if c.is_ascii_digit() {
+            while i < bytes.len() && bytes[i].is_ascii_digit() {
+                i += 1;
+            }
+            let is_real = i + 1 < bytes.len() && bytes[i] == b'.' && bytes[i + 1].is_ascii_digit();
+            if is_real {
+                i += 1;
+                while i < bytes.len() && bytes[i].is_ascii_digit() {
+                    i += 1;
+                }
+                let real = input[start..i].parse().unwrap();
+                tokens.push((Token::Real(real), start, i));
+            } else {
+                let integer = input[start..i]
+                    .parse()
+                    .map_err(|_| ParseError(format!("integer too large: {}", &input[start..i])))?;
+                tokens.push((Token::Integer(integer), start, i));
+            }
+        } else if c == b'\'' {
+            let mut text = String::new();
+            i += 1;
+            loop {
+                match input[i..].find('\'') {
+                    Some(end) => {
+                        text.push_str(&input[i..i + end]);
+                        i += end + 1;
+                        // '' is an escaped quote inside a string literal
+                        if bytes.get(i) == Some(&b'\'') {
+                            text.push('\'');
+                            i += 1;
+                        } else {
+                            break;
+                        }
+                    }
+                    None => return Err(ParseError(String::from("unterminated string literal"))),
+                }
+            }
+            tokens.push((Token::Text(text), start, i));
+        } else if c == b'?' {
+            i += 1;
+            tokens.push((Token::Parameter(None), start, i));
+        } else if c == b':' {
+            i += 1;
+            while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_') {
+                i += 1;
+            }
+            if i == start + 1 {
+                return Err(ParseError(String::from("unrecognized token: \":\"")));
+            }
+            let name = input[start..i].to_string();
+            tokens.push((Token::Parameter(Some(name)), start, i));
+        } else if let Some(symbol) = SYMBOLS.iter().find(|s| input[i..].starts_with(**s)) {
+            i += symbol.len();
+            tokens.push((Token::Symbol(symbol), start, i));
+        } else {
+            let bad = input[i..].chars().next().unwrap();
+            return Err(ParseError(format!("unrecognized token: \"{bad}\"")));
+        }
+    }
+    tokens.push((Token::Eof, input.len(), input.len()));
+    Ok(tokens)
+}
+
+/// Parses a single `SELECT` statement.
+pub fn parse_select(input: &str) -> Result<Select, ParseError> {
+    let mut parser = Parser::new(input)?;
+    let select = parser.select()?;
+    parser.expect_end()?;
+    Ok(select)
+}
+
+/// Parses a single `SELECT` or `INSERT` statement, returning it with the names of its
+/// parameters. Every parameter has a slot, and `:name` parameters also have a name.
+pub fn parse_statement(input: &str) -> Result<(Command, Vec<Option<String>>), ParseError> {
+    let mut parser = Parser::new(input)?;
+    let command = if parser.is_keyword("insert") {
+        Command::Insert(parser.insert()?)
+    } else {
+        Command::Select(Box::new(parser.select()?))
+    };
+    parser.expect_end()?;
+    Ok((command, parser.parameters))
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    tokens: Vec<(Token, usize, usize)>,
+    pos: usize,
+    parameters: Vec<Option<String>>,
+}
+
+impl<'a> Parser<'a> {
+    fn new(input: &'a str) -> Result<Self, ParseError> {
+        Ok(Self {
+            input,
+            tokens: tokenize(input)?,
+            pos: 0,
+            parameters: Vec::new(),
+        })
+    }
+
+    fn peek(&self) -> &Token {
+        &self.tokens[self.pos].0
+    }
+
+    fn error_here(&self) -> ParseError {
+        let (token, start, end) = &self.tokens[self.pos];
+        match token {
+            Token::Eof => ParseError(String::from("incomplete input")),
+            _ => ParseError(format!(
+                "near \"{}\": syntax error",
+                &self.input[*start..*end]
+            )),
+        }
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Token::Ident(word) if word.eq_ignore_ascii_case(keyword))
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        if self.is_keyword(keyword) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<(), ParseError> {
+        if self.eat_keyword(keyword) {
+            Ok(())
+        } else {
+            Err(self.error_here())
+        }
+    }
+
+    fn eat_symbol(&mut self, symbol: &str) -> bool {
+        if matches!(self.peek(), Token::Symbol(s) if *s == symbol) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect_symbol(&mut self, symbol: &str) -> Result<(), ParseError> {
+        if self.eat_symbol(symbol) {
+            Ok(())
+        } else {
+            Err(self.error_here())
+        }
+    }
+
+    fn expect_end(&mut self) -> Result<(), ParseError> {
+        match self.peek() {
+            Token::Eof => Ok(()),
+            _ => Err(self.error_here()),
+        }
+    }
+
+    fn identifier(&mut self) -> Result<String, ParseError> {
+        match self.peek() {
+            Token::Ident(word) if !RESERVED.contains(&word.to_lowercase().as_str()) => {
+                let word = word.to_lowercase();
+                self.pos += 1;
+                Ok(word)
+            }
+            _ => Err(self.error_here()),
+        }
+    }
+
+    /// An optional `[AS] alias` after a select item or table name.
+    fn alias(&mut self) -> Result<Option<String>, ParseError> {
+        if self.is_keyword("as")
+            && matches!(&self.tokens[self.pos + 1].0, Token::Ident(word) if word.eq_ignore_ascii_case("of"))
+        {
+            // The start of `AS OF SNAPSHOT`
+            return Ok(None);
+        }
+        if self.eat_keyword("as") {
+            return self.identifier().map(Some);
+        }
+        match self.peek() {
+            Token::Ident(word) if !RESERVED.contains(&word.to_lowercase().as_str()) => {
+                self.identifier().map(Some)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn select(&mut self) -> Result<Select, ParseError> {
+        self.expect_keyword("select")?;
+        let mut items = Vec::new();
+        loop {
+            if self.eat_symbol("*") {
+                items.push(SelectItem::Wildcard);
+            } else {
+                let start = self.tokens[self.pos].1;
+                let expr = self.expr()?;
+                let end = self.tokens[self.pos - 1].2;
+                let name = match self.alias()? {
+                    Some(alias) => alias,
+                    None => self.input[start..end].to_string(),
+                };
+                items.push(SelectItem::Expr { expr, name });
+            }
+            if !self.eat_symbol(",") {
+                break;
+            }
+        }
+
+        let mut joins = Vec::new();
+        let from = if self.eat_keyword("from") {
+            let from = self.table_ref()?;
+            while let Some(kind) = self.join_operator()? {
+                let table = self.table_ref()?;
+                let on = if self.eat_keyword("on") {
+                    Some(self.expr()?)
+                } else {
+                    None
+                };
+                joins.push(Join { kind, table, on });
+            }
+            Some(from)
+        } else {
+            None
+        };
+
+        let where_clause = if self.eat_keyword("where") {
+            Some(self.expr()?)
+        } else {
+            None
+        };
+
+        let mut group_by = Vec::new();
+        if self.eat_keyword("group") {
+            self.expect_keyword("by")?;
+            group_by = self.expr_list()?;
+        }
+
+        let having = if self.eat_keyword("having") {
+            Some(self.expr()?)
+        } else {
+            None
+        };
+
+        let mut order_by = Vec::new();
+        if self.eat_keyword("order") {
+            self.expect_keyword("by")?;
+            loop {
+                let expr = self.expr()?;
+                let descending = if self.eat_keyword("desc") {
+                    true
+                } else {
+                    self.eat_keyword("asc");
+                    false
+                };
+                order_by.push(OrderingTerm { expr, descending });
+                if !self.eat_symbol(",") {
+                    break;
+                }
+            }
+        }
+
+        let (mut limit, mut offset) = (None, None);
+        if self.eat_keyword("limit") {
+            limit = Some(self.expr()?);
+            if self.eat_keyword("offset") {
+                offset = Some(self.expr()?);
+            } else if self.eat_symbol(",") {
+                // LIMIT <offset>, <count>
+                offset = limit.replace(self.expr()?);
+            }
+        }
+
+        let as_of = if self.eat_keyword("as") {
+            self.expect_keyword("of")?;
+            self.expect_keyword("snapshot")?;
+            Some(self.identifier()?)
+        } else {
+            None
+        };
+
+        Ok(Select {
+            items,
+            from,
+            joins,
+            where_clause,
+            group_by,
+            having,
+            order_by,
+            limit,
+            offset,
+            as_of,
+        })
+    }
+
+    fn insert(&mut self) -> Result<Insert, ParseError> {
+        self.expect_keyword("insert")?;
+        self.expect_keyword("into")?;
+        let table = self.identifier()?;
+        self.expect_keyword("values")?;
+        self.expect_symbol("(")?;
+        let values = self.expr_list()?;
+        self.expect_symbol(")")?;
+        Ok(Insert { table, values })
+    }
+
+    fn table_ref(&mut self) -> Result<TableRef, ParseError> {
+        let name = self.identifier()?;
+        let alias = self.alias()?;
+        Ok(TableRef { name, alias })
+    }
+
+    /// `[INNER | CROSS] JOIN` or `LEFT [OUTER] JOIN`, if one comes next.
+    fn join_operator(&mut self) -> Result<Option<JoinKind>, ParseError> {
+        let kind = if self.eat_keyword("left") {
+            self.eat_keyword("outer");
+            JoinKind::Left
+        } else if self.eat_keyword("inner") || self.eat_keyword("cross") || self.is_keyword("join")
+        {
+            JoinKind::Inner
+        } else {
+            return Ok(None);
+        };
+        self.expect_keyword("join")?;
+        Ok(Some(kind))
+    }
+
+    fn expr_list(&mut self) -> Result<Vec<Expr>, ParseError> {
+        let mut exprs = vec![self.expr()?];
+        while self.eat_symbol(",") {
+            exprs.push(self.expr()?);
+        }
+        Ok(exprs)
+    }
+
+    fn expr(&mut self) -> Result<Expr, ParseError> {
+        self.or()
+    }
+
+    fn or(&mut self) -> Result<Expr, ParseError> {
+        let mut left = self.and()?;
+        while self.eat_keyword("or") {
+            let right = self.and()?;
+            left = Expr::Binary(BinaryOp::Or, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn and(&mut self) -> Result<Expr, ParseError> {
+        let mut left = self.not()?;
+        while self.eat_keyword("and") {
+            let right = self.not()?;
+            left = Expr::Binary(BinaryOp::And, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn not(&mut self) -> Result<Expr, ParseError> {
+        if self.eat_keyword("not") {
+            Ok(Expr::Unary(UnaryOp::Not, Box::new(self.not()?)))
+        } else {
+            self.comparison()
+        }
+    }
+
+    fn comparison(&mut self) -> Result<Expr, ParseError> {
+        let mut left = self.additive()?;
+        loop {
+            let op = match self.peek() {
+                Token::Symbol("=") | Token::Symbol("==") => BinaryOp::Eq,
+                Token::Symbol("!=") | Token::Symbol("<>") => BinaryOp::NotEq,
+                Token::Symbol("<") => BinaryOp::Lt,
+                Token::Symbol("<=") => BinaryOp::LtEq,
+                Token::Symbol(">") => BinaryOp::Gt,
+                Token::Symbol(">=") => BinaryOp::GtEq,
+                _ => {
+                    if self.eat_keyword("is") {
+                        let negated = self.eat_keyword("not");
+                        self.expect_keyword("null")?;
+                        left = Expr::IsNull {
+                            expr: Box::new(left),
+                            negated,
+                        };
+                        continue;
+                    }
+                    let negated = self.is_keyword("not")
+                        && matches!(&self.tokens[self.pos + 1].0, Token::Ident(w) if w.eq_ignore_ascii_case("like"));
+                    if negated {
+                        self.pos += 1;
+                    }
+                    if self.eat_keyword("like") {
+                        let pattern = self.additive()?;
+                        left = Expr::Like {
+                            expr: Box::new(left),
+                            pattern: Box::new(pattern),
+                            negated,
+                        };
+                        continue;
+                    }
+                    return Ok(left);
+                }
+            };
+            self.pos += 1;
+            let right = self.additive()?;
+            left = Expr::Binary(op, Box::new(left), Box::new(right));
+        }
+    }
+
+    fn additive(&mut self) -> Result<Expr, ParseError> {
+        let mut left = self.multiplicative()?;
+        loop {
+            let op = if self.eat_symbol("+") {
+                BinaryOp::Add
+            } else if self.eat_symbol("-") {
+                BinaryOp::Subtract
+            } else {
+                return Ok(left);
+            };
+            let right = self.multiplicative()?;
+            left = Expr::Binary(op, Box::new(left), Box::new(right));
+        }
+    }
+
+    fn multiplicative(&mut self) -> Result<Expr, ParseError> {
+        let mut left = self.concat()?;
+        loop {
+            let op = if self.eat_symbol("*") {
+                BinaryOp::Multiply
+            } else if self.eat_symbol("/") {
+                BinaryOp::Divide
+            } else if self.eat_symbol("%") {
+                BinaryOp::Modulo
+            } else {
+                return Ok(left);
+            };
+            let right = self.concat()?;
+            left = Expr::Binary(op, Box::new(left), Box::new(right));
+        }
+    }
+
+    fn concat(&mut self) -> Result<Expr, ParseError> {
+        let mut left = self.unary()?;
+        while self.eat_symbol("||") {
+            let right = self.unary()?;
+            left = Expr::Binary(BinaryOp::Concat, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Expr, ParseError> {
+        if self.eat_symbol("-") {
+            Ok(Expr::Unary(UnaryOp::Negate, Box::new(self.unary()?)))
+        } else if self.eat_symbol("+") {
+            self.unary()
+        } else {
+            self.primary()
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr, ParseError> {
+        match self.peek().clone() {
+            Token::Integer(i) => {
+                self.pos += 1;
+                Ok(Expr::Literal(Value::Integer(i)))
+            }
+            Token::Real(r) => {
+                self.pos += 1;
+                Ok(Expr::Literal(Value::Real(r)))
+            }
+            Token::Text(s) => {
+                self.pos += 1;
+                Ok(Expr::Literal(Value::Text(s)))
+            }
+            Token::Symbol("(") => {
+                self.pos += 1;
+                let expr = self.expr()?;
+                self.expect_symbol(")")?;
+                Ok(expr)
+            }
+            Token::Parameter(name) => {
+                self.pos += 1;
+                // Every `?` is a new parameter, but a repeated `:name` refers to the same one
+                let existing = name
+                    .as_ref()
+                    .and_then(|_| self.parameters.iter().position(|p| *p == name));
+                Ok(Expr::Parameter(existing.unwrap_or_else(|| {
+                    self.parameters.push(name);
+                    self.parameters.len() - 1
+                })))
+            }
+            Token::Ident(word) if word.eq_ignore_ascii_case("null") => {
+                self.pos += 1;
+                Ok(Expr::Literal(Value::Null))
+            }
+            Token::Ident(_) => {
+                let name = self.identifier()?;
+                if self.eat_symbol("(") {
+                    self.function(name)
+                } else if self.eat_symbol(".") {
+                    let column = self.identifier()?;
+                    Ok(Expr::Column(ColumnRef {
+                        table: Some(name),
+                        name: column,
+                    }))
+                } else {
+                    Ok(Expr::Column(ColumnRef { table: None, name }))
+                }
+            }
+            _ => Err(self.error_here()),
+        }
+    }
+
+    fn function(&mut self, name: String) -> Result<Expr, ParseError> {
+        if name == "count" && self.eat_symbol("*") {
+            self.expect_symbol(")")?;
+            return Ok(Expr::CountStar);
+        }
+        let args = if self.eat_symbol(")") {
+            Vec::new()
+        } else {
+            let args = self.expr_list()?;
+            self.expect_symbol(")")?;
+            args
+        };
+        Ok(Expr::Function { name, args })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::{
+        parse_select, parse_statement, BinaryOp, ColumnRef, Command, Expr, JoinKind, SelectItem,
+    };
+    use crate::value::Value;
+
+    #[test]
+    fn parse_group_by_query() {
+        let select = parse_select(
+            "select substr(email, instr(email, '@') + 1) as domain, count(*) from users \
+             where id >= 10 group by domain having count(*) > 1",
+        )
+        .unwrap();
+        assert_eq!(select.items.len(), 2);
+        assert!(matches!(&select.items[0], SelectItem::Expr { name, .. } if name == "domain"));
+        assert!(
+            matches!(&select.items[1], SelectItem::Expr { expr: Expr::CountStar, name } if name == "count(*)")
+        );
+        assert_eq!(select.from.unwrap().name, "users");
+        assert_eq!(
+            select.group_by,
+            vec![Expr::Column(ColumnRef {
+                table: None,
+                name: String::from("domain")
+            })]
+        );
+        assert_eq!(
+            select.having,
+            Some(Expr::Binary(
+                BinaryOp::Gt,
+                Box::new(Expr::CountStar),
+                Box::new(Expr::Literal(Value::Integer(1)))
+            ))
+        );
+    }
+
+    #[test]
+    fn parse_as_of_snapshot() {
+        let select = parse_select("select id from users as of snapshot before").unwrap();
+        assert_eq!(select.from.unwrap().alias, None);
+        assert_eq!(select.as_of.as_deref(), Some("before"));
+
+        let select = parse_select("select * from users u limit 1 AS OF SNAPSHOT s1").unwrap();
+        assert_eq!(select.as_of.as_deref(), Some("s1"));
+        assert!(parse_select("select * from users as of before").is_err());
+    }
+
+    #[test]
+    fn parse_order_by_and_limit() {
+        let select =
+            parse_select("select id, email from users order by email desc, 1 limit 5, 10").unwrap();
+        assert_eq!(select.order_by.len(), 2);
+        assert!(select.order_by[0].descending);
+        assert!(!select.order_by[1].descending);
+        assert_eq!(select.limit, Some(Expr::Literal(Value::Integer(10))));
+        assert_eq!(select.offset, Some(Expr::Literal(Value::Integer(5))));
+
+        let select = parse_select("select id from users limit 10 offset 5").unwrap();
+        assert_eq!(select.limit, Some(Expr::Literal(Value::Integer(10))));
+        assert_eq!(select.offset, Some(Expr::Literal(Value::Integer(5))));
+    }
+
+    #[test]
+    fn parse_joins() {
+        let select = parse_select(
+            "select u.id, a.email from users u join admins as a on a.id = u.id \
+             left outer join guests g on g.id = u.id cross join staff where u.id > 3",
+        )
+        .unwrap();
+        assert_eq!(select.from.unwrap().alias, Some(String::from("u")));
+        let kinds: Vec<JoinKind> = select.joins.iter().map(|j| j.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![JoinKind::Inner, JoinKind::Left, JoinKind::Inner]
+        );
+        assert_eq!(select.joins[1].table.name, "guests");
+        assert!(select.joins[1].on.is_some());
+        assert!(select.joins[2].on.is_none());
+        assert!(select.where_clause.is_some());
+
+        assert!(parse_select("select * from users left admins").is_err());
+        assert!(parse_select("select * from users join").is_err());
+        assert!(parse_select("select * from users join admins on").is_err());
+    }
+
+    #[test]
+    fn parse_parameters() {
+        let (command, parameters) =
+            parse_statement("insert into users values (?, :name, :name || ?)").unwrap();
+        assert_eq!(parameters, vec![None, Some(String::from(":name")), None]);
+        let Command::Insert(insert) = command else {
+            panic!("{command:?}")
+        };
+        assert_eq!(insert.table, "users");
+        assert_eq!(insert.values[0], Expr::Parameter(0));
+        assert_eq!(insert.values[1], Expr::Parameter(1));
+        assert_eq!(
+            insert.values[2],
+            Expr::Binary(
+                BinaryOp::Concat,
+                Box::new(Expr::Parameter(1)),
+                Box::new(Expr::Parameter(2))
+            )
+        );
+
+        let (command, parameters) = parse_statement("select * from users where id = ?").unwrap();
+        assert!(matches!(command, Command::Select(_)));
+        assert_eq!(parameters, vec![None]);
+
+        assert!(parse_statement("insert into users (?, ?, ?)").is_err());
+        assert!(parse_statement("select :").is_err());
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(parse_select("select").is_err());
+        assert!(parse_select("select id from").is_err());
+        assert!(parse_select("select 'abc from users").is_err());
+        assert!(parse_select("select id from users group id").is_err());
+        assert!(parse_select("select id, from users").is_err());
+        assert!(parse_select("select id from users order email").is_err());
+        assert!(parse_select("select id from users limit").is_err());
+    }
+}
@@ -0,0 +1,242 @@
+use crate::catalog::Catalog;
+use crate::node_type::{InternalNode, NodeType};
+use crate::page::{CHECKSUM_SIZE, PAGE_NUMBER_SIZE};
+use crate::pager::{CorruptPage, Offset, Pager};
+use crate::trace::{event, Level};
+
+/// With auto-vacuum on, every page but the header has an entry in a pointer map saying what kind
+/// of page it is and which page points at it, so it can be moved without searching every tree
+/// for its parent.
+///
+/// Page 1 is the first pointer map, and it holds the entries of the pages after it, as many as
+/// fit. The next map follows them, and so on. An entry is the page's type, then its parent's page
+/// number, which is 0 for roots and free pages.
+const ENTRY_SIZE: usize = 1 + PAGE_NUMBER_SIZE;
+const FIRST_MAP: usize = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PageType {
+    Root = 1,
+    Free = 2,
+    Internal = 3,
+    Leaf = 4,
+}
+
+impl PageType {
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            1 => Some(PageType::Root),
+            2 => Some(PageType::Free),
+            3 => Some(PageType::Internal),
+            4 => Some(PageType::Leaf),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for PageType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            PageType::Root => "a root",
+            PageType::Free => "free",
+            PageType::Internal => "an internal node",
+            PageType::Leaf => "a leaf",
+        };
+        write!(f, "{name}")
+    }
+}
+
+fn entries_per_map(page_size: usize) -> usize {
+    (page_size - CHECKSUM_SIZE) / ENTRY_SIZE
+}
+
+pub fn is_map_page(page: usize, page_size: usize) -> bool {
+    page >= FIRST_MAP && (page - FIRST_MAP).is_multiple_of(entries_per_map(page_size) + 1)
+}
+
+/// The map page holding a page's entry, and where in it the entry is.
+pub fn entry_location(page: usize, page_size: usize) -> (Offset, usize) {
+    let group = entries_per_map(page_size) + 1;
+    let map = FIRST_MAP + (page - FIRST_MAP - 1) / group * group;
+    (Offset(map), (page - map - 1) * ENTRY_SIZE)
+}
+
+pub fn encode(page_type: PageType, parent: Option<Offset>) -> [u8; ENTRY_SIZE] {
+    let mut entry = [0; ENTRY_SIZE];
+    entry[0] = page_type as u8;
+    let parent = parent.map_or(0, |parent| parent.0 as u64);
+    entry[1..].copy_from_slice(&parent.to_le_bytes());
+    entry
+}
+
+/// Reads an entry back, or `None` if it was never written.
+pub fn decode(entry: &[u8]) -> Option<(PageType, Option<Offset>)> {
+    let page_type = PageType::from_byte(entry[0])?;
+    let parent = match u64::from_le_bytes(entry[1..ENTRY_SIZE].try_into().unwrap()) {
+        0 => None,
+        parent => Some(Offset(parent as usize)),
+    };
+    Some((page_type, parent))
+}
+
+/// Shrinks the file by up to `limit` pages, one free page at a time: pages at the end of the file
+/// move into free pages before it, and the end is cut off. Stops early once no free pages are
+/// left, and returns how many pages were freed. Roots that move are updated in the catalog.
+pub fn incremental_vacuum(
+    pager: &Pager,
+    catalog: &mut Catalog,
+    limit: usize,
+) -> Result<usize, CorruptPage> {
+    let mut freed = 0;
+    while freed < limit && pager.free_count() > 0 {
+        let last = Offset(pager.num_pages() - 1);
+        if is_map_page(last.0, pager.page_size()) {
+            // Nothing is left for it to map
+            pager.truncate(last.0);
+            continue;
+        }
+        if !pager.take_free(last) {
+            let slot = pager.new_page();
+            relocate(pager, catalog, last, slot)?;
+        }
+        pager.truncate(last.0);
+        freed += 1;
+    }
+    if freed > 0 {
+        event!(Level::Info, "incremental_vacuum", pages = freed);
+    }
+    Ok(freed)
+}
+
+/// Moves a node to another page, and points its parent, children and sibling leaves at it there.
+fn relocate(
+    pager: &Pager,
+    catalog: &mut Catalog,
+    from: Offset,
+    to: Offset,
+) -> Result<(), CorruptPage> {
+    // A page with no entry, or one that doesn't make sense, means its map page is damaged
+    let map = entry_location(from.0, pager.page_size()).0;
+    let Some((page_type, parent)) = pager.pointer_map_entry(from) else {
+        return Err(CorruptPage(map));
+    };
+    event!(Level::Debug, "page_move", from = from.0, to = to.0);
+    let mut node = pager.get(&from)?;
+    node.offset = to;
+    pager.commit(&node);
+
+    match (page_type, parent) {
+        (PageType::Root, _) => catalog.move_root(from, to),
+        (PageType::Internal | PageType::Leaf, Some(parent)) => {
+            let mut parent = pager.get(&parent)?;
+            if let NodeType::Internal(InternalNode {
+                ref mut children, ..
+            }) = parent.node_type
+            {
+                for child in children.iter_mut().filter(|child| **child == from) {
+                    *child = to;
+                }
+            }
+            pager.commit(&parent);
+        }
+        _ => return Err(CorruptPage(map)),
+    }
+
+    match node.node_type {
+        NodeType::Internal(InternalNode { ref children, .. }) => {
+            for offset in children {
+                let mut child = pager.get(offset)?;
+                child.parent_offset = Some(to);
+                pager.commit(&child);
+            }
+        }
+        NodeType::Leaf(..) => {
+            if let Some(last) = node.get_last_leaf() {
+                let mut last = pager.get(&last)?;
+                last.set_next_leaf(Some(to));
+                pager.commit(&last);
+            }
+            if let Some(next) = node.get_next_leaf() {
+                let mut next = pager.get(&next)?;
+                next.set_last_leaf(Some(to));
+                pager.commit(&next);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::btree::BTree;
+    use crate::catalog::Catalog;
+    use crate::fixtures::row;
+    use crate::integrity::check;
+    use crate::page::{Page, DEFAULT_PAGE_SIZE};
+    use crate::pager::{Offset, Pager};
+    use crate::pointer_map::{entry_location, incremental_vacuum, is_map_page, PageType};
+
+    #[test]
+    fn map_pages_cover_the_pages_after_them() {
+        // 454 entries fit in a 4096-byte page
+        let maps: Vec<usize> = (0..1000)
+            .filter(|&page| is_map_page(page, DEFAULT_PAGE_SIZE))
+            .collect();
+        assert_eq!(maps, vec![1, 456, 911]);
+        assert_eq!(entry_location(2, DEFAULT_PAGE_SIZE), (Offset(1), 0));
+        assert_eq!(entry_location(455, DEFAULT_PAGE_SIZE), (Offset(1), 453 * 9));
+        assert_eq!(entry_location(457, DEFAULT_PAGE_SIZE), (Offset(456), 0));
+    }
+
+    #[test]
+    fn moved_pages_keep_their_links() {
+        let pager = Arc::new(Pager::open(":memory:"));
+        pager.set_pointer_maps(true);
+        pager.commit_page(Offset(0), Page::new(pager.page_size()));
+        let mut catalog = Catalog::new(pager.page_size());
+        let mut users = BTree::create(pager.clone());
+        assert_eq!(users.root(), Offset(2));
+        let gaps: Vec<Offset> = (0..300).map(|_| pager.new_page()).collect();
+        // Enough rows for the root's children to be internal nodes
+        for id in 0..3000 {
+            assert!(users.insert(id as usize, row(id)).unwrap());
+        }
+        let mut logs = BTree::create(pager.clone());
+        for id in 0..3 {
+            assert!(logs.insert(id as usize, row(id)).unwrap());
+        }
+        catalog.add("users", users.root());
+        catalog.add("logs", logs.root());
+        for gap in gaps {
+            pager.recycle(gap);
+        }
+        assert_eq!(
+            pager.pointer_map_entry(Offset(3)),
+            Some((PageType::Free, None))
+        );
+        assert_eq!(check(&pager, &catalog), Vec::<String>::new());
+
+        // The last page is the root of logs
+        let pages = pager.num_pages();
+        assert_eq!(incremental_vacuum(&pager, &mut catalog, 1), Ok(1));
+        assert_eq!(catalog.root("logs"), Some(Offset(3)));
+        assert_eq!(pager.num_pages(), pages - 1);
+        assert_eq!(check(&pager, &catalog), Vec::<String>::new());
+
+        // The rest are users' leaves and internal nodes, and the second map page, which goes
+        // once there's nothing after it
+        while pager.free_count() > 0 {
+            assert!(incremental_vacuum(&pager, &mut catalog, 10).unwrap() > 0);
+            assert_eq!(check(&pager, &catalog), Vec::<String>::new());
+        }
+        assert_eq!(pager.num_pages(), pages - 300 - 1);
+        assert!(pager.num_pages() < 456);
+        assert_eq!(incremental_vacuum(&pager, &mut catalog, 1), Ok(0));
+        let users = BTree::open(pager.clone(), catalog.root("users").unwrap());
+        for id in 0..3000 {
+            assert!(users.find(id).unwrap().is_ok(), "{id} is missing");
+        }
+    }
+}
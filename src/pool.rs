@@ -0,0 +1,389 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+
+use crate::btree::BTree;
+use crate::cursor::Scan;
+use crate::datastore::{ExecuteResult, Row, Table, TABLE_NAME};
+use crate::pager::{CorruptPage, Pager};
+use crate::query::{execute_select, SelectError, DEFAULT_SPILL_THRESHOLD};
+use crate::{Statement, StatementType};
+
+/// A database shared between threads: any number of them can read at once, while one at a time
+/// writes. Readers and the writer share one page cache, and page latches keep readers from seeing
+/// a page half way through a change. Readers see the writer's changes as soon as each statement
+/// is done, before they're committed, unless they read from a snapshot.
+///
+/// The pool holds a shared lock on the file for as long as it's open, so no other process can
+/// commit under it.
+pub struct Pool {
+    writer: Mutex<Table>,
+    pager: Arc<Pager>,
+    /// The readers' copy of every table's tree. The writer swaps in a new one whenever it adds a
+    /// table or moves a root, and each reader statement takes whichever is current when it starts.
+    trees: RwLock<Arc<HashMap<String, BTree>>>,
+    /// Held for reading by each reader statement while it runs, and for writing while the writer
+    /// moves or frees pages, so no statement is part way down a tree while its pages go elsewhere.
+    moving: RwLock<()>,
+    /// Every table's tree as of the last commit, which snapshots are taken of. Whatever commits
+    /// takes it for writing, so a snapshot's trees always match its commit.
+    committed: RwLock<HashMap<String, BTree>>,
+}
+
+impl Pool {
+    /// Opens a database file like [`Table::open`] does. Fails if another process is writing to it
+    /// for longer than the busy timeout, or if the file is corrupt.
+    pub fn open(filename: impl AsRef<Path>) -> io::Result<Self> {
+        let mut table = Table::open(filename);
+        table.pin()?;
+        let pager = table.pager().clone();
+        pager.keep_versions();
+        let trees = table.trees().clone();
+        Ok(Self {
+            writer: Mutex::new(table),
+            pager,
+            trees: RwLock::new(Arc::new(trees.clone())),
+            moving: RwLock::new(()),
+            committed: RwLock::new(trees),
+        })
+    }
+
+    /// Starts reading. A reader holds on to nothing between statements, and its statements only
+    /// wait for the writer while it moves pages around.
+    pub fn read(&self) -> Reader<'_> {
+        Reader { pool: self }
+    }
+
+    /// Starts reading the database as of the last commit. Nothing the writer does afterwards
+    /// shows, and the writer never waits for the snapshot. The pages it reads are kept until it's
+    /// dropped.
+    pub fn snapshot(&self) -> SnapshotReader {
+        let committed = self.committed.read().unwrap();
+        let snapshot = Arc::new(self.pager.snapshot());
+        let trees = committed
+            .iter()
+            .map(|(name, tree)| (name.clone(), tree.as_of(&snapshot)))
+            .collect();
+        SnapshotReader { trees }
+    }
+
+    /// Starts writing, waiting for whoever is writing now to finish.
+    pub fn write(&self) -> Writer<'_> {
+        Writer {
+            table: self.writer.lock().unwrap(),
+            trees: &self.trees,
+            moving: &self.moving,
+            committed: &self.committed,
+        }
+    }
+
+    /// Commits anything still uncommitted. See [`Table::close`].
+    pub fn close(self) {
+        self.writer.into_inner().unwrap().close();
+    }
+}
+
+/// A reader's view of a [`Pool`].
+pub struct Reader<'a> {
+    pool: &'a Pool,
+}
+
+impl Reader<'_> {
+    /// Returns the row with the given id, if the table has one. Fails if a page it reads is corrupt.
+    pub fn get(&self, name: &str, id: u32) -> Result<Option<Row>, CorruptPage> {
+        let _moving = self.pool.moving.read().unwrap();
+        get(&self.trees(), name, id)
+    }
+
+    /// Executes a statement that only reads.
+    pub fn execute(&self, stmt: Statement) -> ExecuteResult {
+        let _moving = self.pool.moving.read().unwrap();
+        execute(&self.trees(), stmt)
+    }
+
+    fn trees(&self) -> Arc<HashMap<String, BTree>> {
+        self.pool.trees.read().unwrap().clone()
+    }
+}
+
+/// A reader of the database as it was at a commit. See [`Pool::snapshot`].
+#[derive(Debug)]
+pub struct SnapshotReader {
+    trees: HashMap<String, BTree>,
+}
+
+impl SnapshotReader {
+    /// Returns the row with the given id, if the table had one. Fails if a page it reads is corrupt.
+    pub fn get(&self, name: &str, id: u32) -> Result<Option<Row>, CorruptPage> {
+        get(&self.trees, name, id)
+    }
+
+    /// Executes a statement that only reads.
+    pub fn execute(&self, stmt: Statement) -> ExecuteResult {
+        execute(&self.trees, stmt)
+    }
+}
+
+fn get(trees: &HashMap<String, BTree>, name: &str, id: u32) -> Result<Option<Row>, CorruptPage> {
+    match trees.get(name) {
+        Some(tree) => tree.search(id as usize),
+        None => Ok(None),
+    }
+}
+
+fn execute(trees: &HashMap<String, BTree>, stmt: Statement) -> ExecuteResult {
+    match stmt.statement_type {
+        StatementType::Select => match trees.get(TABLE_NAME) {
+            Some(tree) => match Scan::new(tree).collect() {
+                Ok(rows) => ExecuteResult::SelectSuccess(rows),
+                Err(corrupt) => ExecuteResult::Corrupt(corrupt),
+            },
+            None => ExecuteResult::SelectSuccess(Vec::new()),
+        },
+        StatementType::Query(select) if select.as_of.is_some() => {
+            ExecuteResult::QueryError(String::from("named snapshots are read through the writer"))
+        }
+        StatementType::Query(select) => {
+            match execute_select(&select, trees, DEFAULT_SPILL_THRESHOLD) {
+                Ok(results) => ExecuteResult::QuerySuccess(results),
+                Err(SelectError::Query(why)) => ExecuteResult::QueryError(why.to_string()),
+                Err(SelectError::Corrupt(corrupt)) => ExecuteResult::Corrupt(corrupt),
+            }
+        }
+        _ => ExecuteResult::QueryError(String::from("readers can't change the database")),
+    }
+}
+
+/// The writer's view of a [`Pool`]; there's only ever one at a time.
+pub struct Writer<'a> {
+    table: MutexGuard<'a, Table>,
+    trees: &'a RwLock<Arc<HashMap<String, BTree>>>,
+    moving: &'a RwLock<()>,
+    committed: &'a RwLock<HashMap<String, BTree>>,
+}
+
+impl Writer<'_> {
+    pub fn execute(&mut self, stmt: Statement) -> ExecuteResult {
+        match stmt.statement_type {
+            StatementType::Select | StatementType::Query(_) => self.table.execute_statement(stmt),
+            StatementType::Insert | StatementType::InsertInto(_)
+                if !self.table.pager().copy_on_write() =>
+            {
+                self.table.execute_statement(stmt)
+            }
+            // Copying the path to the row moves the root, but the pages it replaces are only
+            // freed by the next commit
+            StatementType::Insert
+            | StatementType::InsertInto(_)
+            | StatementType::CreateTable(_) => self.publish(|table| table.execute_statement(stmt)),
+            _ => self.restructure(|table| table.execute_statement(stmt)),
+        }
+    }
+
+    pub fn delete(&mut self, name: &str, id: u32) -> ExecuteResult {
+        if self.table.pager().copy_on_write() {
+            self.publish(|table| table.delete(name, id))
+        } else {
+            self.table.delete(name, id)
+        }
+    }
+
+    /// Commits every change so far. See [`Table::commit`].
+    pub fn commit(&mut self) -> std::io::Result<()> {
+        if self.table.commit_moves_pages() {
+            self.restructure(Table::commit)
+        } else {
+            let mut committed = self.committed.write().unwrap();
+            self.table.commit()?;
+            *committed = self.table.trees().clone();
+            Ok(())
+        }
+    }
+
+    /// Runs something that may add tables, move roots or commit, then hands the readers the trees
+    /// as they are afterwards. Statements already running keep reading the trees they started
+    /// with.
+    fn publish<T>(&mut self, f: impl FnOnce(&mut Table) -> T) -> T {
+        let mut committed = self.committed.write().unwrap();
+        let result = f(&mut self.table);
+        *self.trees.write().unwrap() = Arc::new(self.table.trees().clone());
+        if !self.table.pager().has_changes() {
+            *committed = self.table.trees().clone();
+        }
+        result
+    }
+
+    /// Like [`Writer::publish`], for something that may also move or free pages. It waits for the
+    /// reader statements that are running to finish, and new ones wait for it.
+    fn restructure<T>(&mut self, f: impl FnOnce(&mut Table) -> T) -> T {
+        let _moving = self.moving.write().unwrap();
+        self.publish(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+
+    use super::*;
+    use crate::fixtures::{insert_row, row, select, statement};
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    const _: fn() = assert_send_sync::<Pool>;
+
+    fn insert(writer: &mut Writer, id: u32) {
+        assert_eq!(writer.execute(insert_row(id)), ExecuteResult::InsertSuccess);
+    }
+
+    #[test]
+    fn snapshots_see_the_commit_they_were_taken_at() {
+        let pool = Pool::open(":memory:").unwrap();
+        let mut writer = pool.write();
+        for id in 0..200 {
+            insert(&mut writer, id);
+        }
+        writer.commit().unwrap();
+        for id in 200..250 {
+            insert(&mut writer, id);
+        }
+        // Taken part way through a transaction, so none of it shows
+        let snapshot = pool.snapshot();
+        for id in 250..400 {
+            insert(&mut writer, id);
+        }
+        for id in 0..100 {
+            writer.delete(TABLE_NAME, id);
+        }
+        writer.commit().unwrap();
+        let vacuum = Statement {
+            statement_type: StatementType::Vacuum(None),
+            row_to_insert: None,
+        };
+        assert_eq!(writer.execute(vacuum), ExecuteResult::VacuumSuccess);
+        drop(writer);
+
+        assert_eq!(
+            snapshot.execute(select()),
+            ExecuteResult::SelectSuccess((0..200).map(row).collect())
+        );
+        assert_eq!(snapshot.get(TABLE_NAME, 50), Ok(Some(row(50))));
+        assert_eq!(snapshot.get(TABLE_NAME, 300), Ok(None));
+        assert_eq!(
+            pool.snapshot().execute(select()),
+            ExecuteResult::SelectSuccess((100..400).map(row).collect())
+        );
+
+        assert!(pool.pager.kept_versions() > 0);
+        drop(snapshot);
+        assert_eq!(pool.pager.kept_versions(), 0);
+    }
+
+    #[test]
+    fn readers_follow_roots_that_copying_moves() {
+        let pool = Pool::open(":memory:").unwrap();
+        let mut writer = pool.write();
+        assert!(matches!(
+            writer.execute(statement("pragma copy_on_write = on")),
+            ExecuteResult::QuerySuccess(_)
+        ));
+        for id in 0..100 {
+            insert(&mut writer, id);
+        }
+        writer.commit().unwrap();
+        for id in 0..50 {
+            writer.delete(TABLE_NAME, id);
+        }
+        drop(writer);
+
+        assert_eq!(
+            pool.read().execute(select()),
+            ExecuteResult::SelectSuccess((50..100).map(row).collect())
+        );
+        assert_eq!(
+            pool.snapshot().execute(select()),
+            ExecuteResult::SelectSuccess((0..100).map(row).collect())
+        );
+    }
+
+    #[test]
+    fn readers_held_open_dont_stall_the_writer() {
+        let pool = Pool::open(":memory:").unwrap();
+        let reader = pool.read();
+        let mut writer = pool.write();
+        assert!(matches!(
+            writer.execute(statement("pragma copy_on_write = on")),
+            ExecuteResult::QuerySuccess(_)
+        ));
+        for id in 0..100 {
+            insert(&mut writer, id);
+        }
+        writer.commit().unwrap();
+        assert_eq!(reader.get(TABLE_NAME, 10), Ok(Some(row(10))));
+        for id in 0..50 {
+            writer.delete(TABLE_NAME, id);
+        }
+        writer.commit().unwrap();
+        drop(writer);
+
+        assert_eq!(
+            reader.execute(select()),
+            ExecuteResult::SelectSuccess((50..100).map(row).collect())
+        );
+    }
+
+    #[test]
+    fn readers_and_a_writer_share_a_pool() {
+        const ROWS: u32 = 2000;
+        const COMMIT_EVERY: u32 = 100;
+        let pool = Arc::new(Pool::open(":memory:").unwrap());
+        // The ids the writer has committed, in the order it inserted them
+        let ids: Vec<u32> = (0..ROWS).map(|i| i * 7919 % ROWS).collect();
+        let committed = Arc::new(AtomicUsize::new(0));
+        let done = Arc::new(AtomicBool::new(false));
+
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let (pool, ids) = (pool.clone(), ids.clone());
+                let (committed, done) = (committed.clone(), done.clone());
+                thread::spawn(move || {
+                    let mut seen = 0;
+                    while !done.load(Ordering::Relaxed) {
+                        let snapshot = pool.snapshot();
+                        let before = snapshot.execute(select());
+                        let reader = pool.read();
+                        let committed = committed.load(Ordering::Relaxed);
+                        let ExecuteResult::SelectSuccess(rows) = reader.execute(select()) else {
+                            panic!("the select failed");
+                        };
+                        assert!(rows.windows(2).all(|pair| pair[0].id < pair[1].id));
+                        assert!(rows.len() >= seen.max(committed));
+                        seen = rows.len();
+                        for &id in &ids[..committed] {
+                            assert_eq!(reader.get(TABLE_NAME, id), Ok(Some(row(id))));
+                        }
+                        assert_eq!(snapshot.execute(select()), before);
+                    }
+                })
+            })
+            .collect();
+
+        for (i, &id) in ids.iter().enumerate() {
+            let mut writer = pool.write();
+            insert(&mut writer, id);
+            if (i as u32 + 1).is_multiple_of(COMMIT_EVERY) {
+                writer.commit().unwrap();
+                committed.store(i + 1, Ordering::Relaxed);
+            }
+        }
+        done.store(true, Ordering::Relaxed);
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        assert!(pool.write().table.integrity_check().is_empty());
+        assert_eq!(pool.pager.kept_versions(), 0);
+    }
+}
@@ -0,0 +1,1112 @@
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+
+use crate::aggregate::{AggregateFunction, HashAggregate};
+use crate::btree::BTree;
+use crate::cursor::Scan;
+use crate::datastore::{Row, COLUMNS};
+use crate::pager::CorruptPage;
+use crate::parser::{
+    BinaryOp, ColumnRef, Expr, Join, JoinKind, Select, SelectItem, TableRef, UnaryOp,
+};
+use crate::sort::{ExternalSorter, SortKey};
+use crate::value::Value;
+
+/// How many groups or rows an operator may hold in memory before spilling to disk.
+pub const DEFAULT_SPILL_THRESHOLD: usize = 100_000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueryError(pub String);
+
+impl Display for QueryError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Why a SELECT failed: something was wrong with it, or it read a corrupt page.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SelectError {
+    Query(QueryError),
+    Corrupt(CorruptPage),
+}
+
+impl Display for SelectError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SelectError::Query(why) => write!(f, "{why}"),
+            SelectError::Corrupt(corrupt) => write!(f, "{corrupt}"),
+        }
+    }
+}
+
+impl From<QueryError> for SelectError {
+    fn from(why: QueryError) -> Self {
+        SelectError::Query(why)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResultSet {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<Value>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ScalarFunction {
+    Abs,
+    Coalesce,
+    Instr,
+    Length,
+    Lower,
+    Substr,
+    Upper,
+}
+
+impl ScalarFunction {
+    fn bind(name: &str, arity: usize) -> Result<Self, QueryError> {
+        let (function, arity_ok) = match name {
+            "abs" => (ScalarFunction::Abs, arity == 1),
+            "coalesce" => (ScalarFunction::Coalesce, arity >= 1),
+            "instr" => (ScalarFunction::Instr, arity == 2),
+            "length" => (ScalarFunction::Length, arity == 1),
+            "lower" => (ScalarFunction::Lower, arity == 1),
+            "substr" => (ScalarFunction::Substr, arity == 2 || arity == 3),
+            "upper" => (ScalarFunction::Upper, arity == 1),
+            _ => return Err(QueryError(format!("no such function: {name}"))),
+        };
+        if !arity_ok {
+            return Err(QueryError(format!(
+                "wrong number of arguments to function {name}()"
+            )));
+        }
+        Ok(function)
+    }
+}
+
+/// An expression with its column references resolved to positions in the tuple it is evaluated
+/// against. Aggregate calls never survive binding: they become columns of the aggregated tuple.
+#[derive(Debug, Clone, PartialEq)]
+enum Bound {
+    Column(usize),
+    Literal(Value),
+    Unary(UnaryOp, Box<Bound>),
+    Binary(BinaryOp, Box<Bound>, Box<Bound>),
+    IsNull(Box<Bound>, bool),
+    Like(Box<Bound>, Box<Bound>, bool),
+    Function(ScalarFunction, Vec<Bound>),
+}
+
+/// The columns visible to a query, each qualified by the name (or alias) of its table.
+struct Scope {
+    columns: Vec<(String, String)>,
+}
+
+impl Scope {
+    fn resolve(&self, column: &ColumnRef) -> Result<usize, QueryError> {
+        let mut matches = self
+            .columns
+            .iter()
+            .enumerate()
+            .filter(|(_, (table, name))| {
+                *name == column.name && column.table.as_ref().is_none_or(|t| t == table)
+            });
+        match (matches.next(), matches.next()) {
+            (Some((i, _)), None) => Ok(i),
+            (Some(_), Some(_)) => Err(QueryError(format!("ambiguous column name: {column}"))),
+            (None, _) => Err(QueryError(format!("no such column: {column}"))),
+        }
+    }
+}
+
+fn is_aggregate_call(expr: &Expr) -> bool {
+    match expr {
+        Expr::CountStar => true,
+        Expr::Function { name, args } => {
+            args.len() == 1 && AggregateFunction::from_name(name).is_some()
+        }
+        _ => false,
+    }
+}
+
+fn contains_aggregate(expr: &Expr) -> bool {
+    if is_aggregate_call(expr) {
+        return true;
+    }
+    match expr {
+        Expr::Literal(_) | Expr::Column(_) | Expr::Parameter(_) | Expr::CountStar => false,
+        Expr::Unary(_, e) | Expr::IsNull { expr: e, .. } => contains_aggregate(e),
+        Expr::Binary(_, l, r)
+        | Expr::Like {
+            expr: l,
+            pattern: r,
+            ..
+        } => contains_aggregate(l) || contains_aggregate(r),
+        Expr::Function { args, .. } => args.iter().any(contains_aggregate),
+    }
+}
+
+/// Binds an expression that is evaluated once per input row, where aggregates aren't allowed.
+fn bind(expr: &Expr, scope: &Scope) -> Result<Bound, QueryError> {
+    bind_with(expr, &mut |e| {
+        if is_aggregate_call(e) {
+            return Err(QueryError(String::from("misuse of aggregate function")));
+        }
+        match e {
+            Expr::Column(column) => scope.resolve(column).map(|i| Some(Bound::Column(i))),
+            _ => Ok(None),
+        }
+    })
+}
+
+/// Binds an expression bottom-up. `leaf` gets the first look at every node and may bind it
+/// outright; otherwise the node is rebuilt from its bound children.
+fn bind_with<F>(expr: &Expr, leaf: &mut F) -> Result<Bound, QueryError>
+where
+    F: FnMut(&Expr) -> Result<Option<Bound>, QueryError>,
+{
+    if let Some(bound) = leaf(expr)? {
+        return Ok(bound);
+    }
+    Ok(match expr {
+        Expr::Literal(v) => Bound::Literal(v.clone()),
+        // Parameters that were never bound are NULL
+        Expr::Parameter(_) => Bound::Literal(Value::Null),
+        Expr::Column(column) => return Err(QueryError(format!("no such column: {column}"))),
+        Expr::CountStar => return Err(QueryError(String::from("misuse of aggregate: count(*)"))),
+        Expr::Unary(op, e) => Bound::Unary(*op, Box::new(bind_with(e, leaf)?)),
+        Expr::Binary(op, l, r) => Bound::Binary(
+            *op,
+            Box::new(bind_with(l, leaf)?),
+            Box::new(bind_with(r, leaf)?),
+        ),
+        Expr::IsNull { expr, negated } => Bound::IsNull(Box::new(bind_with(expr, leaf)?), *negated),
+        Expr::Like {
+            expr,
+            pattern,
+            negated,
+        } => Bound::Like(
+            Box::new(bind_with(expr, leaf)?),
+            Box::new(bind_with(pattern, leaf)?),
+            *negated,
+        ),
+        Expr::Function { name, args } => {
+            let function = ScalarFunction::bind(name, args.len())?;
+            let args = args
+                .iter()
+                .map(|a| bind_with(a, leaf))
+                .collect::<Result<_, _>>()?;
+            Bound::Function(function, args)
+        }
+    })
+}
+
+/// Replaces references to output column aliases with the aliased expressions, so that
+/// `GROUP BY domain` can refer to `substr(...) AS domain`. Real columns win over aliases.
+fn resolve_aliases(expr: &Expr, items: &[SelectItem], scope: &Scope) -> Expr {
+    match expr {
+        Expr::Column(column) if column.table.is_none() && scope.resolve(column).is_err() => items
+            .iter()
+            .find_map(|item| match item {
+                SelectItem::Expr { expr, name } if *name == column.name => Some(expr.clone()),
+                _ => None,
+            })
+            .unwrap_or_else(|| expr.clone()),
+        Expr::Unary(op, e) => Expr::Unary(*op, Box::new(resolve_aliases(e, items, scope))),
+        Expr::Binary(op, l, r) => Expr::Binary(
+            *op,
+            Box::new(resolve_aliases(l, items, scope)),
+            Box::new(resolve_aliases(r, items, scope)),
+        ),
+        Expr::IsNull { expr, negated } => Expr::IsNull {
+            expr: Box::new(resolve_aliases(expr, items, scope)),
+            negated: *negated,
+        },
+        Expr::Like {
+            expr,
+            pattern,
+            negated,
+        } => Expr::Like {
+            expr: Box::new(resolve_aliases(expr, items, scope)),
+            pattern: Box::new(resolve_aliases(pattern, items, scope)),
+            negated: *negated,
+        },
+        Expr::Function { name, args } => Expr::Function {
+            name: name.clone(),
+            args: args
+                .iter()
+                .map(|a| resolve_aliases(a, items, scope))
+                .collect(),
+        },
+        Expr::Literal(_) | Expr::Column(_) | Expr::Parameter(_) | Expr::CountStar => expr.clone(),
+    }
+}
+
+/// The aggregates of a grouped query. Output expressions are bound against the aggregated tuple,
+/// which holds the group key values followed by one value per aggregate.
+struct Aggregation<'a> {
+    scope: &'a Scope,
+    group_by: Vec<Bound>,
+    functions: Vec<AggregateFunction>,
+    args: Vec<Option<Bound>>,
+    calls: Vec<Expr>,
+}
+
+impl<'a> Aggregation<'a> {
+    fn bind_output(&mut self, expr: &Expr) -> Result<Bound, QueryError> {
+        bind_with(expr, &mut |e| {
+            if let Ok(bound) = bind(e, self.scope) {
+                if let Some(i) = self.group_by.iter().position(|g| *g == bound) {
+                    return Ok(Some(Bound::Column(i)));
+                }
+            }
+            if is_aggregate_call(e) {
+                return self.bind_aggregate(e).map(Some);
+            }
+            match e {
+                Expr::Column(column) => Err(QueryError(format!(
+                    "column \"{column}\" must appear in the GROUP BY clause or be used in an aggregate function"
+                ))),
+                _ => Ok(None),
+            }
+        })
+    }
+
+    fn bind_aggregate(&mut self, call: &Expr) -> Result<Bound, QueryError> {
+        let column = self.group_by.len();
+        if let Some(i) = self.calls.iter().position(|c| c == call) {
+            return Ok(Bound::Column(column + i));
+        }
+        let (function, arg) = match call {
+            Expr::CountStar => (AggregateFunction::CountStar, None),
+            Expr::Function { name, args } => {
+                if contains_aggregate(&args[0]) {
+                    return Err(QueryError(String::from("misuse of aggregate function")));
+                }
+                (
+                    AggregateFunction::from_name(name).unwrap(),
+                    Some(bind(&args[0], self.scope)?),
+                )
+            }
+            _ => unreachable!(),
+        };
+        self.functions.push(function);
+        self.args.push(arg);
+        self.calls.push(call.clone());
+        Ok(Bound::Column(column + self.calls.len() - 1))
+    }
+}
+
+/// Runs a SELECT against the database's tables. Without ORDER BY, rows come out in key order of
+/// the tables from left to right, or group key order for grouped queries.
+pub fn execute_select(
+    select: &Select,
+    tables: &HashMap<String, BTree>,
+    spill_threshold: usize,
+) -> Result<ResultSet, SelectError> {
+    // A corrupt page one of the scans ran into, which the query fails with
+    let corrupt = Cell::new(None);
+    let (scope, rows): (Scope, Box<dyn Iterator<Item = Vec<Value>> + '_>) = match &select.from {
+        Some(table) => {
+            let tree = find_table(tables, table)?;
+            let mut scope = Scope { columns: vec![] };
+            add_table_columns(&mut scope, table);
+            let mut rows: Box<dyn Iterator<Item = Vec<Value>>> =
+                Box::new(scan(tree, &corrupt).map(row_values));
+            for join in &select.joins {
+                let tree = find_table(tables, &join.table)?;
+                rows = nested_loop_join(rows, &mut scope, join, tree, &corrupt)?;
+            }
+            (scope, rows)
+        }
+        // Without a FROM clause the expressions are evaluated exactly once
+        None => (Scope { columns: vec![] }, Box::new(std::iter::once(vec![]))),
+    };
+
+    let mut items = Vec::new();
+    let mut columns = Vec::new();
+    for item in &select.items {
+        match item {
+            SelectItem::Wildcard => {
+                if scope.columns.is_empty() {
+                    return Err(QueryError(String::from("no tables specified")).into());
+                }
+                for (table, name) in &scope.columns {
+                    items.push(Expr::Column(ColumnRef {
+                        table: Some(table.clone()),
+                        name: name.clone(),
+                    }));
+                    columns.push(name.clone());
+                }
+            }
+            SelectItem::Expr { expr, name } => {
+                items.push(expr.clone());
+                columns.push(name.clone());
+            }
+        }
+    }
+
+    let filter = match &select.where_clause {
+        Some(expr) => Some(bind(expr, &scope)?),
+        None => None,
+    };
+    let rows = rows.filter(move |row| match &filter {
+        Some(filter) => eval(filter, row).as_bool() == Some(true),
+        None => true,
+    });
+
+    let (limit, offset) = bind_limit(select)?;
+
+    let is_aggregate = !select.group_by.is_empty()
+        || items.iter().any(contains_aggregate)
+        || select.having.as_ref().is_some_and(contains_aggregate)
+        || select.order_by.iter().any(|t| contains_aggregate(&t.expr));
+    if !is_aggregate {
+        if select.having.is_some() {
+            return Err(
+                QueryError(String::from("a GROUP BY clause is required before HAVING")).into(),
+            );
+        }
+        let projection = items
+            .iter()
+            .map(|e| bind(e, &scope))
+            .collect::<Result<Vec<_>, _>>()?;
+        let (order_by, sort_keys) = bind_order_by(select, &columns, |e| {
+            bind(&resolve_aliases(e, &select.items, &scope), &scope)
+        })?;
+        let rows = rows.map(|row| {
+            projection
+                .iter()
+                .chain(&order_by)
+                .map(|p| eval(p, &row))
+                .collect()
+        });
+        let rows = sort_and_limit(
+            rows,
+            sort_keys,
+            columns.len(),
+            limit,
+            offset,
+            spill_threshold,
+        );
+        return match corrupt.get() {
+            Some(corrupt) => Err(SelectError::Corrupt(corrupt)),
+            None => Ok(ResultSet { columns, rows }),
+        };
+    }
+
+    let mut aggregation = Aggregation {
+        scope: &scope,
+        group_by: Vec::new(),
+        functions: Vec::new(),
+        args: Vec::new(),
+        calls: Vec::new(),
+    };
+    for expr in &select.group_by {
+        let expr = resolve_aliases(expr, &select.items, &scope);
+        if contains_aggregate(&expr) {
+            return Err(QueryError(String::from(
+                "aggregate functions are not allowed in the GROUP BY clause",
+            ))
+            .into());
+        }
+        aggregation.group_by.push(bind(&expr, &scope)?);
+    }
+    let projection = items
+        .iter()
+        .map(|e| aggregation.bind_output(e))
+        .collect::<Result<Vec<_>, _>>()?;
+    let having = match &select.having {
+        Some(expr) => {
+            Some(aggregation.bind_output(&resolve_aliases(expr, &select.items, &scope))?)
+        }
+        None => None,
+    };
+    let (order_by, sort_keys) = bind_order_by(select, &columns, |e| {
+        aggregation.bind_output(&resolve_aliases(e, &select.items, &scope))
+    })?;
+
+    let mut groups = HashAggregate::new(aggregation.functions.clone(), spill_threshold);
+    if aggregation.group_by.is_empty() {
+        groups.add_group(Vec::new());
+    }
+    for row in rows {
+        let key = aggregation.group_by.iter().map(|g| eval(g, &row)).collect();
+        let args: Vec<Value> = aggregation
+            .args
+            .iter()
+            .map(|arg| arg.as_ref().map_or(Value::Null, |a| eval(a, &row)))
+            .collect();
+        groups.update(key, &args);
+    }
+
+    let rows = groups
+        .finish()
+        .into_iter()
+        .filter_map(|(mut tuple, results)| {
+            tuple.extend(results);
+            if let Some(having) = &having {
+                if eval(having, &tuple).as_bool() != Some(true) {
+                    return None;
+                }
+            }
+            Some(
+                projection
+                    .iter()
+                    .chain(&order_by)
+                    .map(|p| eval(p, &tuple))
+                    .collect(),
+            )
+        });
+    let rows = sort_and_limit(
+        rows,
+        sort_keys,
+        columns.len(),
+        limit,
+        offset,
+        spill_threshold,
+    );
+    match corrupt.get() {
+        Some(corrupt) => Err(SelectError::Corrupt(corrupt)),
+        None => Ok(ResultSet { columns, rows }),
+    }
+}
+
+/// Every row of a table in key order, up to a corrupt page, which is kept in `corrupt`.
+fn scan<'a>(
+    tree: &'a BTree,
+    corrupt: &'a Cell<Option<CorruptPage>>,
+) -> impl Iterator<Item = Row> + 'a {
+    Scan::new(tree).map_while(|row| row.map_err(|why| corrupt.set(Some(why))).ok())
+}
+
+fn find_table<'a>(
+    tables: &'a HashMap<String, BTree>,
+    table: &TableRef,
+) -> Result<&'a BTree, QueryError> {
+    tables
+        .get(&table.name)
+        .ok_or_else(|| QueryError(format!("no such table: {}", table.name)))
+}
+
+fn add_table_columns(scope: &mut Scope, table: &TableRef) {
+    let qualifier = table.alias.as_ref().unwrap_or(&table.name);
+    scope
+        .columns
+        .extend(COLUMNS.iter().map(|c| (qualifier.clone(), c.to_string())));
+}
+
+/// Joins each outer row with the rows of `tree` that satisfy the ON condition, padding outer rows
+/// without a match with NULLs for a LEFT JOIN.
+///
+/// When the condition pins the inner table's primary key to something computed from the outer
+/// row, the matching row is looked up through the B-tree. Otherwise the whole inner table is
+/// scanned once per outer row.
+fn nested_loop_join<'a>(
+    outer: Box<dyn Iterator<Item = Vec<Value>> + 'a>,
+    scope: &mut Scope,
+    join: &Join,
+    tree: &'a BTree,
+    corrupt: &'a Cell<Option<CorruptPage>>,
+) -> Result<Box<dyn Iterator<Item = Vec<Value>> + 'a>, QueryError> {
+    let outer_scope = Scope {
+        columns: scope.columns.clone(),
+    };
+    add_table_columns(scope, &join.table);
+    let (condition, key) = match &join.on {
+        Some(on) => {
+            let key = conjuncts(on)
+                .into_iter()
+                .find_map(|c| lookup_key(c, scope, &outer_scope));
+            (Some(bind(on, scope)?), key)
+        }
+        None => (None, None),
+    };
+    let kind = join.kind;
+    Ok(Box::new(outer.flat_map(move |outer_row| {
+        let inner: Box<dyn Iterator<Item = Row>> = match &key {
+            Some(key) => Box::new(lookup(tree, &eval(key, &outer_row), corrupt).into_iter()),
+            None => Box::new(scan(tree, corrupt)),
+        };
+        let mut joined: Vec<Vec<Value>> = inner
+            .map(|row| {
+                let mut tuple = outer_row.clone();
+                tuple.extend(row_values(row));
+                tuple
+            })
+            .filter(|tuple| {
+                condition
+                    .as_ref()
+                    .is_none_or(|c| eval(c, tuple).as_bool() == Some(true))
+            })
+            .collect();
+        if joined.is_empty() && kind == JoinKind::Left {
+            let mut tuple = outer_row;
+            tuple.resize(tuple.len() + COLUMNS.len(), Value::Null);
+            joined.push(tuple);
+        }
+        joined
+    })))
+}
+
+fn conjuncts(expr: &Expr) -> Vec<&Expr> {
+    match expr {
+        Expr::Binary(BinaryOp::And, l, r) => {
+            let mut terms = conjuncts(l);
+            terms.extend(conjuncts(r));
+            terms
+        }
+        expr => vec![expr],
+    }
+}
+
+/// Recognises `inner.id = <expr>` (either way round) where the expression only uses columns of
+/// the tables already joined, returning that expression bound against the outer row.
+fn lookup_key(term: &Expr, scope: &Scope, outer_scope: &Scope) -> Option<Bound> {
+    let Expr::Binary(BinaryOp::Eq, l, r) = term else {
+        return None;
+    };
+    // The inner table's id is the first column after the outer columns
+    let inner_id = outer_scope.columns.len();
+    [(l, r), (r, l)]
+        .into_iter()
+        .find_map(|(key, other)| match &**key {
+            Expr::Column(column) if scope.resolve(column).ok() == Some(inner_id) => {
+                bind(other, outer_scope).ok()
+            }
+            _ => None,
+        })
+}
+
+/// Finds the row with the given primary key. Keys that can't be a row id match nothing, just like
+/// comparing them with `=` would. A corrupt page is kept in `corrupt`, and matches nothing.
+fn lookup(tree: &BTree, key: &Value, corrupt: &Cell<Option<CorruptPage>>) -> Option<Row> {
+    let key = match key {
+        Value::Integer(i) => u32::try_from(*i).ok()?,
+        Value::Real(r) if r.fract() == 0.0 && *r >= 0.0 && *r <= u32::MAX as f64 => *r as u32,
+        _ => return None,
+    };
+    tree.search(key as usize)
+        .map_err(|why| corrupt.set(Some(why)))
+        .ok()
+        .flatten()
+}
+
+/// Evaluates an expression that doesn't refer to any columns.
+pub fn evaluate_constant(expr: &Expr) -> Result<Value, QueryError> {
+    Ok(eval(&bind(expr, &Scope { columns: vec![] })?, &[]))
+}
+
+/// Evaluates LIMIT and OFFSET, which have to be constant integers. A negative limit means there is
+/// none.
+fn bind_limit(select: &Select) -> Result<(Option<usize>, usize), QueryError> {
+    let constant = |expr: &Option<Expr>, clause: &str| -> Result<Option<i64>, QueryError> {
+        let Some(expr) = expr else {
+            return Ok(None);
+        };
+        match evaluate_constant(expr)? {
+            Value::Integer(i) => Ok(Some(i)),
+            _ => Err(QueryError(format!("datatype mismatch in {clause}"))),
+        }
+    };
+    let limit = constant(&select.limit, "LIMIT")?.and_then(|l| usize::try_from(l).ok());
+    let offset = constant(&select.offset, "OFFSET")?.map_or(0, |o| o.max(0) as usize);
+    Ok((limit, offset))
+}
+
+/// Works out where each ORDER BY term gets its value from. Output column numbers and output
+/// column names sort on that output column. Any other expression is bound with `bind` and
+/// evaluated into an extra column appended after the output columns, returned here in order.
+fn bind_order_by<F>(
+    select: &Select,
+    columns: &[String],
+    mut bind: F,
+) -> Result<(Vec<Bound>, Vec<SortKey>), QueryError>
+where
+    F: FnMut(&Expr) -> Result<Bound, QueryError>,
+{
+    let mut extra = Vec::new();
+    let mut keys = Vec::new();
+    for (i, term) in select.order_by.iter().enumerate() {
+        let column = match &term.expr {
+            Expr::Literal(Value::Integer(n)) => {
+                if *n < 1 || *n as usize > columns.len() {
+                    return Err(QueryError(format!(
+                        "ORDER BY term {} out of range - should be between 1 and {}",
+                        i + 1,
+                        columns.len()
+                    )));
+                }
+                *n as usize - 1
+            }
+            Expr::Column(ColumnRef { table: None, name }) if columns.contains(name) => {
+                columns.iter().position(|c| c == name).unwrap()
+            }
+            expr => {
+                extra.push(bind(expr)?);
+                columns.len() + extra.len() - 1
+            }
+        };
+        keys.push(SortKey {
+            column,
+            descending: term.descending,
+        });
+    }
+    Ok((extra, keys))
+}
+
+/// Sorts output rows (when there are sort keys), applies OFFSET and LIMIT and strips the extra
+/// sort columns. Without ORDER BY the input is only read as far as the limit needs.
+fn sort_and_limit(
+    rows: impl Iterator<Item = Vec<Value>>,
+    sort_keys: Vec<SortKey>,
+    width: usize,
+    limit: Option<usize>,
+    offset: usize,
+    spill_threshold: usize,
+) -> Vec<Vec<Value>> {
+    let limit = limit.unwrap_or(usize::MAX);
+    if sort_keys.is_empty() {
+        return rows.skip(offset).take(limit).collect();
+    }
+    let mut sorter = ExternalSorter::new(sort_keys, spill_threshold);
+    if limit != usize::MAX {
+        sorter.set_limit(offset.saturating_add(limit));
+    }
+    for row in rows {
+        sorter.push(row);
+    }
+    let rows = sorter
+        .sorted()
+        .skip(offset)
+        .take(limit)
+        .map(|mut row| {
+            row.truncate(width);
+            row
+        })
+        .collect();
+    rows
+}
+
+fn row_values(row: Row) -> Vec<Value> {
+    vec![
+        Value::Integer(row.id as i64),
+        Value::Text(row.username),
+        Value::Text(row.email),
+    ]
+}
+
+/// Converts a value for arithmetic. Text is read as a number if it looks like one, else 0.
+fn to_number(v: &Value) -> Value {
+    match v {
+        Value::Text(s) => {
+            let s = s.trim();
+            s.parse::<i64>()
+                .map(Value::Integer)
+                .or_else(|_| s.parse::<f64>().map(Value::Real))
+                .unwrap_or(Value::Integer(0))
+        }
+        v => v.clone(),
+    }
+}
+
+fn to_text(v: &Value) -> String {
+    match v {
+        Value::Text(s) => s.clone(),
+        v => v.to_string(),
+    }
+}
+
+fn from_bool(b: Option<bool>) -> Value {
+    match b {
+        Some(b) => Value::Integer(b as i64),
+        None => Value::Null,
+    }
+}
+
+fn arithmetic(op: BinaryOp, l: &Value, r: &Value) -> Value {
+    match (to_number(l), to_number(r)) {
+        (Value::Integer(a), Value::Integer(b)) => {
+            let result = match op {
+                BinaryOp::Add => a.checked_add(b),
+                BinaryOp::Subtract => a.checked_sub(b),
+                BinaryOp::Multiply => a.checked_mul(b),
+                BinaryOp::Divide if b == 0 => return Value::Null,
+                BinaryOp::Divide => a.checked_div(b),
+                BinaryOp::Modulo if b == 0 => return Value::Null,
+                BinaryOp::Modulo => a.checked_rem(b),
+                _ => unreachable!(),
+            };
+            match result {
+                Some(i) => Value::Integer(i),
+                // Overflowing integer math carries on in floating point
+                None => arithmetic(op, &Value::Real(a as f64), &Value::Real(b as f64)),
+            }
+        }
+        (a, b) => {
+            let (a, b) = (a.as_f64().unwrap(), b.as_f64().unwrap());
+            match op {
+                BinaryOp::Add => Value::Real(a + b),
+                BinaryOp::Subtract => Value::Real(a - b),
+                BinaryOp::Multiply => Value::Real(a * b),
+                BinaryOp::Divide if b == 0.0 => Value::Null,
+                BinaryOp::Divide => Value::Real(a / b),
+                BinaryOp::Modulo if b as i64 == 0 => Value::Null,
+                BinaryOp::Modulo => Value::Real((a as i64 % b as i64) as f64),
+                _ => unreachable!(),
+            }
+        }
+    }
+}
+
+/// `LIKE` matching: `%` matches any run of characters, `_` exactly one, and ASCII letters match
+/// regardless of case.
+fn like(text: &[char], pattern: &[char]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some(('%', rest)) => (0..=text.len()).any(|skip| like(&text[skip..], rest)),
+        Some((p, rest)) => match text.split_first() {
+            Some((t, text_rest)) => {
+                (*p == '_' || t.eq_ignore_ascii_case(p)) && like(text_rest, rest)
+            }
+            None => false,
+        },
+    }
+}
+
+fn substr(text: &str, start: i64, len: Option<i64>) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let n = chars.len() as i64;
+    let mut start = start;
+    let mut len = len.unwrap_or(n.max(1) * 2);
+    if start < 0 {
+        start += n;
+        if start < 0 {
+            len += start;
+            start = 0;
+        }
+    } else if start > 0 {
+        start -= 1;
+    } else if len > 0 {
+        // Position 0 sits just before the first character
+        len -= 1;
+    }
+    if len < 0 {
+        start += len;
+        len = -len;
+        if start < 0 {
+            len += start;
+            start = 0;
+        }
+    }
+    let start = start.min(n) as usize;
+    let end = (start as i64 + len.max(0)).min(n) as usize;
+    chars[start..end].iter().collect()
+}
+
+fn call(function: ScalarFunction, args: &[Value]) -> Value {
+    if function == ScalarFunction::Coalesce {
+        return args
+            .iter()
+            .find(|v| !v.is_null())
+            .cloned()
+            .unwrap_or(Value::Null);
+    }
+    if args.iter().any(Value::is_null) {
+        return Value::Null;
+    }
+    match function {
+        ScalarFunction::Abs => match to_number(&args[0]) {
+            Value::Integer(i) => i
+                .checked_abs()
+                .map_or(Value::Real((i as f64).abs()), Value::Integer),
+            v => Value::Real(v.as_f64().unwrap().abs()),
+        },
+        ScalarFunction::Instr => {
+            let haystack = to_text(&args[0]);
+            let needle = to_text(&args[1]);
+            let position = haystack
+                .find(&needle)
+                .map_or(0, |byte| haystack[..byte].chars().count() + 1);
+            Value::Integer(position as i64)
+        }
+        ScalarFunction::Length => Value::Integer(to_text(&args[0]).chars().count() as i64),
+        ScalarFunction::Lower => Value::Text(to_text(&args[0]).to_lowercase()),
+        ScalarFunction::Upper => Value::Text(to_text(&args[0]).to_uppercase()),
+        ScalarFunction::Substr => {
+            let integer = |v: &Value| match to_number(v) {
+                Value::Integer(i) => i,
+                v => v.as_f64().unwrap() as i64,
+            };
+            Value::Text(substr(
+                &to_text(&args[0]),
+                integer(&args[1]),
+                args.get(2).map(integer),
+            ))
+        }
+        ScalarFunction::Coalesce => unreachable!(),
+    }
+}
+
+fn eval(expr: &Bound, tuple: &[Value]) -> Value {
+    match expr {
+        Bound::Column(i) => tuple[*i].clone(),
+        Bound::Literal(v) => v.clone(),
+        Bound::Unary(UnaryOp::Not, e) => from_bool(eval(e, tuple).as_bool().map(|b| !b)),
+        Bound::Unary(UnaryOp::Negate, e) => match to_number(&eval(e, tuple)) {
+            Value::Null => Value::Null,
+            Value::Integer(i) => i
+                .checked_neg()
+                .map_or(Value::Real(-(i as f64)), Value::Integer),
+            v => Value::Real(-v.as_f64().unwrap()),
+        },
+        Bound::Binary(BinaryOp::And, l, r) => {
+            match (eval(l, tuple).as_bool(), eval(r, tuple).as_bool()) {
+                (Some(false), _) | (_, Some(false)) => from_bool(Some(false)),
+                (Some(true), Some(true)) => from_bool(Some(true)),
+                _ => Value::Null,
+            }
+        }
+        Bound::Binary(BinaryOp::Or, l, r) => {
+            match (eval(l, tuple).as_bool(), eval(r, tuple).as_bool()) {
+                (Some(true), _) | (_, Some(true)) => from_bool(Some(true)),
+                (Some(false), Some(false)) => from_bool(Some(false)),
+                _ => Value::Null,
+            }
+        }
+        Bound::Binary(op, l, r) => {
+            let (l, r) = (eval(l, tuple), eval(r, tuple));
+            if l.is_null() || r.is_null() {
+                return Value::Null;
+            }
+            match op {
+                BinaryOp::Eq => from_bool(Some(l == r)),
+                BinaryOp::NotEq => from_bool(Some(l != r)),
+                BinaryOp::Lt => from_bool(Some(l < r)),
+                BinaryOp::LtEq => from_bool(Some(l <= r)),
+                BinaryOp::Gt => from_bool(Some(l > r)),
+                BinaryOp::GtEq => from_bool(Some(l >= r)),
+                BinaryOp::Concat => Value::Text(to_text(&l) + &to_text(&r)),
+                op => arithmetic(*op, &l, &r),
+            }
+        }
+        Bound::IsNull(e, negated) => from_bool(Some(eval(e, tuple).is_null() != *negated)),
+        Bound::Like(e, pattern, negated) => {
+            let (v, pattern) = (eval(e, tuple), eval(pattern, tuple));
+            if v.is_null() || pattern.is_null() {
+                return Value::Null;
+            }
+            let text: Vec<char> = to_text(&v).chars().collect();
+            let pattern: Vec<char> = to_text(&pattern).chars().collect();
+            from_bool(Some(like(&text, &pattern) != *negated))
+        }
+        Bound::Function(function, args) => {
+            let args: Vec<Value> = args.iter().map(|a| eval(a, tuple)).collect();
+            call(*function, &args)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    use crate::btree::BTree;
+    use crate::pager::Pager;
+    use crate::parser::parse_select;
+    use crate::query::{execute_select, ResultSet, SelectError};
+    use crate::value::Value;
+    use crate::Row;
+
+    /// Builds tables sharing one in-memory database, each holding the rows with the given ids.
+    fn database(tables: &[(&str, Vec<u32>)]) -> HashMap<String, BTree> {
+        let pager = Arc::new(Pager::open(":memory:"));
+        let domains = ["example.com", "example.org", "test.net"];
+        let mut trees = HashMap::new();
+        for (name, ids) in tables {
+            let mut bt = BTree::create(pager.clone());
+            for &i in ids {
+                bt.insert(
+                    i as usize,
+                    Row {
+                        id: i,
+                        username: format!("user{i}"),
+                        email: format!("user{i}@{}", domains[i as usize % domains.len()]),
+                    },
+                )
+                .unwrap();
+            }
+            trees.insert(name.to_string(), bt);
+        }
+        trees
+    }
+
+    fn users(count: u32) -> HashMap<String, BTree> {
+        database(&[("users", (0..count).collect())])
+    }
+
+    fn query(
+        tables: &HashMap<String, BTree>,
+        sql: &str,
+        spill_threshold: usize,
+    ) -> Result<ResultSet, SelectError> {
+        execute_select(&parse_select(sql).unwrap(), tables, spill_threshold)
+    }
+
+    #[test]
+    fn count_users_per_domain() {
+        let bt = users(100);
+        let sql = "select substr(email, instr(email, '@') + 1) as domain, count(*), min(id), \
+                   max(username), sum(id), avg(id) from users group by domain having count(*) > 33";
+        let result = query(&bt, sql, 1000).unwrap();
+        assert_eq!(
+            result.columns,
+            vec![
+                "domain",
+                "count(*)",
+                "min(id)",
+                "max(username)",
+                "sum(id)",
+                "avg(id)"
+            ]
+        );
+        assert_eq!(
+            result.rows,
+            vec![vec![
+                Value::from("example.com"),
+                Value::Integer(34),
+                Value::Integer(0),
+                Value::from("user99"),
+                Value::Integer(1683),
+                Value::Real(49.5),
+            ]]
+        );
+        // Three groups but room for one: every new domain spills
+        assert_eq!(query(&bt, sql, 1).unwrap(), result);
+    }
+
+    #[test]
+    fn aggregates_without_group_by() {
+        let bt = users(10);
+        let result = query(
+            &bt,
+            "select count(*), sum(id) * 2 from users where id > 4",
+            10,
+        )
+        .unwrap();
+        assert_eq!(
+            result.rows,
+            vec![vec![Value::Integer(5), Value::Integer(70)]]
+        );
+
+        let result = query(
+            &bt,
+            "select count(email), sum(id), avg(id) from users where id > 99",
+            10,
+        )
+        .unwrap();
+        assert_eq!(
+            result.rows,
+            vec![vec![Value::Integer(0), Value::Null, Value::Null]]
+        );
+    }
+
+    #[test]
+    fn order_by_with_limit_and_offset() {
+        let bt = users(30);
+        let ids = |result: ResultSet| -> Vec<Value> {
+            result.rows.into_iter().map(|row| row[0].clone()).collect()
+        };
+        let sql = "select id, substr(email, instr(email, '@') + 1) as domain from users \
+                   order by domain desc, username limit 4 offset 1";
+        let expected: Vec<Value> = [14, 17, 2, 20].into_iter().map(Value::Integer).collect();
+        assert_eq!(ids(query(&bt, sql, 1000).unwrap()), expected);
+        // Runs of 3 rows spill to disk, and the limit can't trim them at that size
+        assert_eq!(ids(query(&bt, sql, 3).unwrap()), expected);
+
+        let result = query(
+            &bt,
+            "select id from users order by id % 10, 1 desc limit 3",
+            4,
+        );
+        let expected: Vec<Value> = [20, 10, 0].into_iter().map(Value::Integer).collect();
+        assert_eq!(ids(result.unwrap()), expected);
+
+        let sql = "select substr(email, instr(email, '@') + 1) as domain from users \
+                   group by domain order by max(id) limit 1";
+        assert_eq!(
+            ids(query(&bt, sql, 1000).unwrap()),
+            vec![Value::from("example.com")]
+        );
+        assert!(query(&bt, "select id from users order by 2", 1000).is_err());
+        assert!(query(&bt, "select id from users limit 'a'", 1000).is_err());
+    }
+
+    #[test]
+    fn grouping_errors() {
+        let bt = users(3);
+        assert!(query(
+            &bt,
+            "select username, count(*) from users group by email",
+            10
+        )
+        .is_err());
+        assert!(query(&bt, "select id from users having id > 1", 10).is_err());
+        assert!(query(&bt, "select sum(count(*)) from users", 10).is_err());
+        assert!(query(&bt, "select id from accounts", 10).is_err());
+    }
+
+    #[test]
+    fn inner_and_left_joins() {
+        let db = database(&[("users", (0..10).collect()), ("admins", vec![2, 5, 7, 42])]);
+        let ids = |sql: &str| -> Vec<Vec<Value>> { query(&db, sql, 1000).unwrap().rows };
+        let int = Value::Integer;
+
+        assert_eq!(
+            ids("select u.id, a.username from users u join admins a on a.id = u.id"),
+            vec![
+                vec![int(2), Value::from("user2")],
+                vec![int(5), Value::from("user5")],
+                vec![int(7), Value::from("user7")],
+            ]
+        );
+        // `a.id + 0` can't be looked up by key, so this is a scan per row with the same result
+        assert_eq!(
+            ids("select u.id, a.id from users u join admins a on a.id = u.id and a.id > 3"),
+            ids("select u.id, a.id from users u join admins a on a.id + 0 = u.id and a.id > 3"),
+        );
+        assert_eq!(
+            ids("select u.id, a.id from users u left join admins a on u.id = a.id where u.id < 4"),
+            vec![
+                vec![int(0), Value::Null],
+                vec![int(1), Value::Null],
+                vec![int(2), int(2)],
+                vec![int(3), Value::Null],
+            ]
+        );
+        // The WHERE clause sees the NULLs a LEFT JOIN pads with
+        assert_eq!(
+            ids("select a.id from admins a left join users u on u.id = a.id where u.id is null"),
+            vec![vec![int(42)]]
+        );
+        assert_eq!(
+            ids("select count(*) from users cross join admins"),
+            vec![vec![int(40)]]
+        );
+        assert_eq!(
+            ids(
+                "select u.id, a.id from admins a join users u on u.email = a.email order by 1 desc"
+            ),
+            vec![
+                vec![int(7), int(7)],
+                vec![int(5), int(5)],
+                vec![int(2), int(2)]
+            ]
+        );
+
+        assert!(query(&db, "select id from users join admins on 1", 10).is_err());
+        assert!(query(&db, "select * from users join guests on 1", 10).is_err());
+    }
+}
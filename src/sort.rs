@@ -0,0 +1,168 @@
+use std::cmp::Ordering;
+
+use crate::spill::{Merge, Run, SpillStore};
+use crate::value::Value;
+
+/// How many runs are merged at once. More runs than this are merged in several passes, so the
+/// number of pages held in memory during a merge stays bounded.
+pub const MERGE_FAN_IN: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SortKey {
+    pub column: usize,
+    pub descending: bool,
+}
+
+fn compare(keys: &[SortKey], a: &[Value], b: &[Value]) -> Ordering {
+    for key in keys {
+        let ordering = a[key.column].cmp(&b[key.column]);
+        let ordering = if key.descending {
+            ordering.reverse()
+        } else {
+            ordering
+        };
+        if ordering.is_ne() {
+            return ordering;
+        }
+    }
+    Ordering::Equal
+}
+
+/// An external merge sort over records of values.
+///
+/// Records are buffered until the buffer holds `max_rows` of them, then sorted and written out as
+/// a run through a temporary pager. Reading the result merges the runs back together. The sort
+/// is stable: records with equal keys come out in the order they went in.
+///
+/// With a limit, only the first `limit` records are ever wanted, so the buffer is trimmed back
+/// down to that instead of spilling whenever that's smaller than the buffer.
+pub struct ExternalSorter {
+    keys: Vec<SortKey>,
+    buffer: Vec<Vec<Value>>,
+    max_rows: usize,
+    limit: Option<usize>,
+    spill: Option<SpillStore>,
+    runs: Vec<Run>,
+}
+
+impl ExternalSorter {
+    pub fn new(keys: Vec<SortKey>, max_rows: usize) -> Self {
+        Self {
+            keys,
+            buffer: Vec::new(),
+            max_rows: max_rows.max(1),
+            limit: None,
+            spill: None,
+            runs: Vec::new(),
+        }
+    }
+
+    pub fn set_limit(&mut self, limit: usize) {
+        self.limit = Some(limit);
+    }
+
+    pub fn push(&mut self, record: Vec<Value>) {
+        self.buffer.push(record);
+        if self.buffer.len() < self.max_rows {
+            return;
+        }
+        match self.limit {
+            Some(limit) if limit < self.max_rows / 2 => self.trim(limit),
+            _ => self.spill_buffer(),
+        }
+    }
+
+    fn sort_buffer(&mut self) {
+        let keys = &self.keys;
+        self.buffer.sort_by(|a, b| compare(keys, a, b));
+    }
+
+    fn trim(&mut self, limit: usize) {
+        self.sort_buffer();
+        self.buffer.truncate(limit);
+    }
+
+    fn spill_buffer(&mut self) {
+        self.sort_buffer();
+        let store = self.spill.get_or_insert_with(SpillStore::new);
+        let mut writer = store.writer();
+        for record in self.buffer.drain(..) {
+            writer.write(&record);
+        }
+        self.runs.push(writer.finish());
+    }
+
+    /// Returns the records in sorted order, up to the limit if there is one.
+    pub fn sorted(&mut self) -> Box<dyn Iterator<Item = Vec<Value>> + '_> {
+        let limit = self.limit.unwrap_or(usize::MAX);
+        if self.runs.is_empty() {
+            self.sort_buffer();
+            return Box::new(self.buffer.drain(..).take(limit));
+        }
+        if !self.buffer.is_empty() {
+            self.spill_buffer();
+        }
+        let keys = self.keys.clone();
+        let store = self.spill.as_ref().unwrap();
+        let mut runs: Vec<Run> = self.runs.drain(..).collect();
+        while runs.len() > MERGE_FAN_IN {
+            // Merging the leading runs into one run put back in front keeps the sort stable
+            let batch: Vec<Run> = runs.drain(..MERGE_FAN_IN).collect();
+            let readers = batch.into_iter().map(|run| store.reader(run)).collect();
+            let mut writer = store.writer();
+            for record in Merge::new(readers, |a: &Vec<Value>, b: &Vec<Value>| {
+                compare(&keys, a, b)
+            }) {
+                writer.write(&record);
+            }
+            runs.insert(0, writer.finish());
+        }
+        let readers = runs.into_iter().map(|run| store.reader(run)).collect();
+        Box::new(
+            Merge::new(readers, move |a: &Vec<Value>, b: &Vec<Value>| {
+                compare(&keys, a, b)
+            })
+            .take(limit),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sort::{ExternalSorter, SortKey};
+    use crate::value::Value;
+
+    fn records() -> Vec<Vec<Value>> {
+        // Shuffled keys with plenty of duplicates, tagged with their input position
+        (0..2000_i64)
+            .map(|i| vec![Value::Integer((i * 7919) % 101), Value::Integer(i)])
+            .collect()
+    }
+
+    fn sort(max_rows: usize, limit: Option<usize>) -> Vec<Vec<Value>> {
+        let keys = vec![SortKey {
+            column: 0,
+            descending: true,
+        }];
+        let mut sorter = ExternalSorter::new(keys, max_rows);
+        if let Some(limit) = limit {
+            sorter.set_limit(limit);
+        }
+        for record in records() {
+            sorter.push(record);
+        }
+        let sorted = sorter.sorted().collect();
+        sorted
+    }
+
+    #[test]
+    fn external_sort_is_stable() {
+        let mut expected = records();
+        expected.sort_by(|a, b| b[0].cmp(&a[0]));
+
+        assert_eq!(sort(10_000, None), expected);
+        // 2000 / 7 runs is well over the fan-in, so this takes more than one merge pass
+        assert_eq!(sort(7, None), expected);
+        assert_eq!(sort(100, Some(15)), expected[..15]);
+    }
+}
@@ -0,0 +1,240 @@
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::page::Page;
+use crate::pager::{Offset, Pager};
+use crate::value::Value;
+
+static SPILL_FILES: AtomicUsize = AtomicUsize::new(0);
+
+/// Scratch space for operators that outgrow their memory budget.
+///
+/// Records are written out as runs: a length-prefixed byte stream chopped into the pages of a
+/// temporary pager. Pages go straight to disk, so only the page being filled or read is ever held
+/// in memory. The backing file is deleted when the store is dropped.
+#[derive(Debug)]
+pub struct SpillStore {
+    pager: Pager,
+    path: PathBuf,
+}
+
+/// A finished run of records, in the order they were written.
+#[derive(Debug)]
+pub struct Run {
+    pages: Vec<Offset>,
+    records: usize,
+}
+
+impl SpillStore {
+    pub fn new() -> Self {
+        let path = std::env::temp_dir().join(format!(
+            "tarsier-{}-{}.spill",
+            process::id(),
+            SPILL_FILES.fetch_add(1, Ordering::Relaxed)
+        ));
+        let pager = Pager::open(&path);
+        Self { pager, path }
+    }
+
+    pub fn writer(&self) -> RunWriter<'_> {
+        RunWriter {
+            store: self,
+            pages: Vec::new(),
+            page: Page::new(self.pager.page_size()),
+            pos: 0,
+            records: 0,
+        }
+    }
+
+    /// Reads a run back. Its pages are handed back to the store as they are consumed.
+    pub fn reader(&self, run: Run) -> RunReader<'_> {
+        RunReader {
+            store: self,
+            pages: run.pages.into_iter(),
+            page: None,
+            pos: self.pager.page_size(),
+            remaining: run.records,
+        }
+    }
+}
+
+impl Drop for SpillStore {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+pub struct RunWriter<'a> {
+    store: &'a SpillStore,
+    pages: Vec<Offset>,
+    page: Page,
+    pos: usize,
+    records: usize,
+}
+
+impl<'a> RunWriter<'a> {
+    pub fn write(&mut self, record: &[Value]) {
+        let mut payload = Vec::new();
+        for value in record {
+            value.encode(&mut payload);
+        }
+        self.write_bytes(&(payload.len() as u32).to_le_bytes());
+        self.write_bytes(&payload);
+        self.records += 1;
+    }
+
+    fn write_bytes(&mut self, mut bytes: &[u8]) {
+        while !bytes.is_empty() {
+            let n = bytes.len().min(self.page.size() - self.pos);
+            self.page.as_bytes_mut()[self.pos..self.pos + n].copy_from_slice(&bytes[..n]);
+            self.pos += n;
+            bytes = &bytes[n..];
+            if self.pos == self.page.size() {
+                self.flush();
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        let offset = self.store.pager.new_page();
+        self.store.pager.write_page(&offset, &self.page);
+        self.pages.push(offset);
+        self.pos = 0;
+    }
+
+    pub fn finish(mut self) -> Run {
+        if self.pos > 0 {
+            self.flush();
+        }
+        Run {
+            pages: self.pages,
+            records: self.records,
+        }
+    }
+}
+
+pub struct RunReader<'a> {
+    store: &'a SpillStore,
+    pages: std::vec::IntoIter<Offset>,
+    page: Option<Page>,
+    pos: usize,
+    remaining: usize,
+}
+
+impl<'a> RunReader<'a> {
+    fn read_bytes(&mut self, mut n: usize) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(n);
+        while n > 0 {
+            if self.pos == self.store.pager.page_size() {
+                let offset = self.pages.next().expect("Spilled run ended early");
+                self.page = Some(self.store.pager.read_page(&offset));
+                self.store.pager.recycle(offset);
+                self.pos = 0;
+            }
+            let page = self.page.as_ref().unwrap();
+            let take = n.min(page.size() - self.pos);
+            bytes.extend_from_slice(&page.as_bytes()[self.pos..self.pos + take]);
+            self.pos += take;
+            n -= take;
+        }
+        bytes
+    }
+}
+
+impl<'a> Iterator for RunReader<'a> {
+    type Item = Vec<Value>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let len = u32::from_le_bytes(self.read_bytes(4).try_into().unwrap()) as usize;
+        let payload = self.read_bytes(len);
+        let mut record = Vec::new();
+        let mut pos = 0;
+        while pos < payload.len() {
+            let (value, read) = Value::decode(&payload[pos..]);
+            record.push(value);
+            pos += read;
+        }
+        Some(record)
+    }
+}
+
+/// Merges sorted runs into one sorted stream. Ties are taken from the earliest run first.
+pub struct Merge<'a, F> {
+    readers: Vec<RunReader<'a>>,
+    heads: Vec<Option<Vec<Value>>>,
+    cmp: F,
+}
+
+impl<'a, F> Merge<'a, F>
+where
+    F: Fn(&Vec<Value>, &Vec<Value>) -> std::cmp::Ordering,
+{
+    pub fn new(mut readers: Vec<RunReader<'a>>, cmp: F) -> Self {
+        let heads = readers.iter_mut().map(|r| r.next()).collect();
+        Self {
+            readers,
+            heads,
+            cmp,
+        }
+    }
+}
+
+impl<'a, F> Iterator for Merge<'a, F>
+where
+    F: Fn(&Vec<Value>, &Vec<Value>) -> std::cmp::Ordering,
+{
+    type Item = Vec<Value>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut smallest: Option<usize> = None;
+        for (i, head) in self.heads.iter().enumerate() {
+            if let Some(record) = head {
+                let is_smaller = match smallest {
+                    None => true,
+                    Some(s) => (self.cmp)(record, self.heads[s].as_ref().unwrap()).is_lt(),
+                };
+                if is_smaller {
+                    smallest = Some(i);
+                }
+            }
+        }
+        let i = smallest?;
+        std::mem::replace(&mut self.heads[i], self.readers[i].next())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::spill::{Merge, SpillStore};
+    use crate::value::Value;
+
+    #[test]
+    fn runs_round_trip_across_pages() {
+        let store = SpillStore::new();
+        let mut runs = Vec::new();
+        for run in 0..3_i64 {
+            let mut writer = store.writer();
+            // Long enough that records straddle page boundaries
+            for i in 0..500_i64 {
+                writer.write(&[
+                    Value::Integer(i * 3 + run),
+                    Value::Text("x".repeat((i % 40) as usize)),
+                ]);
+            }
+            runs.push(writer.finish());
+        }
+        assert_eq!(runs[0].records, 500);
+
+        let readers = runs.into_iter().map(|run| store.reader(run)).collect();
+        let merged: Vec<_> = Merge::new(readers, |a: &Vec<Value>, b: &Vec<Value>| a[0].cmp(&b[0]))
+            .map(|record| record[0].clone())
+            .collect();
+        assert_eq!(merged, (0..1500).map(Value::Integer).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,320 @@
+use std::vec::IntoIter;
+
+use crate::datastore::{ExecuteResult, Row, Table, COLUMNS};
+use crate::parser::{
+    parse_statement, Command, Expr, Join, OrderingTerm, ParseError, Select, SelectItem,
+};
+use crate::query::{evaluate_constant, QueryError};
+use crate::value::Value;
+use crate::{Statement, StatementType};
+
+#[derive(Debug, PartialEq)]
+pub enum StepResult {
+    Row(Vec<Value>),
+    Done,
+    Error(ExecuteResult),
+}
+
+/// A statement that is parsed once and then executed any number of times, with new parameter
+/// values bound in between.
+///
+/// Parameters are numbered from 1 in the order they first appear in the SQL. A `:name` that
+/// appears more than once is a single parameter. Parameters that aren't bound are NULL.
+#[derive(Debug)]
+pub struct PreparedStatement {
+    command: Command,
+    parameters: Vec<Option<String>>,
+    values: Vec<Value>,
+    /// Rows of the current execution not yet returned by `step`. `None` until the first step.
+    rows: Option<IntoIter<Vec<Value>>>,
+}
+
+impl PreparedStatement {
+    pub fn new(sql: &str) -> Result<Self, ParseError> {
+        let (command, parameters) = parse_statement(sql)?;
+        if let Command::Insert(insert) = &command {
+            if insert.values.len() != COLUMNS.len() {
+                return Err(ParseError(format!(
+                    "table {} has {} columns but {} values were supplied",
+                    insert.table,
+                    COLUMNS.len(),
+                    insert.values.len()
+                )));
+            }
+        }
+        Ok(Self {
+            command,
+            values: vec![Value::Null; parameters.len()],
+            parameters,
+            rows: None,
+        })
+    }
+
+    pub fn parameter_count(&self) -> usize {
+        self.parameters.len()
+    }
+
+    /// The name of a parameter, with its leading colon, or `None` for a `?`.
+    pub fn parameter_name(&self, index: usize) -> Option<&str> {
+        self.parameters.get(index.checked_sub(1)?)?.as_deref()
+    }
+
+    pub fn parameter_index(&self, name: &str) -> Option<usize> {
+        self.parameters
+            .iter()
+            .position(|p| p.as_deref() == Some(name))
+            .map(|i| i + 1)
+    }
+
+    pub fn bind(&mut self, index: usize, value: impl Into<Value>) -> Result<(), QueryError> {
+        match index.checked_sub(1).and_then(|i| self.values.get_mut(i)) {
+            Some(slot) => {
+                *slot = value.into();
+                Ok(())
+            }
+            None => Err(QueryError(format!("parameter index {index} out of range"))),
+        }
+    }
+
+    pub fn bind_named(&mut self, name: &str, value: impl Into<Value>) -> Result<(), QueryError> {
+        match self.parameter_index(name) {
+            Some(index) => self.bind(index, value),
+            None => Err(QueryError(format!("no such parameter: {name}"))),
+        }
+    }
+
+    pub fn clear_bindings(&mut self) {
+        self.values.fill(Value::Null);
+    }
+
+    /// Gets the statement ready to run again from the start. Bindings are kept.
+    pub fn reset(&mut self) {
+        self.rows = None;
+    }
+
+    /// Runs the statement up to the next result row. The first step executes the statement;
+    /// once it's `Done`, it stays done until `reset`.
+    pub fn step(&mut self, table: &mut Table) -> StepResult {
+        if self.rows.is_none() {
+            let rows = match self.execute(table) {
+                ExecuteResult::QuerySuccess(results) => results.rows,
+                ExecuteResult::InsertSuccess => Vec::new(),
+                error => {
+                    self.rows = Some(Vec::new().into_iter());
+                    return StepResult::Error(error);
+                }
+            };
+            self.rows = Some(rows.into_iter());
+        }
+        match self.rows.as_mut().unwrap().next() {
+            Some(row) => StepResult::Row(row),
+            None => StepResult::Done,
+        }
+    }
+
+    /// Runs the statement to completion with the current bindings.
+    pub fn execute(&mut self, table: &mut Table) -> ExecuteResult {
+        self.reset();
+        match self.statement() {
+            Ok(statement) => table.execute_statement(statement),
+            Err(why) => ExecuteResult::QueryError(why.to_string()),
+        }
+    }
+
+    /// Builds the statement to execute, with the bound values in place of the parameters.
+    fn statement(&self) -> Result<Statement, QueryError> {
+        match &self.command {
+            Command::Select(select) => Ok(Statement {
+                statement_type: StatementType::Query(Box::new(self.bind_select(select))),
+                row_to_insert: None,
+            }),
+            Command::Insert(insert) => {
+                let values = insert
+                    .values
+                    .iter()
+                    .map(|e| evaluate_constant(&self.bind_expr(e)))
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(Statement {
+                    statement_type: StatementType::InsertInto(insert.table.clone()),
+                    row_to_insert: Some(row(&insert.table, &values)?),
+                })
+            }
+        }
+    }
+
+    fn bind_select(&self, select: &Select) -> Select {
+        let bind = |e: &Expr| self.bind_expr(e);
+        let bind_opt = |e: &Option<Expr>| e.as_ref().map(bind);
+        Select {
+            items: select
+                .items
+                .iter()
+                .map(|item| match item {
+                    SelectItem::Wildcard => SelectItem::Wildcard,
+                    SelectItem::Expr { expr, name } => SelectItem::Expr {
+                        expr: bind(expr),
+                        name: name.clone(),
+                    },
+                })
+                .collect(),
+            from: select.from.clone(),
+            joins: select
+                .joins
+                .iter()
+                .map(|join| Join {
+                    kind: join.kind,
+                    table: join.table.clone(),
+                    on: bind_opt(&join.on),
+                })
+                .collect(),
+            where_clause: bind_opt(&select.where_clause),
+            group_by: select.group_by.iter().map(bind).collect(),
+            having: bind_opt(&select.having),
+            order_by: select
+                .order_by
+                .iter()
+                .map(|term| OrderingTerm {
+                    expr: bind(&term.expr),
+                    descending: term.descending,
+                })
+                .collect(),
+            limit: bind_opt(&select.limit),
+            offset: bind_opt(&select.offset),
+            as_of: select.as_of.clone(),
+        }
+    }
+
+    fn bind_expr(&self, expr: &Expr) -> Expr {
+        let bind = |e: &Expr| Box::new(self.bind_expr(e));
+        match expr {
+            Expr::Parameter(i) => Expr::Literal(self.values[*i].clone()),
+            Expr::Unary(op, e) => Expr::Unary(*op, bind(e)),
+            Expr::Binary(op, l, r) => Expr::Binary(*op, bind(l), bind(r)),
+            Expr::IsNull { expr, negated } => Expr::IsNull {
+                expr: bind(expr),
+                negated: *negated,
+            },
+            Expr::Like {
+                expr,
+                pattern,
+                negated,
+            } => Expr::Like {
+                expr: bind(expr),
+                pattern: bind(pattern),
+                negated: *negated,
+            },
+            Expr::Function { name, args } => Expr::Function {
+                name: name.clone(),
+                args: args.iter().map(|a| self.bind_expr(a)).collect(),
+            },
+            Expr::Literal(_) | Expr::Column(_) | Expr::CountStar => expr.clone(),
+        }
+    }
+}
+
+/// Checks inserted values against the fixed schema of every table.
+fn row(table: &str, values: &[Value]) -> Result<Row, QueryError> {
+    let id = match &values[0] {
+        Value::Integer(i) => u32::try_from(*i).ok(),
+        _ => None,
+    };
+    let Some(id) = id else {
+        return Err(QueryError(String::from(
+            "datatype mismatch: id must be a positive integer",
+        )));
+    };
+    let text = |i: usize, max_len: usize| match &values[i] {
+        Value::Null => Err(QueryError(format!(
+            "NOT NULL constraint failed: {table}.{}",
+            COLUMNS[i]
+        ))),
+        v if v.to_string().len() > max_len => {
+            Err(QueryError(format!("{} is too long", COLUMNS[i])))
+        }
+        v => Ok(v.to_string()),
+    };
+    Ok(Row {
+        id,
+        username: text(1, 32)?,
+        email: text(2, 255)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::datastore::ExecuteResult;
+    use crate::fixtures::open_test_db;
+    use crate::statement::StepResult;
+    use crate::value::Value;
+
+    #[test]
+    fn prepare_once_execute_many() {
+        let mut table = open_test_db();
+        let mut insert = table
+            .prepare("insert into users values (?, :name, :name || '@example.com')")
+            .unwrap();
+        assert_eq!(insert.parameter_count(), 2);
+        assert_eq!(insert.parameter_name(1), None);
+        assert_eq!(insert.parameter_name(2), Some(":name"));
+        for i in 0..20_i64 {
+            insert.bind(1, i).unwrap();
+            // Quotes in bound values are just part of the value
+            insert
+                .bind_named(":name", format!("o'user{i}").as_str())
+                .unwrap();
+            assert_eq!(insert.step(&mut table), StepResult::Done);
+            assert_eq!(insert.step(&mut table), StepResult::Done);
+            insert.reset();
+        }
+        assert_eq!(insert.execute(&mut table), ExecuteResult::DuplicateKey);
+        assert!(insert.bind(3, 1_i64).is_err());
+        assert!(insert.bind_named(":email", "x").is_err());
+
+        let mut select = table
+            .prepare("select id, email from users where id >= :low and id < :low + 2")
+            .unwrap();
+        for low in [3_i64, 17] {
+            select.reset();
+            select.bind_named(":low", low).unwrap();
+            for id in low..low + 2 {
+                assert_eq!(
+                    select.step(&mut table),
+                    StepResult::Row(vec![
+                        Value::Integer(id),
+                        Value::Text(format!("o'user{id}@example.com"))
+                    ])
+                );
+            }
+            assert_eq!(select.step(&mut table), StepResult::Done);
+        }
+        // An unbound parameter is NULL, which matches nothing
+        select.clear_bindings();
+        select.reset();
+        assert_eq!(select.step(&mut table), StepResult::Done);
+    }
+
+    #[test]
+    fn insert_values_are_checked() {
+        let mut table = open_test_db();
+        assert!(table.prepare("insert into users values (1, 'a')").is_err());
+        let mut insert = table.prepare("insert into users values (?, ?, ?)").unwrap();
+        insert.bind(1, -1_i64).unwrap();
+        insert.bind(2, "name").unwrap();
+        insert.bind(3, "name@example.com").unwrap();
+        assert!(matches!(
+            insert.step(&mut table),
+            StepResult::Error(ExecuteResult::QueryError(_))
+        ));
+        insert.bind(1, 1_i64).unwrap();
+        insert.bind(2, "a".repeat(33).as_str()).unwrap();
+        assert!(matches!(
+            insert.execute(&mut table),
+            ExecuteResult::QueryError(_)
+        ));
+        insert.bind(2, Value::Null).unwrap();
+        assert!(matches!(
+            insert.execute(&mut table),
+            ExecuteResult::QueryError(_)
+        ));
+    }
+}
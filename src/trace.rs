@@ -0,0 +1,127 @@
+use std::fmt::{Debug, Display, Formatter};
+use std::io::Write;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// The environment variable that turns tracing on at startup, set to a level or `on`.
+pub const TRACE_ENV: &str = "TARSIER_TRACE";
+
+/// How much detail trace events go into. Each level includes the ones before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    /// Statements as they're executed.
+    Info = 1,
+    /// Page reads and writes, and node splits.
+    Debug = 2,
+    /// Page cache hits.
+    Trace = 3,
+}
+
+impl Display for Level {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+        };
+        write!(f, "{name}")
+    }
+}
+
+impl FromStr for Level {
+    type Err = ();
+
+    /// `on` turns everything on.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "info" => Ok(Level::Info),
+            "debug" => Ok(Level::Debug),
+            "trace" | "on" => Ok(Level::Trace),
+            _ => Err(()),
+        }
+    }
+}
+
+const OFF: u8 = 0;
+/// Not set yet, so the environment variable decides.
+const UNSET: u8 = u8::MAX;
+static LEVEL: AtomicU8 = AtomicU8::new(UNSET);
+
+/// The current level, or `None` if tracing is off.
+pub fn level() -> Option<Level> {
+    let mut level = LEVEL.load(Ordering::Relaxed);
+    if level == UNSET {
+        let from_env = std::env::var(TRACE_ENV)
+            .ok()
+            .and_then(|value| value.parse().ok());
+        set_level(from_env);
+        level = LEVEL.load(Ordering::Relaxed);
+    }
+    match level {
+        1 => Some(Level::Info),
+        2 => Some(Level::Debug),
+        3 => Some(Level::Trace),
+        _ => None,
+    }
+}
+
+pub fn set_level(level: Option<Level>) {
+    LEVEL.store(level.map_or(OFF, |l| l as u8), Ordering::Relaxed);
+}
+
+pub fn enabled(level: Level) -> bool {
+    self::level().is_some_and(|current| level <= current)
+}
+
+/// Formats an event as one line: its level and name, then each field as `key=value`.
+pub fn line(level: Level, name: &str, fields: &[(&str, &dyn Debug)]) -> String {
+    let mut line = format!("[{level}] {name}");
+    for (key, value) in fields {
+        line.push_str(&format!(" {key}={value:?}"));
+    }
+    line
+}
+
+pub fn emit(line: String) {
+    let _ = writeln!(std::io::stderr(), "{line}");
+}
+
+/// Emits a trace event to stderr if its level is enabled. Fields are only evaluated then.
+///
+/// ```text
+/// event!(Level::Debug, "page_read", page = offset.0);
+/// ```
+macro_rules! event {
+    ($level:expr, $name:literal $(, $key:ident = $value:expr)* $(,)?) => {
+        if $crate::trace::enabled($level) {
+            $crate::trace::emit($crate::trace::line(
+                $level,
+                $name,
+                &[$((stringify!($key), &$value as &dyn std::fmt::Debug)),*],
+            ));
+        }
+    };
+}
+pub(crate) use event;
+
+#[cfg(test)]
+mod tests {
+    use crate::trace::{line, Level};
+
+    #[test]
+    fn levels_and_lines() {
+        assert_eq!("on".parse(), Ok(Level::Trace));
+        assert_eq!("DEBUG".parse(), Ok(Level::Debug));
+        assert_eq!("loud".parse::<Level>(), Err(()));
+        assert!(Level::Info < Level::Debug);
+
+        assert_eq!(
+            line(
+                Level::Debug,
+                "leaf_split",
+                &[("page", &3), ("table", &"users")]
+            ),
+            "[debug] leaf_split page=3 table=\"users\""
+        );
+    }
+}
@@ -0,0 +1,212 @@
+use std::cmp::Ordering;
+use std::fmt::{Display, Formatter};
+use std::hash::{Hash, Hasher};
+
+/// A single SQL value, as produced by expressions in a query.
+///
+/// Equality, ordering and hashing use the total order used for sorting and grouping: NULL sorts
+/// first and equals itself, numbers compare by value regardless of representation, and text comes
+/// last. SQL's three-valued comparisons live in the expression evaluator instead.
+#[derive(Debug, Clone)]
+pub enum Value {
+    Null,
+    Integer(i64),
+    Real(f64),
+    Text(String),
+}
+
+const NULL_TAG: u8 = 0;
+const INTEGER_TAG: u8 = 1;
+const REAL_TAG: u8 = 2;
+const TEXT_TAG: u8 = 3;
+
+impl Value {
+    pub fn is_null(&self) -> bool {
+        matches!(self, Value::Null)
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Integer(i) => Some(*i as f64),
+            Value::Real(r) => Some(*r),
+            _ => None,
+        }
+    }
+
+    /// SQL truthiness: NULL is "unknown" and text is never true.
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Value::Null => None,
+            Value::Integer(i) => Some(*i != 0),
+            Value::Real(r) => Some(*r != 0.0),
+            Value::Text(_) => Some(false),
+        }
+    }
+
+    /// Appends a self-describing encoding of the value, used for spilling to temporary pages.
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            Value::Null => buf.push(NULL_TAG),
+            Value::Integer(i) => {
+                buf.push(INTEGER_TAG);
+                buf.extend(i.to_le_bytes());
+            }
+            Value::Real(r) => {
+                buf.push(REAL_TAG);
+                buf.extend(r.to_bits().to_le_bytes());
+            }
+            Value::Text(s) => {
+                buf.push(TEXT_TAG);
+                buf.extend((s.len() as u32).to_le_bytes());
+                buf.extend(s.as_bytes());
+            }
+        }
+    }
+
+    /// Decodes a value written by [`Value::encode`], returning it with the number of bytes read.
+    pub fn decode(buf: &[u8]) -> (Value, usize) {
+        match buf[0] {
+            NULL_TAG => (Value::Null, 1),
+            INTEGER_TAG => (
+                Value::Integer(i64::from_le_bytes(buf[1..9].try_into().unwrap())),
+                9,
+            ),
+            REAL_TAG => (
+                Value::Real(f64::from_bits(u64::from_le_bytes(
+                    buf[1..9].try_into().unwrap(),
+                ))),
+                9,
+            ),
+            TEXT_TAG => {
+                let len = u32::from_le_bytes(buf[1..5].try_into().unwrap()) as usize;
+                let text = std::str::from_utf8(&buf[5..5 + len]).unwrap().to_string();
+                (Value::Text(text), 5 + len)
+            }
+            tag => panic!("Unknown value tag {tag} in spilled record"),
+        }
+    }
+
+    fn type_rank(&self) -> u8 {
+        match self {
+            Value::Null => 0,
+            Value::Integer(_) | Value::Real(_) => 1,
+            Value::Text(_) => 2,
+        }
+    }
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Value {}
+
+impl PartialOrd for Value {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Value {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (Value::Integer(a), Value::Integer(b)) => a.cmp(b),
+            (Value::Text(a), Value::Text(b)) => a.cmp(b),
+            (a, b) if a.type_rank() == 1 && b.type_rank() == 1 => {
+                a.as_f64().unwrap().total_cmp(&b.as_f64().unwrap())
+            }
+            (a, b) => a.type_rank().cmp(&b.type_rank()),
+        }
+    }
+}
+
+impl Hash for Value {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self {
+            Value::Null => state.write_u8(NULL_TAG),
+            Value::Integer(i) => {
+                state.write_u8(INTEGER_TAG);
+                i.hash(state);
+            }
+            // Reals that hold an integer have to land in the same bucket as that integer
+            Value::Real(r)
+                if r.fract() == 0.0 && *r >= i64::MIN as f64 && *r <= i64::MAX as f64 =>
+            {
+                state.write_u8(INTEGER_TAG);
+                (*r as i64).hash(state);
+            }
+            Value::Real(r) => {
+                state.write_u8(REAL_TAG);
+                r.to_bits().hash(state);
+            }
+            Value::Text(s) => {
+                state.write_u8(TEXT_TAG);
+                s.hash(state);
+            }
+        }
+    }
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Null => write!(f, "NULL"),
+            Value::Integer(i) => write!(f, "{i}"),
+            Value::Real(r) if r.fract() == 0.0 && r.is_finite() => write!(f, "{r:.1}"),
+            Value::Real(r) => write!(f, "{r}"),
+            Value::Text(s) => write!(f, "{s}"),
+        }
+    }
+}
+
+impl From<i64> for Value {
+    fn from(i: i64) -> Self {
+        Value::Integer(i)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::Text(s.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use crate::value::Value;
+
+    #[test]
+    fn encode_round_trip() {
+        let values = vec![
+            Value::Null,
+            Value::Integer(-42),
+            Value::Real(2.5),
+            Value::Text(String::from("user@example.com")),
+        ];
+        let mut buf = Vec::new();
+        for v in &values {
+            v.encode(&mut buf);
+        }
+        let mut pos = 0;
+        for v in &values {
+            let (decoded, read) = Value::decode(&buf[pos..]);
+            assert_eq!(&decoded, v);
+            pos += read;
+        }
+        assert_eq!(pos, buf.len());
+    }
+
+    #[test]
+    fn numbers_group_together() {
+        let mut set = HashSet::new();
+        set.insert(Value::Integer(1));
+        assert!(set.contains(&Value::Real(1.0)));
+        assert!(Value::Null < Value::Integer(i64::MIN));
+        assert!(Value::Real(1.5) < Value::Integer(2));
+        assert!(Value::Integer(i64::MAX) < Value::Text(String::new()));
+    }
+}
@@ -0,0 +1,437 @@
+use std::fmt::Debug;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use memmap2::{Mmap, MmapOptions};
+
+/// The file name that opens a database held entirely in memory.
+pub const MEMORY: &str = ":memory:";
+
+/// How much of the file a connection has claimed, from least to most. Locks are taken one level
+/// at a time on the way up, but can drop straight to any lower level.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Lock {
+    Unlocked,
+    /// Any number of connections may read.
+    Shared,
+    /// One connection is going to write, and is making its changes in memory. Others may still
+    /// read, but not take a reserved lock of their own.
+    Reserved,
+    /// The connection with the reserved lock is writing its changes to the file, and nobody else
+    /// may read.
+    Exclusive,
+}
+
+/// The storage a pager keeps its pages in, addressed by byte. It knows nothing about pages, so
+/// that the page size can be read out of the storage itself.
+pub trait Vfs: Debug + Send {
+    /// How many bytes are stored.
+    fn size(&mut self) -> io::Result<u64>;
+
+    /// Fills `buf` from `offset` on. Any part of it past the end of the storage reads as zeroes.
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()>;
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()>;
+
+    /// Makes every write so far durable.
+    fn sync(&mut self) -> io::Result<()>;
+
+    /// Cuts the storage down, or grows it with zeroes, to `size` bytes.
+    fn truncate(&mut self, size: u64) -> io::Result<()>;
+
+    /// Moves to another lock level without waiting, returning whether it was granted. A refused
+    /// lock leaves the level where it was.
+    fn lock(&mut self, lock: Lock) -> io::Result<bool>;
+
+    /// Opens the journal that commits to this storage go through, if it has one yet. Storage that
+    /// doesn't outlive the process has nothing to recover after a crash, so it never has one.
+    fn journal(&self) -> io::Result<Option<Box<dyn Vfs>>>;
+
+    /// Deletes the journal, if it's empty, once a connection is done with the storage. Only the
+    /// connection with the exclusive lock may.
+    fn remove_journal(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Maps the first `len` bytes into memory for reading, if the storage can be. The map sees
+    /// later writes, but must be dropped before the storage shrinks under it.
+    fn map(&self, _len: usize) -> io::Result<Option<Mmap>> {
+        Ok(None)
+    }
+
+    /// Whether the storage can only be read. Connections to it never take more than a shared
+    /// lock.
+    fn read_only(&self) -> bool {
+        false
+    }
+}
+
+/// Pages in a file on disk. Shared and exclusive locks are advisory locks on the file itself, and
+/// the reserved lock is an exclusive lock on its journal, which only the writer needs.
+#[derive(Debug)]
+pub struct DiskVfs {
+    file: File,
+    path: PathBuf,
+    lock: Lock,
+    /// The journal, opened to hold the reserved lock the first time it's taken.
+    reserved: Option<File>,
+    read_only: bool,
+}
+
+impl DiskVfs {
+    pub fn open(filename: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&filename)?;
+        Ok(Self {
+            file,
+            path: filename.as_ref().to_path_buf(),
+            lock: Lock::Unlocked,
+            reserved: None,
+            read_only: false,
+        })
+    }
+
+    /// Opens a file that already exists, for reading only.
+    pub fn open_read_only(filename: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).open(&filename)?;
+        Ok(Self {
+            file,
+            path: filename.as_ref().to_path_buf(),
+            lock: Lock::Unlocked,
+            reserved: None,
+            read_only: true,
+        })
+    }
+
+    fn writable(&self) -> io::Result<()> {
+        if self.read_only {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("{} is open read-only", self.path.display()),
+            ));
+        }
+        Ok(())
+    }
+
+    /// The journal of `db.db` is `db.db-journal`.
+    pub fn journal_path(filename: impl AsRef<Path>) -> PathBuf {
+        let mut path = filename.as_ref().as_os_str().to_owned();
+        path.push("-journal");
+        PathBuf::from(path)
+    }
+}
+
+impl Vfs for DiskVfs {
+    fn size(&mut self) -> io::Result<u64> {
+        Ok(self.file.metadata()?.len())
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(offset))?;
+        let mut read = 0;
+        while read < buf.len() {
+            match self.file.read(&mut buf[read..])? {
+                0 => break,
+                n => read += n,
+            }
+        }
+        buf[read..].fill(0);
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        self.writable()?;
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(buf)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        self.file.sync_all()
+    }
+
+    fn truncate(&mut self, size: u64) -> io::Result<()> {
+        self.writable()?;
+        self.file.set_len(size)
+    }
+
+    fn lock(&mut self, lock: Lock) -> io::Result<bool> {
+        if lock == self.lock {
+            return Ok(true);
+        }
+        if lock < self.lock {
+            // Nobody else can hold more than a shared lock, so going back to one never waits
+            match lock {
+                Lock::Unlocked => self.file.unlock()?,
+                _ => self.file.lock_shared()?,
+            }
+            if lock < Lock::Reserved {
+                if let Some(reserved) = &self.reserved {
+                    reserved.unlock()?;
+                }
+            }
+            self.lock = lock;
+            return Ok(true);
+        }
+        let granted = match lock {
+            Lock::Unlocked => true,
+            Lock::Shared => try_lock(self.file.try_lock_shared())?,
+            Lock::Reserved => {
+                self.writable()?;
+                let path = Self::journal_path(&self.path);
+                if !self
+                    .reserved
+                    .as_ref()
+                    .is_some_and(|file| is_at(file, &path))
+                {
+                    let journal = OpenOptions::new()
+                        .write(true)
+                        .create(true)
+                        .truncate(false)
+                        .open(&path)?;
+                    self.reserved = Some(journal);
+                }
+                let journal = self.reserved.as_ref().unwrap();
+                let granted = try_lock(journal.try_lock())?;
+                // The journal may have been deleted after it was opened, and a lock on it no
+                // longer keeps anyone else out
+                if granted && !is_at(journal, &path) {
+                    self.reserved = None;
+                    false
+                } else {
+                    granted
+                }
+            }
+            Lock::Exclusive => {
+                let granted = try_lock(self.file.try_lock())?;
+                if !granted {
+                    // A lock that can't be upgraded is dropped, but with the reserved lock held
+                    // nobody else can have taken more than a shared one since
+                    self.file.lock_shared()?;
+                }
+                granted
+            }
+        };
+        if granted {
+            self.lock = lock;
+        }
+        Ok(granted)
+    }
+
+    fn journal(&self) -> io::Result<Option<Box<dyn Vfs>>> {
+        // The writer's reserved lock is on the journal, so it's there whenever there's a commit
+        // to write. Otherwise it's only there if a crash left it behind, or nobody's deleted it
+        let path = Self::journal_path(&self.path);
+        if !path.exists() {
+            return Ok(None);
+        }
+        // A journal a crash left behind still has to be noticed, though it can't be replayed
+        let journal = if self.read_only {
+            DiskVfs::open_read_only(path)?
+        } else {
+            DiskVfs::open(path)?
+        };
+        Ok(Some(Box::new(journal)))
+    }
+
+    fn remove_journal(&mut self) -> io::Result<()> {
+        self.writable()?;
+        let path = Self::journal_path(&self.path);
+        match std::fs::metadata(&path) {
+            Ok(journal) if journal.len() == 0 => {}
+            Err(why) if why.kind() != io::ErrorKind::NotFound => return Err(why),
+            _ => return Ok(()),
+        }
+        // Deleted before the reserved lock on it is let go, so anyone who opened it meanwhile
+        // finds it gone once they get the lock, and makes a new one
+        std::fs::remove_file(&path)?;
+        self.reserved = None;
+        Ok(())
+    }
+
+    fn map(&self, len: usize) -> io::Result<Option<Mmap>> {
+        // SAFETY: the pager only reads the map while it holds a lock on the file, so other
+        // connections can't write to it meanwhile. It reads pages from the map only while they're
+        // latched and not in its cache, so its own flushes don't write over them, and the file is
+        // only cut short past pages nothing reads any more
+        let map = unsafe { MmapOptions::new().len(len).map(&self.file)? };
+        Ok(Some(map))
+    }
+
+    fn read_only(&self) -> bool {
+        self.read_only
+    }
+}
+
+/// Whether `file` is the one at `path`, and not one that's since been deleted.
+#[cfg(unix)]
+fn is_at(file: &File, path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (file.metadata(), std::fs::metadata(path)) {
+        (Ok(open), Ok(at)) => (open.dev(), open.ino()) == (at.dev(), at.ino()),
+        _ => false,
+    }
+}
+
+/// Open files can't be deleted here, so the one at `path` is the one that was opened.
+#[cfg(not(unix))]
+fn is_at(_file: &File, path: &Path) -> bool {
+    path.exists()
+}
+
+fn try_lock(result: Result<(), TryLockError>) -> io::Result<bool> {
+    match result {
+        Ok(()) => Ok(true),
+        Err(TryLockError::WouldBlock) => Ok(false),
+        Err(TryLockError::Error(why)) => Err(why),
+    }
+}
+
+/// Pages held in memory, gone once it's dropped. Nothing else can see them, so locks are always
+/// granted.
+#[derive(Debug, Default)]
+pub struct MemoryVfs {
+    bytes: Vec<u8>,
+}
+
+impl MemoryVfs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Vfs for MemoryVfs {
+    fn size(&mut self) -> io::Result<u64> {
+        Ok(self.bytes.len() as u64)
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        read_from(&self.bytes, offset, buf);
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        write_to(&mut self.bytes, offset, buf);
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn truncate(&mut self, size: u64) -> io::Result<()> {
+        self.bytes.resize(size as usize, 0);
+        Ok(())
+    }
+
+    fn lock(&mut self, _lock: Lock) -> io::Result<bool> {
+        Ok(true)
+    }
+
+    fn journal(&self) -> io::Result<Option<Box<dyn Vfs>>> {
+        Ok(None)
+    }
+}
+
+/// Reads from bytes held in memory, as zeroes past their end.
+pub(crate) fn read_from(bytes: &[u8], offset: u64, buf: &mut [u8]) {
+    let start = (offset as usize).min(bytes.len());
+    let end = (start + buf.len()).min(bytes.len());
+    buf[..end - start].copy_from_slice(&bytes[start..end]);
+    buf[end - start..].fill(0);
+}
+
+/// Writes to bytes held in memory, growing them with zeroes if the write starts past their end.
+pub(crate) fn write_to(bytes: &mut Vec<u8>, offset: u64, buf: &[u8]) {
+    let end = offset as usize + buf.len();
+    if end > bytes.len() {
+        bytes.resize(end, 0);
+    }
+    bytes[offset as usize..end].copy_from_slice(buf);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fixtures::temp_path;
+    use crate::vfs::{DiskVfs, Lock, MemoryVfs, Vfs};
+
+    fn read_and_write(vfs: &mut dyn Vfs) {
+        assert_eq!(vfs.size().unwrap(), 0);
+        let mut buf = [7; 512];
+        vfs.read_at(1536, &mut buf).unwrap();
+        assert_eq!(buf, [0; 512]);
+
+        vfs.write_at(1024, &[9; 512]).unwrap();
+        assert_eq!(vfs.size().unwrap(), 1536);
+        vfs.read_at(1024, &mut buf).unwrap();
+        assert_eq!(buf, [9; 512]);
+        vfs.read_at(512, &mut buf).unwrap();
+        assert_eq!(buf, [0; 512]);
+        // Straddling the end
+        vfs.read_at(1280, &mut buf).unwrap();
+        assert_eq!(buf[..256], [9; 256]);
+        assert_eq!(buf[256..], [0; 256]);
+
+        vfs.sync().unwrap();
+        vfs.truncate(512).unwrap();
+        assert_eq!(vfs.size().unwrap(), 512);
+    }
+
+    #[test]
+    fn disk_and_memory_behave_alike() {
+        read_and_write(&mut MemoryVfs::new());
+
+        let path = temp_path("vfs");
+        let mut disk = DiskVfs::open(&path).unwrap();
+        read_and_write(&mut disk);
+        assert!(disk.journal().unwrap().is_none());
+        assert!(disk.lock(Lock::Shared).unwrap() && disk.lock(Lock::Reserved).unwrap());
+        assert!(disk.journal().unwrap().is_some());
+        assert!(disk.lock(Lock::Exclusive).unwrap());
+        disk.remove_journal().unwrap();
+        assert!(!DiskVfs::journal_path(&path).exists());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn readers_share_and_writers_take_turns() {
+        let path = temp_path("lock");
+        let mut a = DiskVfs::open(&path).unwrap();
+        let mut b = DiskVfs::open(&path).unwrap();
+        assert!(a.lock(Lock::Shared).unwrap());
+        assert!(b.lock(Lock::Shared).unwrap());
+
+        // One writer at a time, and readers carry on while it gets its changes ready
+        assert!(a.lock(Lock::Reserved).unwrap());
+        assert!(!b.lock(Lock::Reserved).unwrap());
+        // But it can't write them until the readers are done
+        assert!(!a.lock(Lock::Exclusive).unwrap());
+        assert!(!b.lock(Lock::Reserved).unwrap());
+        assert!(b.lock(Lock::Unlocked).unwrap());
+        assert!(a.lock(Lock::Exclusive).unwrap());
+        assert!(!b.lock(Lock::Shared).unwrap());
+
+        assert!(a.lock(Lock::Shared).unwrap());
+        assert!(b.lock(Lock::Shared).unwrap());
+        assert!(b.lock(Lock::Reserved).unwrap());
+        assert!(a.lock(Lock::Unlocked).unwrap());
+        assert!(b.lock(Lock::Exclusive).unwrap());
+
+        // Deleting the journal lets go of the reserved lock on it, and a lock on the deleted
+        // journal is no lock at all
+        b.remove_journal().unwrap();
+        assert!(b.lock(Lock::Unlocked).unwrap());
+        assert!(a.lock(Lock::Shared).unwrap());
+        assert!(a.lock(Lock::Reserved).unwrap());
+        assert!(DiskVfs::journal_path(&path).exists());
+        assert!(a.lock(Lock::Exclusive).unwrap());
+        a.remove_journal().unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,247 @@
+use std::collections::HashSet;
+use std::fmt::Write;
+
+use crate::pager::{Offset, Pager};
+
+/// Describes each table's B-tree as an indented outline, one page per line, with its node type,
+/// keys and sibling links.
+pub fn outline(pager: &Pager, tables: &[(String, Offset)]) -> String {
+    let mut out = String::new();
+    for (name, root) in tables {
+        writeln!(out, "{name}").unwrap();
+        walk(pager, *root, &mut |page, depth| {
+            let indent = "  ".repeat(depth + 1);
+            match page {
+                Visit::Internal { offset, separators } => {
+                    writeln!(
+                        out,
+                        "{indent}page {}: internal, separators {separators:?}",
+                        offset.0
+                    )
+                }
+                Visit::Leaf {
+                    offset,
+                    keys,
+                    last,
+                    next,
+                } => {
+                    write!(out, "{indent}page {}: leaf, keys {keys:?}", offset.0).unwrap();
+                    if let Some(last) = last {
+                        write!(out, ", previous page {}", last.0).unwrap();
+                    }
+                    if let Some(next) = next {
+                        write!(out, ", next page {}", next.0).unwrap();
+                    }
+                    writeln!(out)
+                }
+                Visit::Child { .. } => Ok(()),
+                Visit::Revisit(offset) => {
+                    writeln!(out, "{indent}page {}: already shown", offset.0)
+                }
+                Visit::Corrupt(offset) => writeln!(out, "{indent}page {}: corrupt", offset.0),
+            }
+            .unwrap()
+        });
+    }
+    out
+}
+
+/// The same structure as [`outline`], as a Graphviz DOT graph. Child pointers are solid, links to
+/// the next leaf are dashed and links to the previous leaf are dotted.
+pub fn dot(pager: &Pager, tables: &[(String, Offset)]) -> String {
+    let mut out = String::from("digraph btree {\n  node [shape=record];\n");
+    for (name, root) in tables {
+        writeln!(out, "  subgraph cluster_{name} {{\n    label=\"{name}\";").unwrap();
+        walk(pager, *root, &mut |page, _| {
+            match page {
+                Visit::Internal { offset, separators } => {
+                    writeln!(
+                        out,
+                        "    p{} [label=\"{}\"];",
+                        offset.0,
+                        label(offset, separators)
+                    )
+                }
+                Visit::Leaf {
+                    offset,
+                    keys,
+                    last,
+                    next,
+                } => {
+                    writeln!(
+                        out,
+                        "    p{} [label=\"{}\"];",
+                        offset.0,
+                        label(offset, keys)
+                    )
+                    .unwrap();
+                    if let Some(next) = next {
+                        writeln!(
+                            out,
+                            "    p{} -> p{} [style=dashed, constraint=false];",
+                            offset.0, next.0
+                        )
+                        .unwrap();
+                    }
+                    match last {
+                        Some(last) => writeln!(
+                            out,
+                            "    p{} -> p{} [style=dotted, constraint=false];",
+                            offset.0, last.0
+                        ),
+                        None => Ok(()),
+                    }
+                }
+                Visit::Child { parent, child } => {
+                    writeln!(out, "    p{} -> p{};", parent.0, child.0)
+                }
+                Visit::Corrupt(offset) => {
+                    writeln!(
+                        out,
+                        "    p{} [label=\"page {}|corrupt\"];",
+                        offset.0, offset.0
+                    )
+                }
+                Visit::Revisit(_) => Ok(()),
+            }
+            .unwrap()
+        });
+        out.push_str("  }\n");
+    }
+    out.push_str("}\n");
+    out
+}
+
+fn label(offset: Offset, keys: &[usize]) -> String {
+    let mut label = format!("page {}", offset.0);
+    for key in keys {
+        write!(label, "|{key}").unwrap();
+    }
+    label
+}
+
+enum Visit<'a> {
+    Internal {
+        offset: Offset,
+        separators: &'a [usize],
+    },
+    Leaf {
+        offset: Offset,
+        keys: &'a [usize],
+        last: Option<Offset>,
+        next: Option<Offset>,
+    },
+    Child {
+        parent: Offset,
+        child: Offset,
+    },
+    /// A page that was already visited, which only happens in a corrupt tree.
+    Revisit(Offset),
+    /// A corrupt page, which can't be read.
+    Corrupt(Offset),
+}
+
+/// Visits the pages of a tree depth first, with each page's depth below the root.
+fn walk(pager: &Pager, root: Offset, visit: &mut dyn FnMut(Visit, usize)) {
+    fn go(
+        pager: &Pager,
+        offset: Offset,
+        depth: usize,
+        seen: &mut HashSet<Offset>,
+        visit: &mut dyn FnMut(Visit, usize),
+    ) {
+        if !seen.insert(offset) {
+            return visit(Visit::Revisit(offset), depth);
+        }
+        let Ok(node) = pager.view(&offset) else {
+            return visit(Visit::Corrupt(offset), depth);
+        };
+        if node.is_leaf() {
+            let keys: Vec<usize> = (0..node.num_cells()).map(|cell| node.key(cell)).collect();
+            visit(
+                Visit::Leaf {
+                    offset,
+                    keys: &keys,
+                    last: node.last_leaf(),
+                    next: node.next_leaf(),
+                },
+                depth,
+            );
+        } else {
+            let separators: Vec<usize> = (0..node.num_separators())
+                .map(|slot| node.separator(slot))
+                .collect();
+            visit(
+                Visit::Internal {
+                    offset,
+                    separators: &separators,
+                },
+                depth,
+            );
+            for child in node.children() {
+                visit(
+                    Visit::Child {
+                        parent: offset,
+                        child,
+                    },
+                    depth,
+                );
+                go(pager, child, depth + 1, seen, visit);
+            }
+        }
+    }
+    go(pager, root, 0, &mut HashSet::new(), visit)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::btree::BTree;
+    use crate::page::Page;
+    use crate::pager::{Offset, Pager};
+    use crate::visualize::{dot, outline};
+    use crate::Row;
+
+    #[test]
+    fn outline_and_dot() {
+        let pager = Arc::new(Pager::open(":memory:"));
+        pager.commit_page(Offset(0), Page::new(pager.page_size()));
+        let mut tree = BTree::create(pager.clone());
+        for id in 0..15 {
+            let row = Row {
+                id,
+                username: format!("user{id}"),
+                email: format!("user{id}@example.com"),
+            };
+            tree.insert(id as usize, row).unwrap();
+        }
+        let tables = [(String::from("users"), tree.root())];
+
+        assert_eq!(
+            outline(&pager, &tables),
+            "users
+  page 1: internal, separators [7]
+    page 3: leaf, keys [0, 1, 2, 3, 4, 5, 6], next page 2
+    page 2: leaf, keys [7, 8, 9, 10, 11, 12, 13, 14], previous page 3
+"
+        );
+        assert_eq!(
+            dot(&pager, &tables),
+            "digraph btree {
+  node [shape=record];
+  subgraph cluster_users {
+    label=\"users\";
+    p1 [label=\"page 1|7\"];
+    p1 -> p3;
+    p3 [label=\"page 3|0|1|2|3|4|5|6\"];
+    p3 -> p2 [style=dashed, constraint=false];
+    p1 -> p2;
+    p2 [label=\"page 2|7|8|9|10|11|12|13|14\"];
+    p2 -> p3 [style=dotted, constraint=false];
+  }
+}
+"
+        );
+    }
+}